@@ -0,0 +1,541 @@
+use alloc::collections::BTreeMap;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use alloc::{format, vec};
+use core::fmt;
+
+use crate::decoder::Instruction;
+use crate::symbols::Symbols;
+use crate::PROGRAM_START;
+
+/// Mistake in an assembly program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String, // empty for a program given as text
+    pub line: usize,  // starting at 1, 0 when the file itself couldn't be read
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.file.as_str(), self.line) {
+            ("", line) => write!(f, "line {}: {}", line, self.message),
+            (file, 0) => write!(f, "{}: {}", file, self.message),
+            (file, line) => write!(f, "{}:{}: {}", file, line, self.message),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for AsmError {}
+
+/// Assembled program with the addresses of its labels
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub rom: Vec<u8>,     // bytes to load at 0x200
+    pub symbols: Symbols, // the labels, the constants aren't addresses, and the lines of the instructions
+}
+
+/// Where a line of source comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Location {
+    file: Rc<str>,
+    line: usize,
+}
+
+impl Location {
+    fn error(&self, message: String) -> AsmError {
+        AsmError { file: self.file.to_string(), line: self.line, message }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &*self.file {
+            "" => write!(f, "line {}", self.line),
+            file => write!(f, "{}:{}", file, self.line),
+        }
+    }
+}
+
+/// Argument of an instruction, before the labels are known
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand<'a> {
+    V(u8),          // V0 to VF
+    I,
+    IndirectI,      // [I], the memory I points to
+    Delay,          // DT
+    Sound,          // ST
+    Key,            // K
+    Font,           // F
+    BigFont,        // HF
+    Bcd,            // B
+    Flags,          // R, the SUPER-CHIP flag registers
+    Long(&'a str),  // LONG NNNN, the 16-bit address of `F000 NNNN`
+    Value(&'a str), // number or label
+}
+
+/// Instruction or data directive of one line
+struct Statement<'a> {
+    location: Location,
+    mnemonic: String, // upper case
+    operands: Vec<Operand<'a>>,
+}
+
+/// Deepest nesting of macro expansions, deeper ones are taken as endless recursion
+const MAX_EXPANSION_DEPTH: usize = 16;
+
+/// Lines of a `MACRO` definition and the names of its parameters
+struct Macro {
+    params: Vec<String>,
+    body: Vec<(Location, String)>,
+}
+
+/// Compiles a program written with the mnemonics of the disassembler into
+/// the bytes to load at 0x200.
+///
+/// Each line holds an optional `label:`, an instruction like `LD V0, 0x0a` or
+/// `JP loop`, and an optional `; comment`. `DB` and `DW` put bytes and
+/// big-endian words in the program. Numbers are decimal, `0x` hexadecimal
+/// or `0b` binary.
+///
+/// `const NAME = value` names a number. `MACRO name a, b` up to `ENDM` defines
+/// lines that `name x, y` pastes with the parameters replaced, labels ending
+/// with `@` get a different name in each paste. `REPT n` up to `ENDR` repeats
+/// lines `n` times. `%include "file"` needs [`assemble_with`]
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let program = assemble_with("", source, |_, name| Err(format!("can't include `{}` in a program given as text", name)))?;
+    Ok(program.rom)
+}
+
+/// Like [`assemble`], with `%include "file"` pasting the lines of another file.
+///
+/// `include` gets the name of the file with the include and the name it gives,
+/// and returns the name to show in errors with the text of the file
+pub fn assemble_with<F>(name: &str, source: &str, mut include: F) -> Result<Program, AsmError>
+where
+    F: FnMut(&str, &str) -> Result<(String, String), String>,
+{
+    let mut lines = Vec::new();
+    read(Rc::from(name), source, &mut include, &mut Vec::new(), &mut lines)?;
+    let mut expanded = Vec::new();
+    expand(&lines, &mut BTreeMap::new(), &mut expanded, 0, &mut 0)?;
+
+    let mut symbols = BTreeMap::new();
+    let mut labels = Symbols::new();
+    let mut statements = Vec::new();
+    let mut addr = PROGRAM_START;
+
+    // first pass, where the labels are
+    for (location, text) in &expanded {
+        let error = |message: String| location.error(message);
+
+        let mut text = text.as_str();
+        while let Some((name, rest)) = text.split_once(':') {
+            define(&mut symbols, name.trim(), addr, location).map_err(error)?;
+            // the first label of an address names it in the symbol file
+            if labels.name(addr).is_none() {
+                labels.insert(addr, name.trim());
+            }
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        if mnemonic.eq_ignore_ascii_case("const") {
+            let (name, value) = rest.split_once('=').ok_or_else(|| error("expected `const NAME = value`".to_string()))?;
+            // constants only see the names defined above them
+            let value = resolve(value.trim(), &symbols).map_err(error)?;
+            define(&mut symbols, name.trim(), value as usize, location).map_err(error)?;
+            continue;
+        }
+
+        let operands: Vec<Operand> = match rest.trim() {
+            "" => Vec::new(),
+            rest => rest.split(',').map(|o| operand(o.trim())).collect(),
+        };
+        let statement = Statement { location: location.clone(), mnemonic: mnemonic.to_ascii_uppercase(), operands };
+        // a program given as text has no file to point into
+        if !location.file.is_empty() {
+            labels.insert_line(addr, &location.file, location.line);
+        }
+
+        addr += match (statement.mnemonic.as_str(), statement.operands.as_slice()) {
+            ("DB", operands) => operands.len(),
+            ("DW", operands) => operands.len() * 2,
+            (_, [_, Operand::Long(_)]) => 4,
+            _ => 2,
+        };
+        statements.push(statement);
+    }
+
+    // second pass, the bytes
+    let mut rom = Vec::new();
+    for statement in &statements {
+        let bytes = encode(statement, &symbols).map_err(|message| statement.location.error(message))?;
+        rom.extend(bytes);
+    }
+    Ok(Program { rom, symbols: labels })
+}
+
+/// Assembles the file at `path`, the files it includes are looked for next to it
+#[cfg(feature = "std")]
+pub fn assemble_file<P: AsRef<std::path::Path>>(path: P) -> Result<Program, AsmError> {
+    use std::path::{Component, Path, PathBuf};
+
+    // `lib/../main.s` has to be the same file as `main.s` to notice include loops
+    let normalize = |path: &Path| {
+        let mut normal = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::ParentDir if matches!(normal.components().next_back(), Some(Component::Normal(_))) => {
+                    normal.pop();
+                }
+                Component::CurDir => {}
+                component => normal.push(component),
+            }
+        }
+        normal
+    };
+
+    let path = normalize(path.as_ref());
+    let name = path.display().to_string();
+    let source = std::fs::read_to_string(&path)
+        .map_err(|e| AsmError { file: name.clone(), line: 0, message: e.to_string() })?;
+
+    assemble_with(&name, &source, |from, included| {
+        let path = normalize(&Path::new(from).parent().unwrap_or(Path::new("")).join(included));
+        let text = std::fs::read_to_string(&path).map_err(|e| format!("can't include `{}`: {}", path.display(), e))?;
+        Ok((path.display().to_string(), text))
+    })
+}
+
+/// Puts the lines of `text` and of the files it includes in `out`, without the comments
+fn read<F>(
+    file: Rc<str>,
+    text: &str,
+    include: &mut F,
+    including: &mut Vec<Rc<str>>, // files whose include is being read, to catch loops
+    out: &mut Vec<(Location, String)>,
+) -> Result<(), AsmError>
+where
+    F: FnMut(&str, &str) -> Result<(String, String), String>,
+{
+    including.push(file.clone());
+    for (index, raw) in text.lines().enumerate() {
+        let location = Location { file: file.clone(), line: index + 1 };
+        let text = raw.split(';').next().unwrap_or("").trim();
+
+        let directive = text.get(..8).filter(|d| d.eq_ignore_ascii_case("%include"));
+        if directive.is_none() {
+            out.push((location, text.to_string()));
+            continue;
+        }
+
+        let name = text[8..].trim();
+        let name = name
+            .strip_prefix('"')
+            .and_then(|n| n.strip_suffix('"'))
+            .ok_or_else(|| location.error("expected `%include \"file\"`".to_string()))?;
+        let (path, source) = include(&file, name).map_err(|message| location.error(message))?;
+        if including.iter().any(|f| **f == *path) {
+            return Err(location.error(format!("`{}` ends up including itself", path)));
+        }
+        read(Rc::from(path), &source, include, including, out)?;
+    }
+    including.pop();
+    Ok(())
+}
+
+/// Gives `value` to a new label or constant
+fn define(
+    symbols: &mut BTreeMap<String, (usize, Location)>,
+    name: &str,
+    value: usize,
+    location: &Location,
+) -> Result<(), String> {
+    if !is_identifier(name) {
+        return Err(format!("`{}` is not a valid name", name));
+    }
+    if let Some((_, first)) = symbols.get(name) {
+        return Err(format!("`{}` is already defined at {}", name, first));
+    }
+    symbols.insert(name.to_string(), (value, location.clone()));
+    Ok(())
+}
+
+/// Pastes the macros and repetitions of `lines` into `out`, the lines keep
+/// their number except the ones of macros which take the one of the call
+fn expand(
+    lines: &[(Location, String)],
+    macros: &mut BTreeMap<String, Macro>,
+    out: &mut Vec<(Location, String)>,
+    depth: usize,
+    pastes: &mut usize, // gives the labels ending with `@` a different name in each paste
+) -> Result<(), AsmError> {
+    let mut index = 0;
+    while index < lines.len() {
+        let (location, text) = &lines[index];
+        let error = |message: String| location.error(message);
+        index += 1;
+
+        // labels in front of a macro call stay where they are
+        let mut text = text.as_str();
+        while let Some((name, rest)) = text.split_once(':') {
+            if !is_identifier(name.trim()) {
+                break;
+            }
+            out.push((location.clone(), format!("{}:", name.trim())));
+            text = rest.trim();
+        }
+
+        let (word, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        match word.to_ascii_uppercase().as_str() {
+            "MACRO" => {
+                let (name, params) = rest.trim().split_once(char::is_whitespace).unwrap_or((rest.trim(), ""));
+                if !is_identifier(name) {
+                    return Err(error(format!("`{}` is not a valid macro name", name)));
+                }
+                let params: Vec<String> = params.split(',').map(str::trim).filter(|p| !p.is_empty()).map(String::from).collect();
+                let end = block_end(lines, index, "MACRO", "ENDM").ok_or_else(|| error("`MACRO` without `ENDM`".to_string()))?;
+                macros.insert(name.to_string(), Macro { params, body: lines[index..end].to_vec() });
+                index = end + 1;
+            }
+            "REPT" => {
+                let count = resolve(rest.trim(), &BTreeMap::new()).map_err(error)?;
+                let end = block_end(lines, index, "REPT", "ENDR").ok_or_else(|| error("`REPT` without `ENDR`".to_string()))?;
+                for _ in 0..count {
+                    expand(&lines[index..end], macros, out, depth, pastes)?;
+                }
+                index = end + 1;
+            }
+            "ENDM" | "ENDR" => return Err(error(format!("`{}` without its start", word))),
+            _ if macros.contains_key(word) => {
+                if depth == MAX_EXPANSION_DEPTH {
+                    return Err(error(format!("macro `{}` expands without end", word)));
+                }
+                let args: Vec<&str> = match rest.trim() {
+                    "" => Vec::new(),
+                    rest => rest.split(',').map(str::trim).collect(),
+                };
+                let definition = &macros[word];
+                if args.len() != definition.params.len() {
+                    let expected = definition.params.len();
+                    return Err(error(format!("macro `{}` takes {} arguments, not {}", word, expected, args.len())));
+                }
+
+                *pastes += 1;
+                let body: Vec<(Location, String)> = definition
+                    .body
+                    .iter()
+                    .map(|(_, text)| (location.clone(), substitute(text, &definition.params, &args, *pastes)))
+                    .collect();
+                expand(&body, macros, out, depth + 1, pastes)?;
+            }
+            _ if !text.is_empty() => out.push((location.clone(), text.to_string())),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Index of the line that closes the block opened before `start`
+fn block_end(lines: &[(Location, String)], start: usize, open: &str, close: &str) -> Option<usize> {
+    let mut nesting = 0;
+    for (index, (_, text)) in lines.iter().enumerate().skip(start) {
+        let word = text.split_whitespace().next().unwrap_or("");
+        if word.eq_ignore_ascii_case(open) {
+            nesting += 1;
+        } else if word.eq_ignore_ascii_case(close) {
+            if nesting == 0 {
+                return Some(index);
+            }
+            nesting -= 1;
+        }
+    }
+    None
+}
+
+/// Line of a macro with its parameters replaced by the arguments of a call
+fn substitute(text: &str, params: &[String], args: &[&str], paste: usize) -> String {
+    let mut result = String::new();
+    let mut word = String::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            word.push(c);
+            if chars.peek().is_some_and(|n| n.is_ascii_alphanumeric() || *n == '_') {
+                continue;
+            }
+            match params.iter().position(|p| *p == word) {
+                Some(i) => result.push_str(args[i]),
+                None => result.push_str(&word),
+            }
+            if chars.peek() == Some(&'@') {
+                chars.next();
+                result.push_str(&format!("_{}", paste));
+            }
+            word.clear();
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Classifies one argument
+fn operand(text: &str) -> Operand<'_> {
+    let upper = text.to_ascii_uppercase();
+    match upper.as_str() {
+        "I" => Operand::I,
+        "[I]" => Operand::IndirectI,
+        "DT" => Operand::Delay,
+        "ST" => Operand::Sound,
+        "K" => Operand::Key,
+        "F" => Operand::Font,
+        "HF" => Operand::BigFont,
+        "B" => Operand::Bcd,
+        "R" => Operand::Flags,
+        _ if upper.len() == 2 && upper.starts_with('V') => match u8::from_str_radix(&upper[1..], 16) {
+            Ok(x) => Operand::V(x),
+            Err(_) => Operand::Value(text),
+        },
+        _ if upper.starts_with("LONG ") => Operand::Long(text[5..].trim()),
+        _ => Operand::Value(text),
+    }
+}
+
+/// Bytes of one statement
+fn encode(statement: &Statement, symbols: &BTreeMap<String, (usize, Location)>) -> Result<Vec<u8>, String> {
+    use Operand::*;
+
+    let value = |text: &str, max: u32| {
+        let value = resolve(text, symbols)?;
+        if value > max {
+            return Err(format!("`{}` is larger than {:#x}", text, max));
+        }
+        Ok(value)
+    };
+    let addr = |text: &str| value(text, 0xFFF).map(|v| v as u16);
+    let byte = |text: &str| value(text, 0xFF).map(|v| v as u8);
+    let nibble = |text: &str| value(text, 0xF).map(|v| v as u8);
+
+    let instruction = match (statement.mnemonic.as_str(), statement.operands.as_slice()) {
+        ("DB", values) => return values.iter().map(|v| data(v, 0xFF, &value).map(|b| b as u8)).collect(),
+        ("DW", values) => {
+            let mut bytes = Vec::new();
+            for v in values {
+                bytes.extend((data(v, 0xFFFF, &value)? as u16).to_be_bytes());
+            }
+            return Ok(bytes);
+        }
+        ("LD", [I, Long(target)]) => {
+            let mut bytes = vec![0xF0, 0x00];
+            bytes.extend((value(target, 0xFFFF)? as u16).to_be_bytes());
+            return Ok(bytes);
+        }
+
+        ("HALT", []) => Instruction::Halt,
+        ("SYS", [Value(a)]) => Instruction::Sys { addr: addr(a)? },
+        ("SCD", [Value(n)]) => Instruction::ScrollDown { n: nibble(n)? },
+        ("SCU", [Value(n)]) => Instruction::ScrollUp { n: nibble(n)? },
+        ("CLS", []) => Instruction::Cls,
+        ("RET", []) => Instruction::Ret,
+        ("SCR", []) => Instruction::ScrollRight,
+        ("SCL", []) => Instruction::ScrollLeft,
+        ("EXIT", []) => Instruction::Exit,
+        ("LOW", []) => Instruction::LowRes,
+        ("HIGH", []) => Instruction::HighRes,
+        ("JP", [Value(a)]) => Instruction::Jump { addr: addr(a)? },
+        ("JP", [V(0), Value(a)]) => {
+            let addr = addr(a)?;
+            Instruction::JumpV0 { addr, x: (addr >> 8) as u8 }
+        }
+        ("CALL", [Value(a)]) => Instruction::Call { addr: addr(a)? },
+        ("SE", [V(x), Value(kk)]) => Instruction::SkipEqByte { x: *x, kk: byte(kk)? },
+        ("SE", [V(x), V(y)]) => Instruction::SkipEq { x: *x, y: *y },
+        ("SNE", [V(x), Value(kk)]) => Instruction::SkipNeByte { x: *x, kk: byte(kk)? },
+        ("SNE", [V(x), V(y)]) => Instruction::SkipNe { x: *x, y: *y },
+        ("SAVE", [V(x), V(y)]) => Instruction::SaveRange { x: *x, y: *y },
+        ("LOAD", [V(x), V(y)]) => Instruction::LoadRange { x: *x, y: *y },
+        ("LD", [V(x), Value(kk)]) => Instruction::LoadByte { x: *x, kk: byte(kk)? },
+        ("LD", [V(x), V(y)]) => Instruction::Load { x: *x, y: *y },
+        ("LD", [I, Value(a)]) => Instruction::LoadI { addr: addr(a)? },
+        ("LD", [V(x), Delay]) => Instruction::LoadDelay { x: *x },
+        ("LD", [V(x), Key]) => Instruction::WaitKey { x: *x },
+        ("LD", [Delay, V(x)]) => Instruction::SetDelay { x: *x },
+        ("LD", [Sound, V(x)]) => Instruction::SetSound { x: *x },
+        ("LD", [Font, V(x)]) => Instruction::LoadFont { x: *x },
+        ("LD", [BigFont, V(x)]) => Instruction::LoadBigFont { x: *x },
+        ("LD", [Bcd, V(x)]) => Instruction::Bcd { x: *x },
+        ("LD", [IndirectI, V(x)]) => Instruction::StoreRegs { x: *x },
+        ("LD", [V(x), IndirectI]) => Instruction::LoadRegs { x: *x },
+        ("LD", [Flags, V(x)]) => Instruction::SaveFlags { x: *x },
+        ("LD", [V(x), Flags]) => Instruction::LoadFlags { x: *x },
+        ("ADD", [V(x), Value(kk)]) => Instruction::AddByte { x: *x, kk: byte(kk)? },
+        ("ADD", [V(x), V(y)]) => Instruction::Add { x: *x, y: *y },
+        ("ADD", [I, V(x)]) => Instruction::AddI { x: *x },
+        ("OR", [V(x), V(y)]) => Instruction::Or { x: *x, y: *y },
+        ("AND", [V(x), V(y)]) => Instruction::And { x: *x, y: *y },
+        ("XOR", [V(x), V(y)]) => Instruction::Xor { x: *x, y: *y },
+        ("SUB", [V(x), V(y)]) => Instruction::Sub { x: *x, y: *y },
+        ("SUBN", [V(x), V(y)]) => Instruction::SubN { x: *x, y: *y },
+        ("SHR", [V(x)]) => Instruction::ShiftRight { x: *x, y: *x },
+        ("SHR", [V(x), V(y)]) => Instruction::ShiftRight { x: *x, y: *y },
+        ("SHL", [V(x)]) => Instruction::ShiftLeft { x: *x, y: *x },
+        ("SHL", [V(x), V(y)]) => Instruction::ShiftLeft { x: *x, y: *y },
+        ("RND", [V(x), Value(kk)]) => Instruction::Random { x: *x, kk: byte(kk)? },
+        ("DRW", [V(x), V(y), Value(n)]) => Instruction::Draw { x: *x, y: *y, n: nibble(n)? },
+        ("SKP", [V(x)]) => Instruction::SkipKey { x: *x },
+        ("SKNP", [V(x)]) => Instruction::SkipNoKey { x: *x },
+        ("PLANE", [Value(mask)]) => Instruction::SelectPlanes { mask: nibble(mask)? },
+        ("AUDIO", []) => Instruction::LoadAudio,
+        ("PITCH", [V(x)]) => Instruction::SetPitch { x: *x },
+
+        (mnemonic, []) => return Err(format!("`{}` needs operands or doesn't exist", mnemonic)),
+        (mnemonic, _) => return Err(format!("`{}` doesn't take these operands", mnemonic)),
+    };
+    Ok(instruction.to_bytes().to_vec())
+}
+
+/// Value of a `DB` or `DW` item
+fn data<F>(operand: &Operand, max: u32, value: &F) -> Result<u32, String>
+where
+    F: Fn(&str, u32) -> Result<u32, String>,
+{
+    match operand {
+        Operand::Value(text) => value(text, max),
+        _ => Err("data must be numbers or labels".to_string()),
+    }
+}
+
+/// Number written in the program, the address of a label or a constant
+fn resolve(text: &str, symbols: &BTreeMap<String, (usize, Location)>) -> Result<u32, String> {
+    let number = if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix("0b").or_else(|| text.strip_prefix("0B")) {
+        u32::from_str_radix(binary, 2).ok()
+    } else {
+        text.parse().ok()
+    };
+
+    match number {
+        Some(number) => Ok(number),
+        None if is_identifier(text) => match symbols.get(text) {
+            Some(&(value, _)) => Ok(value as u32),
+            None => Err(format!("unknown name `{}`", text)),
+        },
+        None => Err(format!("`{}` is not a number", text)),
+    }
+}
+
+/// Tells if `text` can name a label
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
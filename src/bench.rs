@@ -0,0 +1,145 @@
+use std::process;
+use std::time::{Duration, Instant};
+
+use cpu_caller::{RunSummary, StopReason, CPU};
+
+use crate::cli::Options;
+
+/// Instructions executed unless `--instructions` says otherwise
+const DEFAULT_BUDGET: u64 = 10_000_000;
+
+/// What `measure` found out
+struct Measurement {
+    executed: u64,
+    frames: u64,
+    restarts: u64,
+    framed: Duration,   // time the frames took
+    overhead: Duration, // cost of each call to the CPU, see `measure`
+}
+
+/// Runs the CPU without a screen as fast as it goes and prints how fast that was
+pub fn run(cpu: &mut CPU, options: &Options) {
+    let per_frame = cpu.instructions_per_frame() as u64;
+    let m = match measure(cpu, options.instructions.unwrap_or(DEFAULT_BUDGET)) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let seconds = m.framed.as_secs_f64();
+    println!("instructions:      {}", m.executed);
+    println!("frames:            {} of {} instructions", m.frames, per_frame);
+    println!("time:              {:.3} s", seconds);
+    println!("speed:             {:.2} MIPS", m.executed as f64 / seconds / 1e6);
+    let frame = micros(m.framed) / m.frames as f64;
+    println!("time per frame:    {:.2} µs, {:.0} times faster than 60 Hz", frame, 1e6 / 60.0 / frame);
+    println!("dispatch overhead: {:.1} ns per call to the CPU", m.overhead.as_secs_f64() * 1e9);
+    if m.restarts > 0 {
+        println!("the program stopped and was restarted {} times", m.restarts);
+    }
+}
+
+/// Executes `budget` instructions a frame at a time, the way frontends run
+/// them, restarting the program whenever it stops. A program that stops
+/// before executing anything would be restarted forever and is an error
+fn measure(cpu: &mut CPU, budget: u64) -> Result<Measurement, String> {
+    let per_frame = cpu.instructions_per_frame() as u64;
+
+    let mut executed = 0;
+    let mut frames = 0;
+    let mut restarts = 0;
+    let mut since_restart = 0;
+    let start = Instant::now();
+    while executed < budget {
+        let summary = cpu.advance(per_frame.min(budget - executed) as usize);
+        executed += summary.executed as u64;
+        since_restart += summary.executed as u64;
+        frames += 1;
+        if stopped(summary.stopped) {
+            // the Fx0A that waits for a key counts as executed, nothing else did
+            let waited = summary.stopped == StopReason::WaitingForKey;
+            if since_restart == waited as u64 {
+                return Err(format!("program stops immediately: {:?}", summary.stopped));
+            }
+            cpu.reset(true);
+            restarts += 1;
+            since_restart = 0;
+        }
+    }
+    let framed = start.elapsed();
+
+    // the same instructions with a call to `advance` for each one instead of
+    // each frame, the difference is what entering and leaving the CPU costs
+    let sample = (budget / 10).max(1);
+    let batched = time(cpu, sample, |cpu| cpu.advance(per_frame as usize));
+    let single = time(cpu, sample, |cpu| cpu.advance(1));
+    let calls = sample as f64 - sample as f64 / per_frame.max(1) as f64;
+    // the timings are noisy, the difference can't go below nothing
+    let overhead = Duration::from_secs_f64((single.as_secs_f64() - batched.as_secs_f64()).max(0.0) / calls.max(1.0));
+
+    Ok(Measurement { executed, frames, restarts, framed, overhead })
+}
+
+/// Time `run` takes to execute `count` instructions, restarting the program
+/// when it stops. `measure` made sure it executes some before that
+fn time(cpu: &mut CPU, count: u64, mut run: impl FnMut(&mut CPU) -> RunSummary) -> Duration {
+    cpu.reset(true);
+    let start = Instant::now();
+    let mut executed = 0;
+    while executed < count {
+        let summary = run(cpu);
+        executed += summary.executed as u64;
+        if stopped(summary.stopped) {
+            cpu.reset(true);
+        }
+    }
+    start.elapsed()
+}
+
+/// Tells if the program can't go on without a restart
+fn stopped(reason: StopReason) -> bool {
+    !matches!(reason, StopReason::InstructionBudgetExhausted | StopReason::WaitingForVblank)
+}
+
+fn micros(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1e6
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpu(rom: &[u8]) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load_rom(rom).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn programs_that_stop_right_away_are_errors() {
+        // halts, waits for a key
+        for rom in [[0x00, 0x00], [0xF0, 0x0A]] {
+            let e = measure(&mut cpu(&rom), 1000).err().unwrap();
+            assert!(e.starts_with("program stops immediately"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn programs_that_stop_later_are_restarted() {
+        // LD V0, 1 then waits for a key
+        let m = measure(&mut cpu(&[0x60, 0x01, 0xF0, 0x0A]), 100).unwrap();
+        assert_eq!(m.executed, 100);
+        assert_eq!(m.restarts, 50);
+    }
+
+    #[test]
+    fn loops_run_the_whole_budget() {
+        // ADD V0, 1 and jump back
+        let mut cpu = cpu(&[0x70, 0x01, 0x12, 0x00]);
+        let frames = 1000u64.div_ceil(cpu.instructions_per_frame() as u64);
+        let m = measure(&mut cpu, 1000).unwrap();
+        assert_eq!((m.executed, m.frames, m.restarts), (1000, frames, 0));
+    }
+}
@@ -0,0 +1,209 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::{CpuError, Machine, Quirks, Variant, XorShiftRng, CPU, PROGRAM_START, XO_MEMORY_SIZE};
+
+// the fonts live below this address so smaller memories can't hold them
+const MIN_MEMORY_SIZE: usize = 0x200;
+
+/// Invalid setting found by [`CpuBuilder::build`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    MemorySize(usize), // memory must be between 512 bytes and 64 KB
+    StartAddress(u16), // the start address is outside of memory
+    StackDepth,        // the stack must hold at least one return address
+    RomTooLarge { size: usize, available: usize },
+    XoChipMemory(usize), // XO-CHIP programs need the whole 64 KB
+    Load(CpuError),      // any other reason the ROM couldn't be loaded
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::MemorySize(size) => write!(
+                f,
+                "memory size {} is not between {} and {} bytes",
+                size, MIN_MEMORY_SIZE, XO_MEMORY_SIZE
+            ),
+            BuildError::StartAddress(addr) => {
+                write!(f, "start address {:#05x} is outside of memory", addr)
+            }
+            BuildError::StackDepth => write!(f, "the stack must hold at least one address"),
+            BuildError::RomTooLarge { size, available } => write!(
+                f,
+                "ROM of {} bytes does not fit in the {} bytes after the start address",
+                size, available
+            ),
+            BuildError::XoChipMemory(size) => {
+                write!(f, "XO-CHIP needs {} bytes of memory, not {}", XO_MEMORY_SIZE, size)
+            }
+            BuildError::Load(e) => write!(f, "can't load the ROM: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BuildError {}
+
+/// Configures a [`CPU`] step by step:
+///
+/// ```
+/// use cpu_caller::{CpuBuilder, Machine};
+///
+/// let cpu = CpuBuilder::new()
+///     .machine(Machine::SchipModern)
+///     .seed(42)
+///     .rom(&[0x00, 0xFD])
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CpuBuilder {
+    memory_size: Option<usize>,
+    stack_depth: Option<usize>,
+    machine: Option<Machine>,
+    quirks: Option<Quirks>,
+    seed: Option<u32>,
+    rom: Option<Vec<u8>>,
+    start_address: Option<u16>,
+}
+
+impl CpuBuilder {
+    /// Starts from the same settings as [`CPU::new`]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bytes of memory, 4096 by default and 64 KB for XO-CHIP
+    pub fn memory_size(mut self, size: usize) -> Self {
+        self.memory_size = Some(size);
+        self
+    }
+
+    /// Nested calls the stack can hold, 16 by default. Deeper stacks help
+    /// recursive test programs that the original interpreters couldn't run
+    pub fn stack_depth(mut self, depth: usize) -> Self {
+        self.stack_depth = Some(depth);
+        self
+    }
+
+    /// Emulates one of the historical interpreters
+    pub fn machine(mut self, machine: Machine) -> Self {
+        self.machine = Some(machine);
+        self
+    }
+
+    /// Compatibility settings, these win over the ones of the machine
+    pub fn quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = Some(quirks);
+        self
+    }
+
+    /// Seed of the random number generator used by CXNN
+    pub fn seed(mut self, seed: u32) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Program loaded at the start address
+    pub fn rom(mut self, rom: &[u8]) -> Self {
+        self.rom = Some(rom.to_vec());
+        self
+    }
+
+    /// Address of the first instruction, 0x200 by default
+    pub fn start_address(mut self, addr: u16) -> Self {
+        self.start_address = Some(addr);
+        self
+    }
+
+    /// Checks the settings and creates the CPU
+    pub fn build(self) -> Result<CPU, BuildError> {
+        let mut cpu = match self.machine {
+            Some(machine) => CPU::for_machine(machine),
+            None => CPU::new(),
+        };
+
+        if let Some(quirks) = self.quirks {
+            cpu.set_quirks(quirks);
+        }
+
+        if let Some(size) = self.memory_size {
+            if !(MIN_MEMORY_SIZE..=XO_MEMORY_SIZE).contains(&size) {
+                return Err(BuildError::MemorySize(size));
+            }
+            if size < XO_MEMORY_SIZE && self.machine.is_some_and(|machine| machine.variant() == Variant::XoChip) {
+                return Err(BuildError::XoChipMemory(size));
+            }
+            cpu.resize_memory(size);
+        }
+
+        match self.stack_depth {
+            Some(0) => return Err(BuildError::StackDepth),
+            Some(depth) => cpu.resize_stack(depth),
+            None => {}
+        }
+
+        let start = self.start_address.unwrap_or(PROGRAM_START as u16);
+        let memory_size = cpu.memory_size();
+        if start as usize >= memory_size {
+            return Err(BuildError::StartAddress(start));
+        }
+
+        if let Some(seed) = self.seed {
+            cpu.set_rng(Box::new(XorShiftRng::new(seed)));
+        }
+
+        match self.rom {
+            Some(rom) => match cpu.load_rom_at(&rom, start) {
+                Ok(()) => {}
+                Err(CpuError::RomTooLarge { size, available }) => return Err(BuildError::RomTooLarge { size, available }),
+                Err(e) => return Err(BuildError::Load(e)),
+            },
+            None => cpu.set_start_address(start as usize),
+        }
+
+        Ok(cpu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::StopReason;
+
+    #[test]
+    fn xochip_needs_all_its_memory() {
+        let built = CpuBuilder::new().machine(Machine::XoChip).memory_size(4096).build();
+        assert_eq!(built.err(), Some(BuildError::XoChipMemory(4096)));
+        assert!(CpuBuilder::new().machine(Machine::XoChip).memory_size(XO_MEMORY_SIZE).build().is_ok());
+    }
+
+    #[test]
+    fn rom_too_large() {
+        let built = CpuBuilder::new().memory_size(0x300).rom(&[0; 0x101]).build();
+        assert_eq!(built.err(), Some(BuildError::RomTooLarge { size: 0x101, available: 0x100 }));
+    }
+
+    #[test]
+    fn stacks_hold_their_depth() {
+        // CALL 0x200, calling itself forever
+        let mut cpu = CpuBuilder::new().stack_depth(40).rom(&[0x22, 0x00]).build().unwrap();
+        assert_eq!(cpu.run(), StopReason::Error(CpuError::StackOverflow { addr: PROGRAM_START as u16 }));
+        assert_eq!((cpu.stack_pointer(), cpu.instructions_executed()), (40, 40));
+        assert_eq!(CpuBuilder::new().stack_depth(0).build().err(), Some(BuildError::StackDepth));
+    }
+
+    #[test]
+    fn memory_holds_its_size() {
+        let mut cpu = CpuBuilder::new().memory_size(0x300).build().unwrap();
+        assert_eq!(cpu.read_memory(0x2FF, 2), Err(CpuError::OutOfBounds { addr: 0x300 }));
+        assert_eq!(cpu.read_memory(0x2FF, usize::MAX), Err(CpuError::OutOfBounds { addr: 0x300 }));
+        assert_eq!(cpu.read_memory(usize::MAX, 2), Err(CpuError::OutOfBounds { addr: usize::MAX }));
+        assert!(cpu.write_memory(usize::MAX, &[1, 2]).is_err());
+        assert_eq!(cpu.write_memory(0x2FE, &[1, 2]), Ok(()));
+        assert_eq!(cpu.read_memory(0x2FE, 2), Ok(Vec::from([1, 2])));
+    }
+}
@@ -0,0 +1,187 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::CpuError;
+
+/// Memory as seen by the CPU, every instruction reads and writes through it.
+/// Implementations can map devices, grow the address space or record accesses
+pub trait Bus {
+    /// Reads a byte on behalf of the program, devices may react to it
+    fn read8(&mut self, addr: usize) -> Result<u8, CpuError>;
+
+    /// Writes a byte on behalf of the program
+    fn write8(&mut self, addr: usize, value: u8) -> Result<(), CpuError>;
+
+    /// Reads a byte without side effects, used by debuggers and other tools
+    fn peek8(&self, addr: usize) -> Result<u8, CpuError>;
+
+    /// Number of addressable bytes
+    fn size(&self) -> usize;
+
+    /// Reads a big-endian word, the order in which opcodes are stored
+    fn read16(&mut self, addr: usize) -> Result<u16, CpuError> {
+        let high = self.read8(addr)? as u16;
+        let low = self.read8(addr + 1)? as u16;
+        Ok(high << 8 | low)
+    }
+
+    /// Changes the number of addressable bytes, buses of a fixed size ignore it
+    fn resize(&mut self, _size: usize) {}
+}
+
+/// Plain RAM, the memory of the original machines
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlatMemory {
+    bytes: Vec<u8>,
+}
+
+impl FlatMemory {
+    /// Creates `size` bytes of zeroed memory
+    pub fn new(size: usize) -> Self {
+        FlatMemory { bytes: vec![0; size] }
+    }
+
+    /// Returns the whole memory
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl Bus for FlatMemory {
+    fn read8(&mut self, addr: usize) -> Result<u8, CpuError> {
+        self.peek8(addr)
+    }
+
+    fn write8(&mut self, addr: usize, value: u8) -> Result<(), CpuError> {
+        match self.bytes.get_mut(addr) {
+            Some(byte) => {
+                *byte = value;
+                Ok(())
+            }
+            None => Err(CpuError::OutOfBounds { addr }),
+        }
+    }
+
+    fn peek8(&self, addr: usize) -> Result<u8, CpuError> {
+        self.bytes.get(addr).copied().ok_or(CpuError::OutOfBounds { addr })
+    }
+
+    fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    fn resize(&mut self, size: usize) {
+        self.bytes.resize(size, 0);
+    }
+}
+
+/// Peripheral that answers the memory accesses of a range of addresses.
+/// Offsets are relative to the start of the range the device is attached at
+pub trait Device {
+    /// Answers a read made by the program
+    fn read(&mut self, offset: usize) -> u8;
+
+    /// Receives a write made by the program
+    fn write(&mut self, offset: usize, value: u8);
+
+    /// Returns what a read would without side effects, for debuggers and other tools
+    fn peek(&self, offset: usize) -> u8;
+}
+
+/// Bus that routes accesses to the devices attached to it and the rest to memory:
+///
+/// ```
+/// use cpu_caller::{Device, FlatMemory, MappedBus, CPU};
+///
+/// /// Counts how many times it was read
+/// struct Counter(u8);
+///
+/// impl Device for Counter {
+///     fn read(&mut self, _offset: usize) -> u8 {
+///         self.0 = self.0.wrapping_add(1);
+///         self.0
+///     }
+///     fn write(&mut self, _offset: usize, value: u8) {
+///         self.0 = value;
+///     }
+///     fn peek(&self, _offset: usize) -> u8 {
+///         self.0
+///     }
+/// }
+///
+/// let mut bus = MappedBus::new(FlatMemory::new(4096));
+/// bus.attach_device(0xF00..0xF01, Box::new(Counter(0)));
+///
+/// let mut cpu = CPU::new();
+/// cpu.set_bus(Box::new(bus));
+/// ```
+pub struct MappedBus {
+    memory: FlatMemory,
+    devices: Vec<(Range<usize>, Box<dyn Device>)>,
+}
+
+impl MappedBus {
+    /// Creates a bus without devices on top of `memory`
+    pub fn new(memory: FlatMemory) -> Self {
+        MappedBus { memory, devices: Vec::new() }
+    }
+
+    /// Routes the accesses to `range` to `device`.
+    ///
+    /// Panics if the range overlaps the one of another device
+    pub fn attach_device(&mut self, range: Range<usize>, device: Box<dyn Device>) {
+        let overlaps = self
+            .devices
+            .iter()
+            .any(|(r, _)| range.start < r.end && r.start < range.end);
+        assert!(!overlaps, "device range {:#x}..{:#x} overlaps another device", range.start, range.end);
+
+        self.devices.push((range, device));
+    }
+
+    /// Finds the device attached at `addr` and the offset of `addr` inside its range
+    fn device_at(&self, addr: usize) -> Option<(usize, usize)> {
+        self.devices
+            .iter()
+            .position(|(r, _)| r.contains(&addr))
+            .map(|index| (index, addr - self.devices[index].0.start))
+    }
+}
+
+impl Bus for MappedBus {
+    fn read8(&mut self, addr: usize) -> Result<u8, CpuError> {
+        match self.device_at(addr) {
+            Some((index, offset)) => Ok(self.devices[index].1.read(offset)),
+            None => self.memory.read8(addr),
+        }
+    }
+
+    fn write8(&mut self, addr: usize, value: u8) -> Result<(), CpuError> {
+        match self.device_at(addr) {
+            Some((index, offset)) => {
+                self.devices[index].1.write(offset, value);
+                Ok(())
+            }
+            None => self.memory.write8(addr, value),
+        }
+    }
+
+    fn peek8(&self, addr: usize) -> Result<u8, CpuError> {
+        match self.device_at(addr) {
+            Some((index, offset)) => Ok(self.devices[index].1.peek(offset)),
+            None => self.memory.peek8(addr),
+        }
+    }
+
+    fn size(&self) -> usize {
+        // devices can live past the end of memory
+        let devices_end = self.devices.iter().map(|(r, _)| r.end).max().unwrap_or(0);
+        self.memory.size().max(devices_end)
+    }
+
+    fn resize(&mut self, size: usize) {
+        self.memory.resize(size);
+    }
+}
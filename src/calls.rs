@@ -0,0 +1,152 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use crate::decoder::Instruction;
+use crate::disasm::disassemble;
+use crate::flow::{control_flow, ControlFlowGraph, Edge};
+use crate::Variant;
+
+/// Subroutines of a program and the ones each of them calls
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallGraph {
+    pub entry: usize,
+    pub functions: BTreeMap<usize, BTreeSet<usize>>, // reachable subroutines, the entry point included
+    pub unreachable: BTreeMap<usize, usize>,         // subroutine called only from code that never runs, and a call site
+    pub into_data: Vec<(usize, usize)>,              // call site and target of calls that don't reach sound code
+}
+
+/// Follows the calls of `rom` loaded at `base` from `entry`.
+///
+/// A call goes into data when its target is outside of the ROM, is used by
+/// `LD I` to point at sprites, or runs into opcodes that don't exist
+pub fn call_graph(rom: &[u8], base: usize, entry: usize, variant: Variant) -> CallGraph {
+    let graph = control_flow(rom, base, entry, variant);
+
+    let mut functions = BTreeMap::new();
+    let mut pending = Vec::from([entry]);
+    let mut calls = Vec::new();
+    while let Some(function) = pending.pop() {
+        if functions.contains_key(&function) || !graph.blocks.contains_key(&function) {
+            continue;
+        }
+        let sites = calls_of(&graph, function);
+        pending.extend(sites.iter().map(|&(_, callee)| callee));
+        functions.insert(function, sites.iter().map(|&(_, callee)| callee).collect());
+        calls.extend(sites);
+    }
+
+    // places where reachable code points I, they hold sprites or other data
+    let data: BTreeSet<usize> = graph
+        .blocks
+        .values()
+        .flat_map(|block| &block.lines)
+        .filter_map(|line| match line.instruction {
+            Some(Instruction::LoadI { addr }) => Some(addr as usize),
+            _ => line.long_address().map(usize::from),
+        })
+        .collect();
+    let into_data = calls
+        .into_iter()
+        .filter(|&(_, callee)| data.contains(&callee) || !is_sound(&graph, callee))
+        .collect();
+
+    // calls written in the ROM that the reachable code never makes
+    let unreachable = disassemble(rom, base, variant)
+        .iter()
+        .filter(|line| !graph.blocks.values().any(|block| block.lines.contains(line)))
+        .filter_map(|line| match line.instruction {
+            Some(Instruction::Call { addr }) if !functions.contains_key(&(addr as usize)) => Some((addr as usize, line.addr)),
+            _ => None,
+        })
+        .collect();
+
+    CallGraph { entry, functions, unreachable, into_data }
+}
+
+/// Call sites and targets of the calls made by the subroutine at `function`
+fn calls_of(graph: &ControlFlowGraph, function: usize) -> Vec<(usize, usize)> {
+    let mut calls = Vec::new();
+    for block in body(graph, function).iter().filter_map(|start| graph.blocks.get(start)) {
+        for &(target, edge) in &block.successors {
+            if edge == Edge::Call {
+                calls.push((block.lines[block.lines.len() - 1].addr, target));
+            }
+        }
+    }
+    calls
+}
+
+/// Blocks reachable from `function` without entering the subroutines it calls,
+/// the ones outside of the ROM included
+pub(crate) fn body(graph: &ControlFlowGraph, function: usize) -> BTreeSet<usize> {
+    let mut seen = BTreeSet::new();
+    let mut pending = Vec::from([function]);
+
+    while let Some(start) = pending.pop() {
+        if !seen.insert(start) {
+            continue;
+        }
+        if let Some(block) = graph.blocks.get(&start) {
+            pending.extend(block.successors.iter().filter(|(_, edge)| *edge != Edge::Call).map(|(target, _)| target));
+        }
+    }
+    seen
+}
+
+/// Tells if the code reachable from `start` without calls stays in the ROM
+/// and only has opcodes that exist
+fn is_sound(graph: &ControlFlowGraph, start: usize) -> bool {
+    body(graph, start).iter().all(|start| match graph.blocks.get(start) {
+        Some(block) => !matches!(block.lines.last().map(|line| line.instruction), Some(None | Some(Instruction::Unknown(_)))),
+        None => false,
+    })
+}
+
+impl CallGraph {
+    /// Graphviz description of the graph, calls into data are red and the
+    /// unreachable subroutines gray
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph calls {\n    node [shape=box fontname=\"monospace\"];\n");
+        let _ = writeln!(dot, "    \"{:#05x}\" [style=bold];", self.entry);
+        for (&function, callees) in &self.functions {
+            for callee in callees {
+                let _ = writeln!(dot, "    \"{:#05x}\" -> \"{:#05x}\";", function, callee);
+            }
+        }
+        let data: BTreeSet<usize> = self.into_data.iter().map(|&(_, target)| target).collect();
+        for target in data {
+            let _ = writeln!(dot, "    \"{:#05x}\" [color=red label=\"{:#05x} data\"];", target, target);
+        }
+        for &function in self.unreachable.keys() {
+            let _ = writeln!(dot, "    \"{:#05x}\" [color=gray fontcolor=gray];", function);
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// One line per subroutine with the ones it calls, then the problems found
+impl fmt::Display for CallGraph {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (&function, callees) in &self.functions {
+            let role = if function == self.entry { " (entry)" } else { "" };
+            write!(f, "{:#05x}{} calls", function, role)?;
+            if callees.is_empty() {
+                write!(f, " nothing")?;
+            }
+            for (i, callee) in callees.iter().enumerate() {
+                write!(f, "{} {:#05x}", if i == 0 { "" } else { "," }, callee)?;
+            }
+            writeln!(f)?;
+        }
+        for (&function, &site) in &self.unreachable {
+            writeln!(f, "unreachable subroutine {:#05x}, called at {:#05x}", function, site)?;
+        }
+        for &(site, target) in &self.into_data {
+            writeln!(f, "call at {:#05x} goes into data at {:#05x}", site, target)?;
+        }
+        Ok(())
+    }
+}
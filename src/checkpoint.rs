@@ -0,0 +1,149 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use crate::snapshot::Change;
+use crate::{Snapshot, CPU};
+
+/// Snapshots of a CPU taken every few instructions, the oldest ones are
+/// forgotten once there are too many.
+///
+/// Running again from one of them gives back any state after it, which is
+/// how rewinding and stepping backwards work
+#[derive(Debug, Clone)]
+pub struct Checkpointer {
+    interval: u64,                   // instructions between two checkpoints
+    capacity: usize,                 // checkpoints kept
+    checkpoints: VecDeque<Snapshot>, // oldest first
+}
+
+impl Checkpointer {
+    /// Keeps up to `capacity` snapshots taken `interval` instructions apart
+    pub fn new(interval: u64, capacity: usize) -> Self {
+        Checkpointer { interval: interval.max(1), capacity: capacity.max(1), checkpoints: VecDeque::new() }
+    }
+
+    /// Takes a snapshot of `cpu` if `interval` instructions ran since the last
+    /// one, returns whether it did. Call it between instructions as often as
+    /// convenient, e.g. once per frame
+    pub fn record(&mut self, cpu: &CPU) -> bool {
+        let executed = cpu.instructions_executed();
+        let due = match self.checkpoints.back() {
+            Some(last) => executed >= last.instructions_executed + self.interval || executed < last.instructions_executed,
+            None => true,
+        };
+        if due {
+            self.push(cpu.snapshot());
+        }
+        due
+    }
+
+    /// Keeps `snapshot` as the newest checkpoint
+    pub fn push(&mut self, snapshot: Snapshot) {
+        self.checkpoints.push_back(snapshot);
+        if self.checkpoints.len() > self.capacity {
+            self.checkpoints.pop_front();
+        }
+    }
+
+    /// The checkpoints, oldest first
+    pub fn list(&self) -> impl Iterator<Item = &Snapshot> {
+        self.checkpoints.iter()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Snapshot> {
+        self.checkpoints.get(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.checkpoints.is_empty()
+    }
+
+    /// Index of the newest checkpoint taken once `instructions` had executed or before
+    pub fn latest_at(&self, instructions: u64) -> Option<usize> {
+        self.checkpoints.iter().rposition(|checkpoint| checkpoint.instructions_executed <= instructions)
+    }
+
+    /// Puts `cpu` back in the state of checkpoint `index` and forgets the ones
+    /// after it, which may not happen again. Returns whether it exists
+    pub fn restore(&mut self, index: usize, cpu: &mut CPU) -> bool {
+        let Some(checkpoint) = self.checkpoints.get(index) else {
+            return false;
+        };
+        cpu.restore(checkpoint);
+        self.checkpoints.truncate(index + 1);
+        true
+    }
+
+    /// What changed from checkpoint `from` to checkpoint `to`
+    pub fn diff(&self, from: usize, to: usize) -> Option<Vec<Change>> {
+        Some(self.checkpoints.get(from)?.diff(self.checkpoints.get(to)?))
+    }
+
+    pub fn clear(&mut self) {
+        self.checkpoints.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CPU of a program counting in V0, ADD V0, 1 and jump back, with
+    /// checkpoints recorded before each of its first `count` instructions
+    fn recorded(checkpoints: &mut Checkpointer, count: usize) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load_rom(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        for _ in 0..count {
+            checkpoints.record(&cpu);
+            cpu.step().unwrap();
+        }
+        cpu
+    }
+
+    fn taken(checkpoints: &Checkpointer) -> Vec<u64> {
+        checkpoints.list().map(|checkpoint| checkpoint.instructions_executed).collect()
+    }
+
+    #[test]
+    fn records_every_interval_and_keeps_the_newest() {
+        let mut checkpoints = Checkpointer::new(4, 3);
+        let mut cpu = recorded(&mut checkpoints, 20);
+        assert_eq!(taken(&checkpoints), [8, 12, 16]);
+        assert!(checkpoints.record(&cpu));
+        assert!(!checkpoints.record(&cpu));
+        assert_eq!(taken(&checkpoints), [12, 16, 20]);
+
+        // time going backwards, when the CPU was restored elsewhere, is due too
+        let oldest = checkpoints.get(0).unwrap().clone();
+        cpu.restore(&oldest);
+        assert!(checkpoints.record(&cpu));
+        assert_eq!(taken(&checkpoints), [16, 20, 12]);
+    }
+
+    #[test]
+    fn restore_forgets_what_came_after() {
+        let mut checkpoints = Checkpointer::new(4, 8);
+        let mut cpu = recorded(&mut checkpoints, 20);
+        assert_eq!((checkpoints.latest_at(13), checkpoints.latest_at(4), checkpoints.latest_at(3)), (Some(3), Some(1), Some(0)));
+        let third = checkpoints.get(2).unwrap().clone();
+        assert!(!checkpoints.restore(5, &mut cpu));
+        assert!(checkpoints.restore(2, &mut cpu));
+        assert_eq!(cpu.snapshot(), third);
+        assert_eq!(taken(&checkpoints), [0, 4, 8]);
+        assert_eq!(checkpoints.latest_at(13), Some(2));
+    }
+
+    #[test]
+    fn diffs() {
+        let mut checkpoints = Checkpointer::new(4, 8);
+        recorded(&mut checkpoints, 9);
+        // two laps of the loop
+        assert_eq!(checkpoints.diff(1, 2), Some(Vec::from([Change::Register { x: 0, before: 2, after: 4 }])));
+        assert_eq!(checkpoints.diff(2, 2), Some(Vec::new()));
+        assert_eq!(checkpoints.diff(0, 3), None);
+    }
+}
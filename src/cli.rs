@@ -0,0 +1,712 @@
+use std::ffi::OsString;
+
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::error::ErrorKind;
+use clap::{value_parser, CommandFactory, Parser, Subcommand, ValueEnum};
+use cpu_caller::{parse_hex, Machine, Quirks, Variant};
+
+use crate::config::{parse_color, Config, Palette, DEFAULT_KEYS};
+use crate::crash::CrashFormat;
+
+/// Port `gdb` listens on unless `--port` says otherwise
+const GDB_PORT: u16 = 1234;
+
+/// Port `remote` listens on unless `--port` says otherwise
+const REMOTE_PORT: u16 = 6464;
+
+/// CHIP-8, SUPER-CHIP and XO-CHIP emulator, debugger and tools
+#[derive(Parser, Debug)]
+#[command(name = "cpu-caller", version, arg_required_else_help = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Sub,
+}
+
+#[derive(Subcommand, Debug)]
+enum Sub {
+    /// execute a ROM, or an assembly program ending in .s or .asm
+    Run {
+        #[arg(value_name = "ROM", required_unless_present = "stdin_hex")]
+        rom: Option<String>,
+        /// read the ROM from standard input as hexadecimal bytes
+        #[arg(long, conflicts_with_all = ["rom", "watch"])]
+        stdin_hex: bool,
+        /// execute the file again whenever it changes
+        #[arg(long)]
+        watch: bool,
+        /// with `--watch`, swap the program without resetting the registers, memory and screen
+        #[arg(long, requires = "watch")]
+        keep_state: bool,
+        /// change memory after loading, can be repeated
+        #[arg(long = "poke", value_name = "ADDR=BYTE", value_parser = poke)]
+        pokes: Vec<(usize, u8)>,
+        /// how a fault is described: the instructions around it, the registers, the calls and the last instructions executed
+        #[arg(long, value_enum, value_name = "FORMAT", default_value = "text")]
+        crash_report: CrashFormat,
+        /// names of addresses, `<rom>.sym` is used if it exists
+        #[arg(long, value_name = "FILE")]
+        symbols: Option<String>,
+        #[command(flatten)]
+        machine: MachineArgs,
+        #[command(flatten)]
+        display: DisplayArgs,
+    },
+    /// print the instructions of a ROM
+    Disasm {
+        rom: String,
+        /// draw how the instructions flow instead
+        #[arg(long, value_name = "FORMAT", value_parser = ["dot"])]
+        cfg: Option<String>,
+        /// list the subroutines instead
+        #[arg(long, value_name = "FORMAT", value_parser = ["text", "dot"], conflicts_with = "cfg")]
+        calls: Option<String>,
+        /// names of addresses, `<rom>.sym` is used if it exists
+        #[arg(long, value_name = "FILE")]
+        symbols: Option<String>,
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// print the bytes of a ROM next to the instruction they decode to
+    Hexdump {
+        rom: String,
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// look for mistakes in a ROM, fails if there are any
+    Check {
+        rom: String,
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// count the instructions of a ROM and the extensions it uses
+    Stats {
+        rom: String,
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// execute a ROM one instruction at a time, `help` at the prompt lists the commands
+    Debug {
+        rom: String,
+        /// show the display, disassembly, registers and memory on the whole terminal
+        #[arg(long)]
+        tui: bool,
+        /// run the debugger commands of a file first, or load a `.rhai` script with `--features rhai`
+        #[arg(long, value_name = "FILE", conflicts_with = "tui")]
+        script: Option<String>,
+        /// names of addresses, `<rom>.sym` is used if it exists
+        #[arg(long, value_name = "FILE")]
+        symbols: Option<String>,
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// log every executed instruction, to the file given by `-o` or to standard output
+    Trace {
+        /// the ROM, or with `--diff` the two logs to compare
+        #[arg(value_name = "FILE", required = true, num_args = 1..=2)]
+        files: Vec<String>,
+        /// show where two logs of `trace` start to differ
+        #[arg(long, conflicts_with = "output")]
+        diff: bool,
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<String>,
+        /// how many instructions to run, like 200k or 5M
+        #[arg(long, value_name = "N", value_parser = count)]
+        instructions: Option<u64>,
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// run a ROM without a screen as fast as possible and report the speed
+    Bench {
+        rom: String,
+        /// how many instructions to run, like 200k or 5M
+        #[arg(long, value_name = "N", value_parser = count)]
+        instructions: Option<u64>,
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// wait for gdb to connect and let it control the ROM
+    Gdb {
+        rom: String,
+        #[arg(long, default_value_t = GDB_PORT)]
+        port: u16,
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// let tools control the ROM with JSON requests, one per line
+    Remote {
+        rom: String,
+        #[arg(long, default_value_t = REMOTE_PORT)]
+        port: u16,
+        /// names of addresses, `<rom>.sym` is used if it exists
+        #[arg(long, value_name = "FILE")]
+        symbols: Option<String>,
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// speak the Debug Adapter Protocol on standard input and output for editors
+    /// like VS Code, which give the program to launch
+    Dap {
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// run the ROMs of a directory and check the results listed in its tests.toml
+    Test {
+        dir: String,
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// execute instructions as they are typed and show the registers
+    Repl {
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// assemble a program, its labels and lines go to a symbol file with the
+    /// same name ending in .sym
+    Asm {
+        source: String,
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<String>,
+    },
+    /// write bytes over a ROM at a memory address
+    Patch {
+        rom: String,
+        #[arg(long, value_name = "ADDR", value_parser = address)]
+        at: usize,
+        #[arg(long, value_name = "HEX", value_parser = hex)]
+        bytes: Vec<u8>,
+        /// where the patched ROM goes, `<rom>.patched.ch8` by default
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<String>,
+    },
+    /// add numbers by calling a function, the original demo
+    Demo,
+}
+
+/// Settings of the commands that decode or run instructions
+#[derive(clap::Args, Debug)]
+struct MachineArgs {
+    /// instruction set, detected from the ROM by default
+    #[arg(long, value_parser = PossibleValuesParser::new(["chip8", "schip", "xochip"]).map(|name| Variant::from_name(&name).expect("checked by clap")))]
+    variant: Option<Variant>,
+    /// interpreter to emulate
+    #[arg(long, value_parser = PossibleValuesParser::new(["vip", "chip48", "schip-legacy", "schip", "xochip"]).map(|name| Machine::from_name(&name).expect("checked by clap")))]
+    machine: Option<Machine>,
+    /// override one quirk, can be repeated
+    #[arg(long = "quirk", value_name = "NAME=BOOL", value_parser = quirk)]
+    quirks: Vec<(String, bool)>,
+    /// quirks and speeds of known ROMs by the SHA-1 hash of their bytes, used when they are loaded
+    #[arg(long, value_name = "FILE")]
+    quirk_db: Option<String>,
+    /// instructions per 60 Hz frame
+    #[arg(long, value_name = "N", value_parser = value_parser!(u32).range(1..))]
+    speed: Option<u32>,
+    /// stop a program looping for N instructions without changing memory, the display or the timers, like 1M
+    #[arg(long, value_name = "N", value_parser = count)]
+    watchdog: Option<u64>,
+    /// settings to use instead of ./cpu-caller.toml or ~/.config/cpu-caller/config.toml, flags win over them
+    #[arg(long, value_name = "FILE")]
+    config: Option<String>,
+}
+
+/// Settings of the frontends that show the display
+#[derive(clap::Args, Debug)]
+struct DisplayArgs {
+    /// where the display is shown: `window` opens a window, `terminal` and `braille` draw it with characters
+    /// and read the keys typed. With the feature of the same name, `sdl` opens a window with sound, `wgpu`
+    /// one with shaders and `minifb` a small one drawn without the GPU
+    #[arg(long, value_enum)]
+    frontend: Option<Frontend>,
+    /// windows draw each pixel with N by N pixels of the screen, by default the picture grows by whole
+    /// multiples to fill them
+    #[arg(long, value_name = "N", value_parser = value_parser!(u32).range(1..), conflicts_with_all = ["fit", "stretch"])]
+    scale: Option<u32>,
+    /// make the picture as large as the window, keeping its proportions
+    #[arg(long, conflicts_with = "stretch")]
+    fit: bool,
+    /// make the picture fill the window
+    #[arg(long)]
+    stretch: bool,
+    /// colors of the display in every frontend
+    #[arg(long, value_parser = PossibleValuesParser::new(Palette::NAMES).map(|name| Palette::from_name(&name).expect("checked by clap")))]
+    palette: Option<Palette>,
+    /// change one color of the palette: background, foreground, plane2 or both for the planes of XO-CHIP, can
+    /// be repeated
+    #[arg(long = "color", value_name = "NAME=#RRGGBB", value_parser = color)]
+    colors: Vec<(String, [u8; 3])>,
+    /// effect of the `wgpu` frontend, dark lines between the rows of pixels, or those on the curved and
+    /// glowing glass of a CRT
+    #[arg(long, value_enum)]
+    shader: Option<Shader>,
+}
+
+/// Where the program comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    File(String),
+    StdinHex, // hexadecimal bytes piped from another tool
+}
+
+/// What the user asked for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Run { rom: Source, watch: Option<Reload> },
+    Disasm { rom: String, graph: Option<(Graph, GraphFormat)> },
+    Hexdump { rom: String },
+    Check { rom: String },
+    Stats { rom: String },
+    Debug { rom: String, tui: bool, script: Option<String> },
+    Trace { rom: String, output: Option<String> },
+    TraceDiff { a: String, b: String },
+    Bench { rom: String },
+    Gdb { rom: String, port: u16 },
+    Remote { rom: String, port: u16 },
+    Dap,
+    Test { dir: String },
+    Repl,
+    Asm { source: String, output: Option<String> },
+    Patch { rom: String, at: usize, bytes: Vec<u8>, output: Option<String> },
+    Demo,
+}
+
+/// What `run --watch` does with the running program when its file changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reload {
+    Reset,     // starts the new program from scratch
+    KeepState, // writes the new program over the old one and goes on from the same state
+}
+
+/// Graph printed by `disasm` instead of the instructions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Graph {
+    ControlFlow,
+    Calls,
+}
+
+/// Language of the graphs printed by the analysis commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    Text, // one line per node
+    Dot,  // Graphviz
+}
+
+/// Where the emulator shows its display
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Frontend {
+    Headless, // nothing is shown, the program runs until it stops
+    Window,   // window drawn with the GPU, with the `window` feature
+    Terminal, // characters of two pixels, one above the other
+    Braille,  // braille characters of 2x4 pixels, a smaller picture
+    Sdl,      // window with sound, only with the `sdl` feature
+    Wgpu,     // window with shaders, only with the `wgpu` feature
+    Minifb,   // window drawn without the GPU, only with the `minifb` feature
+}
+
+impl Frontend {
+    pub fn from_name(name: &str) -> Option<Frontend> {
+        match name {
+            "headless" => Some(Frontend::Headless),
+            "window" => Some(Frontend::Window),
+            "terminal" => Some(Frontend::Terminal),
+            "braille" => Some(Frontend::Braille),
+            "sdl" => Some(Frontend::Sdl),
+            "wgpu" => Some(Frontend::Wgpu),
+            "minifb" => Some(Frontend::Minifb),
+            _ => None,
+        }
+    }
+}
+
+/// How the window frontends size the picture to their window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scaling {
+    Whole,      // as large as fits with the same whole number of window pixels per pixel
+    Fixed(u32), // window pixels per pixel of low resolution, whatever the size of the window
+    Fit,        // as large as fits keeping its proportions, the pixels may differ by one
+    Stretch,    // the whole window, whatever its proportions
+}
+
+/// Effect the `wgpu` frontend applies to the picture
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Shader {
+    None,
+    Scanlines, // dark lines between the rows of pixels
+    Crt,       // scanlines on curved glass, with the light bleeding and darker corners
+}
+
+impl Shader {
+    pub fn from_name(name: &str) -> Option<Shader> {
+        match name {
+            "none" => Some(Shader::None),
+            "scanlines" => Some(Shader::Scanlines),
+            "crt" => Some(Shader::Crt),
+            _ => None,
+        }
+    }
+}
+
+/// Settings shared by the commands that build a CPU
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    pub variant: Option<Variant>,
+    pub machine: Option<Machine>,
+    pub quirks: Vec<(String, bool)>, // applied in order after the ones of the machine
+    pub quirk_db: Option<String>,    // profiles of known ROMs, the quirks above win over them
+    pub speed: Option<u32>,
+    pub pokes: Vec<(usize, u8)>, // written to memory once the ROM is loaded
+    pub instructions: Option<u64>, // limit of the commands that run without a screen
+    pub watchdog: Option<u64>,     // instructions a program can spend stuck in a loop
+    pub crash_report: CrashFormat,
+    pub symbols: Option<String>,   // symbol file to use instead of the one next to the ROM
+    pub frontend: Frontend,
+    pub shader: Shader,
+    pub scaling: Scaling,
+    pub keys: Vec<(char, u8)>, // keyboard key and CHIP-8 key
+    pub palette: Palette,
+}
+
+/// Parsed command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cli {
+    pub command: Command,
+    pub options: Options,
+}
+
+impl Cli {
+    /// Parses the command line, `args` starting with the program name. The
+    /// error prints the help or the mistake and exits with `exit`
+    pub fn parse<I, T>(args: I) -> Result<Cli, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let args = Args::try_parse_from(args)?;
+        args.command.into_cli().map_err(|e| Args::command().error(ErrorKind::ValueValidation, e))
+    }
+}
+
+impl Sub {
+    /// Command and settings it asks for, with the missing settings taken from
+    /// the configuration file
+    fn into_cli(self) -> Result<Cli, String> {
+        let mut options = Options {
+            variant: None,
+            machine: None,
+            quirks: Vec::new(),
+            quirk_db: None,
+            speed: None,
+            pokes: Vec::new(),
+            instructions: None,
+            watchdog: None,
+            crash_report: CrashFormat::Text,
+            symbols: None,
+            frontend: Frontend::Headless,
+            shader: Shader::None,
+            scaling: Scaling::Whole,
+            keys: DEFAULT_KEYS.to_vec(),
+            palette: Palette::default(),
+        };
+
+        // the settings only matter to the commands that decode or run instructions
+        let (command, machine, display) = match self {
+            Sub::Run { rom, stdin_hex, watch, keep_state, pokes, crash_report, symbols, machine, display } => {
+                options.pokes = pokes;
+                options.crash_report = crash_report;
+                options.symbols = symbols;
+                let rom = match rom {
+                    Some(path) if !stdin_hex => Source::File(path),
+                    _ => Source::StdinHex,
+                };
+                let watch = watch.then_some(if keep_state { Reload::KeepState } else { Reload::Reset });
+                (Command::Run { rom, watch }, Some(machine), Some(display))
+            }
+            Sub::Disasm { rom, cfg, calls, symbols, machine } => {
+                options.symbols = symbols;
+                let graph = match (cfg, calls.as_deref()) {
+                    (Some(_), _) => Some((Graph::ControlFlow, GraphFormat::Dot)),
+                    (None, Some("text")) => Some((Graph::Calls, GraphFormat::Text)),
+                    (None, Some(_)) => Some((Graph::Calls, GraphFormat::Dot)),
+                    (None, None) => None,
+                };
+                (Command::Disasm { rom, graph }, Some(machine), None)
+            }
+            Sub::Hexdump { rom, machine } => (Command::Hexdump { rom }, Some(machine), None),
+            Sub::Check { rom, machine } => (Command::Check { rom }, Some(machine), None),
+            Sub::Stats { rom, machine } => (Command::Stats { rom }, Some(machine), None),
+            Sub::Debug { rom, tui, script, symbols, machine } => {
+                options.symbols = symbols;
+                (Command::Debug { rom, tui, script }, Some(machine), None)
+            }
+            Sub::Trace { files, diff: true, .. } => match <[String; 2]>::try_from(files) {
+                Ok([a, b]) => (Command::TraceDiff { a, b }, None, None),
+                Err(_) => return Err("`trace --diff` needs two trace files".to_string()),
+            },
+            Sub::Trace { mut files, output, instructions, machine, .. } => {
+                if files.len() > 1 {
+                    return Err("`trace` runs one ROM, `--diff` compares two traces".to_string());
+                }
+                options.instructions = instructions;
+                (Command::Trace { rom: files.remove(0), output }, Some(machine), None)
+            }
+            Sub::Bench { rom, instructions, machine } => {
+                options.instructions = instructions;
+                (Command::Bench { rom }, Some(machine), None)
+            }
+            Sub::Gdb { rom, port, machine } => (Command::Gdb { rom, port }, Some(machine), None),
+            Sub::Remote { rom, port, symbols, machine } => {
+                options.symbols = symbols;
+                (Command::Remote { rom, port }, Some(machine), None)
+            }
+            Sub::Dap { machine } => (Command::Dap, Some(machine), None),
+            Sub::Test { dir, machine } => (Command::Test { dir }, Some(machine), None),
+            Sub::Repl { machine } => (Command::Repl, Some(machine), None),
+            Sub::Asm { source, output } => (Command::Asm { source, output }, None, None),
+            Sub::Patch { rom, at, bytes, output } => (Command::Patch { rom, at, bytes, output }, None, None),
+            Sub::Demo => (Command::Demo, None, None),
+        };
+
+        if let Some(machine) = machine {
+            options.variant = machine.variant;
+            options.machine = machine.machine;
+            options.quirks = machine.quirks;
+            options.quirk_db = machine.quirk_db;
+            options.speed = machine.speed;
+            options.watchdog = machine.watchdog;
+            let (frontend, shader) = display.as_ref().map_or((None, None), |display| (display.frontend, display.shader));
+            options.apply(Config::load(machine.config.as_deref())?, frontend, shader);
+        }
+        if let Some(display) = display {
+            if let Some(scale) = display.scale {
+                options.scaling = Scaling::Fixed(scale);
+            } else if display.fit {
+                options.scaling = Scaling::Fit;
+            } else if display.stretch {
+                options.scaling = Scaling::Stretch;
+            }
+            // the palette of the command line wins over the file's and its colors over both
+            if let Some(palette) = display.palette {
+                options.palette = palette;
+            }
+            for (name, color) in display.colors {
+                *options.palette.by_name(&name).expect("color names are checked while parsing") = color;
+            }
+        }
+
+        Ok(Cli { command, options })
+    }
+}
+
+/// Reads `--quirk <name>=<true|false>`
+fn quirk(setting: &str) -> Result<(String, bool), String> {
+    let parsed = setting.split_once('=').and_then(|(name, flag)| Some((name, flag.parse().ok()?)));
+    let (name, flag) = parsed.ok_or_else(|| format!("expected `<name>=<true|false>`, got `{}`", setting))?;
+    if Quirks::default().by_name(name).is_none() {
+        return Err(format!("unknown quirk `{}`, expected one of {}", name, Quirks::NAMES.join(", ")));
+    }
+    Ok((name.to_string(), flag))
+}
+
+/// Reads `--color <name>=<#RRGGBB>`
+fn color(setting: &str) -> Result<(String, [u8; 3]), String> {
+    let (name, color) = setting.split_once('=').ok_or_else(|| format!("expected `<name>=<#RRGGBB>`, got `{}`", setting))?;
+    if Palette::default().by_name(name).is_none() {
+        return Err(format!("unknown color `{}`, expected background, foreground, plane2 or both", name));
+    }
+    let color = parse_color(color).ok_or_else(|| format!("`{}` is not a `#RRGGBB` color", color))?;
+    Ok((name.to_string(), color))
+}
+
+/// Reads `--poke <addr>=<byte>`
+fn poke(poke: &str) -> Result<(usize, u8), String> {
+    let parsed = poke.split_once('=').and_then(|(addr, byte)| {
+        let byte = parse_address(byte).filter(|&b| b <= 0xFF)?;
+        Some((parse_address(addr)?, byte as u8))
+    });
+    parsed.ok_or_else(|| format!("expected `<addr>=<byte>`, got `{}`", poke))
+}
+
+fn address(text: &str) -> Result<usize, String> {
+    parse_address(text).ok_or_else(|| format!("`{}` is not a memory address", text))
+}
+
+fn count(text: &str) -> Result<u64, String> {
+    parse_count(text).ok_or_else(|| format!("`{}` is not a number of instructions", text))
+}
+
+fn hex(text: &str) -> Result<Vec<u8>, String> {
+    parse_hex(text).map_err(|e| e.to_string())
+}
+
+/// Reads a decimal or `0x` hexadecimal address
+pub fn parse_address(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Reads a count like `5000`, `200k` or `50M`
+fn parse_count(text: &str) -> Option<u64> {
+    let (digits, scale) = match text.char_indices().last()? {
+        (i, 'k' | 'K') => (&text[..i], 1_000),
+        (i, 'm' | 'M') => (&text[..i], 1_000_000),
+        (i, 'g' | 'G') => (&text[..i], 1_000_000_000),
+        _ => (text, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(scale).filter(|&n| n > 0)
+}
+
+
+impl Options {
+    /// Fills the settings missing from the command line with the ones of `config`
+    fn apply(&mut self, config: Config, frontend: Option<Frontend>, shader: Option<Shader>) {
+        self.variant = self.variant.or(config.variant);
+        self.machine = self.machine.or(config.machine);
+        self.speed = self.speed.or(config.speed);
+        self.quirk_db = self.quirk_db.take().or(config.quirk_db);
+        self.frontend = frontend.or(config.frontend).unwrap_or(Frontend::Headless);
+        self.shader = shader.or(config.shader).unwrap_or(Shader::None);
+
+        let flags = std::mem::replace(&mut self.quirks, config.quirks);
+        self.quirks.extend(flags);
+
+        // the keys of the file replace the default ones for the same keyboard or CHIP-8 key,
+        // several keys of the file can press the same CHIP-8 key
+        self.keys.retain(|&(k, chip8)| !config.keys.iter().any(|&(keyboard, key)| k == keyboard || chip8 == key));
+        for (keyboard, key) in config.keys {
+            self.keys.retain(|&(k, _)| k != keyboard);
+            self.keys.push((keyboard, key));
+        }
+        if let Some(scaling) = config.scaling {
+            self.scaling = scaling;
+        }
+        if let Some(palette) = config.palette {
+            self.palette = palette;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Command line of the arguments, with an empty configuration file for
+    /// the commands that read one
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        let empty = std::env::temp_dir().join("cpu-caller-empty.toml");
+        std::fs::write(&empty, "").unwrap();
+        let mut line = vec!["cpu-caller"];
+        line.extend(args);
+        if !matches!(args[0], "asm" | "patch" | "demo") && !args.contains(&"--diff") {
+            line.extend(["--config", empty.to_str().unwrap()]);
+        }
+        Cli::parse(line)
+    }
+
+    /// Options of `run` with the default keys
+    fn options() -> Options {
+        parse(&["run", "rom.ch8"]).unwrap().options
+    }
+
+    #[test]
+    fn config_keys_keep_each_other() {
+        let mut options = options();
+        let config = Config { keys: vec![('j', 0x5), ('k', 0x5)], ..Config::default() };
+        options.apply(config, None, None);
+        assert!(options.keys.contains(&('j', 0x5)) && options.keys.contains(&('k', 0x5)));
+        // the default key of 5 is gone, the others stay
+        assert!(!options.keys.contains(&('w', 0x5)) && options.keys.contains(&('q', 0x4)));
+    }
+
+    #[test]
+    fn config_keys_replace_the_same_keyboard_key() {
+        let mut options = options();
+        let config = Config { keys: vec![('q', 0x1), ('q', 0x2)], ..Config::default() };
+        options.apply(config, None, None);
+        assert_eq!(options.keys.iter().filter(|&&(k, _)| k == 'q').collect::<Vec<_>>(), [&('q', 0x2)]);
+    }
+
+    #[test]
+    fn the_definition_is_consistent() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn flags_reach_the_options() {
+        let args = ["run", "rom.ch8", "--machine", "schip", "--quirk", "vf_reset=true", "--speed", "30", "--poke", "0x300=0xff"];
+        let options = parse(&args).unwrap().options;
+        assert_eq!(options.machine, Some(Machine::from_name("schip").unwrap()));
+        assert_eq!(options.quirks, [("vf_reset".to_string(), true)]);
+        assert_eq!((options.speed, options.pokes), (Some(30), vec![(0x300, 0xFF)]));
+
+        let cli = parse(&["bench", "rom.ch8", "--instructions", "5M", "--watchdog", "200k"]).unwrap();
+        assert_eq!((cli.options.instructions, cli.options.watchdog), (Some(5_000_000), Some(200_000)));
+        let cli = parse(&["gdb", "rom.ch8"]).unwrap();
+        assert_eq!(cli.command, Command::Gdb { rom: "rom.ch8".to_string(), port: GDB_PORT });
+        let cli = parse(&["disasm", "rom.ch8", "--calls", "text"]).unwrap();
+        assert_eq!(cli.command, Command::Disasm { rom: "rom.ch8".to_string(), graph: Some((Graph::Calls, GraphFormat::Text)) });
+    }
+
+    #[test]
+    fn flags_belong_to_their_commands() {
+        for args in [
+            &["disasm", "rom.ch8", "--frontend", "window"][..],
+            &["run", "rom.ch8", "--tui"],
+            &["hexdump", "rom.ch8", "--poke", "0x200=1"],
+            &["asm", "game.s", "--machine", "vip"],
+            &["check", "rom.ch8", "--port", "4000"],
+        ] {
+            assert_eq!(parse(args).unwrap_err().kind(), ErrorKind::UnknownArgument, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn flags_that_need_each_other() {
+        let cli = parse(&["run", "game.s", "--watch", "--keep-state"]).unwrap();
+        assert_eq!(cli.command, Command::Run { rom: Source::File("game.s".to_string()), watch: Some(Reload::KeepState) });
+        assert_eq!(parse(&["run", "game.s", "--keep-state"]).unwrap_err().kind(), ErrorKind::MissingRequiredArgument);
+
+        assert_eq!(parse(&["run", "--stdin-hex"]).unwrap().command, Command::Run { rom: Source::StdinHex, watch: None });
+        assert_eq!(parse(&["run", "--stdin-hex", "--watch"]).unwrap_err().kind(), ErrorKind::ArgumentConflict);
+        assert_eq!(parse(&["run"]).unwrap_err().kind(), ErrorKind::MissingRequiredArgument);
+        assert_eq!(parse(&["debug", "rom.ch8", "--tui", "--script", "a.txt"]).unwrap_err().kind(), ErrorKind::ArgumentConflict);
+        assert_eq!(parse(&["run", "rom.ch8", "--fit", "--scale", "3"]).unwrap_err().kind(), ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn traces() {
+        let cli = parse(&["trace", "rom.ch8", "-o", "log.txt"]).unwrap();
+        assert_eq!(cli.command, Command::Trace { rom: "rom.ch8".to_string(), output: Some("log.txt".to_string()) });
+        let cli = parse(&["trace", "--diff", "a.txt", "b.txt"]).unwrap();
+        assert_eq!(cli.command, Command::TraceDiff { a: "a.txt".to_string(), b: "b.txt".to_string() });
+        assert!(parse(&["trace", "--diff", "a.txt"]).unwrap_err().to_string().contains("needs two trace files"));
+        assert!(parse(&["trace", "a.ch8", "b.ch8"]).is_err());
+    }
+
+    #[test]
+    fn wrong_values() {
+        for args in [
+            &["run", "rom.ch8", "--quirk", "vf_reset=maybe"][..],
+            &["run", "rom.ch8", "--quirk", "fast=true"],
+            &["run", "rom.ch8", "--variant", "chip9"],
+            &["run", "rom.ch8", "--speed", "0"],
+            &["run", "rom.ch8", "--color", "plane3=#ffffff"],
+            &["run", "rom.ch8", "--palette", "blue"],
+            &["bench", "rom.ch8", "--instructions", "lots"],
+            &["patch", "rom.ch8", "--at", "0x200", "--bytes", "0g"],
+        ] {
+            let kind = parse(args).unwrap_err().kind();
+            assert!(matches!(kind, ErrorKind::InvalidValue | ErrorKind::ValueValidation), "{:?}: {:?}", args, kind);
+        }
+        let e = parse(&["run", "rom.ch8", "--quirk", "fast=true"]).unwrap_err().to_string();
+        assert!(e.contains("unknown quirk `fast`"), "{}", e);
+    }
+
+    #[test]
+    fn counts() {
+        assert_eq!(parse_count("5000"), Some(5000));
+        assert_eq!(parse_count("200k"), Some(200_000));
+        assert_eq!(parse_count("2G"), Some(2_000_000_000));
+        assert_eq!(parse_count("0"), None);
+        assert_eq!(parse_count("99999999999999G"), None);
+    }
+}
@@ -0,0 +1,321 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use cpu_caller::{Machine, Quirks, Variant};
+
+use crate::cli::{Frontend, Scaling, Shader};
+
+/// File looked for in the current directory when `--config` isn't given
+pub const DEFAULT_FILE: &str = "cpu-caller.toml";
+
+/// Colors of the display. A pixel takes the color of the planes lit there,
+/// only XO-CHIP programs draw on the second one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    pub background: [u8; 3], // no plane lit
+    pub foreground: [u8; 3], // first plane lit
+    pub plane2: [u8; 3],     // second plane lit
+    pub both: [u8; 3],       // both planes lit
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette { background: [0x00, 0x00, 0x00], foreground: [0xFF, 0xFF, 0xFF], plane2: [0xAA, 0xAA, 0xAA], both: [0x55, 0x55, 0x55] }
+    }
+}
+
+impl Palette {
+    /// Names of the built-in palettes, for error messages
+    pub const NAMES: [&'static str; 4] = ["classic", "green", "amber", "high-contrast"];
+
+    /// Built-in palette called `name`
+    pub fn from_name(name: &str) -> Option<Palette> {
+        match name {
+            "classic" => Some(Palette::default()),
+            // phosphors of the monochrome monitors of the time
+            "green" => Some(Palette { background: [0x0A, 0x1A, 0x0A], foreground: [0x33, 0xFF, 0x33], plane2: [0x1A, 0x99, 0x1A], both: [0xAA, 0xFF, 0xAA] }),
+            "amber" => Some(Palette { background: [0x1A, 0x0F, 0x00], foreground: [0xFF, 0xB0, 0x00], plane2: [0xB3, 0x6B, 0x00], both: [0xFF, 0xDD, 0x88] }),
+            "high-contrast" => Some(Palette { background: [0x00, 0x00, 0x00], foreground: [0xFF, 0xFF, 0xFF], plane2: [0xFF, 0xFF, 0x00], both: [0x00, 0xFF, 0xFF] }),
+            _ => None,
+        }
+    }
+
+    /// Color called `name`, to change it
+    pub fn by_name(&mut self, name: &str) -> Option<&mut [u8; 3]> {
+        match name {
+            "background" => Some(&mut self.background),
+            "foreground" => Some(&mut self.foreground),
+            "plane2" => Some(&mut self.plane2),
+            "both" => Some(&mut self.both),
+            _ => None,
+        }
+    }
+
+    /// Color of a pixel lit in the planes whose bits are set in `planes`,
+    /// bit 0 for the first plane
+    pub fn color(&self, planes: usize) -> [u8; 3] {
+        match planes & 0b11 {
+            0b00 => self.background,
+            0b01 => self.foreground,
+            0b10 => self.plane2,
+            _ => self.both,
+        }
+    }
+}
+
+/// Keyboard layout of the COSMAC VIP keypad on the left of a QWERTY keyboard:
+///
+/// ```text
+/// 1 2 3 C      1 2 3 4
+/// 4 5 6 D  ->  q w e r
+/// 7 8 9 E      a s d f
+/// A 0 B F      z x c v
+/// ```
+pub const DEFAULT_KEYS: [(char, u8); 16] = [
+    ('1', 0x1), ('2', 0x2), ('3', 0x3), ('4', 0xC),
+    ('q', 0x4), ('w', 0x5), ('e', 0x6), ('r', 0xD),
+    ('a', 0x7), ('s', 0x8), ('d', 0x9), ('f', 0xE),
+    ('z', 0xA), ('x', 0x0), ('c', 0xB), ('v', 0xF),
+];
+
+/// Settings read from a configuration file, the command line wins over them.
+///
+/// The file is a small subset of TOML:
+///
+/// ```toml
+/// machine = "vip"      # or `variant = "schip"`
+/// speed = 15           # instructions per 60 Hz frame
+/// quirk_db = "roms.toml" # quirks and speeds of known ROMs
+/// frontend = "window" # or "headless", "terminal", "braille", "sdl", "wgpu" or "minifb"
+/// shader = "crt"       # or "none" or "scanlines", for `wgpu`
+/// scale = 8            # or "fit" or "stretch", for the windows
+///
+/// [quirks]
+/// shift_uses_vy = true
+///
+/// [keys]               # keyboard key = CHIP-8 key
+/// x = 0x0
+///
+/// [palette]
+/// name = "green"       # or "classic", "amber", "high-contrast"
+/// foreground = "#33FF33"
+/// background = "#000000"
+/// plane2 = "#1A991A"   # colors of the second XO-CHIP plane
+/// both = "#AAFFAA"     # and where both planes are lit
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    pub machine: Option<Machine>,
+    pub variant: Option<Variant>,
+    pub speed: Option<u32>,
+    pub quirk_db: Option<String>, // see `QuirkDatabase`
+    pub frontend: Option<Frontend>,
+    pub shader: Option<Shader>,
+    pub scaling: Option<Scaling>,
+    pub quirks: Vec<(String, bool)>,
+    pub keys: Vec<(char, u8)>, // replace the default ones with the same CHIP-8 key
+    pub palette: Option<Palette>,
+}
+
+impl Config {
+    /// Reads the configuration at `path`, or the first one found in the default
+    /// places: `cpu-caller.toml` in the current directory, then
+    /// `$XDG_CONFIG_HOME/cpu-caller/config.toml` or `~/.config/cpu-caller/config.toml`
+    pub fn load(path: Option<&str>) -> Result<Config, String> {
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => match default_path() {
+                Some(path) => path,
+                None => return Ok(Config::default()),
+            },
+        };
+
+        let text = fs::read_to_string(&path).map_err(|e| format!("can't read `{}`: {}", path.display(), e))?;
+        Config::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Reads a configuration from its text representation
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut section = String::new();
+        // the colors change the named palette wherever it is in the section
+        let mut palette_name = None;
+        let mut colors = Vec::new();
+
+        for (index, raw) in text.lines().enumerate() {
+            let error = |message: String| format!("line {}: {}", index + 1, message);
+
+            let content = strip_comment(raw);
+            if content.is_empty() {
+                continue;
+            }
+
+            if let Some(header) = content.strip_prefix('[') {
+                section = header.strip_suffix(']').ok_or_else(|| error("missing `]`".to_string()))?.trim().to_string();
+                if !matches!(section.as_str(), "quirks" | "keys" | "palette") {
+                    return Err(error(format!("unknown section `{}`", section)));
+                }
+                continue;
+            }
+
+            let (key, value) = content
+                .split_once('=')
+                .ok_or_else(|| error("expected `key = value`".to_string()))?;
+            let (key, value) = (unquote(key.trim()), value.trim());
+
+            match (section.as_str(), key) {
+                ("", "machine") => {
+                    let name = string(value).map_err(error)?;
+                    config.machine = Some(Machine::from_name(name).ok_or_else(|| error(format!("unknown machine `{}`", name)))?);
+                }
+                ("", "variant") => {
+                    let name = string(value).map_err(error)?;
+                    config.variant = Some(Variant::from_name(name).ok_or_else(|| error(format!("unknown variant `{}`", name)))?);
+                }
+                ("", "speed") => {
+                    let speed = number(value).filter(|&n| n > 0);
+                    config.speed = Some(speed.ok_or_else(|| error(format!("`{}` is not a number of instructions", value)))?);
+                }
+                ("", "quirk_db") => config.quirk_db = Some(string(value).map_err(error)?.to_string()),
+                ("", "frontend") => {
+                    let name = string(value).map_err(error)?;
+                    config.frontend = Some(Frontend::from_name(name).ok_or_else(|| error(format!("unknown frontend `{}`", name)))?);
+                }
+                ("", "shader") => {
+                    let name = string(value).map_err(error)?;
+                    config.shader = Some(Shader::from_name(name).ok_or_else(|| error(format!("unknown shader `{}`", name)))?);
+                }
+                ("", "scale") => {
+                    let scaling = match string(value) {
+                        Ok("fit") => Some(Scaling::Fit),
+                        Ok("stretch") => Some(Scaling::Stretch),
+                        Ok(_) => None,
+                        Err(_) => number(value).filter(|&n| n > 0).map(Scaling::Fixed),
+                    };
+                    config.scaling = Some(scaling.ok_or_else(|| error(format!("`{}` is not a scale, \"fit\" or \"stretch\"", value)))?);
+                }
+                ("quirks", name) => {
+                    if Quirks::default().by_name(name).is_none() {
+                        return Err(error(format!("unknown quirk `{}`", name)));
+                    }
+                    let flag = match value {
+                        "true" => true,
+                        "false" => false,
+                        _ => return Err(error(format!("`{}` is not a boolean", value))),
+                    };
+                    config.quirks.push((name.to_string(), flag));
+                }
+                ("keys", name) => {
+                    let mut chars = name.chars();
+                    let (Some(keyboard), None) = (chars.next(), chars.next()) else {
+                        return Err(error(format!("`{}` is not a single keyboard key", name)));
+                    };
+                    let key = number(value).filter(|&k| k < 16);
+                    let key = key.ok_or_else(|| error(format!("`{}` is not a key between 0x0 and 0xF", value)))?;
+                    config.keys.push((keyboard.to_ascii_lowercase(), key as u8));
+                }
+                ("palette", "name") => {
+                    let name = string(value).map_err(error)?;
+                    let palette = Palette::from_name(name);
+                    palette_name = Some(palette.ok_or_else(|| error(format!("unknown palette `{}`, expected one of {}", name, Palette::NAMES.join(", "))))?);
+                }
+                ("palette", name) => {
+                    if Palette::default().by_name(name).is_none() {
+                        return Err(error(format!("unknown color `{}`", name)));
+                    }
+                    let color = string(value).map_err(error)?;
+                    let color = parse_color(color).ok_or_else(|| error(format!("`{}` is not a `#RRGGBB` color", color)))?;
+                    colors.push((name, color));
+                }
+                (_, key) => return Err(error(format!("unknown setting `{}`", key))),
+            }
+        }
+
+        if palette_name.is_some() || !colors.is_empty() {
+            let mut palette = palette_name.unwrap_or_default();
+            for (name, color) in colors {
+                *palette.by_name(name).expect("color names are checked while parsing") = color;
+            }
+            config.palette = Some(palette);
+        }
+        Ok(config)
+    }
+}
+
+/// First configuration file found in the default places
+fn default_path() -> Option<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+
+    let mut candidates = vec![PathBuf::from(DEFAULT_FILE)];
+    if let Some(dir) = config_home {
+        candidates.push(dir.join("cpu-caller").join("config.toml"));
+    }
+    candidates.into_iter().find(|path| path.is_file())
+}
+
+/// Line without its comment and surrounding whitespace, a comment starts at
+/// any `#` outside of a quoted string so that colors aren't comments
+pub fn strip_comment(raw: &str) -> &str {
+    let mut quoted = false;
+    let comment = raw.char_indices().find(|&(_, c)| {
+        quoted ^= c == '"';
+        c == '#' && !quoted
+    });
+    match comment {
+        Some((start, _)) => raw[..start].trim(),
+        None => raw.trim(),
+    }
+}
+
+/// Removes the quotes around a key or a string
+pub fn unquote(text: &str) -> &str {
+    text.strip_prefix('"').and_then(|t| t.strip_suffix('"')).unwrap_or(text)
+}
+
+/// Reads a quoted string
+pub fn string(value: &str) -> Result<&str, String> {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        Ok(&value[1..value.len() - 1])
+    } else {
+        Err(format!("`{}` is not a quoted string", value))
+    }
+}
+
+/// Reads a decimal or `0x` hexadecimal number
+pub fn number(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Reads a `#RRGGBB` color
+pub fn parse_color(text: &str) -> Option<[u8; 3]> {
+    let hex = text.strip_prefix('#')?;
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments() {
+        assert_eq!(strip_comment("key = 5\t# note"), "key = 5");
+        assert_eq!(strip_comment("key = 5# note"), "key = 5");
+        assert_eq!(strip_comment("# the whole line"), "");
+        assert_eq!(strip_comment("  speed = 30  "), "speed = 30");
+    }
+
+    #[test]
+    fn colors_are_not_comments() {
+        assert_eq!(strip_comment("background = \"#202020\" # dark"), "background = \"#202020\"");
+    }
+}
@@ -0,0 +1,1938 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::decoder::{decode, Instruction};
+use crate::expr::Expr;
+use crate::font::{BIG_FONT, BIG_FONT_ADDRESS, DEFAULT_FONT, FONT_ADDRESS};
+use crate::hooks::Hooks;
+use crate::profiles::QuirkDatabase;
+use crate::snapshot::Internals;
+use crate::watchdog::Watchdog;
+use crate::{AudioSink, Bus, CpuError, Display, FlatMemory, Framebuffer, Keypad, Null, Renderer};
+use crate::{HistoryEntry, RegisterDelta, TraceRecord, TraceSink};
+use crate::{detect_variant_at, Machine, OpcodePattern, Quirks, Rng, Variant, XorShiftRng};
+use crate::{PLANES, PROGRAM_START, STACK_DEPTH, XO_MEMORY_SIZE};
+
+/// Why execution stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    ExitOpcode,                 // the 00FD opcode asked the interpreter to exit
+    HaltOpcode,                 // the 0000 opcode or a jump to itself was reached
+    WaitingForKey,              // Fx0A is blocking until the frontend delivers a key
+    WaitingForVblank,           // DXYN is blocking until the next 60 Hz tick, see `Quirks::display_wait`
+    Breakpoint(u16),            // execution reached a breakpoint at this address
+    // the instruction at `pc` has an opcode matching one of the opcode breakpoints
+    OpcodeBreakpoint { pc: u16, opcode: u16 },
+    // the instruction at `pc` read or wrote the watched byte at `addr`
+    Watchpoint { pc: u16, addr: u16, access: Access },
+    // the program went around `start..=end` for the watchdog limit without changing anything
+    Stuck { start: u16, end: u16 },
+    InstructionBudgetExhausted, // the program can go on, it ran out of the time it was given
+    Error(CpuError),            // the program faulted, the PC is left on the culprit
+}
+
+/// Memory accesses a watchpoint stops on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Any, // reads and writes, a hit is reported as the one that happened
+}
+
+/// What happened after executing an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    Continue,            // the program can go on with the next instruction
+    Stopped(StopReason), // calling `step` again won't make progress until the reason goes away
+}
+
+/// Result of running a bounded number of instructions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunSummary {
+    pub executed: usize,     // instructions actually executed
+    pub stopped: StopReason, // `InstructionBudgetExhausted` when the whole budget was used
+}
+
+/// Routine run for 0NNN calls, it receives the address of the call
+pub type SysHandler = Box<dyn FnMut(&mut CPU, u16)>;
+
+/// Tells if an extension handles an opcode
+type ExtensionMatcher = Box<dyn Fn(u16) -> bool>;
+
+/// Executes a custom instruction, it receives its opcode
+type ExtensionHandler = Box<dyn FnMut(&mut CPU, u16) -> Result<(), CpuError>>;
+
+/// Instructions kept by the history unless `set_history_len` says otherwise
+pub const HISTORY_LEN: usize = 64;
+
+/// How 0NNN calls to machine code routines of the host computer are handled
+pub enum SysPolicy {
+    Ignore,
+    Trap,
+    Handler(SysHandler),
+}
+
+/// CHIP-8 virtual machine: registers, memory, display, keypad and timers
+#[allow(clippy::upper_case_acronyms)]
+pub struct CPU {
+    registers: [u8; 16], // (container of data that the CPU accesses directly
+    position_in_memory: usize,
+    start_address: usize, // where execution begins after a reset
+    rom: Vec<u8>, // last program loaded, written again by `reset`
+    memory: Box<dyn Bus>, // every memory access of the program goes through it
+    i: u16, // index register, holds memory addresses used by some opcodes
+    stack: Vec<u16>, // specialized memory for storing addresses, its length is the maximum depth
+    stack_pointer: usize,
+    variant: Variant,
+    quirks: Quirks,
+    auto_quirks: bool, // pick the variant and quirks of loaded ROMs automatically
+    variant_override: Option<Variant>, // variant used for loaded ROMs instead of detecting it
+    quirk_database: Option<QuirkDatabase>, // known ROMs get their quirks applied when loaded
+    tickrate: Option<u32>, // instructions per frame recommended for the loaded ROM
+    rng: Box<dyn Rng>,
+    sys_policy: SysPolicy,
+    extensions: Vec<(ExtensionMatcher, ExtensionHandler)>, // custom instructions in place of unknown opcodes
+    hooks: Hooks,
+    trace: Option<Box<dyn TraceSink>>, // receives a record of every executed instruction
+    display: Box<dyn Renderer>,
+    audio: Box<dyn AudioSink>,
+    input: Box<dyn Keypad>, // source of the key events fed to `keypad`
+    screen: Framebuffer,
+    display_dirty: bool, // framebuffer changed since the last redraw
+    rpl_flags: [u8; 16], // HP-48 user flags kept by Fx75/Fx85
+    audio_pattern: [u8; 16], // XO-CHIP 1-bit samples played while the sound timer runs
+    pitch: u8, // XO-CHIP playback rate of the audio pattern, 64 means 4000 Hz
+    keypad: [bool; 16], // state of the hexadecimal keys 0-F, `true` means pressed
+    delay_timer: u8,
+    sound_timer: u8, // a tone plays while this is above zero
+    waiting_for_key: Option<u8>, // register that receives the key Fx0A is waiting for
+    pending_key: Option<u8>,     // key pressed while waiting, delivered once released
+    waiting_for_vblank: bool, // a sprite was drawn with the display wait quirk
+    instructions_executed: u64,
+    cycles_since_tick: u32, // cycles `advance` ran since it last ticked the timers
+    breakpoints: BTreeMap<usize, Option<Expr>>, // where execution stops before the instruction, if the condition holds
+    at_breakpoint: bool, // stopped at the breakpoint of the PC, the next step goes past it
+    opcode_breakpoints: Vec<OpcodePattern>, // opcodes execution stops before wherever they are
+    watchpoints: Vec<(Range<usize>, Access)>, // bytes whose accesses by the program stop execution
+    watch_hit: Option<(usize, Access)>, // first watched access of the instruction being executed
+    watchdog: Option<Watchdog>, // stops programs stuck in a loop, see `set_watchdog`
+    stored: bool, // the instruction being executed wrote memory
+    history: VecDeque<(u64, u16, u16)>, // cycle, address and opcode of the last instructions executed
+    history_len: usize,
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CPU {
+    /// Creates a CPU in its power-on state with the default font loaded,
+    /// quirks are chosen for every ROM loaded into it
+    pub fn new() -> Self {
+        let mut cpu = Self::with_quirks(Quirks::default());
+        cpu.auto_quirks = true;
+        cpu
+    }
+
+    /// Creates a CPU that emulates one of the historical interpreters
+    pub fn for_machine(machine: Machine) -> Self {
+        let mut cpu = Self::with_quirks(machine.quirks());
+        cpu.set_variant(machine.variant());
+        cpu.variant_override = Some(machine.variant()); // loaded ROMs don't change the machine
+        cpu.load_font(machine.font());
+        cpu.tickrate = Some(machine.tickrate());
+        cpu
+    }
+
+    /// Creates a CPU that behaves like the interpreter described by `quirks`,
+    /// these are kept when loading ROMs unless the quirk database knows better
+    pub fn with_quirks(quirks: Quirks) -> Self {
+        let mut cpu = CPU {
+            registers: [0; 16],
+            memory: Box::new(FlatMemory::new(4096)),
+            position_in_memory: 0,
+            start_address: PROGRAM_START,
+            rom: Vec::new(),
+            i: 0,
+            stack: vec![0; STACK_DEPTH],
+            stack_pointer: 0,
+            variant: Variant::Chip8,
+            quirks,
+            auto_quirks: false,
+            variant_override: None,
+            quirk_database: None,
+            tickrate: None,
+            rng: Box::new(XorShiftRng::new(0)),
+            sys_policy: SysPolicy::Ignore,
+            extensions: Vec::new(),
+            hooks: Hooks::default(),
+            trace: None,
+            display: Box::new(Null),
+            audio: Box::new(Null),
+            input: Box::new(Null),
+            screen: Framebuffer::new(),
+            display_dirty: false,
+            rpl_flags: [0; 16],
+            audio_pattern: [0; 16],
+            pitch: 64,
+            keypad: [false; 16],
+            delay_timer: 0,
+            sound_timer: 0,
+            waiting_for_key: None,
+            pending_key: None,
+            waiting_for_vblank: false,
+            instructions_executed: 0,
+            cycles_since_tick: 0,
+            breakpoints: BTreeMap::new(),
+            at_breakpoint: false,
+            opcode_breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            watchdog: None,
+            stored: false,
+            history: VecDeque::new(),
+            history_len: HISTORY_LEN,
+        };
+
+        cpu.load_font(&DEFAULT_FONT);
+        cpu.write_memory(BIG_FONT_ADDRESS, &BIG_FONT).expect("fonts fit in 4 KB");
+        cpu
+    }
+
+    /// Returns the compatibility settings in use
+    pub fn quirks(&self) -> &Quirks {
+        &self.quirks
+    }
+
+    /// Changes the compatibility settings, loaded ROMs will keep them
+    /// unless the quirk database knows better
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+        self.auto_quirks = false;
+    }
+
+    /// Sets the database used to look up the settings of loaded ROMs
+    pub fn set_quirk_database(&mut self, database: QuirkDatabase) {
+        self.quirk_database = Some(database);
+    }
+
+    /// Returns the instructions per frame the loaded ROM is known to need
+    pub fn tickrate(&self) -> Option<u32> {
+        self.tickrate
+    }
+
+    /// Instructions executed per 60 Hz tick: the recommended tickrate or the
+    /// one of the emulated variant
+    pub fn instructions_per_frame(&self) -> u32 {
+        self.tickrate
+            .unwrap_or_else(|| Machine::for_variant(self.variant).tickrate())
+    }
+
+    /// Sets the number of instructions `advance` executes per 60 Hz tick
+    pub fn set_tickrate(&mut self, tickrate: u32) {
+        self.tickrate = Some(tickrate.max(1));
+    }
+
+    /// Uses `variant` for loaded ROMs instead of detecting it from their opcodes
+    pub fn set_variant_override(&mut self, variant: Variant) {
+        self.variant_override = Some(variant);
+    }
+
+    /// Copies a program into memory at the conventional address 0x200 and
+    /// points the CPU at it, applying its profile when the ROM is found in
+    /// the quirk database
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), CpuError> {
+        self.load_rom_at(rom, PROGRAM_START as u16)
+    }
+
+    /// Loads a program at `base`, e.g. test programs that start at 0x000.
+    /// Nothing changes when the ROM doesn't fit in memory
+    pub fn load_rom_at(&mut self, rom: &[u8], base: u16) -> Result<(), CpuError> {
+        let base = base as usize;
+        let variant = self.variant_override.unwrap_or_else(|| detect_variant_at(rom, base, base));
+
+        // XO-CHIP ROMs get the bigger memory
+        let memory_size = if variant == Variant::XoChip {
+            self.memory.size().max(XO_MEMORY_SIZE)
+        } else {
+            self.memory.size()
+        };
+        let available = memory_size.saturating_sub(base);
+        if rom.len() > available {
+            return Err(CpuError::RomTooLarge { size: rom.len(), available });
+        }
+
+        self.set_variant(variant);
+        if self.auto_quirks {
+            let machine = Machine::for_variant(variant);
+            self.quirks = machine.quirks();
+            self.tickrate = Some(machine.tickrate());
+        }
+
+        self.write_memory(base, rom)?;
+        self.rom = rom.to_vec();
+        self.set_start_address(base);
+
+        if let Some(profile) = self.quirk_database.as_ref().and_then(|db| db.lookup(rom)) {
+            self.quirks = profile.apply(self.quirks);
+            self.tickrate = profile.tickrate.or(self.tickrate);
+        }
+        Ok(())
+    }
+
+    /// Points the CPU at `addr` and makes it the address `reset` returns to
+    pub(crate) fn set_start_address(&mut self, addr: usize) {
+        self.start_address = addr;
+        self.position_in_memory = addr;
+    }
+
+    /// Restores the power-on state, like the reset button of a frontend:
+    /// registers, timers, stack, display and keypad are cleared and execution
+    /// goes back to the start address.
+    ///
+    /// Program memory is zeroed, with `keep_rom` the last loaded ROM is then
+    /// written again so it starts from its original contents. The fonts,
+    /// quirks, breakpoints and attached devices are kept
+    pub fn reset(&mut self, keep_rom: bool) {
+        self.registers = [0; 16];
+        self.i = 0;
+        self.stack.fill(0);
+        self.stack_pointer = 0;
+        self.delay_timer = 0;
+        self.sound_timer = 0;
+        self.update_audio();
+        self.keypad = [false; 16];
+        self.waiting_for_key = None;
+        self.pending_key = None;
+        self.waiting_for_vblank = false;
+        self.cycles_since_tick = 0;
+        self.at_breakpoint = false;
+        if let Some(watchdog) = &mut self.watchdog {
+            watchdog.reset();
+        }
+        self.history.clear();
+
+        self.screen.reset();
+        self.display_dirty = true;
+        self.audio_pattern = [0; 16];
+        self.pitch = 64;
+
+        // everything below the program area belongs to the interpreter and survives
+        for addr in PROGRAM_START..self.memory.size() {
+            // devices can reject writes, memory is cleared as far as possible
+            let _ = self.memory.write8(addr, 0);
+        }
+        if keep_rom {
+            let rom = core::mem::take(&mut self.rom);
+            let _ = self.write_memory(self.start_address, &rom);
+            self.rom = rom;
+        }
+
+        self.position_in_memory = self.start_address;
+    }
+
+    /// Selects the machine whose instruction set is emulated
+    pub fn set_variant(&mut self, variant: Variant) {
+        self.variant = variant;
+
+        if variant == Variant::XoChip && self.memory.size() < XO_MEMORY_SIZE {
+            self.memory.resize(XO_MEMORY_SIZE);
+        }
+    }
+
+    /// Tells if the SUPER-CHIP instructions are available, XO-CHIP includes them
+    fn schip(&self) -> bool {
+        self.variant != Variant::Chip8
+    }
+
+    /// Tells if the XO-CHIP instructions are available
+    fn xochip(&self) -> bool {
+        self.variant == Variant::XoChip
+    }
+
+    /// Returns the 16 bytes of the XO-CHIP audio pattern
+    pub fn audio_pattern(&self) -> &[u8; 16] {
+        &self.audio_pattern
+    }
+
+    /// Returns the playback rate of the audio pattern in Hz
+    pub fn audio_frequency(&self) -> f64 {
+        // 2^((pitch - 64) / 48) one step at a time, `powf` is not available without std
+        const STEP: f64 = 1.0145453349375237; // 2^(1/48)
+        let steps = self.pitch as i32 - 64;
+        let mut frequency = 4000.0;
+        for _ in 0..steps.abs() {
+            if steps > 0 { frequency *= STEP } else { frequency /= STEP }
+        }
+        frequency
+    }
+
+    /// Sets the playback rate of the audio pattern to the value of register `x`
+    fn set_pitch(&mut self, x: u8) {
+        self.pitch = self.registers[x as usize];
+        self.update_audio();
+    }
+
+    /// Loads the audio pattern from memory at I
+    fn ld_audio(&mut self) -> Result<(), CpuError> {
+        let mut pattern = [0; 16];
+        self.read_into(self.i as usize, &mut pattern)?;
+        self.audio_pattern = pattern;
+        self.update_audio();
+        Ok(())
+    }
+
+    /// Loads the 16-bit address stored after the current instruction into I
+    fn ld_i_long(&mut self) -> Result<(), CpuError> {
+        self.i = self.read_opcode()?;
+        self.position_in_memory += 2; // the address is part of this instruction
+        Ok(())
+    }
+
+    /// Stores registers Vx to Vy in memory at I, in reverse order when `x` > `y`
+    fn save_xy(&mut self, x: u8, y: u8) -> Result<(), CpuError> {
+        for (offset, reg) in Self::register_range(x, y).enumerate() {
+            self.store(self.i as usize + offset, &[self.registers[reg]])?;
+        }
+        Ok(())
+    }
+
+    /// Loads registers Vx to Vy from memory at I, in reverse order when `x` > `y`
+    fn load_xy(&mut self, x: u8, y: u8) -> Result<(), CpuError> {
+        for (offset, reg) in Self::register_range(x, y).enumerate() {
+            let mut value = [0];
+            self.read_into(self.i as usize + offset, &mut value)?;
+            self.registers[reg] = value[0];
+        }
+        Ok(())
+    }
+
+    /// Registers from `x` to `y` both included, counting down if needed
+    fn register_range(x: u8, y: u8) -> Box<dyn Iterator<Item = usize>> {
+        let (x, y) = (x as usize, y as usize);
+        if x <= y {
+            Box::new(x..=y)
+        } else {
+            Box::new((y..=x).rev())
+        }
+    }
+
+    /// Replaces the font sprites used by Fx29 with a custom set of 16 glyphs
+    pub fn load_font(&mut self, font: &[u8; 80]) {
+        self.write_memory(FONT_ADDRESS, font).expect("fonts fit in 4 KB");
+    }
+
+    /// Points the index register at the font sprite for the digit in register `x`
+    fn ld_fx(&mut self, x: u8) {
+        let digit = (self.registers[x as usize] & 0xF) as usize;
+        self.i = (FONT_ADDRESS + digit * 5) as u16;
+    }
+
+    /// Points the index register at the big font sprite for the digit in register `x`
+    fn ld_hfx(&mut self, x: u8) {
+        let digit = (self.registers[x as usize] % 10) as usize; // only 0-9 have big glyphs
+        self.i = (BIG_FONT_ADDRESS + digit * 10) as u16;
+    }
+
+    /// Saves registers V0 to Vx in the RPL user flags
+    fn ld_rx(&mut self, x: u8) {
+        let count = x as usize + 1;
+        self.rpl_flags[..count].copy_from_slice(&self.registers[..count]);
+    }
+
+    /// Restores registers V0 to Vx from the RPL user flags
+    fn ld_xr(&mut self, x: u8) {
+        let count = x as usize + 1;
+        self.registers[..count].copy_from_slice(&self.rpl_flags[..count]);
+    }
+
+    /// Returns the value of register `x`, only its low nibble counts like for `key`
+    pub fn register(&self, x: u8) -> u8 {
+        self.registers[(x & 0xF) as usize]
+    }
+
+    /// Sets the value of register `x`, only its low nibble counts like for `key`
+    pub fn set_register(&mut self, x: u8, value: u8) {
+        self.registers[(x & 0xF) as usize] = value;
+    }
+
+    /// Returns the memory the CPU executes against
+    pub fn bus(&self) -> &dyn Bus {
+        self.memory.as_ref()
+    }
+
+    /// Returns the memory the CPU executes against for direct access
+    pub fn bus_mut(&mut self) -> &mut dyn Bus {
+        self.memory.as_mut()
+    }
+
+    /// Replaces the memory, e.g. with one that has devices mapped into it
+    pub fn set_bus(&mut self, bus: Box<dyn Bus>) {
+        self.memory = bus;
+    }
+
+    /// Number of addressable bytes
+    pub fn memory_size(&self) -> usize {
+        self.memory.size()
+    }
+
+    /// Copies `len` bytes of memory starting at `addr` without side effects
+    pub fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, CpuError> {
+        // a range past the end of the address space goes past the end of memory first
+        let end = addr.checked_add(len).ok_or(CpuError::OutOfBounds { addr: addr.max(self.memory.size()) })?;
+        (addr..end).map(|a| self.memory.peek8(a)).collect()
+    }
+
+    /// Copies `bytes` into memory starting at `addr`, e.g. to poke a program into it
+    pub fn write_memory(&mut self, addr: usize, bytes: &[u8]) -> Result<(), CpuError> {
+        for (offset, &byte) in bytes.iter().enumerate() {
+            let at = addr.checked_add(offset).ok_or(CpuError::OutOfBounds { addr })?;
+            self.memory.write8(at, byte)?;
+        }
+        Ok(())
+    }
+
+    /// Writes bytes on behalf of the program, reporting them to the memory write hook
+    fn store(&mut self, addr: usize, bytes: &[u8]) -> Result<(), CpuError> {
+        self.stored = true;
+        for (offset, &byte) in bytes.iter().enumerate() {
+            self.memory.write8(addr + offset, byte)?;
+            if let Some(hook) = &mut self.hooks.on_memory_write {
+                hook(addr + offset, byte);
+            }
+            if !self.watchpoints.is_empty() {
+                self.watch(addr + offset, Access::Write);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fills `buf` with the bytes stored from `addr` on, as the program reads them
+    fn read_into(&mut self, addr: usize, buf: &mut [u8]) -> Result<(), CpuError> {
+        for (offset, byte) in buf.iter_mut().enumerate() {
+            *byte = self.memory.read8(addr + offset)?;
+            if !self.watchpoints.is_empty() {
+                self.watch(addr + offset, Access::Read);
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes the amount of memory, new bytes are zeroed
+    pub(crate) fn resize_memory(&mut self, size: usize) {
+        self.memory.resize(size);
+    }
+
+    /// Changes how many nested calls the stack can hold, emptying it
+    pub(crate) fn resize_stack(&mut self, depth: usize) {
+        self.stack = vec![0; depth];
+        self.stack_pointer = 0;
+    }
+
+    /// Returns the address of the next instruction to execute
+    pub fn pc(&self) -> usize {
+        self.position_in_memory
+    }
+
+    /// Points the CPU at the instruction stored at `addr`
+    pub fn set_pc(&mut self, addr: usize) {
+        self.position_in_memory = addr;
+        self.at_breakpoint = false;
+    }
+
+    /// State that `Snapshot` keeps besides what it shows
+    pub(crate) fn internals(&self) -> Internals {
+        Internals {
+            planes: self.screen.pixels().collect(),
+            plane_mask: self.screen.plane_mask(),
+            hires: self.screen.hires(),
+            rpl_flags: self.rpl_flags,
+            audio_pattern: self.audio_pattern,
+            pitch: self.pitch,
+            keypad: self.keypad,
+            waiting_for_key: self.waiting_for_key,
+            pending_key: self.pending_key,
+            waiting_for_vblank: self.waiting_for_vblank,
+            cycles_since_tick: self.cycles_since_tick,
+            rng: self.rng.state(),
+        }
+    }
+
+    /// Restores what `internals` returned, with the calls being executed
+    pub(crate) fn set_internals(&mut self, internals: &Internals, stack: &[u16], instructions_executed: u64) {
+        self.screen.load(&internals.planes, internals.plane_mask, internals.hires);
+        self.display_dirty = true;
+        self.rpl_flags = internals.rpl_flags;
+        self.audio_pattern = internals.audio_pattern;
+        self.pitch = internals.pitch;
+        self.keypad = internals.keypad;
+        self.waiting_for_key = internals.waiting_for_key;
+        self.pending_key = internals.pending_key;
+        self.waiting_for_vblank = internals.waiting_for_vblank;
+        self.cycles_since_tick = internals.cycles_since_tick;
+        if let Some(state) = internals.rng {
+            self.rng.set_state(state);
+        }
+
+        // a deeper stack than this CPU has is cut, the snapshot is from another machine
+        let depth = stack.len().min(self.stack.len());
+        self.stack.fill(0);
+        self.stack[..depth].copy_from_slice(&stack[..depth]);
+        self.stack_pointer = depth;
+        self.instructions_executed = instructions_executed;
+        // what ran after the snapshot hasn't happened anymore
+        self.history.retain(|&(cycle, _, _)| cycle < instructions_executed);
+    }
+
+    /// Makes execution stop with `StopReason::Breakpoint` when the PC reaches
+    /// `addr`, before the instruction there runs. Resuming executes it
+    pub fn add_breakpoint(&mut self, addr: usize) {
+        self.breakpoints.insert(addr, None);
+    }
+
+    /// Like `add_breakpoint`, stopping only when `condition` holds as the PC
+    /// reaches `addr`, e.g. to trap a rare state of a loop
+    pub fn add_breakpoint_if(&mut self, addr: usize, condition: Expr) {
+        self.breakpoints.insert(addr, Some(condition));
+    }
+
+    /// Returns whether there was a breakpoint at `addr`
+    pub fn remove_breakpoint(&mut self, addr: usize) -> bool {
+        self.breakpoints.remove(&addr).is_some()
+    }
+
+    /// Condition of the breakpoint at `addr`, `None` when it always stops or there's none
+    pub fn breakpoint_condition(&self, addr: usize) -> Option<&Expr> {
+        self.breakpoints.get(&addr)?.as_ref()
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// Makes execution stop with `StopReason::OpcodeBreakpoint` before any
+    /// instruction whose opcode matches `pattern`, wherever it is, e.g. to
+    /// find where an unfamiliar ROM draws with `D___`
+    pub fn add_opcode_breakpoint(&mut self, pattern: OpcodePattern) {
+        if !self.opcode_breakpoints.contains(&pattern) {
+            self.opcode_breakpoints.push(pattern);
+        }
+    }
+
+    /// Returns whether there was a breakpoint on `pattern`
+    pub fn remove_opcode_breakpoint(&mut self, pattern: OpcodePattern) -> bool {
+        let count = self.opcode_breakpoints.len();
+        self.opcode_breakpoints.retain(|&other| other != pattern);
+        self.opcode_breakpoints.len() != count
+    }
+
+    pub fn clear_opcode_breakpoints(&mut self) {
+        self.opcode_breakpoints.clear();
+    }
+
+    /// Patterns of the opcode breakpoints in the order they were added
+    pub fn opcode_breakpoints(&self) -> impl Iterator<Item = OpcodePattern> + '_ {
+        self.opcode_breakpoints.iter().copied()
+    }
+
+    /// Makes the instructions that read or write the bytes of `range`, as told
+    /// by `access`, stop execution with `StopReason::Watchpoint` once they ran.
+    /// Only accesses of the program count, not fetching opcodes or the
+    /// frontend poking memory
+    pub fn add_watchpoint(&mut self, range: Range<usize>, access: Access) {
+        self.watchpoints.push((range, access));
+    }
+
+    /// Returns whether a watchpoint of `range` was there
+    pub fn remove_watchpoint(&mut self, range: Range<usize>) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|(watched, _)| *watched != range);
+        self.watchpoints.len() != count
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    /// Makes execution stop with `StopReason::Stuck` once the program has run
+    /// `limit` instructions in a loop of a few bytes without writing memory,
+    /// drawing or running a timer, so headless runs of a program waiting for
+    /// input don't go on forever. Pressing or releasing a key starts counting
+    /// again. `None` turns it off
+    pub fn set_watchdog(&mut self, limit: Option<u64>) {
+        self.watchdog = limit.map(Watchdog::new);
+    }
+
+    /// Limit given to `set_watchdog`
+    pub fn watchdog(&self) -> Option<u64> {
+        self.watchdog.as_ref().map(Watchdog::limit)
+    }
+
+    /// Watched ranges in the order they were added
+    pub fn watchpoints(&self) -> impl Iterator<Item = (Range<usize>, Access)> + '_ {
+        self.watchpoints.iter().cloned()
+    }
+
+    /// Remembers the access of the program to `addr` if a watchpoint covers it
+    fn watch(&mut self, addr: usize, access: Access) {
+        if self.watch_hit.is_some() {
+            return;
+        }
+        let watched = |(range, kind): &(Range<usize>, Access)| range.contains(&addr) && (*kind == access || *kind == Access::Any);
+        if self.watchpoints.iter().any(watched) {
+            self.watch_hit = Some((addr, access));
+        }
+    }
+
+    /// Addresses with a breakpoint, lowest first
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.keys().copied()
+    }
+
+    /// Returns all registers, VF included
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.registers
+    }
+
+    /// Returns the return addresses of the functions being executed, the innermost last
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.stack_pointer]
+    }
+
+    /// Returns how many functions are being executed
+    pub fn stack_pointer(&self) -> usize {
+        self.stack_pointer
+    }
+
+    /// Returns the value of the delay timer
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    /// Sets the value of the delay timer
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.delay_timer = value;
+    }
+
+    /// Returns the value of the sound timer
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    /// Sets the value of the sound timer
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.sound_timer = value;
+        self.update_audio();
+    }
+
+    /// Tells if `key` is held down
+    pub fn key(&self, key: u8) -> bool {
+        self.keypad[(key & 0xF) as usize]
+    }
+
+    /// Returns the machine whose instruction set is emulated
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    /// Returns the value of the index register
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    /// Sets the value of the index register
+    pub fn set_i(&mut self, addr: u16) {
+        self.i = addr;
+    }
+
+    /// Adds register `x` to the index register
+    fn add_ix(&mut self, x: u8) {
+        let val = self.i.wrapping_add(self.registers[x as usize] as u16);
+        self.i = val;
+
+        // Spaceflight 2091! relies on this, most interpreters leave VF alone
+        if self.quirks.i_overflow_sets_vf {
+            self.registers[0xF] = (val > 0xFFF) as u8;
+        }
+    }
+
+    /// Stores the decimal digits of register `x` in memory at I, I+1 and I+2
+    fn ld_bx(&mut self, x: u8) -> Result<(), CpuError> {
+        let val = self.registers[x as usize];
+        let digits = [
+            val / 100, // hundreds
+            (val / 10) % 10, // tens
+            val % 10, // ones
+        ];
+
+        self.store(self.i as usize, &digits)
+    }
+
+    /// Stores registers V0 to Vx in memory starting at I
+    fn ld_ix(&mut self, x: u8) -> Result<(), CpuError> {
+        let count = x as usize + 1; // register `x` is included
+        let registers = self.registers;
+
+        self.store(self.i as usize, &registers[..count])?;
+
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add(count as u16);
+        }
+        Ok(())
+    }
+
+    /// Loads registers V0 to Vx from memory starting at I
+    fn ld_xi(&mut self, x: u8) -> Result<(), CpuError> {
+        let count = x as usize + 1;
+        let mut values = [0; 16];
+        self.read_into(self.i as usize, &mut values[..count])?;
+
+        self.registers[..count].copy_from_slice(&values[..count]);
+
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add(count as u16);
+        }
+        Ok(())
+    }
+
+    /// Reads an opcode from memory by combining two values into a single u16 value
+    fn read_opcode(&mut self) -> Result<u16, CpuError> {
+        let p = self.position_in_memory;
+        let op_byte1 = self.memory.read8(p)? as u16;
+        let op_byte2 = self.memory.read8(p + 1)? as u16;
+
+        // Move the value of ´óp_byte1´ 8 places to the left 
+        // and allocate the value of ´op_byte2´ to the right
+        // in order to create and u16 value (16 bits)
+        Ok(op_byte1 << 8 | op_byte2) // same as (op_byte1 << 8) | op_byte2
+    }
+
+    /// Chooses what happens when a 0NNN opcode is executed
+    pub fn set_sys_policy(&mut self, policy: SysPolicy) {
+        self.sys_policy = policy;
+    }
+
+    /// Runs a 0NNN call according to the SYS policy
+    fn sys(&mut self, routine: u16) -> Result<(), CpuError> {
+        match &mut self.sys_policy {
+            SysPolicy::Ignore => Ok(()),
+            SysPolicy::Trap => Err(CpuError::SysCall { routine, addr: self.instruction_addr() }),
+            SysPolicy::Handler(_) => {
+                // the handler is moved out so that it can borrow the CPU mutably
+                let mut policy = core::mem::replace(&mut self.sys_policy, SysPolicy::Ignore);
+                if let SysPolicy::Handler(handler) = &mut policy {
+                    handler(self, routine);
+                }
+                self.sys_policy = policy;
+                Ok(())
+            }
+        }
+    }
+
+    /// Claims the opcodes accepted by `matcher` that the machine doesn't know,
+    /// `handler` executes them with the program counter already past the
+    /// instruction. When several extensions match the first registered wins
+    ///
+    /// ```
+    /// use cpu_caller::CPU;
+    ///
+    /// let mut cpu = CPU::new();
+    /// // EXF0 stores the number of executed instructions in Vx
+    /// cpu.register_extension(
+    ///     |opcode| opcode & 0xF0FF == 0xE0F0,
+    ///     |cpu, opcode| {
+    ///         let x = ((opcode & 0x0F00) >> 8) as u8;
+    ///         cpu.set_register(x, cpu.instructions_executed() as u8);
+    ///         Ok(())
+    ///     },
+    /// );
+    /// ```
+    pub fn register_extension(
+        &mut self,
+        matcher: impl Fn(u16) -> bool + 'static,
+        handler: impl FnMut(&mut CPU, u16) -> Result<(), CpuError> + 'static,
+    ) {
+        self.extensions.push((Box::new(matcher), Box::new(handler)));
+    }
+
+    /// Runs the extension that claims `opcode`, it is unknown when there's none
+    fn extension(&mut self, opcode: u16) -> Result<(), CpuError> {
+        let index = self.extensions.iter().position(|(matches, _)| matches(opcode));
+        let Some(index) = index else {
+            return Err(CpuError::UnknownOpcode { opcode, addr: self.instruction_addr() });
+        };
+
+        // the extensions are moved out so that the handler can borrow the CPU mutably
+        let mut extensions = core::mem::take(&mut self.extensions);
+        let result = (extensions[index].1)(self, opcode);
+        // handlers registered by the handler itself go after the existing ones
+        extensions.append(&mut self.extensions);
+        self.extensions = extensions;
+        result
+    }
+
+    /// Address of the instruction being executed, `position_in_memory`
+    /// already points past it
+    fn instruction_addr(&self) -> u16 {
+        (self.position_in_memory - 2) as u16
+    }
+
+    /// Jumps to `addr` without touching the stack
+    fn jp(&mut self, addr: u16) {
+        self.position_in_memory = addr as usize;
+    }
+
+    /// Calls a function
+    fn call(&mut self, addr: u16) -> Result<(), CpuError> {
+        let sp = self.stack_pointer;
+
+        if sp >= self.stack.len() {
+            return Err(CpuError::StackOverflow { addr: self.instruction_addr() });
+        }
+
+        let stack = &mut self.stack;
+
+        // ´position_in_memory´ is two bytes higher than the calling location
+        stack[sp] = self.position_in_memory as u16;
+        self.stack_pointer += 1; // prevent memory to be overwritten
+
+        if let Some(hook) = &mut self.hooks.on_stack_push {
+            hook(stack[sp]);
+        }
+        self.position_in_memory = addr as usize;
+        Ok(())
+    }
+
+    /// Returns from a function
+    fn ret(&mut self) -> Result<(), CpuError> {
+        if self.stack_pointer == 0 {
+            return Err(CpuError::StackUnderflow { addr: self.instruction_addr() });
+        }
+
+        self.stack_pointer -= 1;
+        let addr = self.stack[self.stack_pointer];
+        self.position_in_memory = addr as usize; // set memory asdress to the previous CALL opcode
+        Ok(())
+    }
+
+    /// Skips the next instruction when `condition` holds
+    fn skip_if(&mut self, condition: bool) -> Result<(), CpuError> {
+        if condition {
+            // each opcode takes two bytes, except the XO-CHIP long load that takes four
+            let long = self.xochip() && self.read_opcode()? == 0xF000;
+            self.position_in_memory += if long { 4 } else { 2 };
+        }
+        Ok(())
+    }
+
+    /// Loads the constant `kk` into register `x`
+    fn ld_xkk(&mut self, x: u8, kk: u8) {
+        self.registers[x as usize] = kk;
+    }
+
+    /// Adds the constant `kk` to register `x`
+    fn add_xkk(&mut self, x: u8, kk: u8) {
+        // unlike 8xy4 this wraps silently, the carry flag is left untouched
+        self.registers[x as usize] = self.registers[x as usize].wrapping_add(kk);
+    }
+
+    /// Jumps to `addr` plus the value of an offset register
+    fn jp_v0(&mut self, addr: u16, x: u8) {
+        // SUPER-CHIP reads the high nibble of the address as a register
+        let offset = if self.quirks.jump_with_vx {
+            self.registers[x as usize]
+        } else {
+            self.registers[0]
+        };
+
+        self.position_in_memory = (addr + offset as u16) as usize;
+    }
+
+    /// Replaces the random number source, e.g. to replay a recorded session
+    pub fn set_rng(&mut self, rng: Box<dyn Rng>) {
+        self.rng = rng;
+    }
+
+    /// Stores a random number masked with `kk` in register `x`
+    fn rnd_xkk(&mut self, x: u8, kk: u8) {
+        self.registers[x as usize] = self.rng.next_u8() & kk;
+    }
+
+    /// Returns the pixels of the display row by row, `true` means the pixel is on
+    pub fn framebuffer(&self) -> &[bool] {
+        self.plane(0)
+    }
+
+    /// Returns the pixels of one XO-CHIP bit plane, plane 0 is the regular display
+    pub fn plane(&self, index: usize) -> &[bool] {
+        self.screen.plane(index)
+    }
+
+    /// The framebuffer the display instructions draw on
+    pub fn screen(&self) -> &Framebuffer {
+        &self.screen
+    }
+
+    /// Width in pixels of the current display mode
+    pub fn display_width(&self) -> usize {
+        self.screen.width()
+    }
+
+    /// Height in pixels of the current display mode
+    pub fn display_height(&self) -> usize {
+        self.screen.height()
+    }
+
+    /// Sets the screen frames are presented to at each 60 Hz tick
+    pub fn set_display(&mut self, display: Box<dyn Display>) {
+        self.display = Box::new(display);
+    }
+
+    /// Like `set_display` for frontends that want the whole framebuffer
+    pub fn set_renderer(&mut self, renderer: Box<dyn Renderer>) {
+        self.display = renderer;
+    }
+
+    /// Sets the speaker the tone is played through
+    pub fn set_audio_sink(&mut self, audio: Box<dyn AudioSink>) {
+        self.audio = audio;
+        self.update_audio();
+    }
+
+    /// Sets the keyboard key events are read from before each instruction
+    pub fn set_keypad(&mut self, keypad: Box<dyn Keypad>) {
+        self.input = keypad;
+    }
+
+    /// Tells if the framebuffer changed since the renderer last drew it.
+    /// `tick_timers` clears it after presenting the frame to the display
+    /// so frontends that poll it must do it before ticking
+    pub fn display_dirty(&self) -> bool {
+        self.display_dirty
+    }
+
+    /// Marks the framebuffer as drawn, renderers call this after a redraw
+    pub fn mark_display_clean(&mut self) {
+        self.display_dirty = false;
+    }
+
+    /// Turns off every pixel of the selected planes
+    fn cls(&mut self) {
+        self.screen.clear();
+        self.display_dirty = true;
+    }
+
+    /// Switches between the 64x32 and 128x64 display modes
+    fn set_hires(&mut self, hires: bool) {
+        self.screen.set_hires(hires);
+        self.display_dirty = true;
+    }
+
+    /// Moves the picture `n` rows down, rows entering from the top are blank
+    fn scroll_down(&mut self, n: usize) {
+        self.screen.scroll_down(n);
+        self.display_dirty = true;
+    }
+
+    /// Moves the picture `n` rows up, rows entering from the bottom are blank
+    fn scroll_up(&mut self, n: usize) {
+        self.screen.scroll_up(n);
+        self.display_dirty = true;
+    }
+
+    /// Moves the picture 4 pixels to the right
+    fn scroll_right(&mut self) {
+        self.screen.scroll_right(4);
+        self.display_dirty = true;
+    }
+
+    /// Moves the picture 4 pixels to the left
+    fn scroll_left(&mut self) {
+        self.screen.scroll_left(4);
+        self.display_dirty = true;
+    }
+
+    /// Draws a sprite of `n` bytes read from memory at I,
+    /// on SUPER-CHIP `n` = 0 draws a 16x16 sprite made of 2 bytes per row
+    fn drw_xyn(&mut self, x: u8, y: u8, n: u8) -> Result<(), CpuError> {
+        let (origin_x, origin_y) = (self.registers[x as usize] as usize, self.registers[y as usize] as usize);
+        let (sprite_width, rows) = if n == 0 && self.schip() { (16, 16) } else { (8, n as usize) };
+        let bytes_per_row = sprite_width / 8;
+        let mut addr = self.i as usize;
+        let mut collision = false;
+
+        // with several planes selected their sprites are stored one after the other
+        let mask = self.screen.plane_mask();
+        for p in (0..PLANES).filter(|p| mask & (1 << p) != 0) {
+            let mut sprite = [0u16; 16];
+            for row in sprite.iter_mut().take(rows) {
+                let mut bytes = [0; 2];
+                self.read_into(addr, &mut bytes[..bytes_per_row])?;
+                *row = if bytes_per_row == 2 { (bytes[0] as u16) << 8 | bytes[1] as u16 } else { bytes[0] as u16 };
+                addr += bytes_per_row;
+            }
+            collision |= self.screen.draw(p, origin_x, origin_y, &sprite[..rows], sprite_width, self.quirks.clip_sprites);
+        }
+
+        self.registers[0xF] = collision as u8;
+        self.display_dirty = true;
+
+        // the COSMAC VIP drew sprites during the vertical blank so it could draw
+        // one per frame at most, many games rely on this to run at the right speed
+        if self.quirks.display_wait {
+            self.waiting_for_vblank = true;
+        }
+        Ok(())
+    }
+
+    /// Updates the state of a key, frontends call this on keyboard events
+    pub fn set_key(&mut self, key: u8, pressed: bool) {
+        let key = key & 0xF;
+        self.keypad[key as usize] = pressed;
+        // the program may have been waiting for this
+        if let Some(watchdog) = &mut self.watchdog {
+            watchdog.reset();
+        }
+
+        // like the original hardware, Fx0A completes when the key is released
+        if let Some(x) = self.waiting_for_key {
+            if pressed {
+                self.pending_key.get_or_insert(key);
+            } else if self.pending_key == Some(key) {
+                self.registers[x as usize] = key;
+                self.waiting_for_key = None;
+                self.pending_key = None;
+            }
+        }
+    }
+
+    /// Tells if execution is blocked by Fx0A
+    pub fn is_waiting_for_key(&self) -> bool {
+        self.waiting_for_key.is_some()
+    }
+
+    /// Tells if the key whose number is stored in register `x` is pressed
+    fn key_pressed(&self, x: u8) -> bool {
+        let key = self.registers[x as usize] & 0xF; // only 16 keys exist
+        self.keypad[key as usize]
+    }
+
+    /// Decrements both timers, must be called 60 times per second
+    /// regardless of how fast instructions are executed.
+    /// Each call also counts as a vertical blank for the display wait quirk
+    pub fn tick_timers(&mut self) {
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        if self.sound_timer > 0 {
+            self.sound_timer -= 1;
+            if self.sound_timer == 0 {
+                self.update_audio();
+            }
+        }
+        self.waiting_for_vblank = false;
+        self.present();
+    }
+
+    /// Presents the frame to the display if it changed since the last time,
+    /// `tick_timers` does it on its own but single-stepping frontends want it sooner
+    pub fn present(&mut self) {
+        if self.display_dirty {
+            self.display.render(&self.screen);
+            self.display_dirty = false;
+        }
+    }
+
+    /// Tells the speaker to play or stop according to the sound timer
+    fn update_audio(&mut self) {
+        if self.sound_active() {
+            let frequency = self.audio_frequency();
+            self.audio.play(&self.audio_pattern, frequency);
+        } else {
+            self.audio.stop();
+        }
+    }
+
+    /// Tells if execution is blocked until the next vertical blank
+    pub fn is_waiting_for_vblank(&self) -> bool {
+        self.waiting_for_vblank
+    }
+
+    /// Tells if the buzzer should be sounding
+    pub fn sound_active(&self) -> bool {
+        self.sound_timer > 0
+    }
+
+    /// Copies the value of register `y` into register `x`
+    fn ld_xy(&mut self, x: u8, y: u8) {
+        self.registers[x as usize] = self.registers[y as usize];
+    }
+
+    /// Bitwise OR of two registers, stored in register `x`
+    fn or_xy(&mut self, x: u8, y: u8) {
+        self.registers[x as usize] |= self.registers[y as usize];
+        self.reset_vf();
+    }
+
+    /// Bitwise AND of two registers, stored in register `x`
+    fn and_xy(&mut self, x: u8, y: u8) {
+        self.registers[x as usize] &= self.registers[y as usize];
+        self.reset_vf();
+    }
+
+    /// Bitwise XOR of two registers, stored in register `x`
+    fn xor_xy(&mut self, x: u8, y: u8) {
+        self.registers[x as usize] ^= self.registers[y as usize];
+        self.reset_vf();
+    }
+
+    /// Clears the carry flag after a logic operation, as the COSMAC VIP did
+    fn reset_vf(&mut self) {
+        if self.quirks.vf_reset {
+            self.registers[0xF] = 0;
+        }
+    }
+
+    /// Adds two numbers located in registers of CPU
+    fn add_xy(&mut self, x: u8, y: u8) {
+        let arg1 = self.registers[x as usize];
+        let arg2 = self.registers[y as usize];
+
+        let (val, overflow_detected) = arg1.overflowing_add(arg2);
+        self.registers[x as usize] = val;
+
+        // the last register is termed *carry flag* that indicates if an operation
+        // has overflowed
+        if overflow_detected {
+            self.registers[0xF] = 1;
+        } else {
+            self.registers[0xF] = 0;
+        }
+    }
+
+    /// Subtracts register `y` from register `x`
+    fn sub_xy(&mut self, x: u8, y: u8) {
+        let arg1 = self.registers[x as usize];
+        let arg2 = self.registers[y as usize];
+
+        let (val, borrow_detected) = arg1.overflowing_sub(arg2);
+        self.registers[x as usize] = val;
+
+        // for subtraction the flag works the other way around:
+        // 1 means *no borrow* happened
+        self.registers[0xF] = if borrow_detected { 0 } else { 1 };
+    }
+
+    /// Subtracts register `x` from register `y`, storing the result in `x`
+    fn subn_xy(&mut self, x: u8, y: u8) {
+        let arg1 = self.registers[x as usize];
+        let arg2 = self.registers[y as usize];
+
+        let (val, borrow_detected) = arg2.overflowing_sub(arg1);
+        self.registers[x as usize] = val;
+
+        self.registers[0xF] = if borrow_detected { 0 } else { 1 };
+    }
+
+    /// Shifts register `x` (or `y` with the shift quirk) one bit to the right
+    fn shr_xy(&mut self, x: u8, y: u8) {
+        let arg = self.shift_source(x, y);
+
+        self.registers[x as usize] = arg >> 1;
+        self.registers[0xF] = arg & 0x1; // bit that was shifted out
+    }
+
+    /// Shifts register `x` (or `y` with the shift quirk) one bit to the left
+    fn shl_xy(&mut self, x: u8, y: u8) {
+        let arg = self.shift_source(x, y);
+
+        self.registers[x as usize] = arg << 1;
+        self.registers[0xF] = arg >> 7; // bit that was shifted out
+    }
+
+    /// Value to be shifted, the original CHIP-8 shifts Vy while CHIP-48 shifts Vx
+    fn shift_source(&self, x: u8, y: u8) -> u8 {
+        if self.quirks.shift_uses_vy {
+            self.registers[y as usize]
+        } else {
+            self.registers[x as usize]
+        }
+    }
+
+    /// Fetches, decodes and executes exactly one instruction.
+    ///
+    /// Instructions that stop the program or fail are not retired: the
+    /// program counter stays on them so inspecting the state shows the
+    /// culprit and stepping again repeats them
+    pub fn step(&mut self) -> Result<StepOutcome, CpuError> {
+        while let Some((key, pressed)) = self.input.poll() {
+            self.set_key(key, pressed);
+        }
+
+        // Fx0A halts execution until a key has been pressed and released
+        if self.waiting_for_key.is_some() {
+            return Ok(StepOutcome::Stopped(StopReason::WaitingForKey));
+        }
+        if self.waiting_for_vblank {
+            return Ok(StepOutcome::Stopped(StopReason::WaitingForVblank));
+        }
+
+        let pc = self.position_in_memory;
+        // stops once, the step after that executes the instruction
+        let hit = match self.breakpoints.get(&pc) {
+            Some(condition) => condition.as_ref().is_none_or(|condition| condition.holds(self)),
+            None => false,
+        };
+        if hit && !self.at_breakpoint {
+            self.at_breakpoint = true;
+            return Ok(StepOutcome::Stopped(StopReason::Breakpoint(pc as u16)));
+        }
+        if !self.opcode_breakpoints.is_empty() && !self.at_breakpoint {
+            // a PC outside of memory is left for the fetch to report
+            let opcode = self.memory.peek8(pc).ok().zip(self.memory.peek8(pc + 1).ok()).map(|(high, low)| (high as u16) << 8 | low as u16);
+            if let Some(opcode) = opcode.filter(|&opcode| self.opcode_breakpoints.iter().any(|pattern| pattern.matches(opcode))) {
+                self.at_breakpoint = true;
+                return Ok(StepOutcome::Stopped(StopReason::OpcodeBreakpoint { pc: pc as u16, opcode }));
+            }
+        }
+        self.at_breakpoint = false;
+
+        // the state before the instruction is kept to tell what it changed
+        let before = match self.trace {
+            Some(_) => Some((self.peek_instruction(pc)?, self.registers, self.i)),
+            None => None,
+        };
+        // read before the instruction runs, it may write over itself
+        let opcode = match self.history_len {
+            0 => None,
+            _ => self.memory.peek8(pc).ok().zip(self.memory.peek8(pc + 1).ok()).map(|(high, low)| (high as u16) << 8 | low as u16),
+        };
+        self.watch_hit = None;
+        self.stored = false;
+        let drawn = self.display_dirty;
+        let mut outcome = self.execute();
+        if let (Ok(StepOutcome::Continue), Some((addr, access))) = (&outcome, self.watch_hit.take()) {
+            // the instruction is done, the next step goes on after it
+            outcome = Ok(StepOutcome::Stopped(StopReason::Watchpoint { pc: pc as u16, addr: addr as u16, access }));
+        }
+        if let (Ok(StepOutcome::Continue), Some(watchdog)) = (&outcome, &mut self.watchdog) {
+            let busy = self.stored || (self.display_dirty && !drawn);
+            if let Some((start, end)) = watchdog.check(pc, busy, (self.delay_timer, self.sound_timer)) {
+                outcome = Ok(StepOutcome::Stopped(StopReason::Stuck { start: start as u16, end: end as u16 }));
+            }
+        }
+
+        if matches!(outcome, Err(_) | Ok(StepOutcome::Stopped(StopReason::HaltOpcode | StopReason::ExitOpcode))) {
+            self.position_in_memory = pc;
+        } else {
+            if let Some((instruction, registers, i)) = before {
+                self.trace_instruction(pc, instruction, registers, i);
+            }
+            if let Some(opcode) = opcode {
+                if self.history.len() >= self.history_len {
+                    self.history.pop_front();
+                }
+                self.history.push_back((self.instructions_executed, pc as u16, opcode));
+            }
+            self.instructions_executed += 1;
+        }
+        outcome
+    }
+
+    /// Registers a callback run before each instruction with its address and opcode
+    pub fn on_fetch(&mut self, hook: impl FnMut(u16, u16) + 'static) {
+        self.hooks.on_fetch = Some(Box::new(hook));
+    }
+
+    /// Registers a callback run with each instruction that completes
+    pub fn on_execute(&mut self, hook: impl FnMut(Instruction) + 'static) {
+        self.hooks.on_execute = Some(Box::new(hook));
+    }
+
+    /// Registers a callback run with the address and value of each byte the program writes
+    pub fn on_memory_write(&mut self, hook: impl FnMut(usize, u8) + 'static) {
+        self.hooks.on_memory_write = Some(Box::new(hook));
+    }
+
+    /// Registers a callback run with the return address of each subroutine call
+    pub fn on_stack_push(&mut self, hook: impl FnMut(u16) + 'static) {
+        self.hooks.on_stack_push = Some(Box::new(hook));
+    }
+
+    /// Removes every registered hook
+    pub fn clear_hooks(&mut self) {
+        self.hooks = Hooks::default();
+    }
+
+    /// Starts sending a record of every executed instruction to `sink`
+    pub fn set_trace_sink(&mut self, sink: Box<dyn TraceSink>) {
+        self.trace = Some(sink);
+    }
+
+    /// Stops tracing and gives the sink back
+    pub fn take_trace_sink(&mut self) -> Option<Box<dyn TraceSink>> {
+        self.trace.take()
+    }
+
+    /// Decodes the instruction at `addr` without executing it
+    fn peek_instruction(&self, addr: usize) -> Result<Instruction, CpuError> {
+        let opcode = (self.memory.peek8(addr)? as u16) << 8 | self.memory.peek8(addr + 1)? as u16;
+        Ok(decode(opcode, self.variant))
+    }
+
+    /// Reports an executed instruction to the trace sink, comparing the registers
+    /// with their values before it was executed
+    fn trace_instruction(&mut self, pc: usize, instruction: Instruction, registers: [u8; 16], i: u16) {
+        let deltas = (0..16u8)
+            .filter(|&r| registers[r as usize] != self.registers[r as usize])
+            .map(|r| RegisterDelta { register: r, before: registers[r as usize], after: self.registers[r as usize] })
+            .collect();
+
+        let record = TraceRecord {
+            cycle: self.instructions_executed,
+            pc: pc as u16,
+            instruction,
+            registers: deltas,
+            i: if i != self.i { Some((i, self.i)) } else { None },
+        };
+        if let Some(sink) = &mut self.trace {
+            sink.record(record);
+        }
+    }
+
+    /// The last instructions executed, oldest first, e.g. to tell how the
+    /// program got to a fault. Restoring a snapshot forgets the ones after it
+    pub fn history(&self) -> impl Iterator<Item = HistoryEntry> + '_ {
+        self.history.iter().map(|&(cycle, pc, opcode)| HistoryEntry { cycle, pc, opcode, instruction: decode(opcode, self.variant) })
+    }
+
+    /// Keeps the last `len` instructions executed for `history`, 0 stops
+    /// keeping them. It's `HISTORY_LEN` by default
+    pub fn set_history_len(&mut self, len: usize) {
+        self.history_len = len;
+        while self.history.len() > len {
+            self.history.pop_front();
+        }
+    }
+
+    /// Number of instructions executed since the CPU was created
+    pub fn instructions_executed(&self) -> u64 {
+        self.instructions_executed
+    }
+
+    /// Executes the instruction at `position_in_memory`
+    fn execute(&mut self) -> Result<StepOutcome, CpuError> {
+        let opcode = self.read_opcode()?;
+        if let Some(hook) = &mut self.hooks.on_fetch {
+            hook(self.position_in_memory as u16, opcode);
+        }
+        self.position_in_memory += 2;
+
+        let instruction = decode(opcode, self.variant);
+        match instruction {
+            Instruction::Halt => { return Ok(StepOutcome::Stopped(StopReason::HaltOpcode)); },
+            Instruction::Sys { addr } => self.sys(addr)?,
+            Instruction::ScrollDown { n } => self.scroll_down(n as usize),
+            Instruction::ScrollUp { n } => self.scroll_up(n as usize),
+            Instruction::Cls => self.cls(),
+            Instruction::Ret => self.ret()?,
+            Instruction::ScrollRight => self.scroll_right(),
+            Instruction::ScrollLeft => self.scroll_left(),
+            Instruction::Exit => { return Ok(StepOutcome::Stopped(StopReason::ExitOpcode)); },
+            Instruction::LowRes => self.set_hires(false),
+            Instruction::HighRes => self.set_hires(true),
+            Instruction::Jump { addr } => {
+                // `position_in_memory` already points past the jump,
+                // programs commonly end with a jump to itself
+                let idle = addr as usize == self.position_in_memory - 2;
+                self.jp(addr);
+
+                if idle {
+                    return Ok(StepOutcome::Stopped(StopReason::HaltOpcode));
+                }
+            },
+            Instruction::Call { addr } => self.call(addr)?,
+            Instruction::SkipEqByte { x, kk } => self.skip_if(self.registers[x as usize] == kk)?,
+            Instruction::SkipNeByte { x, kk } => self.skip_if(self.registers[x as usize] != kk)?,
+            Instruction::SkipEq { x, y } => self.skip_if(self.registers[x as usize] == self.registers[y as usize])?,
+            Instruction::SaveRange { x, y } => self.save_xy(x, y)?,
+            Instruction::LoadRange { x, y } => self.load_xy(x, y)?,
+            Instruction::LoadByte { x, kk } => self.ld_xkk(x, kk),
+            Instruction::AddByte { x, kk } => self.add_xkk(x, kk),
+            Instruction::Load { x, y } => self.ld_xy(x, y),
+            Instruction::Or { x, y } => self.or_xy(x, y),
+            Instruction::And { x, y } => self.and_xy(x, y),
+            Instruction::Xor { x, y } => self.xor_xy(x, y),
+            Instruction::Add { x, y } => self.add_xy(x, y),
+            Instruction::Sub { x, y } => self.sub_xy(x, y),
+            Instruction::ShiftRight { x, y } => self.shr_xy(x, y),
+            Instruction::SubN { x, y } => self.subn_xy(x, y),
+            Instruction::ShiftLeft { x, y } => self.shl_xy(x, y),
+            Instruction::SkipNe { x, y } => self.skip_if(self.registers[x as usize] != self.registers[y as usize])?,
+            Instruction::LoadI { addr } => self.set_i(addr),
+            Instruction::JumpV0 { addr, x } => self.jp_v0(addr, x),
+            Instruction::Random { x, kk } => self.rnd_xkk(x, kk),
+            Instruction::Draw { x, y, n } => self.drw_xyn(x, y, n)?,
+            Instruction::SkipKey { x } => self.skip_if(self.key_pressed(x))?,
+            Instruction::SkipNoKey { x } => self.skip_if(!self.key_pressed(x))?,
+            Instruction::LoadILong => self.ld_i_long()?,
+            Instruction::SelectPlanes { mask } => self.screen.select_planes(mask),
+            Instruction::LoadAudio => self.ld_audio()?,
+            Instruction::LoadDelay { x } => self.registers[x as usize] = self.delay_timer,
+            Instruction::WaitKey { x } => self.waiting_for_key = Some(x),
+            Instruction::SetDelay { x } => self.delay_timer = self.registers[x as usize],
+            Instruction::SetSound { x } => self.set_sound_timer(self.registers[x as usize]),
+            Instruction::AddI { x } => self.add_ix(x),
+            Instruction::LoadFont { x } => self.ld_fx(x),
+            Instruction::LoadBigFont { x } => self.ld_hfx(x),
+            Instruction::Bcd { x } => self.ld_bx(x)?,
+            Instruction::SetPitch { x } => self.set_pitch(x),
+            Instruction::StoreRegs { x } => self.ld_ix(x)?,
+            Instruction::LoadRegs { x } => self.ld_xi(x)?,
+            // the HP-48 only has 8 flags
+            Instruction::SaveFlags { x } => self.ld_rx(if self.xochip() { x } else { x & 0x7 }),
+            Instruction::LoadFlags { x } => self.ld_xr(if self.xochip() { x } else { x & 0x7 }),
+            Instruction::Unknown(opcode) => self.extension(opcode)?,
+        }
+
+        // instructions that halted or failed returned early and are not reported
+        if let Some(hook) = &mut self.hooks.on_execute {
+            hook(instruction);
+        }
+
+        if self.waiting_for_key.is_some() {
+            Ok(StepOutcome::Stopped(StopReason::WaitingForKey))
+        } else if self.waiting_for_vblank {
+            Ok(StepOutcome::Stopped(StopReason::WaitingForVblank))
+        } else {
+            Ok(StepOutcome::Continue)
+        }
+    }
+
+    /// Executes at most `budget` instructions, stopping earlier if the program does.
+    /// Frontends call this once per frame to share time with rendering and input
+    pub fn run_for(&mut self, budget: usize) -> RunSummary {
+        let start = self.instructions_executed;
+        let mut stopped = StopReason::InstructionBudgetExhausted;
+
+        for _ in 0..budget {
+            match self.step() {
+                Ok(StepOutcome::Continue) => {}
+                Ok(StepOutcome::Stopped(reason)) => {
+                    stopped = reason;
+                    break;
+                }
+                Err(e) => {
+                    stopped = StopReason::Error(e);
+                    break;
+                }
+            }
+        }
+
+        let executed = (self.instructions_executed - start) as usize;
+        RunSummary { executed, stopped }
+    }
+
+    /// Lets `cycles` instruction times pass, ticking the timers every `tickrate`
+    /// of them. The core never reads the wall clock: time only moves through
+    /// this or `tick_timers`, so the same calls always give the same results.
+    ///
+    /// Cycles spent waiting for a key or a vertical blank still count as time,
+    /// execution only stops early when the program halts, exits or hits a breakpoint
+    pub fn advance(&mut self, cycles: usize) -> RunSummary {
+        let tickrate = self.instructions_per_frame();
+        let start = self.instructions_executed;
+        let mut stopped = StopReason::InstructionBudgetExhausted;
+
+        for _ in 0..cycles {
+            // watchpoints and the watchdog stop after their instruction ran, which took its time
+            let mut watched = false;
+            match self.step() {
+                Ok(StepOutcome::Continue) => stopped = StopReason::InstructionBudgetExhausted,
+                Ok(StepOutcome::Stopped(reason @ (StopReason::WaitingForKey | StopReason::WaitingForVblank))) => {
+                    stopped = reason;
+                }
+                Ok(StepOutcome::Stopped(reason @ (StopReason::Watchpoint { .. } | StopReason::Stuck { .. }))) => {
+                    stopped = reason;
+                    watched = true;
+                }
+                Ok(StepOutcome::Stopped(reason)) => {
+                    stopped = reason;
+                    break;
+                }
+                Err(e) => {
+                    stopped = StopReason::Error(e);
+                    break;
+                }
+            }
+
+            self.cycles_since_tick += 1;
+            if self.cycles_since_tick >= tickrate {
+                self.cycles_since_tick = 0;
+                self.tick_timers();
+            }
+            if watched {
+                break;
+            }
+        }
+
+        let executed = (self.instructions_executed - start) as usize;
+        RunSummary { executed, stopped }
+    }
+
+    /// Call functions exeuting them in the CPU emulator.
+    /// Blocks until the program stops for any reason, errors included
+    pub fn run(&mut self) -> StopReason {
+        loop {
+            match self.step() {
+                Ok(StepOutcome::Continue) => {}
+                Ok(StepOutcome::Stopped(reason)) => return reason,
+                Err(e) => return StopReason::Error(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CPU with `rom` loaded and the registers set, after running its first instruction
+    fn after_first(rom: &[u8], registers: &[(u8, u8)]) -> CPU {
+        let mut cpu = CPU::with_quirks(Quirks::default());
+        cpu.load_rom(rom).unwrap();
+        for &(x, value) in registers {
+            cpu.set_register(x, value);
+        }
+        cpu.step().unwrap();
+        cpu
+    }
+
+    #[test]
+    fn skip_eq_byte() {
+        assert_eq!(after_first(&[0x31, 0x42], &[(1, 0x42)]).pc(), PROGRAM_START + 4);
+        assert_eq!(after_first(&[0x31, 0x42], &[(1, 0x41)]).pc(), PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn skip_ne_byte() {
+        assert_eq!(after_first(&[0x41, 0x42], &[(1, 0x41)]).pc(), PROGRAM_START + 4);
+        assert_eq!(after_first(&[0x41, 0x42], &[(1, 0x42)]).pc(), PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn skip_eq_registers() {
+        assert_eq!(after_first(&[0x51, 0x20], &[(1, 7), (2, 7)]).pc(), PROGRAM_START + 4);
+        assert_eq!(after_first(&[0x51, 0x20], &[(1, 7), (2, 8)]).pc(), PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn skip_ne_registers() {
+        assert_eq!(after_first(&[0x91, 0x20], &[(1, 7), (2, 8)]).pc(), PROGRAM_START + 4);
+        assert_eq!(after_first(&[0x91, 0x20], &[(1, 7), (2, 7)]).pc(), PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn load_byte() {
+        let cpu = after_first(&[0x6A, 0x5C], &[(0xA, 0x01)]);
+        assert_eq!(cpu.register(0xA), 0x5C);
+        assert_eq!(cpu.pc(), PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn add_byte() {
+        let cpu = after_first(&[0x73, 0x05], &[(3, 0x10), (0xF, 0x77)]);
+        assert_eq!((cpu.register(3), cpu.register(0xF)), (0x15, 0x77));
+    }
+
+    #[test]
+    fn add_byte_wraps_without_carry() {
+        let cpu = after_first(&[0x73, 0x02], &[(3, 0xFF), (0xF, 0x00)]);
+        assert_eq!((cpu.register(3), cpu.register(0xF)), (0x01, 0x00));
+        let cpu = after_first(&[0x73, 0x02], &[(3, 0xFF), (0xF, 0x01)]);
+        assert_eq!((cpu.register(3), cpu.register(0xF)), (0x01, 0x01));
+    }
+
+    #[test]
+    fn register_numbers_wrap() {
+        let mut cpu = CPU::new();
+        cpu.set_register(0x13, 9);
+        assert_eq!((cpu.register(3), cpu.register(0xF3)), (9, 9));
+    }
+
+    /// Vx and VF after the first instruction of `rom`, with the shift quirk set to `shift_uses_vy`
+    fn vx_vf(rom: [u8; 2], registers: &[(u8, u8)], shift_uses_vy: bool) -> (u8, u8) {
+        let mut cpu = CPU::with_quirks(Quirks { shift_uses_vy, ..Quirks::default() });
+        cpu.load_rom(&rom).unwrap();
+        for &(x, value) in registers {
+            cpu.set_register(x, value);
+        }
+        cpu.step().unwrap();
+        let x = rom[0] & 0x0F;
+        (cpu.register(x), cpu.register(0xF))
+    }
+
+    #[test]
+    fn sub_flags_no_borrow() {
+        // 8125: V1 - V2
+        assert_eq!(vx_vf([0x81, 0x25], &[(1, 5), (2, 3)], false), (2, 1));
+        assert_eq!(vx_vf([0x81, 0x25], &[(1, 3), (2, 5)], false), (0xFE, 0));
+        // equal values don't borrow
+        assert_eq!(vx_vf([0x81, 0x25], &[(1, 4), (2, 4)], false), (0, 1));
+    }
+
+    #[test]
+    fn subn_flags_no_borrow() {
+        // 8127: V2 - V1 into V1
+        assert_eq!(vx_vf([0x81, 0x27], &[(1, 3), (2, 5)], false), (2, 1));
+        assert_eq!(vx_vf([0x81, 0x27], &[(1, 5), (2, 3)], false), (0xFE, 0));
+        assert_eq!(vx_vf([0x81, 0x27], &[(1, 4), (2, 4)], false), (0, 1));
+    }
+
+    #[test]
+    fn shifts_keep_the_bit_shifted_out() {
+        // 8126 and 812E
+        assert_eq!(vx_vf([0x81, 0x26], &[(1, 0b1000_0011)], false), (0b0100_0001, 1));
+        assert_eq!(vx_vf([0x81, 0x26], &[(1, 0b1000_0010)], false), (0b0100_0001, 0));
+        assert_eq!(vx_vf([0x81, 0x2E], &[(1, 0b1000_0011)], false), (0b0000_0110, 1));
+        assert_eq!(vx_vf([0x81, 0x2E], &[(1, 0b0100_0011)], false), (0b1000_0110, 0));
+    }
+
+    #[test]
+    fn shift_quirk_picks_the_register() {
+        let registers = [(1, 0b0000_0001), (2, 0b1000_0100)];
+        // CHIP-48 shifts V1 in place
+        assert_eq!(vx_vf([0x81, 0x26], &registers, false), (0b0000_0000, 1));
+        assert_eq!(vx_vf([0x81, 0x2E], &registers, false), (0b0000_0010, 0));
+        // the original CHIP-8 shifts V2 into V1
+        assert_eq!(vx_vf([0x81, 0x26], &registers, true), (0b0100_0010, 0));
+        assert_eq!(vx_vf([0x81, 0x2E], &registers, true), (0b0000_1000, 1));
+    }
+
+    #[test]
+    fn flag_wins_over_the_result_in_vf() {
+        // with VF as Vx the flag is written after the result
+        assert_eq!(vx_vf([0x8F, 0x14], &[(0xF, 0xFF), (1, 3)], false), (1, 1)); // add, carry
+        assert_eq!(vx_vf([0x8F, 0x15], &[(0xF, 10), (1, 3)], false), (1, 1)); // sub, no borrow
+        assert_eq!(vx_vf([0x8F, 0x17], &[(0xF, 10), (1, 3)], false), (0, 0)); // subn, borrow
+        assert_eq!(vx_vf([0x8F, 0x06], &[(0xF, 0b10)], false), (0, 0)); // shr, bit 0
+        assert_eq!(vx_vf([0x8F, 0x0E], &[(0xF, 0x80)], false), (1, 1)); // shl, bit 7
+    }
+
+    #[test]
+    fn scroll_instructions_move_the_picture() {
+        // the 0 of the font drawn at 8, 8, then 00C3, 00FB and 00FC twice
+        let rom = [0x60, 0x08, 0xF1, 0x29, 0xD0, 0x05, 0x00, 0xC3, 0x00, 0xFB, 0x00, 0xFC, 0x00, 0xFC];
+        let expected = |x: usize, y: usize, hires: bool| {
+            let mut screen = Framebuffer::new();
+            screen.set_hires(hires);
+            screen.draw(0, x, y, &[0xF0, 0x90, 0x90, 0x90, 0xF0], 8, false);
+            screen
+        };
+        for hires in [false, true] {
+            let mut cpu = CPU::for_machine(Machine::SchipModern);
+            cpu.load_rom(&rom).unwrap();
+            cpu.screen.set_hires(hires);
+            for _ in 0..4 {
+                cpu.step().unwrap();
+            }
+            assert_eq!(cpu.screen, expected(8, 11, hires));
+            cpu.step().unwrap();
+            assert_eq!(cpu.screen, expected(12, 11, hires));
+            cpu.step().unwrap();
+            cpu.step().unwrap();
+            assert_eq!(cpu.screen, expected(4, 11, hires));
+        }
+    }
+
+    #[test]
+    fn breakpoints_stop_before_their_instruction() {
+        // LD V0, 1, LD V1, 2, LD V2, 3 and halts
+        let mut cpu = CPU::new();
+        cpu.load_rom(&[0x60, 0x01, 0x61, 0x02, 0x62, 0x03, 0x00, 0x00]).unwrap();
+        cpu.add_breakpoint(PROGRAM_START + 2);
+        assert_eq!(cpu.run(), StopReason::Breakpoint(PROGRAM_START as u16 + 2));
+        assert_eq!((cpu.pc(), cpu.registers()[..2].to_vec()), (PROGRAM_START + 2, vec![1, 0]));
+        // resuming executes it
+        assert_eq!(cpu.run(), StopReason::HaltOpcode);
+        assert_eq!(cpu.registers()[..3], [1, 2, 3]);
+
+        // `advance` stops there too, after what ran before
+        cpu.reset(true);
+        let summary = cpu.advance(100);
+        assert_eq!((summary.executed, summary.stopped), (1, StopReason::Breakpoint(PROGRAM_START as u16 + 2)));
+
+        assert!(cpu.remove_breakpoint(PROGRAM_START + 2));
+        assert!(!cpu.remove_breakpoint(PROGRAM_START + 2));
+        cpu.reset(true);
+        assert_eq!(cpu.run(), StopReason::HaltOpcode);
+    }
+
+    #[test]
+    fn conditional_breakpoints_stop_when_it_holds() {
+        // ADD V0, 1 and jump back
+        let mut cpu = CPU::new();
+        cpu.load_rom(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        let condition = Expr::parse("v0 == 5").unwrap();
+        cpu.add_breakpoint_if(PROGRAM_START, condition.clone());
+        assert_eq!(cpu.breakpoint_condition(PROGRAM_START), Some(&condition));
+        assert_eq!(cpu.run(), StopReason::Breakpoint(PROGRAM_START as u16));
+        assert_eq!(cpu.register(0), 5);
+        // going on doesn't stop again until it holds again
+        cpu.set_register(0, 1);
+        assert_eq!(cpu.run(), StopReason::Breakpoint(PROGRAM_START as u16));
+        assert_eq!((cpu.register(0), cpu.instructions_executed()), (5, 18));
+    }
+
+    /// LD I, 0x300, LD V0, 7, LD [I], V0, LD I, 0x300, LD V1, [I] and halts
+    const STORE_LOAD: [u8; 12] = [0xA3, 0x00, 0x60, 0x07, 0xF0, 0x55, 0xA3, 0x00, 0xF1, 0x65, 0x00, 0x00];
+
+    #[test]
+    fn watchpoints_stop_after_the_access() {
+        let mut cpu = CPU::new();
+        cpu.load_rom(&STORE_LOAD).unwrap();
+        cpu.add_watchpoint(0x300..0x302, Access::Write);
+        cpu.add_watchpoint(0x301..0x302, Access::Read);
+        let write = StopReason::Watchpoint { pc: 0x204, addr: 0x300, access: Access::Write };
+        assert_eq!(cpu.run(), write);
+        // the instruction ran
+        assert_eq!((cpu.pc(), cpu.read_memory(0x300, 1).unwrap()[0]), (0x206, 7));
+        assert_eq!(cpu.run(), StopReason::Watchpoint { pc: 0x208, addr: 0x301, access: Access::Read });
+        assert_eq!(cpu.run(), StopReason::HaltOpcode);
+
+        // `advance` stops there too, counting the instruction
+        cpu.reset(true);
+        assert_eq!(cpu.advance(100), RunSummary { executed: 3, stopped: write });
+    }
+
+    #[test]
+    fn watchpoints_on_any_access() {
+        let mut cpu = CPU::new();
+        cpu.load_rom(&STORE_LOAD).unwrap();
+        cpu.add_watchpoint(0x300..0x301, Access::Any);
+        assert_eq!(cpu.run(), StopReason::Watchpoint { pc: 0x204, addr: 0x300, access: Access::Write });
+        assert_eq!(cpu.run(), StopReason::Watchpoint { pc: 0x208, addr: 0x300, access: Access::Read });
+
+        // fetching the instructions doesn't count
+        assert!(cpu.remove_watchpoint(0x300..0x301));
+        assert!(!cpu.remove_watchpoint(0x300..0x301));
+        cpu.add_watchpoint(PROGRAM_START..PROGRAM_START + STORE_LOAD.len(), Access::Any);
+        cpu.reset(true);
+        assert_eq!(cpu.run(), StopReason::HaltOpcode);
+    }
+
+    #[test]
+    fn watchdog_stops_programs_going_nowhere() {
+        // LD V0, 1 and jump back
+        let mut cpu = CPU::new();
+        cpu.load_rom(&[0x60, 0x01, 0x12, 0x00]).unwrap();
+        cpu.set_watchdog(Some(100));
+        let stuck = StopReason::Stuck { start: PROGRAM_START as u16, end: PROGRAM_START as u16 + 2 };
+        assert_eq!(cpu.run(), stuck);
+        assert_eq!(cpu.instructions_executed(), 100);
+        // a key starts counting again
+        assert_eq!(cpu.run_for(60).stopped, StopReason::InstructionBudgetExhausted);
+        cpu.set_key(1, true);
+        assert_eq!(cpu.run(), stuck);
+        assert_eq!(cpu.instructions_executed(), 260);
+
+        // storing in memory is doing something
+        let mut cpu = CPU::new();
+        cpu.load_rom(&[0xA3, 0x00, 0xF0, 0x55, 0x12, 0x00]).unwrap();
+        cpu.set_watchdog(Some(100));
+        assert_eq!(cpu.advance(1000).stopped, StopReason::InstructionBudgetExhausted);
+    }
+
+    #[test]
+    fn history_keeps_the_last_instructions() {
+        // LD I, 0x200, LD V0, 0x71, then LD [I], V0 makes the first one ADD V1, 0, ADD V1, 1 and jump back to it
+        let mut cpu = CPU::new();
+        cpu.load_rom(&[0xA2, 0x00, 0x60, 0x71, 0xF0, 0x55, 0x71, 0x01, 0x12, 0x00]).unwrap();
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+        let entries: Vec<_> = cpu.history().map(|entry| (entry.cycle, entry.pc, entry.opcode)).collect();
+        assert_eq!(entries, [(0, 0x200, 0xA200), (1, 0x202, 0x6071), (2, 0x204, 0xF055), (3, 0x206, 0x7101), (4, 0x208, 0x1200)]);
+        assert_eq!(cpu.history().next().unwrap().to_string(), "       0 0x200  A200  LD I, 0x200");
+        let early = cpu.snapshot();
+
+        // each opcode as it was when it ran
+        cpu.step().unwrap();
+        assert_eq!(cpu.history().last().map(|entry| (entry.pc, entry.opcode)), Some((0x200, 0x7100)));
+        assert_eq!(cpu.history().next().map(|entry| entry.opcode), Some(0xA200));
+
+        cpu.set_history_len(3);
+        let cycles: Vec<_> = cpu.history().map(|entry| entry.cycle).collect();
+        assert_eq!(cycles, [3, 4, 5]);
+        // restoring forgets what ran after
+        cpu.restore(&early);
+        assert_eq!(cpu.history().map(|entry| entry.cycle).collect::<Vec<_>>(), [3, 4]);
+
+        cpu.set_history_len(0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.history().count(), 0);
+    }
+
+    #[test]
+    fn trace_sinks_get_what_changed() {
+        use alloc::rc::Rc;
+        use core::cell::RefCell;
+
+        let records = Rc::new(RefCell::new(Vec::new()));
+        let mut cpu = CPU::new();
+        // LD V3, 5, LD I, 0x300, JP 0x206 and halts
+        cpu.load_rom(&[0x63, 0x05, 0xA3, 0x00, 0x12, 0x06, 0x00, 0x00]).unwrap();
+        let sink = records.clone();
+        cpu.set_trace_sink(Box::new(move |record| sink.borrow_mut().push(record)));
+        assert_eq!(cpu.run(), StopReason::HaltOpcode);
+
+        let records = records.borrow();
+        let lines: Vec<_> = records.iter().map(|record| record.to_string()).collect();
+        assert_eq!(lines, ["       0 0x200  LD V3, 0x05            V3 00->05", "       1 0x202  LD I, 0x300            I 000->300", "       2 0x204  JP 0x206"]);
+        assert_eq!(records[0].registers, [RegisterDelta { register: 3, before: 0, after: 5 }]);
+        assert_eq!((records[1].i, records[2].i), (Some((0, 0x300)), None));
+
+        // without the sink, nothing is recorded
+        assert!(cpu.take_trace_sink().is_some());
+        cpu.reset(true);
+        cpu.run();
+        assert_eq!(records.len(), 3);
+    }
+
+    #[test]
+    fn opcode_breakpoints_stop_before_matching_instructions() {
+        let mut cpu = CPU::new();
+        cpu.load_rom(&STORE_LOAD).unwrap();
+        cpu.add_opcode_breakpoint(OpcodePattern::parse("F_55").unwrap());
+        cpu.add_opcode_breakpoint(OpcodePattern::parse("Fx65").unwrap());
+        cpu.add_opcode_breakpoint(OpcodePattern::parse("F_55").unwrap());
+        assert_eq!(cpu.opcode_breakpoints().count(), 2);
+
+        assert_eq!(cpu.run(), StopReason::OpcodeBreakpoint { pc: 0x204, opcode: 0xF055 });
+        assert_eq!((cpu.pc(), cpu.instructions_executed()), (0x204, 2));
+        assert_eq!(cpu.run(), StopReason::OpcodeBreakpoint { pc: 0x208, opcode: 0xF165 });
+        assert!(cpu.remove_opcode_breakpoint(OpcodePattern::parse("Fx65").unwrap()));
+        assert!(!cpu.remove_opcode_breakpoint(OpcodePattern::parse("Fx65").unwrap()));
+        assert_eq!(cpu.run(), StopReason::HaltOpcode);
+    }
+
+    #[test]
+    fn quirk_database_changes_the_machine() {
+        // the hash of the ROM `60 01`
+        let text = "[50d705af141eba1f6b6b3e92580d730d7c5f62f9]\nvf_reset = false\ntickrate = 30";
+        let mut cpu = CPU::for_machine(Machine::VipChip8);
+        cpu.set_quirk_database(QuirkDatabase::parse(text).unwrap());
+        cpu.load_rom(&[0x60, 0x01]).unwrap();
+        assert_eq!(*cpu.quirks(), Quirks { vf_reset: false, ..Machine::VipChip8.quirks() });
+        assert_eq!(cpu.instructions_per_frame(), 30);
+
+        // other ROMs keep the machine
+        let mut cpu = CPU::for_machine(Machine::VipChip8);
+        cpu.set_quirk_database(QuirkDatabase::parse(text).unwrap());
+        cpu.load_rom(&[0x60, 0x02]).unwrap();
+        assert_eq!(*cpu.quirks(), Machine::VipChip8.quirks());
+    }
+}
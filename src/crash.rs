@@ -0,0 +1,200 @@
+use cpu_caller::{disassemble_at, CpuError, DisasmLine, HistoryEntry, Symbols, CPU};
+
+use crate::debugger::{self, HISTORY_LINES};
+use crate::json::Json;
+
+/// Instructions shown before and after the faulting one
+const CONTEXT: usize = 3;
+
+/// How `--crash-report` prints what the program was doing when it faulted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CrashFormat {
+    Text,
+    Json, // one line, for tools
+}
+
+/// Everything worth knowing about a fault, taken from the CPU left on it
+pub struct CrashReport {
+    error: CpuError,
+    pc: usize,
+    disassembly: Vec<(DisasmLine, String)>, // instructions around the PC with their text
+    registers: [u8; 16],
+    i: u16,
+    delay_timer: u8,
+    sound_timer: u8,
+    stack: Vec<u16>,            // return addresses, the innermost call last
+    backtrace: Vec<String>,     // innermost first
+    history: Vec<HistoryEntry>, // last instructions executed, oldest first
+}
+
+impl CrashReport {
+    pub fn new(cpu: &CPU, symbols: &Symbols, error: CpuError) -> CrashReport {
+        CrashReport {
+            error,
+            pc: cpu.pc(),
+            disassembly: around(cpu, symbols),
+            registers: *cpu.registers(),
+            i: cpu.i(),
+            delay_timer: cpu.delay_timer(),
+            sound_timer: cpu.sound_timer(),
+            stack: cpu.stack()[..cpu.stack_pointer()].to_vec(),
+            backtrace: debugger::backtrace(cpu, symbols),
+            history: debugger::history(cpu, HISTORY_LINES),
+        }
+    }
+
+    pub fn print(&self, format: CrashFormat) {
+        match format {
+            CrashFormat::Text => eprint!("{}", self.text()),
+            CrashFormat::Json => eprintln!("{}", self.json()),
+        }
+    }
+
+    /// Sections one after the other, the error first
+    fn text(&self) -> String {
+        let mut text = format!("error: {}\n", self.error);
+        for (line, instruction) in &self.disassembly {
+            let marker = if line.addr == self.pc { '>' } else { ' ' };
+            text += &format!("{} {:#05x}  {:<8}  {}\n", marker, line.addr, hex(&line.bytes), instruction);
+        }
+        text += "registers:\n";
+        let registers: Vec<String> = self.registers.iter().enumerate().map(|(x, v)| format!("V{:X}={:02X}", x, v)).collect();
+        text += &format!("{}\n{}\n", registers[..8].join(" "), registers[8..].join(" "));
+        text += &format!("I={:#05x} PC={:#05x} DT={} ST={}\n", self.i, self.pc, self.delay_timer, self.sound_timer);
+        text += "backtrace:\n";
+        for line in &self.backtrace {
+            text += &format!("{}\n", line);
+        }
+        text += "last instructions:\n";
+        for entry in &self.history {
+            text += &format!("{}\n", entry);
+        }
+        text
+    }
+
+    fn json(&self) -> Json {
+        let disassembly: Vec<Json> = self
+            .disassembly
+            .iter()
+            .map(|(line, instruction)| {
+                Json::object([("addr", line.addr.into()), ("bytes", hex(&line.bytes).into()), ("instruction", instruction.as_str().into())])
+            })
+            .collect();
+        let faulting = self.disassembly.iter().find(|(line, _)| line.addr == self.pc);
+        let registers: Vec<Json> = self.registers.iter().map(|&v| Json::from(v as u64)).collect();
+        let stack: Vec<Json> = self.stack.iter().map(|&addr| Json::from(addr as u64)).collect();
+        let history: Vec<Json> = self
+            .history
+            .iter()
+            .map(|entry| {
+                Json::object([
+                    ("cycle", entry.cycle.into()),
+                    ("pc", (entry.pc as u64).into()),
+                    ("opcode", format!("{:04X}", entry.opcode).into()),
+                    ("instruction", entry.instruction.to_string().into()),
+                ])
+            })
+            .collect();
+        Json::object([
+            ("error", self.error.to_string().into()),
+            ("pc", self.pc.into()),
+            ("instruction", faulting.map_or(Json::Null, |(_, instruction)| instruction.as_str().into())),
+            ("disassembly", disassembly.into()),
+            (
+                "registers",
+                Json::object([
+                    ("v", registers.into()),
+                    ("i", (self.i as u64).into()),
+                    ("dt", (self.delay_timer as u64).into()),
+                    ("st", (self.sound_timer as u64).into()),
+                ]),
+            ),
+            ("stack", stack.into()),
+            ("backtrace", self.backtrace.iter().map(|line| Json::from(line.as_str())).collect::<Vec<_>>().into()),
+            ("history", history.into()),
+        ])
+    }
+}
+
+/// Instructions from a few before the PC to a few after it. Instructions
+/// are 2 bytes long, except `F000 NNNN`, so going back 2 bytes at a time
+/// usually lands on the ones that ran
+fn around(cpu: &CPU, symbols: &Symbols) -> Vec<(DisasmLine, String)> {
+    let mut lines = Vec::new();
+    let mut addr = cpu.pc().saturating_sub(2 * CONTEXT);
+    while lines.len() < 2 * CONTEXT + 1 {
+        let len = 4.min(cpu.memory_size().saturating_sub(addr));
+        let Some(line) = cpu
+            .read_memory(addr, len)
+            .ok()
+            .and_then(|bytes| disassemble_at(&bytes, addr, addr, cpu.variant()))
+        else {
+            break;
+        };
+        addr += line.bytes.len();
+        let instruction = line.symbolic(symbols);
+        lines.push((line, instruction));
+    }
+    lines
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Report of a program that calls a function with an instruction that doesn't exist
+    fn report() -> CrashReport {
+        let mut cpu = CPU::new();
+        // LD V0, 5, CALL 0x206, halts, then E000
+        cpu.load_rom(&[0x60, 0x05, 0x22, 0x06, 0x00, 0x00, 0xE0, 0x00]).unwrap();
+        let cpu_caller::StopReason::Error(e) = cpu.run() else {
+            panic!("the program didn't fault");
+        };
+        let mut symbols = Symbols::new();
+        symbols.insert(0x200, "start");
+        symbols.insert(0x206, "broken");
+        CrashReport::new(&cpu, &symbols, e)
+    }
+
+    #[test]
+    fn text() {
+        let expected = "\
+error: unknown opcode e000 at 0x206
+  0x200  6005      LD V0, 0x05
+  0x202  2206      CALL broken
+  0x204  0000      HALT
+> 0x206  E000      DW 0xe000
+  0x208  0000      HALT
+  0x20a  0000      HALT
+  0x20c  0000      HALT
+registers:
+V0=05 V1=00 V2=00 V3=00 V4=00 V5=00 V6=00 V7=00
+V8=00 V9=00 VA=00 VB=00 VC=00 VD=00 VE=00 VF=00
+I=0x000 PC=0x206 DT=0 ST=0
+backtrace:
+#0  0x206 in broken
+#1  0x202 in start+0x2
+last instructions:
+       0 0x200  6005  LD V0, 0x05
+       1 0x202  2206  CALL 0x206
+";
+        assert_eq!(report().text(), expected);
+    }
+
+    #[test]
+    fn json() {
+        let json = Json::parse(&report().json().to_string()).unwrap();
+        assert_eq!(json.get("error"), &Json::from("unknown opcode e000 at 0x206"));
+        assert_eq!((json.get("pc"), json.get("instruction")), (&Json::from(0x206u64), &Json::from("DW 0xe000")));
+        assert_eq!(json.get("disassembly").as_array().len(), 7);
+        assert_eq!(json.get("registers").get("v").as_array()[0], Json::from(5u64));
+        assert_eq!(json.get("stack"), &Json::from(vec![Json::from(0x204u64)]));
+        assert_eq!(json.get("backtrace").as_array(), [Json::from("#0  0x206 in broken"), Json::from("#1  0x202 in start+0x2")]);
+        let history = json.get("history").as_array();
+        assert_eq!((history.len(), history[1].get("opcode"), history[1].get("instruction")), (2, &Json::from("2206"), &Json::from("CALL 0x206")));
+    }
+}
@@ -0,0 +1,726 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Sender, TryRecvError};
+use std::thread;
+
+use cpu_caller::{disassemble_at, Access, Expr, StopReason, Symbols, CPU};
+
+use crate::cli::{parse_address, Options, Source};
+use crate::json::Json;
+
+/// The one thread of the program, the protocol wants one
+const THREAD: u64 = 1;
+
+/// `variablesReference` of the scopes
+const REGISTERS: u64 = 1;
+const MEMORY: u64 = 2;
+
+/// Rows of 16 bytes from I the memory scope shows
+const MEMORY_ROWS: usize = 4;
+
+/// What the program does between two requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Running {
+    Stopped,
+    Continue,
+    StepOut(usize), // until no more than this many functions are being executed
+}
+
+/// Debug adapter of one program, between the client and the CPU
+struct Adapter<W: Write> {
+    out: W, // where the messages to the client go
+    options: Options,
+    cpu: Option<CPU>, // once launched
+    symbols: Symbols,
+    stop_on_entry: bool,
+    running: Running,
+    seq: u64,                                        // of the last message sent
+    events: Vec<Json>,                               // sent after the response being written
+    source_breakpoints: BTreeMap<String, Vec<usize>>, // addresses set for each file, replaced together
+    instruction_breakpoints: Vec<usize>,
+}
+
+/// Speaks the Debug Adapter Protocol on standard input and output, so that
+/// editors like VS Code can launch a program and debug it: breakpoints,
+/// stepping, registers and memory, and the lines of source when the symbol
+/// file has them
+pub fn run(options: &Options) {
+    let (sender, messages) = mpsc::channel();
+    // reading on its own thread lets `pause` come while the program runs
+    thread::spawn(move || read_messages(sender));
+
+    let mut adapter = Adapter::new(options, io::stdout());
+    loop {
+        let message = match adapter.running {
+            Running::Stopped => messages.recv().ok(),
+            _ => match messages.try_recv() {
+                Ok(message) => Some(message),
+                Err(TryRecvError::Empty) => {
+                    adapter.run_frame();
+                    if adapter.flush().is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                Err(TryRecvError::Disconnected) => None,
+            },
+        };
+        // the client closed its end
+        let Some(message) = message else {
+            break;
+        };
+        match adapter.handle(&message) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// Sends each message of standard input, `Content-Length` headers and a JSON body
+fn read_messages(sender: Sender<Json>) {
+    let mut input = io::stdin().lock();
+    loop {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            if input.read_line(&mut header).unwrap_or(0) == 0 {
+                return;
+            }
+            match header.trim() {
+                "" => break,
+                header => {
+                    if let Some(value) = header.strip_prefix("Content-Length:") {
+                        length = value.trim().parse().ok();
+                    }
+                }
+            }
+        }
+        let Some(length) = length else {
+            continue;
+        };
+
+        let mut body = vec![0; length];
+        if input.read_exact(&mut body).is_err() {
+            return;
+        }
+        match Json::parse(&String::from_utf8_lossy(&body)) {
+            Ok(message) => {
+                if sender.send(message).is_err() {
+                    return;
+                }
+            }
+            Err(e) => eprintln!("error: can't read a message: {}", e),
+        }
+    }
+}
+
+impl<W: Write> Adapter<W> {
+    fn new(options: &Options, out: W) -> Self {
+        Adapter {
+            out,
+            options: options.clone(),
+            cpu: None,
+            symbols: Symbols::new(),
+            stop_on_entry: false,
+            running: Running::Stopped,
+            seq: 0,
+            events: Vec::new(),
+            source_breakpoints: BTreeMap::new(),
+            instruction_breakpoints: Vec::new(),
+        }
+    }
+
+    /// Answers a request, returns whether to wait for another one
+    fn handle(&mut self, message: &Json) -> io::Result<bool> {
+        if message.get("type").as_str() != Some("request") {
+            return Ok(true);
+        }
+        let command = message.get("command").as_str().unwrap_or("");
+        let args = message.get("arguments");
+        let body = match command {
+            "initialize" => Ok(capabilities()),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.stop(StopReason::InstructionBudgetExhausted, "entry");
+                } else {
+                    self.resume(Running::Continue);
+                }
+                Ok(Json::object([]))
+            }
+            "threads" => Ok(Json::object([("threads", vec![Json::object([("id", THREAD.into()), ("name", "CHIP-8".into())])].into())])),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(Json::object([(
+                "scopes",
+                vec![
+                    Json::object([("name", "Registers".into()), ("variablesReference", REGISTERS.into()), ("expensive", false.into())]),
+                    Json::object([("name", "Memory at I".into()), ("variablesReference", MEMORY.into()), ("expensive", false.into())]),
+                ]
+                .into(),
+            )])),
+            "variables" => self.variables(args),
+            "setVariable" => self.set_variable(args),
+            "readMemory" => self.read_memory(args),
+            "disassemble" => self.disassemble(args),
+            "continue" => {
+                self.resume(Running::Continue);
+                Ok(Json::object([("allThreadsContinued", true.into())]))
+            }
+            "next" => self.step_over().map(|_| Json::object([])),
+            "stepIn" => self.step_in().map(|_| Json::object([])),
+            "stepOut" => self.step_out().map(|_| Json::object([])),
+            "pause" => {
+                if self.running != Running::Stopped {
+                    self.stop(StopReason::InstructionBudgetExhausted, "pause");
+                }
+                Ok(Json::object([]))
+            }
+            "disconnect" | "terminate" => {
+                self.respond(message, Ok(Json::object([])))?;
+                return Ok(false);
+            }
+            command => Err(format!("`{}` isn't supported", command)),
+        };
+        self.respond(message, body)?;
+        self.flush()?;
+        Ok(true)
+    }
+
+    fn cpu(&mut self) -> Result<&mut CPU, String> {
+        self.cpu.as_mut().ok_or_else(|| "no program is launched".to_string())
+    }
+
+    /// Loads the `program` of the arguments, from the start if `stopOnEntry`
+    fn launch(&mut self, args: &Json) -> Result<Json, String> {
+        let program = args.get("program").as_str().ok_or("`launch` needs the `program` to debug")?;
+        let mut options = self.options.clone();
+        if let Some(symbols) = args.get("symbols").as_str() {
+            options.symbols = Some(symbols.to_string());
+        }
+        self.cpu = Some(crate::load_cpu(&Source::File(program.to_string()), &options)?);
+        self.symbols = crate::read_symbols(program, &options)?;
+        self.stop_on_entry = args.get("stopOnEntry").as_bool().unwrap_or(false);
+        // the client sends the breakpoints once it knows there's a program to put them in
+        self.event("initialized", Json::object([]));
+        Ok(Json::object([]))
+    }
+
+    /// Replaces the breakpoints of a file of source by the ones of its lines
+    fn set_breakpoints(&mut self, args: &Json) -> Result<Json, String> {
+        let path = args.get("source").get("path").as_str().unwrap_or("").to_string();
+        let file = self.source_file(&path);
+        let cpu = self.cpu.as_mut().ok_or("no program is launched")?;
+        for addr in self.source_breakpoints.remove(&path).unwrap_or_default() {
+            cpu.remove_breakpoint(addr);
+        }
+
+        let mut addrs = Vec::new();
+        let mut breakpoints = Vec::new();
+        for breakpoint in args.get("breakpoints").as_array() {
+            let line = breakpoint.get("line").as_u64().unwrap_or(0) as usize;
+            // a line without an instruction stops at the next one that has one
+            let found = file.as_deref().and_then(|file| self.symbols.address_of_line(file, line));
+            let Some((addr, line)) = found else {
+                let message = "there's no instruction at or after this line in the symbol file";
+                breakpoints.push(Json::object([("verified", false.into()), ("line", line.into()), ("message", message.into())]));
+                continue;
+            };
+            match breakpoint.get("condition").as_str().map(Expr::parse) {
+                Some(Err(e)) => {
+                    breakpoints.push(Json::object([("verified", false.into()), ("line", line.into()), ("message", e.into())]));
+                    continue;
+                }
+                Some(Ok(condition)) => cpu.add_breakpoint_if(addr, condition),
+                None => cpu.add_breakpoint(addr),
+            }
+            addrs.push(addr);
+            breakpoints.push(Json::object([("verified", true.into()), ("line", line.into())]));
+        }
+        self.source_breakpoints.insert(path, addrs);
+        Ok(Json::object([("breakpoints", breakpoints.into())]))
+    }
+
+    /// Replaces the breakpoints set on addresses, from the disassembly
+    fn set_instruction_breakpoints(&mut self, args: &Json) -> Result<Json, String> {
+        let cpu = self.cpu.as_mut().ok_or("no program is launched")?;
+        for addr in self.instruction_breakpoints.drain(..) {
+            cpu.remove_breakpoint(addr);
+        }
+
+        let mut breakpoints = Vec::new();
+        for breakpoint in args.get("breakpoints").as_array() {
+            let addr = breakpoint.get("instructionReference").as_str().and_then(parse_address);
+            let addr = addr.map(|addr| addr.saturating_add_signed(number(breakpoint.get("offset")) as isize));
+            let Some(addr) = addr else {
+                breakpoints.push(Json::object([("verified", false.into())]));
+                continue;
+            };
+            match breakpoint.get("condition").as_str().map(Expr::parse) {
+                Some(Err(e)) => {
+                    breakpoints.push(Json::object([("verified", false.into()), ("message", e.into())]));
+                    continue;
+                }
+                Some(Ok(condition)) => cpu.add_breakpoint_if(addr, condition),
+                None => cpu.add_breakpoint(addr),
+            }
+            self.instruction_breakpoints.push(addr);
+            breakpoints.push(Json::object([("verified", true.into()), ("instructionReference", reference(addr).into())]));
+        }
+        Ok(Json::object([("breakpoints", breakpoints.into())]))
+    }
+
+    /// Name of `path` in the symbol file, the client gives absolute paths
+    fn source_file(&self, path: &str) -> Option<String> {
+        let wanted = fs::canonicalize(path).ok();
+        let files: BTreeSet<&str> = self.symbols.lines().map(|(_, file, _)| file).collect();
+        files
+            .into_iter()
+            .find(|file| wanted.is_some() && fs::canonicalize(file).ok() == wanted || Path::new(path).ends_with(file))
+            .map(str::to_string)
+    }
+
+    /// Where the PC is, then where each function being executed was called from
+    fn stack_trace(&mut self) -> Result<Json, String> {
+        let cpu = self.cpu()?;
+        let mut addrs = vec![cpu.pc()];
+        // return addresses are past the CALL
+        addrs.extend(cpu.stack().iter().rev().map(|&addr| (addr as usize).saturating_sub(2)));
+
+        let frames: Vec<Json> = addrs.iter().enumerate().map(|(id, &addr)| self.frame(id, addr)).collect();
+        Ok(Json::object([("totalFrames", frames.len().into()), ("stackFrames", frames.into())]))
+    }
+
+    fn frame(&self, id: usize, addr: usize) -> Json {
+        // named after the closest label before it
+        let name = match self.symbols.enclosing(addr) {
+            Some((start, name)) if start == addr => name.to_string(),
+            Some((start, name)) => format!("{}+{:#x}", name, addr - start),
+            None => format!("{:#05x}", addr),
+        };
+        let frame = Json::object([("id", id.into()), ("name", name.into()), ("instructionPointerReference", reference(addr).into())]);
+        match self.symbols.line(addr) {
+            Some((file, line)) => frame.with("source", source(file)).with("line", line.into()).with("column", 1u64.into()),
+            None => frame.with("line", 0u64.into()).with("column", 0u64.into()),
+        }
+    }
+
+    fn variables(&mut self, args: &Json) -> Result<Json, String> {
+        let cpu = self.cpu()?;
+        let variable = |name: String, value: String| Json::object([("name", name.into()), ("value", value.into()), ("variablesReference", 0u64.into())]);
+        let variables = match args.get("variablesReference").as_u64() {
+            Some(REGISTERS) => {
+                let mut variables: Vec<Json> = (0..16).map(|x| variable(format!("V{:X}", x), format!("{:#04x}", cpu.register(x)))).collect();
+                variables.push(variable("I".to_string(), format!("{:#06x}", cpu.i())).with("memoryReference", reference(cpu.i() as usize).into()));
+                variables.push(variable("PC".to_string(), format!("{:#06x}", cpu.pc())).with("memoryReference", reference(cpu.pc()).into()));
+                variables.push(variable("SP".to_string(), cpu.stack_pointer().to_string()));
+                variables.push(variable("DT".to_string(), cpu.delay_timer().to_string()));
+                variables.push(variable("ST".to_string(), cpu.sound_timer().to_string()));
+                variables
+            }
+            Some(MEMORY) => {
+                let start = cpu.i() as usize;
+                let len = (MEMORY_ROWS * 16).min(cpu.memory_size().saturating_sub(start));
+                let bytes = cpu.read_memory(start, len).map_err(|e| e.to_string())?;
+                bytes
+                    .chunks(16)
+                    .enumerate()
+                    .map(|(row, chunk)| {
+                        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+                        variable(format!("{:#06x}", start + row * 16), hex.join(" ")).with("memoryReference", reference(start + row * 16).into())
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        Ok(Json::object([("variables", variables.into())]))
+    }
+
+    /// Changes a register, the stack depth can't change
+    fn set_variable(&mut self, args: &Json) -> Result<Json, String> {
+        let cpu = self.cpu()?;
+        let text = args.get("value").as_str().unwrap_or("");
+        let value = parse_address(text).ok_or_else(|| format!("`{}` is not a number", text))?;
+        let name = args.get("name").as_str().unwrap_or("");
+        match (args.get("variablesReference").as_u64(), name) {
+            (Some(REGISTERS), "I") => cpu.set_i(value as u16),
+            (Some(REGISTERS), "PC") => cpu.set_pc(value),
+            (Some(REGISTERS), "DT") => cpu.set_delay_timer(value as u8),
+            (Some(REGISTERS), "ST") => cpu.set_sound_timer(value as u8),
+            (Some(REGISTERS), name) if name.len() == 2 && name.starts_with('V') => {
+                let x = u8::from_str_radix(&name[1..], 16).map_err(|_| format!("there's no register {}", name))?;
+                cpu.set_register(x, value as u8);
+            }
+            _ => return Err(format!("{} can't be changed", name)),
+        }
+        Ok(Json::object([("value", text.into())]))
+    }
+
+    /// Bytes of memory as base64, what lies outside of memory is unreadable
+    fn read_memory(&mut self, args: &Json) -> Result<Json, String> {
+        let cpu = self.cpu()?;
+        let memory_reference = args.get("memoryReference").as_str().unwrap_or("");
+        let start = parse_address(memory_reference).ok_or_else(|| format!("`{}` is not an address", memory_reference))?;
+        let start = start.saturating_add_signed(number(args.get("offset")) as isize);
+        let count = args.get("count").as_u64().unwrap_or(0) as usize;
+        let len = count.min(cpu.memory_size().saturating_sub(start));
+        let bytes = cpu.read_memory(start, len).map_err(|e| e.to_string())?;
+        Ok(Json::object([
+            ("address", reference(start).into()),
+            ("data", base64(&bytes).into()),
+            ("unreadableBytes", (count - len).into()),
+        ]))
+    }
+
+    /// `instructionCount` instructions around `memoryReference`, taking each
+    /// one as 2 bytes to go back
+    fn disassemble(&mut self, args: &Json) -> Result<Json, String> {
+        let memory_reference = args.get("memoryReference").as_str().unwrap_or("");
+        let start = parse_address(memory_reference).ok_or_else(|| format!("`{}` is not an address", memory_reference))? as i64;
+        let start = start + number(args.get("offset")) + number(args.get("instructionOffset")) * 2;
+        let count = args.get("instructionCount").as_u64().unwrap_or(0);
+        let cpu = self.cpu.as_ref().ok_or("no program is launched")?;
+
+        let mut instructions = Vec::new();
+        let mut addr = start;
+        for _ in 0..count {
+            // the client wants as many as it asked for, there's nothing outside of memory
+            let line = usize::try_from(addr).ok().and_then(|addr| {
+                let len = 4.min(cpu.memory_size().saturating_sub(addr));
+                let bytes = cpu.read_memory(addr, len).ok()?;
+                disassemble_at(&bytes, addr, addr, cpu.variant())
+            });
+            let Some(line) = line else {
+                let address = format!("{:#06x}", addr.max(0));
+                instructions.push(Json::object([("address", address.into()), ("instruction", "".into()), ("presentationHint", "invalid".into())]));
+                addr += 2;
+                continue;
+            };
+
+            let bytes: Vec<String> = line.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let mut instruction = Json::object([
+                ("address", reference(line.addr).into()),
+                ("instructionBytes", bytes.join(" ").into()),
+                ("instruction", line.symbolic(&self.symbols).into()),
+            ]);
+            if let Some(name) = self.symbols.name(line.addr) {
+                instruction = instruction.with("symbol", name.into());
+            }
+            if let Some((file, number)) = self.symbols.line(line.addr) {
+                instruction = instruction.with("location", source(file)).with("line", number.into());
+            }
+            instructions.push(instruction);
+            addr += line.bytes.len() as i64;
+        }
+        Ok(Json::object([("instructions", instructions.into())]))
+    }
+
+    /// Lets the program run, past the breakpoint the PC is on
+    fn resume(&mut self, running: Running) {
+        let Some(cpu) = self.cpu.as_mut() else {
+            return;
+        };
+        if cpu.breakpoints().any(|addr| addr == cpu.pc()) {
+            if let Some(reason) = step(cpu) {
+                return self.stop(reason, "step");
+            }
+        }
+        self.running = running;
+    }
+
+    /// Executes one instruction
+    fn step_in(&mut self) -> Result<(), String> {
+        let reason = step(self.cpu()?);
+        self.stop(reason.unwrap_or(StopReason::InstructionBudgetExhausted), "step");
+        Ok(())
+    }
+
+    /// Executes one instruction, and the whole function when it's a call
+    fn step_over(&mut self) -> Result<(), String> {
+        let cpu = self.cpu()?;
+        let depth = cpu.stack_pointer();
+        match step(cpu) {
+            Some(reason) => self.stop(reason, "step"),
+            None if cpu.stack_pointer() > depth => self.running = Running::StepOut(depth),
+            None => self.stop(StopReason::InstructionBudgetExhausted, "step"),
+        }
+        Ok(())
+    }
+
+    /// Runs until the function being executed returns
+    fn step_out(&mut self) -> Result<(), String> {
+        match self.cpu()?.stack_pointer() {
+            0 => self.resume(Running::Continue), // nothing to return from
+            depth => self.resume(Running::StepOut(depth - 1)),
+        }
+        Ok(())
+    }
+
+    /// Runs the program for a frame, or until `StepOut` is done
+    fn run_frame(&mut self) {
+        let Some(cpu) = self.cpu.as_mut() else {
+            self.running = Running::Stopped;
+            return;
+        };
+        let reason = match self.running {
+            Running::Stopped => return,
+            Running::Continue => cpu.advance(cpu.instructions_per_frame() as usize).stopped,
+            // an instruction at a time to notice the return
+            Running::StepOut(depth) => {
+                let mut reason = StopReason::InstructionBudgetExhausted;
+                for _ in 0..cpu.instructions_per_frame() {
+                    reason = cpu.advance(1).stopped;
+                    if cpu.stack_pointer() <= depth {
+                        return self.stop(reason, "step");
+                    }
+                    if !matches!(reason, StopReason::InstructionBudgetExhausted | StopReason::WaitingForVblank) {
+                        break;
+                    }
+                }
+                reason
+            }
+        };
+        if !matches!(reason, StopReason::InstructionBudgetExhausted | StopReason::WaitingForVblank) {
+            self.stop(reason, "pause");
+        }
+    }
+
+    /// Tells the client why the program stopped, `why` unless it's something
+    /// other than running out of time
+    fn stop(&mut self, reason: StopReason, why: &str) {
+        self.running = Running::Stopped;
+        let (why, text) = match reason {
+            StopReason::Breakpoint(_) => ("breakpoint", None),
+            StopReason::Watchpoint { pc, addr, access } => {
+                let verb = if access == Access::Write { "wrote" } else { "read" };
+                ("data breakpoint", Some(format!("the instruction at {:#05x} {} {:#05x}", pc, verb, addr)))
+            }
+            StopReason::Error(e) => ("exception", Some(e.to_string())),
+            StopReason::HaltOpcode | StopReason::ExitOpcode => {
+                self.event("exited", Json::object([("exitCode", 0u64.into())]));
+                self.event("terminated", Json::object([]));
+                return;
+            }
+            StopReason::WaitingForKey => ("pause", Some("waiting for a key, the debugger can't press one".to_string())),
+            _ => (why, None),
+        };
+        let mut body = Json::object([("reason", why.into()), ("threadId", THREAD.into()), ("allThreadsStopped", true.into())]);
+        if let Some(text) = text {
+            body = body.with("description", text.clone().into()).with("text", text.into());
+        }
+        self.event("stopped", body);
+    }
+
+    fn event(&mut self, event: &str, body: Json) {
+        self.events.push(Json::object([("type", "event".into()), ("event", event.into()), ("body", body)]));
+    }
+
+    /// Answers `request` with the body of its response or why it failed
+    fn respond(&mut self, request: &Json, body: Result<Json, String>) -> io::Result<()> {
+        let response = Json::object([
+            ("type", "response".into()),
+            ("request_seq", request.get("seq").clone()),
+            ("command", request.get("command").clone()),
+            ("success", body.is_ok().into()),
+        ]);
+        let response = match body {
+            Ok(body) => response.with("body", body),
+            Err(message) => response.with("message", message.into()),
+        };
+        self.send(response)
+    }
+
+    /// Sends the events of the last response or frame
+    fn flush(&mut self) -> io::Result<()> {
+        for event in std::mem::take(&mut self.events) {
+            self.send(event)?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, message: Json) -> io::Result<()> {
+        self.seq += 1;
+        let text = message.with("seq", self.seq.into()).to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{}", text.len(), text)?;
+        self.out.flush()
+    }
+}
+
+/// What the adapter can do, the reply to `initialize`
+fn capabilities() -> Json {
+    Json::object([
+        ("supportsConfigurationDoneRequest", true.into()),
+        ("supportsConditionalBreakpoints", true.into()),
+        ("supportsInstructionBreakpoints", true.into()),
+        ("supportsDisassembleRequest", true.into()),
+        ("supportsReadMemoryRequest", true.into()),
+        ("supportsSetVariable", true.into()),
+        ("supportsSteppingGranularity", false.into()),
+    ])
+}
+
+/// Executes one instruction, `None` unless it stopped the program. The
+/// breakpoint it starts on doesn't count, like waiting for the screen
+fn step(cpu: &mut CPU) -> Option<StopReason> {
+    let executed = cpu.instructions_executed();
+    let start = cpu.pc();
+    while cpu.instructions_executed() == executed {
+        match cpu.advance(1).stopped {
+            StopReason::InstructionBudgetExhausted | StopReason::WaitingForVblank => {}
+            StopReason::Breakpoint(addr) if addr as usize == start => {}
+            reason => return Some(reason),
+        }
+    }
+    None
+}
+
+/// `Source` of a file named in the symbol file
+fn source(file: &str) -> Json {
+    let name = Path::new(file).file_name().map_or(file.to_string(), |name| name.to_string_lossy().into_owned());
+    let path = fs::canonicalize(file).map_or(file.to_string(), |path| path.display().to_string());
+    Json::object([("name", name.into()), ("path", path.into())])
+}
+
+/// Memory and instruction references are addresses like `0x0206`
+fn reference(addr: usize) -> String {
+    format!("{:#06x}", addr)
+}
+
+/// Integer of an optional argument that may be negative, 0 without one
+fn number(value: &Json) -> i64 {
+    match value {
+        Json::Number(n) => *n as i64,
+        _ => 0,
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::new();
+    for chunk in bytes.chunks(3) {
+        let word = chunk.iter().enumerate().fold(0u32, |word, (i, &b)| word | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(ALPHABET[(word >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cli::Cli;
+
+    /// ADD V0, 1, CALL 0x208 and jump back, the function adds 2 to V1
+    const ROM: [u8; 12] = [0x70, 0x01, 0x22, 0x08, 0x12, 0x00, 0x00, 0x00, 0x71, 0x02, 0x00, 0xEE];
+
+    /// Names and lines of `ROM` as if it was assembled from `prog.s`
+    const SYMBOLS: &str = "0x200 = start\n0x208 = sub\n0x200 @ prog.s:2\n0x202 @ prog.s:3\n0x204 @ prog.s:4\n0x208 @ prog.s:7\n0x20A @ prog.s:8\n";
+
+    /// Adapter writing to memory, with the options of `dap` and an empty configuration
+    fn adapter() -> Adapter<Vec<u8>> {
+        let empty = std::env::temp_dir().join("cpu-caller-empty.toml");
+        fs::write(&empty, "").unwrap();
+        let cli = Cli::parse(["cpu-caller", "dap", "--config", empty.to_str().unwrap()]).unwrap();
+        Adapter::new(&cli.options, Vec::new())
+    }
+
+    /// Messages the adapter sent since the last call
+    fn sent(adapter: &mut Adapter<Vec<u8>>) -> Vec<Json> {
+        adapter.flush().unwrap();
+        let text = String::from_utf8(std::mem::take(&mut adapter.out)).unwrap();
+        text.split("Content-Length: ")
+            .filter(|message| !message.is_empty())
+            .map(|message| Json::parse(message.split_once("\r\n\r\n").unwrap().1).unwrap())
+            .collect()
+    }
+
+    /// What the adapter sent back for the request
+    fn request(adapter: &mut Adapter<Vec<u8>>, command: &str, arguments: &str) -> Vec<Json> {
+        let text = format!(r#"{{"seq": 1, "type": "request", "command": "{}", "arguments": {}}}"#, command, arguments);
+        assert!(adapter.handle(&Json::parse(&text).unwrap()).unwrap());
+        sent(adapter)
+    }
+
+    /// Body of the response, which comes first
+    fn body(messages: &[Json]) -> &Json {
+        assert_eq!(messages[0].get("success"), &Json::from(true), "{:?}", messages[0]);
+        messages[0].get("body")
+    }
+
+    /// Reason of the `stopped` event among the messages
+    fn stopped(messages: &[Json]) -> Option<&str> {
+        let event = messages.iter().find(|message| message.get("event").as_str() == Some("stopped"))?;
+        event.get("body").get("reason").as_str()
+    }
+
+    #[test]
+    fn a_session() {
+        let rom = std::env::temp_dir().join(format!("cpu-caller-dap-{}.ch8", std::process::id()));
+        fs::write(&rom, ROM).unwrap();
+        fs::write(rom.with_extension("sym"), SYMBOLS).unwrap();
+        let mut adapter = adapter();
+
+        let capabilities = request(&mut adapter, "initialize", "{}");
+        assert_eq!(body(&capabilities).get("supportsConditionalBreakpoints"), &Json::from(true));
+        let launch = request(&mut adapter, "launch", &format!(r#"{{"program": "{}", "stopOnEntry": true}}"#, rom.display()));
+        assert_eq!(launch[1].get("event").as_str(), Some("initialized"));
+        fs::remove_file(rom.with_extension("sym")).unwrap();
+        fs::remove_file(&rom).unwrap();
+
+        // line 5 has no instruction, the one after it does
+        let set = request(&mut adapter, "setBreakpoints", r#"{"source": {"path": "/work/prog.s"}, "breakpoints": [{"line": 5}, {"line": 20}]}"#);
+        let breakpoints = body(&set).get("breakpoints").as_array();
+        assert_eq!((breakpoints[0].get("verified"), breakpoints[0].get("line")), (&Json::from(true), &Json::from(7u64)));
+        assert_eq!(breakpoints[1].get("verified"), &Json::from(false));
+        assert_eq!(stopped(&request(&mut adapter, "configurationDone", "{}")), Some("entry"));
+
+        request(&mut adapter, "continue", "{}");
+        adapter.run_frame();
+        assert_eq!(stopped(&sent(&mut adapter)), Some("breakpoint"));
+        let trace = request(&mut adapter, "stackTrace", "{}");
+        let frames = body(&trace).get("stackFrames").as_array();
+        let names: Vec<_> = frames.iter().map(|frame| frame.get("name").as_str().unwrap()).collect();
+        assert_eq!(names, ["sub", "start+0x2"]);
+        assert_eq!((frames[0].get("line"), frames[1].get("line")), (&Json::from(7u64), &Json::from(3u64)));
+
+        // stepping over the breakpoint the PC is on, then out of the function
+        assert_eq!(stopped(&request(&mut adapter, "next", "{}")), Some("step"));
+        request(&mut adapter, "stepOut", "{}");
+        adapter.run_frame();
+        assert_eq!(stopped(&sent(&mut adapter)), Some("step"));
+        assert_eq!(adapter.cpu.as_ref().unwrap().pc(), 0x204);
+
+        request(&mut adapter, "setVariable", r#"{"variablesReference": 1, "name": "V3", "value": "0x10"}"#);
+        let registers = request(&mut adapter, "variables", r#"{"variablesReference": 1}"#);
+        let registers = body(&registers).get("variables").as_array();
+        let values: Vec<_> = registers[..4].iter().map(|variable| variable.get("value").as_str().unwrap()).collect();
+        assert_eq!(values, ["0x01", "0x02", "0x00", "0x10"]);
+        let memory = request(&mut adapter, "readMemory", r#"{"memoryReference": "0x0200", "count": 4}"#);
+        assert_eq!(body(&memory).get("data"), &Json::from("cAEiCA=="));
+
+        let disconnect = Json::parse(r#"{"seq": 2, "type": "request", "command": "disconnect"}"#).unwrap();
+        assert!(!adapter.handle(&disconnect).unwrap());
+    }
+
+    #[test]
+    fn mistakes() {
+        let mut adapter = adapter();
+        for (command, arguments) in [("stackTrace", "{}"), ("next", "{}"), ("launch", "{}"), ("fly", "{}")] {
+            let messages = request(&mut adapter, command, arguments);
+            assert_eq!(messages[0].get("success"), &Json::from(false), "{}", command);
+        }
+    }
+}
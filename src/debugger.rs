@@ -0,0 +1,1008 @@
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+
+use cpu_caller::{disassemble_at, Access, Change, Checkpointer, Expr, HistoryEntry, OpcodePattern, Snapshot, StopReason, Symbols, CPU};
+
+use crate::cli::{parse_address, Options, Source};
+use crate::repl::print_state;
+#[cfg(feature = "rhai")]
+use crate::script::Script;
+
+const HELP: &str = "\
+commands:
+    step [n]             execute one instruction, or n
+    step-back [n]        go back one instruction, or n, by executing again from an earlier state
+    continue             run until the program stops or reaches a breakpoint
+    until <condition>    execute instructions until a condition like `v3 == 0 || pc >= 0x400` holds,
+                         written like the ones of `break`
+    break [addr]         stop when the PC reaches addr, without one list the breakpoints
+    break <addr> if <condition>
+                         only stop when a condition like `v0 == 0x1F && sp > 2` holds, over
+                         v0-vf, i, pc, sp, dt, st and [addr] for a byte of memory
+    break opcode <pattern>
+                         stop before any instruction matching an opcode like `D___` or `Fx55`, where
+                         `_`, `x`, `y`, `n` and `k` match any digit
+    delete [addr]        remove the breakpoint at addr, or all of them
+    delete opcode <pattern>
+                         remove the breakpoint on the opcode pattern
+    watch <read|write|access> <addr>[..<end>]
+                         stop after an instruction reads or writes memory of addr or up to end,
+                         without arguments list the watchpoints
+    unwatch [addr[..end]]
+                         remove the watchpoint of the range, or all of them
+    regs                 show the registers, I, the timers and the stack
+    print <expression>   show the value of an expression over the same names as conditions
+    set <register> <value>
+                         change v0-vf, i, pc, dt or st
+    assert <condition>   complain when a condition doesn't hold, in `on` commands it stops the program
+    on <break|step> [command; command...]
+                         run commands whenever the program stops at a breakpoint or watchpoint, or
+                         after each instruction, where `continue` and `stop` decide if it goes on,
+                         without commands forget them
+    on                   list the commands of `on`
+    source <file>        run the commands of a file, one per line, `#` starts a comment, or load a
+                         rhai script ending in `.rhai` whose `on_break` and `on_step` functions
+                         run after the commands of `on` and give back whether it goes on
+    mem <addr> <len>     show memory as hexadecimal
+    disasm [addr] [n]    show n instructions from addr, the PC by default
+    backtrace            show the calls that led to the PC, innermost first
+    history [n]          show the last instructions executed, 10 of them or n
+    mark [name]          remember the state under a name, without one list the marks
+    diff <mark> [mark]   show the registers and memory that changed from a mark to another one or
+                         the current state
+    quit                 leave, like end of input
+an empty line repeats the last command, addresses can be names of the symbol file";
+
+/// Instructions `disasm` shows unless told otherwise
+const DISASM_LINES: usize = 10;
+
+/// Instructions `history` shows unless told otherwise, and faults show
+pub const HISTORY_LINES: usize = 10;
+
+/// Instructions between two snapshots `step-back` executes again from
+pub const CHECKPOINT_INTERVAL: u64 = 256;
+
+/// Snapshots kept, about as many kilobytes each as the memory and the screen take
+pub const CHECKPOINTS: usize = 256;
+
+/// Debugger of a loaded program, reading commands from standard input
+struct Debugger {
+    cpu: CPU,
+    symbols: Symbols,
+    checkpoints: Checkpointer, // states to execute again from for `step-back`
+    on_break: Vec<String>,     // commands run when a breakpoint or watchpoint stops the program
+    on_step: Vec<String>,      // commands run after each instruction
+    verdict: Option<Verdict>,  // set by the `on` commands running, `None` outside of them
+    marks: Vec<(String, Snapshot)>, // states kept by `mark` to compare with `diff`
+    sourcing: Vec<PathBuf>,         // files whose commands are running, the innermost last
+    #[cfg(feature = "rhai")]
+    script: Option<Script>, // the last rhai script loaded
+}
+
+/// What the commands of `on` decided
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Verdict {
+    Undecided, // none of them said, the program does what it would have done
+    Continue,
+    Stop,
+}
+
+/// How far the program runs
+enum Run {
+    Step(usize),
+    Continue,
+    Until(Expr),
+}
+
+/// Loads the ROM at `path`, runs the commands of `script` and asks what to do
+/// with it until `quit`
+pub fn run(path: &str, script: Option<&str>, options: &Options) {
+    let mut debugger = Debugger::new(crate::build_cpu(&Source::File(path.to_string()), options), crate::load_symbols(path, options));
+
+    println!("{}", HELP);
+    debugger.disasm(debugger.cpu.pc(), 1);
+    if let Some(script) = script {
+        match debugger.source(script) {
+            Ok(true) => return,
+            Ok(false) => {}
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut last = String::new();
+    loop {
+        print!("(debug) ");
+        let _ = io::stdout().flush();
+        let Some(Ok(line)) = lines.next() else {
+            break;
+        };
+
+        let line = match line.trim() {
+            "" => last.clone(),
+            line => line.to_string(),
+        };
+        if debugger.line(&line) {
+            break;
+        }
+        last = line;
+    }
+}
+
+impl Debugger {
+    fn new(cpu: CPU, symbols: Symbols) -> Self {
+        Debugger {
+            cpu,
+            symbols,
+            checkpoints: Checkpointer::new(CHECKPOINT_INTERVAL, CHECKPOINTS),
+            on_break: Vec::new(),
+            on_step: Vec::new(),
+            verdict: None,
+            marks: Vec::new(),
+            sourcing: Vec::new(),
+            #[cfg(feature = "rhai")]
+            script: None,
+        }
+    }
+
+    /// Carries out a line of commands, returns whether it was `quit`
+    fn line(&mut self, line: &str) -> bool {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            ["quit" | "q"] => return true,
+            ["help" | "h"] => println!("{}", HELP),
+            command => {
+                if let Err(e) = self.execute(command) {
+                    println!("error: {}", e);
+                }
+            }
+        }
+        false
+    }
+
+    /// Carries out the lines of the file at `path`, returns whether one was `quit`
+    fn source(&mut self, path: &str) -> Result<bool, String> {
+        if path.ends_with(".rhai") {
+            return self.load_script(path).map(|()| false);
+        }
+        let text = std::fs::read_to_string(path).map_err(|e| format!("can't read `{}`: {}", path, e))?;
+        // a file sourcing itself, or one that sources it back, would never end
+        let file = std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+        if self.sourcing.contains(&file) {
+            return Err(format!("`{}` is already running, it can't be sourced again from itself", path));
+        }
+        self.sourcing.push(file);
+        let quit = text.lines().map(|line| line.split('#').next().unwrap_or_default().trim()).any(|line| !line.is_empty() && self.line(line));
+        self.sourcing.pop();
+        Ok(quit)
+    }
+
+    /// Carries out one command other than `quit` and `help`
+    fn execute(&mut self, command: &[&str]) -> Result<(), String> {
+        let running = matches!(command, ["step" | "s" | "step-back" | "sb" | "until" | "u" | "source", ..]);
+        if running && self.verdict.is_some() {
+            return Err("the commands of `on` can't run the program, they can `continue` or `stop` it".to_string());
+        }
+        match command {
+            ["continue" | "c"] if self.verdict.is_some() => self.verdict = Some(Verdict::Continue),
+            ["stop"] if self.verdict.is_some() => self.verdict = Some(Verdict::Stop),
+            ["step" | "s"] => self.go(Run::Step(1)),
+            ["step" | "s", count] => self.go(Run::Step(count.parse().map_err(|_| format!("`{}` is not a number", count))?)),
+            ["step-back" | "sb"] => self.step_back(1)?,
+            ["step-back" | "sb", count] => self.step_back(count.parse().map_err(|_| format!("`{}` is not a number", count))?)?,
+            ["continue" | "c"] => self.go(Run::Continue),
+            ["until" | "u", condition @ ..] if !condition.is_empty() => self.go(Run::Until(Expr::parse(&condition.join(" "))?)),
+            ["print" | "p", expression @ ..] if !expression.is_empty() => {
+                let value = Expr::parse(&expression.join(" "))?.eval(&self.cpu);
+                println!("{} ({:#x})", value, value);
+            }
+            ["set", name, value] => {
+                let value = parse_address(value).ok_or_else(|| format!("`{}` is not a number", value))?;
+                match name.to_ascii_lowercase().as_str() {
+                    "i" => self.cpu.set_i(value as u16),
+                    "pc" => self.cpu.set_pc(value),
+                    "dt" => self.cpu.set_delay_timer(value as u8),
+                    "st" => self.cpu.set_sound_timer(value as u8),
+                    name if name.len() == 2 && name.starts_with('v') => {
+                        let x = u8::from_str_radix(&name[1..], 16).map_err(|_| format!("there's no register `{}`", name))?;
+                        self.cpu.set_register(x, value as u8);
+                    }
+                    name => return Err(format!("there's no register `{}`", name)),
+                }
+            }
+            ["assert", condition @ ..] if !condition.is_empty() => {
+                let condition = Expr::parse(&condition.join(" "))?;
+                if !condition.holds(&self.cpu) {
+                    println!("assertion failed at {}: {}", self.describe(self.cpu.pc()), condition);
+                    if self.verdict.is_some() {
+                        self.verdict = Some(Verdict::Stop);
+                    }
+                }
+            }
+            ["on"] => {
+                println!("on break: {}", self.on_break.join("; "));
+                println!("on step: {}", self.on_step.join("; "));
+            }
+            ["on", event, commands @ ..] => {
+                let commands: Vec<String> = commands
+                    .join(" ")
+                    .split(';')
+                    .map(str::trim)
+                    .filter(|command| !command.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                match *event {
+                    "break" => self.on_break = commands,
+                    "step" => self.on_step = commands,
+                    other => return Err(format!("unknown event `{}`, expected break or step", other)),
+                }
+            }
+            ["source", path] => {
+                self.source(path)?;
+            }
+            ["break" | "b"] => {
+                for addr in self.cpu.breakpoints() {
+                    match self.cpu.breakpoint_condition(addr) {
+                        Some(condition) => println!("{} if {}", self.describe(addr), condition),
+                        None => println!("{}", self.describe(addr)),
+                    }
+                }
+                for pattern in self.cpu.opcode_breakpoints() {
+                    println!("opcode {}", pattern);
+                }
+            }
+            ["break" | "b", "opcode", pattern] => {
+                let pattern = OpcodePattern::parse(pattern)?;
+                println!("breakpoint on opcode {}", pattern);
+                self.cpu.add_opcode_breakpoint(pattern);
+            }
+            ["break" | "b", addr] => {
+                let addr = self.address(addr)?;
+                self.cpu.add_breakpoint(addr);
+                println!("breakpoint at {}", self.describe(addr));
+            }
+            ["break" | "b", addr, "if", condition @ ..] => {
+                let addr = self.address(addr)?;
+                let condition = Expr::parse(&condition.join(" "))?;
+                println!("breakpoint at {} if {}", self.describe(addr), condition);
+                self.cpu.add_breakpoint_if(addr, condition);
+            }
+            ["watch" | "w"] => {
+                for (range, access) in self.cpu.watchpoints() {
+                    let access = match access {
+                        Access::Read => "read",
+                        Access::Write => "write",
+                        Access::Any => "access",
+                    };
+                    println!("{} {}", access, self.describe_range(&range));
+                }
+            }
+            ["watch" | "w", access, range] => {
+                let access = match *access {
+                    "read" => Access::Read,
+                    "write" => Access::Write,
+                    "access" => Access::Any,
+                    other => return Err(format!("unknown access `{}`, expected read, write or access", other)),
+                };
+                let range = self.range(range)?;
+                println!("watchpoint on {}", self.describe_range(&range));
+                self.cpu.add_watchpoint(range, access);
+            }
+            ["unwatch"] => self.cpu.clear_watchpoints(),
+            ["unwatch", range] => {
+                let range = self.range(range)?;
+                if !self.cpu.remove_watchpoint(range.clone()) {
+                    return Err(format!("there's no watchpoint on {}", self.describe_range(&range)));
+                }
+            }
+            ["delete"] => {
+                self.cpu.clear_breakpoints();
+                self.cpu.clear_opcode_breakpoints();
+            }
+            ["delete", "opcode", pattern] => {
+                let pattern = OpcodePattern::parse(pattern)?;
+                if !self.cpu.remove_opcode_breakpoint(pattern) {
+                    return Err(format!("there's no breakpoint on opcode {}", pattern));
+                }
+            }
+            ["delete", addr] => {
+                let addr = self.address(addr)?;
+                if !self.cpu.remove_breakpoint(addr) {
+                    return Err(format!("there's no breakpoint at {}", self.describe(addr)));
+                }
+            }
+            ["regs" | "r"] => print_state(&self.cpu),
+            ["backtrace" | "bt"] => {
+                for line in backtrace(&self.cpu, &self.symbols) {
+                    println!("{}", line);
+                }
+            }
+            ["history"] => print_history(&self.cpu, HISTORY_LINES),
+            ["history", count] => print_history(&self.cpu, count.parse().map_err(|_| format!("`{}` is not a number", count))?),
+            ["mark"] => {
+                for (name, snapshot) in &self.marks {
+                    println!("{}  at {} after {} instructions", name, self.describe(snapshot.pc), snapshot.instructions_executed);
+                }
+            }
+            ["mark", name] => {
+                self.marks.retain(|(mark, _)| mark != name);
+                self.marks.push((name.to_string(), self.cpu.snapshot()));
+            }
+            ["diff", from] => {
+                let changes = self.cpu.changes_since(self.mark(from)?);
+                self.print_changes(&changes);
+            }
+            ["diff", from, to] => {
+                let changes = self.mark(from)?.diff(self.mark(to)?);
+                self.print_changes(&changes);
+            }
+            ["mem" | "m", addr, len] => {
+                let len = parse_address(len).ok_or_else(|| format!("`{}` is not a length", len))?;
+                self.mem(self.address(addr)?, len)?;
+            }
+            ["disasm" | "d"] => self.disasm(self.cpu.pc(), DISASM_LINES),
+            ["disasm" | "d", addr] => self.disasm(self.address(addr)?, DISASM_LINES),
+            ["disasm" | "d", addr, count] => {
+                let count = count.parse().map_err(|_| format!("`{}` is not a number", count))?;
+                self.disasm(self.address(addr)?, count);
+            }
+            [name, ..] => return Err(format!("unknown command or arguments of `{}`, `help` lists them", name)),
+            [] => {}
+        }
+        Ok(())
+    }
+
+    /// Number or name of the symbol file
+    fn address(&self, text: &str) -> Result<usize, String> {
+        parse_address(text)
+            .or_else(|| self.symbols.address(text))
+            .ok_or_else(|| format!("`{}` is not an address or a known name", text))
+    }
+
+    /// `addr` for a single byte or `start..end` without `end`
+    fn range(&self, text: &str) -> Result<Range<usize>, String> {
+        let range = match text.split_once("..") {
+            Some((start, end)) => self.address(start)?..self.address(end)?,
+            None => {
+                let addr = self.address(text)?;
+                addr..addr.checked_add(1).ok_or_else(|| format!("`{}` is past the end of memory", text))?
+            }
+        };
+        if range.is_empty() {
+            return Err(format!("`{}` has no bytes", text));
+        }
+        Ok(range)
+    }
+
+    fn describe_range(&self, range: &Range<usize>) -> String {
+        match range.len() {
+            1 => self.describe(range.start),
+            _ => format!("{}..{:#05x}", self.describe(range.start), range.end),
+        }
+    }
+
+    /// Address with its name when the symbol file has one, like `0x206 <draw_score>`
+    fn describe(&self, addr: usize) -> String {
+        match self.symbols.name(addr) {
+            Some(name) => format!("{:#05x} <{}>", addr, name),
+            None => format!("{:#05x}", addr),
+        }
+    }
+
+    /// Tells why the program stopped
+    fn report(&self, reason: StopReason) {
+        match reason {
+            StopReason::Breakpoint(addr) => println!("breakpoint at {}", self.describe(addr as usize)),
+            StopReason::OpcodeBreakpoint { pc, opcode } => println!("breakpoint on opcode {:04X} at {}", opcode, self.describe(pc as usize)),
+            StopReason::Watchpoint { pc, addr, access } => {
+                let verb = if access == Access::Write { "wrote" } else { "read" };
+                println!("watchpoint: the instruction at {} {} {}", self.describe(pc as usize), verb, self.describe(addr as usize));
+                self.disasm(pc as usize, 1);
+            }
+            StopReason::Stuck { start, end } => println!("{}", crate::stuck(&self.cpu, start, end)),
+            StopReason::Error(e) => {
+                println!("error: {}", e);
+                for line in backtrace(&self.cpu, &self.symbols) {
+                    println!("{}", line);
+                }
+                println!("last instructions:");
+                print_history(&self.cpu, HISTORY_LINES);
+            }
+            reason => println!("stopped: {:?}", reason),
+        }
+    }
+
+    /// Runs the program as far as `run` says and shows the next instruction.
+    /// The commands of `on break` can make it go on from a breakpoint
+    fn go(&mut self, run: Run) {
+        loop {
+            let reason = match &run {
+                _ if !self.on_step.is_empty() || self.scripted("on_step") => self.go_slowly(&run),
+                Run::Step(count) => step(&mut self.cpu, &mut self.checkpoints, *count),
+                Run::Continue => Some(self.resume()),
+                Run::Until(condition) => until(&mut self.cpu, &mut self.checkpoints, condition),
+            };
+            let Some(reason) = reason else {
+                break;
+            };
+            self.report(reason);
+            let hit = matches!(reason, StopReason::Breakpoint(_) | StopReason::OpcodeBreakpoint { .. } | StopReason::Watchpoint { .. });
+            if !hit || self.hook(true) != Verdict::Continue {
+                break;
+            }
+        }
+        self.disasm(self.cpu.pc(), 1);
+    }
+
+    /// Like `go` an instruction at a time, running the commands of `on step`
+    /// after each. A program they stop gives no reason
+    fn go_slowly(&mut self, run: &Run) -> Option<StopReason> {
+        let mut executed = 0;
+        loop {
+            let reason = match executed {
+                0 => step(&mut self.cpu, &mut self.checkpoints, 1),
+                _ => step_on(&mut self.cpu, &mut self.checkpoints),
+            };
+            if reason.is_some() {
+                return reason;
+            }
+            executed += 1;
+            if self.hook(false) == Verdict::Stop {
+                return None;
+            }
+            match run {
+                Run::Step(count) if executed >= *count => return None,
+                Run::Until(condition) if condition.holds(&self.cpu) => return None,
+                _ => {}
+            }
+        }
+    }
+
+    /// Carries out the commands of `on break`, or `on step`, then the function
+    /// of the script for it if they didn't decide, and tells what they decided
+    fn hook(&mut self, at_break: bool) -> Verdict {
+        let commands = if at_break { self.on_break.clone() } else { self.on_step.clone() };
+        self.verdict = Some(Verdict::Undecided);
+        for command in &commands {
+            let words: Vec<&str> = command.split_whitespace().collect();
+            if let Err(e) = self.execute(&words) {
+                println!("error: `{}`: {}", command, e);
+                self.verdict = Some(Verdict::Stop);
+            }
+            if self.verdict != Some(Verdict::Undecided) {
+                break;
+            }
+        }
+        match self.verdict.take() {
+            Some(Verdict::Undecided) => self.script_hook(if at_break { "on_break" } else { "on_step" }),
+            verdict => verdict.unwrap_or(Verdict::Undecided),
+        }
+    }
+
+    #[cfg(feature = "rhai")]
+    fn load_script(&mut self, path: &str) -> Result<(), String> {
+        self.script = Some(Script::load(path, &mut self.cpu)?);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "rhai"))]
+    fn load_script(&mut self, path: &str) -> Result<(), String> {
+        Err(format!("`{}` is a rhai script, build with `--features rhai` to run it", path))
+    }
+
+    /// Whether the script loaded has the function `name`
+    #[cfg(feature = "rhai")]
+    fn scripted(&self, name: &str) -> bool {
+        self.script.as_ref().is_some_and(|script| script.has(name))
+    }
+
+    #[cfg(not(feature = "rhai"))]
+    fn scripted(&self, _name: &str) -> bool {
+        false
+    }
+
+    /// Calls the function `name` of the script, an error stops the program
+    #[cfg(feature = "rhai")]
+    fn script_hook(&mut self, name: &str) -> Verdict {
+        let Some(script) = self.script.as_mut().filter(|script| script.has(name)) else {
+            return Verdict::Undecided;
+        };
+        match script.call(name, &mut self.cpu) {
+            Ok(Some(true)) => Verdict::Continue,
+            Ok(Some(false)) => Verdict::Stop,
+            Ok(None) => Verdict::Undecided,
+            Err(e) => {
+                println!("error: {}", e);
+                Verdict::Stop
+            }
+        }
+    }
+
+    #[cfg(not(feature = "rhai"))]
+    fn script_hook(&mut self, _name: &str) -> Verdict {
+        Verdict::Undecided
+    }
+
+    /// Runs a frame at a time until the program stops, waits for a key or reaches a breakpoint
+    fn resume(&mut self) -> StopReason {
+        loop {
+            self.checkpoints.record(&self.cpu);
+            match self.cpu.advance(self.cpu.instructions_per_frame() as usize).stopped {
+                StopReason::InstructionBudgetExhausted | StopReason::WaitingForVblank => {}
+                reason => return reason,
+            }
+        }
+    }
+
+    /// Goes back `count` instructions and shows the next one
+    fn step_back(&mut self, count: u64) -> Result<(), String> {
+        step_back(&mut self.cpu, &mut self.checkpoints, count)?;
+        self.disasm(self.cpu.pc(), 1);
+        Ok(())
+    }
+
+    fn mark(&self, name: &str) -> Result<&Snapshot, String> {
+        self.marks
+            .iter()
+            .find(|(mark, _)| mark == name)
+            .map(|(_, snapshot)| snapshot)
+            .ok_or_else(|| format!("there's no mark `{}`", name))
+    }
+
+    /// Prints the changes one per line, bytes of memory next to each other
+    /// on the same line, like `0x300..0x303  00 00 00 -> 01 02 03`
+    fn print_changes(&self, changes: &[Change]) {
+        if changes.is_empty() {
+            println!("nothing changed");
+        }
+        let mut bytes: Vec<(usize, u8, u8)> = Vec::new();
+        for change in changes {
+            match *change {
+                Change::Memory { addr, before, after } if bytes.last().is_none_or(|&(last, _, _)| last + 1 == addr) && bytes.len() < 8 => {
+                    bytes.push((addr, before, after));
+                }
+                Change::Memory { addr, before, after } => {
+                    print_bytes(&bytes);
+                    bytes = vec![(addr, before, after)];
+                }
+                ref change => println!("{}", change),
+            }
+        }
+        print_bytes(&bytes);
+    }
+
+    /// Prints `len` bytes from `addr`, 16 per line
+    fn mem(&self, addr: usize, len: usize) -> Result<(), String> {
+        let bytes = self.cpu.read_memory(addr, len).map_err(|e| e.to_string())?;
+        for (row, chunk) in bytes.chunks(16).enumerate() {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+            println!("{:#06x}  {}", addr + row * 16, hex.join(" "));
+        }
+        Ok(())
+    }
+
+    /// Prints `count` instructions from `addr`, the one at the PC marked with `>`
+    fn disasm(&self, addr: usize, count: usize) {
+        let mut addr = addr;
+        for _ in 0..count {
+            // `F000 NNNN` is the longest instruction
+            let len = 4.min(self.cpu.memory_size().saturating_sub(addr));
+            let Some(line) = self
+                .cpu
+                .read_memory(addr, len)
+                .ok()
+                .and_then(|bytes| disassemble_at(&bytes, addr, addr, self.cpu.variant()))
+            else {
+                break;
+            };
+
+            if let Some(name) = self.symbols.name(addr) {
+                println!("{}:", name);
+            }
+            let marker = if addr == self.cpu.pc() { '>' } else { ' ' };
+            let bytes: String = line.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            println!("{} {:#05x}  {:<8}  {}", marker, addr, bytes, line.symbolic(&self.symbols));
+            addr += line.bytes.len();
+        }
+    }
+}
+
+/// Prints the last `count` instructions executed, oldest first
+fn print_history(cpu: &CPU, count: usize) {
+    for entry in history(cpu, count) {
+        println!("{}", entry);
+    }
+}
+
+/// The last `count` instructions executed, oldest first
+pub fn history(cpu: &CPU, count: usize) -> Vec<HistoryEntry> {
+    let entries: Vec<HistoryEntry> = cpu.history().collect();
+    entries[entries.len().saturating_sub(count)..].to_vec()
+}
+
+/// Changed bytes at consecutive addresses, before and after
+fn print_bytes(bytes: &[(usize, u8, u8)]) {
+    let (Some(first), Some(last)) = (bytes.first(), bytes.last()) else {
+        return;
+    };
+    let before: Vec<String> = bytes.iter().map(|(_, before, _)| format!("{:02X}", before)).collect();
+    let after: Vec<String> = bytes.iter().map(|(_, _, after)| format!("{:02X}", after)).collect();
+    let place = match bytes.len() {
+        1 => format!("{:#05x}", first.0),
+        _ => format!("{:#05x}..{:#05x}", first.0, last.0 + 1),
+    };
+    println!("{:<14} {} -> {}", place, before.join(" "), after.join(" "));
+}
+
+/// Executes `count` instructions, gives back what stopped the program before
+/// that. Time passes the way `advance` counts it so that `step_back` can
+/// replay the same steps from the checkpoints taken on the way
+pub fn step(cpu: &mut CPU, checkpoints: &mut Checkpointer, count: usize) -> Option<StopReason> {
+    let target = cpu.instructions_executed() + count as u64;
+    let mut first = true;
+    while cpu.instructions_executed() < target {
+        checkpoints.record(cpu);
+        match cpu.advance(1).stopped {
+            StopReason::InstructionBudgetExhausted | StopReason::WaitingForVblank => {}
+            // the breakpoint the PC starts on doesn't keep it from stepping over it
+            StopReason::Breakpoint(_) | StopReason::OpcodeBreakpoint { .. } if first => {}
+            reason => return Some(reason),
+        }
+        first = false;
+    }
+    None
+}
+
+/// Executes instructions until `condition` holds after one of them, gives
+/// back what stopped the program before that. At least one instruction runs
+/// so that the same `until` can be repeated
+pub fn until(cpu: &mut CPU, checkpoints: &mut Checkpointer, condition: &Expr) -> Option<StopReason> {
+    if let Some(reason) = step(cpu, checkpoints, 1) {
+        return Some(reason);
+    }
+    while !condition.holds(cpu) {
+        if let Some(reason) = step_on(cpu, checkpoints) {
+            return Some(reason);
+        }
+    }
+    None
+}
+
+/// Executes one instruction, stopping at a breakpoint on it unlike `step`
+fn step_on(cpu: &mut CPU, checkpoints: &mut Checkpointer) -> Option<StopReason> {
+    checkpoints.record(cpu);
+    match cpu.advance(1).stopped {
+        StopReason::InstructionBudgetExhausted | StopReason::WaitingForVblank => None,
+        reason => Some(reason),
+    }
+}
+
+/// Goes back `count` instructions by restoring the last checkpoint before
+/// them and executing again up to there
+pub fn step_back(cpu: &mut CPU, checkpoints: &mut Checkpointer, count: u64) -> Result<(), String> {
+    let target = cpu.instructions_executed().saturating_sub(count);
+    let index = checkpoints.latest_at(target).ok_or("the instructions that far back are forgotten")?;
+    checkpoints.restore(index, cpu);
+
+    while cpu.instructions_executed() < target {
+        let reason = cpu.advance(1).stopped;
+        if matches!(reason, StopReason::Error(_) | StopReason::WaitingForKey | StopReason::HaltOpcode | StopReason::ExitOpcode) {
+            return Err(format!("the program went another way when executed again: {:?}", reason));
+        }
+    }
+    Ok(())
+}
+
+/// Where the PC is and where each function being executed was called from,
+/// innermost first, like `#1  0x204 in start+0x4`
+pub fn backtrace(cpu: &CPU, symbols: &Symbols) -> Vec<String> {
+    // return addresses are past the CALL
+    let calls = cpu.stack().iter().rev().map(|&addr| (addr as usize).saturating_sub(2));
+    std::iter::once(cpu.pc())
+        .chain(calls)
+        .enumerate()
+        .map(|(depth, addr)| match symbols.enclosing(addr) {
+            Some((start, name)) if start == addr => format!("#{:<2} {:#05x} in {}", depth, addr, name),
+            Some((start, name)) => format!("#{:<2} {:#05x} in {}+{:#x}", depth, addr, name, addr - start),
+            None => format!("#{:<2} {:#05x}", depth, addr),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cpu_caller::PROGRAM_START;
+
+    /// ADD V0, 1 then ADD V1, 2 and jump back
+    const COUNT: [u8; 6] = [0x70, 0x01, 0x71, 0x02, 0x12, 0x00];
+
+    /// Debugger of a program of `rom`, without symbols
+    fn debugger(rom: &[u8]) -> Debugger {
+        let mut cpu = CPU::new();
+        cpu.load_rom(rom).unwrap();
+        Debugger::new(cpu, Symbols::new())
+    }
+
+    /// File of commands for `source`, named after the test
+    fn commands(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("cpu-caller-{}-{}.txt", name, std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn commands_change_the_state() {
+        let mut debugger = debugger(&COUNT);
+        assert!(!debugger.line("set v3 0x10"));
+        assert!(!debugger.line("set I 0x300"));
+        assert!(!debugger.line("step 2"));
+        let cpu = &debugger.cpu;
+        assert_eq!((cpu.register(0), cpu.register(1), cpu.register(3), cpu.i()), (1, 2, 0x10, 0x300));
+        assert_eq!(cpu.pc(), PROGRAM_START + 4);
+        debugger.line("s");
+        assert_eq!(debugger.cpu.pc(), PROGRAM_START);
+        assert!(debugger.line("quit"));
+    }
+
+    #[test]
+    fn wrong_commands_are_errors() {
+        let mut debugger = debugger(&COUNT);
+        for command in [&["step", "two"][..], &["set", "vz", "1"], &["set", "sp", "1"], &["mem", "0x300", "zz"], &["mem", "0xFFF", "2"], &["frobnicate"]] {
+            assert!(debugger.execute(command).is_err(), "{:?}", command);
+        }
+        // nothing ran
+        assert_eq!(debugger.cpu.instructions_executed(), 0);
+    }
+
+    #[test]
+    fn files_can_source_each_other_but_not_themselves() {
+        let mut debugger = debugger(&COUNT);
+        let inner = commands("inner", "step\n");
+        let outer = commands("outer", "");
+        // the outer file runs the inner one, then itself, which is refused
+        let text = format!("set v5 7  # a comment\nsource {}\nsource {}\n", inner.display(), outer.display());
+        std::fs::write(&outer, text).unwrap();
+        assert_eq!(debugger.source(outer.to_str().unwrap()), Ok(false));
+        assert_eq!((debugger.cpu.register(5), debugger.cpu.instructions_executed()), (7, 1));
+
+        // once it's done it can run again
+        std::fs::write(&inner, "step\nsource ".to_string() + outer.to_str().unwrap()).unwrap();
+        std::fs::write(&outer, format!("source {}\nquit\nstep\n", inner.display())).unwrap();
+        assert_eq!(debugger.source(outer.to_str().unwrap()), Ok(true));
+        assert_eq!(debugger.cpu.instructions_executed(), 2);
+        assert!(debugger.sourcing.is_empty());
+        std::fs::remove_file(inner).unwrap();
+        std::fs::remove_file(outer).unwrap();
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut symbols = Symbols::new();
+        symbols.insert(PROGRAM_START + 4, "back");
+        let mut cpu = CPU::new();
+        cpu.load_rom(&COUNT).unwrap();
+        let mut debugger = Debugger::new(cpu, symbols);
+        debugger.line("break back");
+        assert_eq!(debugger.cpu.breakpoints().collect::<Vec<_>>(), [PROGRAM_START + 4]);
+        debugger.line("continue");
+        assert_eq!((debugger.cpu.pc(), debugger.cpu.register(0)), (PROGRAM_START + 4, 1));
+        // continuing goes around the loop back to it
+        debugger.line("c");
+        assert_eq!((debugger.cpu.pc(), debugger.cpu.register(0)), (PROGRAM_START + 4, 2));
+        // stepping from it executes it
+        debugger.line("step");
+        assert_eq!(debugger.cpu.pc(), PROGRAM_START);
+
+        assert!(debugger.execute(&["delete", "0x206"]).is_err());
+        assert_eq!(debugger.execute(&["delete", "back"]), Ok(()));
+        assert!(debugger.execute(&["delete", "back"]).is_err());
+        assert!(debugger.execute(&["break", "nowhere"]).is_err());
+        debugger.line("step 10");
+        assert_eq!(debugger.cpu.instructions_executed(), 16);
+    }
+
+    #[test]
+    fn conditional_breakpoints() {
+        let mut debugger = debugger(&COUNT);
+        assert!(debugger.execute(&["break", "0x204", "if", "v0", "=="]).is_err());
+        assert_eq!(debugger.cpu.breakpoints().count(), 0);
+        debugger.line("break 0x204 if v0 == 3 && v1 > 4");
+        debugger.line("continue");
+        assert_eq!((debugger.cpu.pc(), debugger.cpu.register(0), debugger.cpu.register(1)), (PROGRAM_START + 4, 3, 6));
+    }
+
+    #[test]
+    fn watchpoints() {
+        // LD I, 0x300, LD [I], V0 and halts
+        let mut debugger = debugger(&[0xA3, 0x00, 0xF0, 0x55, 0x00, 0x00]);
+        assert!(debugger.execute(&["watch", "peek", "0x300"]).is_err());
+        debugger.line("watch write 0x2FF..0x301");
+        assert_eq!(debugger.cpu.watchpoints().collect::<Vec<_>>(), [(0x2FF..0x301, Access::Write)]);
+        debugger.line("continue");
+        assert_eq!(debugger.cpu.pc(), PROGRAM_START + 4);
+
+        assert!(debugger.execute(&["unwatch", "0x300"]).is_err());
+        assert_eq!(debugger.execute(&["unwatch", "0x2FF..0x301"]), Ok(()));
+        assert_eq!(debugger.cpu.watchpoints().count(), 0);
+    }
+
+    /// ADD V0, 3, stores it at 0x300 and in the delay timer and jumps back
+    const STORE: [u8; 10] = [0x70, 0x03, 0xA3, 0x00, 0xF0, 0x55, 0xF0, 0x15, 0x12, 0x00];
+
+    #[test]
+    fn stepping_back_is_executing_again() {
+        let mut cpu = CPU::new();
+        cpu.load_rom(&STORE).unwrap();
+        let mut checkpoints = Checkpointer::new(4, 8);
+        let mut states = vec![cpu.snapshot()];
+        for _ in 0..20 {
+            assert_eq!(step(&mut cpu, &mut checkpoints, 1), None);
+            states.push(cpu.snapshot());
+        }
+
+        step_back(&mut cpu, &mut checkpoints, 1).unwrap();
+        assert_eq!(cpu.snapshot(), states[19]);
+        step_back(&mut cpu, &mut checkpoints, 6).unwrap();
+        assert_eq!(cpu.snapshot(), states[13]);
+        // and forward again to the same states
+        assert_eq!(step(&mut cpu, &mut checkpoints, 7), None);
+        assert_eq!(cpu.snapshot(), states[20]);
+        step_back(&mut cpu, &mut checkpoints, 20).unwrap();
+        assert_eq!(cpu.snapshot(), states[0]);
+    }
+
+    #[test]
+    fn stepping_back_past_the_checkpoints() {
+        let mut debugger = debugger(&STORE);
+        debugger.checkpoints = Checkpointer::new(4, 2);
+        debugger.line("step 20");
+        assert!(debugger.execute(&["step-back", "19"]).is_err());
+        assert_eq!(debugger.cpu.instructions_executed(), 20);
+        debugger.line("sb 2");
+        assert_eq!((debugger.cpu.instructions_executed(), debugger.cpu.pc()), (18, PROGRAM_START + 6));
+    }
+
+    #[test]
+    fn backtraces() {
+        // CALL 0x206, then 0x206 calls 0x20A
+        let mut cpu = CPU::new();
+        cpu.load_rom(&[0x22, 0x06, 0x12, 0x00, 0x00, 0x00, 0x22, 0x0A, 0x00, 0xEE, 0x71, 0x02, 0x00, 0xEE]).unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(backtrace(&cpu, &Symbols::new()), ["#0  0x20a", "#1  0x206", "#2  0x200"]);
+
+        let mut symbols = Symbols::new();
+        symbols.insert(PROGRAM_START, "start");
+        symbols.insert(0x20A, "inner");
+        assert_eq!(backtrace(&cpu, &symbols), ["#0  0x20a in inner", "#1  0x206 in start+0x6", "#2  0x200 in start"]);
+
+        // back in the first function
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(backtrace(&cpu, &symbols), ["#0  0x208 in start+0x8", "#1  0x200 in start"]);
+    }
+
+    #[test]
+    fn until_the_condition_holds() {
+        let mut debugger = debugger(&COUNT);
+        debugger.line("until v0 == 3 || pc >= 0x400");
+        assert_eq!((debugger.cpu.instructions_executed(), debugger.cpu.pc(), debugger.cpu.register(0)), (7, PROGRAM_START + 2, 3));
+        // it still holds, the same command goes on by one instruction
+        debugger.line("u v0 == 3");
+        assert_eq!(debugger.cpu.instructions_executed(), 8);
+        assert!(debugger.execute(&["until"]).is_err());
+        assert!(debugger.execute(&["until", "v0", "=="]).is_err());
+        assert_eq!(debugger.cpu.instructions_executed(), 8);
+    }
+
+    #[test]
+    fn until_stops_at_breakpoints() {
+        let mut cpu = CPU::new();
+        cpu.load_rom(&COUNT).unwrap();
+        cpu.add_breakpoint(PROGRAM_START + 4);
+        let mut checkpoints = Checkpointer::new(CHECKPOINT_INTERVAL, CHECKPOINTS);
+        let condition = Expr::parse("v0 == 10").unwrap();
+        assert_eq!(until(&mut cpu, &mut checkpoints, &condition), Some(StopReason::Breakpoint(PROGRAM_START as u16 + 4)));
+        assert_eq!(cpu.register(0), 1);
+        // without it, until it holds
+        cpu.remove_breakpoint(PROGRAM_START + 4);
+        assert_eq!(until(&mut cpu, &mut checkpoints, &condition), None);
+        assert_eq!((cpu.register(0), cpu.pc()), (10, PROGRAM_START + 2));
+    }
+
+    #[test]
+    fn marks_and_diffs() {
+        let mut debugger = debugger(&STORE);
+        debugger.line("mark before");
+        debugger.line("step 3");
+        debugger.line("mark after");
+        assert_eq!(debugger.execute(&["diff", "before", "after"]), Ok(()));
+        assert_eq!(debugger.execute(&["diff", "before"]), Ok(()));
+        assert!(debugger.execute(&["diff", "before", "later"]).is_err());
+        let changes = debugger.mark("before").unwrap().diff(debugger.mark("after").unwrap());
+        assert!(changes.contains(&Change::Memory { addr: 0x300, before: 0, after: 3 }), "{:?}", changes);
+
+        // marking again moves the mark
+        debugger.line("step");
+        debugger.line("mark before");
+        assert_eq!(debugger.marks.len(), 2);
+        assert_eq!(debugger.mark("before").unwrap().instructions_executed, 4);
+    }
+
+    #[test]
+    fn history_shows_the_last_ones() {
+        let mut debugger = debugger(&COUNT);
+        debugger.line("step 4");
+        let pcs: Vec<_> = history(&debugger.cpu, 2).iter().map(|entry| entry.pc).collect();
+        assert_eq!(pcs, [0x204, 0x200]);
+        assert_eq!(history(&debugger.cpu, 100).len(), 4);
+        assert_eq!(debugger.execute(&["history", "2"]), Ok(()));
+        assert!(debugger.execute(&["history", "-1"]).is_err());
+    }
+
+    #[test]
+    fn opcode_breakpoints() {
+        let mut debugger = debugger(&STORE);
+        assert!(debugger.execute(&["break", "opcode", "F_5"]).is_err());
+        debugger.line("break opcode Fx15");
+        debugger.line("continue");
+        assert_eq!(debugger.cpu.pc(), PROGRAM_START + 6);
+        // stepping over it, then around the loop back to it
+        debugger.line("continue");
+        assert_eq!((debugger.cpu.pc(), debugger.cpu.instructions_executed()), (PROGRAM_START + 6, 8));
+
+        assert_eq!(debugger.execute(&["delete", "opcode", "F_15"]), Ok(()));
+        assert!(debugger.execute(&["delete", "opcode", "F_15"]).is_err());
+    }
+
+    #[test]
+    fn hooks_decide_whether_to_go_on() {
+        let mut debugger = debugger(&COUNT);
+        debugger.line("break 0x204");
+        debugger.line("on break assert v0 < 3; continue");
+        debugger.line("continue");
+        assert_eq!((debugger.cpu.pc(), debugger.cpu.register(0)), (PROGRAM_START + 4, 3));
+
+        debugger.line("delete");
+        debugger.line("on break");
+        debugger.line("on step assert v1 < 10");
+        debugger.line("step 100");
+        assert_eq!(debugger.cpu.register(1), 10);
+        // they can't run the program themselves
+        debugger.line("on step step");
+        let executed = debugger.cpu.instructions_executed();
+        debugger.line("step 5");
+        assert_eq!(debugger.cpu.instructions_executed(), executed + 1);
+        assert!(debugger.execute(&["on", "fault", "stop"]).is_err());
+    }
+
+    #[cfg(feature = "rhai")]
+    #[test]
+    fn scripts_decide_whether_to_go_on() {
+        let mut debugger = debugger(&COUNT);
+        let path = std::env::temp_dir().join(format!("cpu-caller-hooks-{}.rhai", std::process::id()));
+        std::fs::write(&path, "breakpoint(0x204);\nfn on_break() { v(0) < 3 }\n").unwrap();
+        debugger.line(&format!("source {}", path.display()));
+        std::fs::remove_file(&path).unwrap();
+        debugger.line("continue");
+        assert_eq!((debugger.cpu.pc(), debugger.cpu.register(0)), (PROGRAM_START + 4, 3));
+    }
+
+    #[test]
+    fn ranges() {
+        let debugger = debugger(&COUNT);
+        assert_eq!(debugger.range("0x300..0x302"), Ok(0x300..0x302));
+        assert_eq!(debugger.range("768"), Ok(0x300..0x301));
+        assert!(debugger.range("0x302..0x300").is_err());
+        assert!(debugger.range("0x300..0x300").is_err());
+        assert!(debugger.range(&usize::MAX.to_string()).is_err());
+    }
+}
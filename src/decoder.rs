@@ -0,0 +1,271 @@
+use core::fmt;
+
+use crate::Variant;
+
+/// One decoded instruction, `x` and `y` are register numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Halt,                          // 0000
+    Sys { addr: u16 },             // 0NNN, machine code routine of the host computer
+    ScrollDown { n: u8 },          // 00CN, SUPER-CHIP
+    ScrollUp { n: u8 },            // 00DN, XO-CHIP
+    Cls,                           // 00E0
+    Ret,                           // 00EE
+    ScrollRight,                   // 00FB, SUPER-CHIP
+    ScrollLeft,                    // 00FC, SUPER-CHIP
+    Exit,                          // 00FD, SUPER-CHIP
+    LowRes,                        // 00FE, SUPER-CHIP
+    HighRes,                       // 00FF, SUPER-CHIP
+    Jump { addr: u16 },            // 1NNN
+    Call { addr: u16 },            // 2NNN
+    SkipEqByte { x: u8, kk: u8 },  // 3XKK
+    SkipNeByte { x: u8, kk: u8 },  // 4XKK
+    SkipEq { x: u8, y: u8 },       // 5XY0
+    SaveRange { x: u8, y: u8 },    // 5XY2, XO-CHIP
+    LoadRange { x: u8, y: u8 },    // 5XY3, XO-CHIP
+    LoadByte { x: u8, kk: u8 },    // 6XKK
+    AddByte { x: u8, kk: u8 },     // 7XKK
+    Load { x: u8, y: u8 },         // 8XY0
+    Or { x: u8, y: u8 },           // 8XY1
+    And { x: u8, y: u8 },          // 8XY2
+    Xor { x: u8, y: u8 },          // 8XY3
+    Add { x: u8, y: u8 },          // 8XY4
+    Sub { x: u8, y: u8 },          // 8XY5
+    ShiftRight { x: u8, y: u8 },   // 8XY6
+    SubN { x: u8, y: u8 },         // 8XY7
+    ShiftLeft { x: u8, y: u8 },    // 8XYE
+    SkipNe { x: u8, y: u8 },       // 9XY0
+    LoadI { addr: u16 },           // ANNN
+    JumpV0 { addr: u16, x: u8 },   // BNNN, `x` is only used with `Quirks::jump_with_vx`
+    Random { x: u8, kk: u8 },      // CXKK
+    Draw { x: u8, y: u8, n: u8 },  // DXYN
+    SkipKey { x: u8 },             // EX9E
+    SkipNoKey { x: u8 },           // EXA1
+    LoadILong,                     // F000 NNNN, XO-CHIP, the address is the next word
+    SelectPlanes { mask: u8 },     // FN01, XO-CHIP
+    LoadAudio,                     // F002, XO-CHIP
+    LoadDelay { x: u8 },           // FX07
+    WaitKey { x: u8 },             // FX0A
+    SetDelay { x: u8 },            // FX15
+    SetSound { x: u8 },            // FX18
+    AddI { x: u8 },                // FX1E
+    LoadFont { x: u8 },            // FX29
+    LoadBigFont { x: u8 },         // FX30, SUPER-CHIP
+    Bcd { x: u8 },                 // FX33
+    SetPitch { x: u8 },            // FX3A, XO-CHIP
+    StoreRegs { x: u8 },           // FX55
+    LoadRegs { x: u8 },            // FX65
+    SaveFlags { x: u8 },           // FX75, SUPER-CHIP
+    LoadFlags { x: u8 },           // FX85, SUPER-CHIP
+    Unknown(u16),                  // the opcode doesn't exist on the machine
+}
+
+/// Turns an opcode into the instruction it means on `variant`, opcodes added
+/// by the extensions are plain CHIP-8 instructions (or unknown) on older machines
+pub fn decode(opcode: u16, variant: Variant) -> Instruction {
+    let schip = variant != Variant::Chip8;
+    let xochip = variant == Variant::XoChip;
+
+    let c = ((opcode & 0xF000) >> 12) as u8;
+    let x = ((opcode & 0x0F00) >> 8) as u8;
+    let y = ((opcode & 0x00F0) >> 4) as u8;
+    let d = (opcode & 0x000F) as u8;
+
+    // get memory address from opcode
+    let addr = opcode & 0xFFF;
+    // get an 8-bit constant from opcode
+    let kk = (opcode & 0x00FF) as u8;
+
+    match(c, x, y, d) {
+        ( 0, 0, 0, 0) => Instruction::Halt,
+        ( 0, 0, 0xC, _) if schip => Instruction::ScrollDown { n: d },
+        ( 0, 0, 0xD, _) if xochip => Instruction::ScrollUp { n: d },
+        ( 0, 0, 0xE, 0) => Instruction::Cls,
+        ( 0, 0, 0xE, 0xE) => Instruction::Ret,
+        ( 0, 0, 0xF, 0xB) if schip => Instruction::ScrollRight,
+        ( 0, 0, 0xF, 0xC) if schip => Instruction::ScrollLeft,
+        ( 0, 0, 0xF, 0xD) if schip => Instruction::Exit,
+        ( 0, 0, 0xF, 0xE) if schip => Instruction::LowRes,
+        ( 0, 0, 0xF, 0xF) if schip => Instruction::HighRes,
+        (0x0, _, _, _) => Instruction::Sys { addr },
+        (0x1, _, _, _) => Instruction::Jump { addr },
+        (0x2, _, _, _) => Instruction::Call { addr },
+        (0x3, _, _, _) => Instruction::SkipEqByte { x, kk },
+        (0x4, _, _, _) => Instruction::SkipNeByte { x, kk },
+        (0x5, _, _, 0x0) => Instruction::SkipEq { x, y },
+        (0x5, _, _, 0x2) if xochip => Instruction::SaveRange { x, y },
+        (0x5, _, _, 0x3) if xochip => Instruction::LoadRange { x, y },
+        (0x6, _, _, _) => Instruction::LoadByte { x, kk },
+        (0x7, _, _, _) => Instruction::AddByte { x, kk },
+        (0x8, _, _, 0x0) => Instruction::Load { x, y },
+        (0x8, _, _, 0x1) => Instruction::Or { x, y },
+        (0x8, _, _, 0x2) => Instruction::And { x, y },
+        (0x8, _, _, 0x3) => Instruction::Xor { x, y },
+        (0x8, _, _, 0x4) => Instruction::Add { x, y },
+        (0x8, _, _, 0x5) => Instruction::Sub { x, y },
+        (0x8, _, _, 0x6) => Instruction::ShiftRight { x, y },
+        (0x8, _, _, 0x7) => Instruction::SubN { x, y },
+        (0x8, _, _, 0xE) => Instruction::ShiftLeft { x, y },
+        (0x9, _, _, 0x0) => Instruction::SkipNe { x, y },
+        (0xA, _, _, _) => Instruction::LoadI { addr },
+        (0xB, _, _, _) => Instruction::JumpV0 { addr, x },
+        (0xC, _, _, _) => Instruction::Random { x, kk },
+        (0xD, _, _, _) => Instruction::Draw { x, y, n: d },
+        (0xE, _, 0x9, 0xE) => Instruction::SkipKey { x },
+        (0xE, _, 0xA, 0x1) => Instruction::SkipNoKey { x },
+        (0xF, 0, 0x0, 0x0) if xochip => Instruction::LoadILong,
+        (0xF, _, 0x0, 0x1) if xochip => Instruction::SelectPlanes { mask: x },
+        (0xF, 0, 0x0, 0x2) if xochip => Instruction::LoadAudio,
+        (0xF, _, 0x0, 0x7) => Instruction::LoadDelay { x },
+        (0xF, _, 0x0, 0xA) => Instruction::WaitKey { x },
+        (0xF, _, 0x1, 0x5) => Instruction::SetDelay { x },
+        (0xF, _, 0x1, 0x8) => Instruction::SetSound { x },
+        (0xF, _, 0x1, 0xE) => Instruction::AddI { x },
+        (0xF, _, 0x2, 0x9) => Instruction::LoadFont { x },
+        (0xF, _, 0x3, 0x0) if schip => Instruction::LoadBigFont { x },
+        (0xF, _, 0x3, 0x3) => Instruction::Bcd { x },
+        (0xF, _, 0x3, 0xA) if xochip => Instruction::SetPitch { x },
+        (0xF, _, 0x5, 0x5) => Instruction::StoreRegs { x },
+        (0xF, _, 0x6, 0x5) => Instruction::LoadRegs { x },
+        (0xF, _, 0x7, 0x5) if schip => Instruction::SaveFlags { x },
+        (0xF, _, 0x8, 0x5) if schip => Instruction::LoadFlags { x },
+        _ => Instruction::Unknown(opcode),
+    }
+}
+
+impl Instruction {
+    /// Turns the instruction back into its opcode, `decode` undoes it on any
+    /// machine that has the instruction. `LoadILong` only gives the first word,
+    /// the address goes in the next one
+    pub fn encode(self) -> u16 {
+        // puts the four nibbles of an opcode together
+        let nibbles = |c: u16, x: u8, y: u8, d: u8| {
+            c << 12 | (x as u16 & 0xF) << 8 | (y as u16 & 0xF) << 4 | d as u16 & 0xF
+        };
+        let byte = |c: u16, x: u8, kk: u8| c << 12 | (x as u16 & 0xF) << 8 | kk as u16;
+        let address = |c: u16, addr: u16| c << 12 | addr & 0xFFF;
+
+        match self {
+            Instruction::Halt => 0x0000,
+            Instruction::Sys { addr } => address(0x0, addr),
+            Instruction::ScrollDown { n } => nibbles(0x0, 0, 0xC, n),
+            Instruction::ScrollUp { n } => nibbles(0x0, 0, 0xD, n),
+            Instruction::Cls => 0x00E0,
+            Instruction::Ret => 0x00EE,
+            Instruction::ScrollRight => 0x00FB,
+            Instruction::ScrollLeft => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::LowRes => 0x00FE,
+            Instruction::HighRes => 0x00FF,
+            Instruction::Jump { addr } => address(0x1, addr),
+            Instruction::Call { addr } => address(0x2, addr),
+            Instruction::SkipEqByte { x, kk } => byte(0x3, x, kk),
+            Instruction::SkipNeByte { x, kk } => byte(0x4, x, kk),
+            Instruction::SkipEq { x, y } => nibbles(0x5, x, y, 0x0),
+            Instruction::SaveRange { x, y } => nibbles(0x5, x, y, 0x2),
+            Instruction::LoadRange { x, y } => nibbles(0x5, x, y, 0x3),
+            Instruction::LoadByte { x, kk } => byte(0x6, x, kk),
+            Instruction::AddByte { x, kk } => byte(0x7, x, kk),
+            Instruction::Load { x, y } => nibbles(0x8, x, y, 0x0),
+            Instruction::Or { x, y } => nibbles(0x8, x, y, 0x1),
+            Instruction::And { x, y } => nibbles(0x8, x, y, 0x2),
+            Instruction::Xor { x, y } => nibbles(0x8, x, y, 0x3),
+            Instruction::Add { x, y } => nibbles(0x8, x, y, 0x4),
+            Instruction::Sub { x, y } => nibbles(0x8, x, y, 0x5),
+            Instruction::ShiftRight { x, y } => nibbles(0x8, x, y, 0x6),
+            Instruction::SubN { x, y } => nibbles(0x8, x, y, 0x7),
+            Instruction::ShiftLeft { x, y } => nibbles(0x8, x, y, 0xE),
+            Instruction::SkipNe { x, y } => nibbles(0x9, x, y, 0x0),
+            Instruction::LoadI { addr } => address(0xA, addr),
+            Instruction::JumpV0 { addr, .. } => address(0xB, addr), // `x` is the high nibble of `addr`
+            Instruction::Random { x, kk } => byte(0xC, x, kk),
+            Instruction::Draw { x, y, n } => nibbles(0xD, x, y, n),
+            Instruction::SkipKey { x } => byte(0xE, x, 0x9E),
+            Instruction::SkipNoKey { x } => byte(0xE, x, 0xA1),
+            Instruction::LoadILong => 0xF000,
+            Instruction::SelectPlanes { mask } => byte(0xF, mask, 0x01),
+            Instruction::LoadAudio => 0xF002,
+            Instruction::LoadDelay { x } => byte(0xF, x, 0x07),
+            Instruction::WaitKey { x } => byte(0xF, x, 0x0A),
+            Instruction::SetDelay { x } => byte(0xF, x, 0x15),
+            Instruction::SetSound { x } => byte(0xF, x, 0x18),
+            Instruction::AddI { x } => byte(0xF, x, 0x1E),
+            Instruction::LoadFont { x } => byte(0xF, x, 0x29),
+            Instruction::LoadBigFont { x } => byte(0xF, x, 0x30),
+            Instruction::Bcd { x } => byte(0xF, x, 0x33),
+            Instruction::SetPitch { x } => byte(0xF, x, 0x3A),
+            Instruction::StoreRegs { x } => byte(0xF, x, 0x55),
+            Instruction::LoadRegs { x } => byte(0xF, x, 0x65),
+            Instruction::SaveFlags { x } => byte(0xF, x, 0x75),
+            Instruction::LoadFlags { x } => byte(0xF, x, 0x85),
+            Instruction::Unknown(opcode) => opcode,
+        }
+    }
+
+    /// Big-endian bytes of the opcode, the order they are stored in memory
+    pub fn to_bytes(self) -> [u8; 2] {
+        self.encode().to_be_bytes()
+    }
+}
+
+/// Mnemonic of the instruction in the usual CHIP-8 assembly syntax, like `LD V1, 0x0a`.
+/// `LoadILong` is shown without the address from the next word
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::Halt => write!(f, "HALT"),
+            Instruction::Sys { addr } => write!(f, "SYS {:#05x}", addr),
+            Instruction::ScrollDown { n } => write!(f, "SCD {}", n),
+            Instruction::ScrollUp { n } => write!(f, "SCU {}", n),
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::ScrollRight => write!(f, "SCR"),
+            Instruction::ScrollLeft => write!(f, "SCL"),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::LowRes => write!(f, "LOW"),
+            Instruction::HighRes => write!(f, "HIGH"),
+            Instruction::Jump { addr } => write!(f, "JP {:#05x}", addr),
+            Instruction::Call { addr } => write!(f, "CALL {:#05x}", addr),
+            Instruction::SkipEqByte { x, kk } => write!(f, "SE V{:X}, {:#04x}", x, kk),
+            Instruction::SkipNeByte { x, kk } => write!(f, "SNE V{:X}, {:#04x}", x, kk),
+            Instruction::SkipEq { x, y } => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::SaveRange { x, y } => write!(f, "SAVE V{:X}, V{:X}", x, y),
+            Instruction::LoadRange { x, y } => write!(f, "LOAD V{:X}, V{:X}", x, y),
+            Instruction::LoadByte { x, kk } => write!(f, "LD V{:X}, {:#04x}", x, kk),
+            Instruction::AddByte { x, kk } => write!(f, "ADD V{:X}, {:#04x}", x, kk),
+            Instruction::Load { x, y } => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or { x, y } => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And { x, y } => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor { x, y } => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::Add { x, y } => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub { x, y } => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::ShiftRight { x, y } => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::SubN { x, y } => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::ShiftLeft { x, y } => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SkipNe { x, y } => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LoadI { addr } => write!(f, "LD I, {:#05x}", addr),
+            Instruction::JumpV0 { addr, .. } => write!(f, "JP V0, {:#05x}", addr),
+            Instruction::Random { x, kk } => write!(f, "RND V{:X}, {:#04x}", x, kk),
+            Instruction::Draw { x, y, n } => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::SkipKey { x } => write!(f, "SKP V{:X}", x),
+            Instruction::SkipNoKey { x } => write!(f, "SKNP V{:X}", x),
+            Instruction::LoadILong => write!(f, "LD I, LONG"),
+            Instruction::SelectPlanes { mask } => write!(f, "PLANE {}", mask),
+            Instruction::LoadAudio => write!(f, "AUDIO"),
+            Instruction::LoadDelay { x } => write!(f, "LD V{:X}, DT", x),
+            Instruction::WaitKey { x } => write!(f, "LD V{:X}, K", x),
+            Instruction::SetDelay { x } => write!(f, "LD DT, V{:X}", x),
+            Instruction::SetSound { x } => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddI { x } => write!(f, "ADD I, V{:X}", x),
+            Instruction::LoadFont { x } => write!(f, "LD F, V{:X}", x),
+            Instruction::LoadBigFont { x } => write!(f, "LD HF, V{:X}", x),
+            Instruction::Bcd { x } => write!(f, "LD B, V{:X}", x),
+            Instruction::SetPitch { x } => write!(f, "PITCH V{:X}", x),
+            Instruction::StoreRegs { x } => write!(f, "LD [I], V{:X}", x),
+            Instruction::LoadRegs { x } => write!(f, "LD V{:X}, [I]", x),
+            Instruction::SaveFlags { x } => write!(f, "LD R, V{:X}", x),
+            Instruction::LoadFlags { x } => write!(f, "LD V{:X}, R", x),
+            Instruction::Unknown(opcode) => write!(f, "DW {:#06x}", opcode),
+        }
+    }
+}
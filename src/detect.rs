@@ -0,0 +1,83 @@
+use crate::decoder::decode;
+use crate::flow::control_flow;
+use crate::{Variant, PROGRAM_START};
+
+/// Guesses which machine a ROM loaded at the conventional 0x200 was written
+/// for, see `detect_variant_at`
+pub fn detect_variant(rom: &[u8]) -> Variant {
+    detect_variant_at(rom, PROGRAM_START, PROGRAM_START)
+}
+
+/// Guesses which machine a ROM loaded at `base` and started at `entry` was
+/// written for by looking for opcodes that only exist in the SUPER-CHIP and
+/// XO-CHIP instruction sets among the instructions the program can reach.
+///
+/// This is a heuristic: ROMs are not tagged and code only reached through
+/// computed jumps is not seen, so the guess can be wrong for unusual programs.
+pub fn detect_variant_at(rom: &[u8], base: usize, entry: usize) -> Variant {
+    // only XO-CHIP can address programs that don't fit in 4 KB
+    if base + rom.len() > 0x1000 {
+        return Variant::XoChip;
+    }
+
+    // the widest instruction set follows every instruction the others know
+    let graph = control_flow(rom, base, entry, Variant::XoChip);
+    let mut variant = Variant::Chip8;
+    for line in graph.blocks.values().flat_map(|block| &block.lines) {
+        let [hi, lo, ..] = line.bytes[..] else {
+            continue; // the odd byte at the end
+        };
+        let opcode = u16::from_be_bytes([hi, lo]);
+
+        if is_xochip_opcode(opcode) {
+            return Variant::XoChip;
+        }
+        if is_schip_opcode(opcode) {
+            variant = Variant::SuperChip;
+        }
+    }
+
+    variant
+}
+
+/// Opcodes added by SUPER-CHIP 1.1
+pub(crate) fn is_schip_opcode(opcode: u16) -> bool {
+    // a sprite of zero rows is a no-op on CHIP-8, it only makes sense as a 16x16 sprite
+    opcode & 0xF00F == 0xD000 || decode(opcode, Variant::SuperChip) != decode(opcode, Variant::Chip8)
+}
+
+/// Opcodes added by XO-CHIP
+pub(crate) fn is_xochip_opcode(opcode: u16) -> bool {
+    decode(opcode, Variant::XoChip) != decode(opcode, Variant::SuperChip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_is_not_code() {
+        // CLS, I = sprite, draw it, loop forever, then the sprite 00 FF
+        let rom = [0x00, 0xE0, 0xA2, 0x08, 0xD0, 0x02, 0x12, 0x06, 0x00, 0xFF];
+        assert_eq!(detect_variant(&rom), Variant::Chip8);
+        let rom = [0x00, 0xE0, 0xA2, 0x08, 0xD0, 0x02, 0x12, 0x06, 0x00, 0xFE, 0xF0, 0x00];
+        assert_eq!(detect_variant(&rom), Variant::Chip8);
+    }
+
+    #[test]
+    fn reached_extensions() {
+        // HIGH then loop
+        assert_eq!(detect_variant(&[0x00, 0xFF, 0x12, 0x02]), Variant::SuperChip);
+        // long load of I then loop
+        assert_eq!(detect_variant(&[0xF0, 0x00, 0x03, 0x00, 0x12, 0x04]), Variant::XoChip);
+    }
+
+    #[test]
+    fn other_base() {
+        // the jump goes over the data at 0x002 to the scroll at 0x004
+        let rom = [0x10, 0x04, 0x00, 0xFF, 0x00, 0xC1, 0x10, 0x06];
+        assert_eq!(detect_variant_at(&rom, 0, 0), Variant::SuperChip);
+        let rom = [0x10, 0x04, 0x00, 0xFF, 0x10, 0x04];
+        assert_eq!(detect_variant_at(&rom, 0, 0), Variant::Chip8);
+    }
+}
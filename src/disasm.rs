@@ -0,0 +1,110 @@
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::decoder::{decode, Instruction};
+use crate::symbols::Symbols;
+use crate::Variant;
+
+/// One instruction of a disassembled program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisasmLine {
+    pub addr: usize,
+    pub bytes: Vec<u8>,                   // 4 for `F000 NNNN`, 1 for an odd byte at the end
+    pub instruction: Option<Instruction>, // `None` for the odd byte at the end
+}
+
+impl DisasmLine {
+    /// Address loaded by `F000 NNNN`, that is the word after the opcode
+    pub fn long_address(&self) -> Option<u16> {
+        match (self.instruction, self.bytes.as_slice()) {
+            (Some(Instruction::LoadILong), [_, _, hi, lo]) => Some(u16::from_be_bytes([*hi, *lo])),
+            _ => None,
+        }
+    }
+
+    /// Where a jump or a call of the line goes, `JP V0` uses V0 as an offset from it
+    pub fn target(&self) -> Option<usize> {
+        match self.instruction? {
+            Instruction::Jump { addr } | Instruction::Call { addr } | Instruction::JumpV0 { addr, .. } => {
+                Some(addr as usize)
+            }
+            _ => None,
+        }
+    }
+
+    /// Mnemonic with the addresses found in `names` replaced by their name,
+    /// the ones of jumps, calls and loads of I
+    pub fn symbolic(&self, names: &Symbols) -> String {
+        if let Some(name) = self.long_address().and_then(|addr| names.name(addr as usize)) {
+            return format!("LD I, LONG {}", name);
+        }
+        let name = match self.instruction {
+            Some(Instruction::LoadI { addr }) => names.name(addr as usize),
+            _ => self.target().and_then(|target| names.name(target)),
+        };
+        match (self.instruction, name) {
+            (Some(Instruction::Jump { .. }), Some(name)) => format!("JP {}", name),
+            (Some(Instruction::Call { .. }), Some(name)) => format!("CALL {}", name),
+            (Some(Instruction::JumpV0 { .. }), Some(name)) => format!("JP V0, {}", name),
+            (Some(Instruction::LoadI { .. }), Some(name)) => format!("LD I, {}", name),
+            _ => format!("{}", self),
+        }
+    }
+}
+
+/// Name given to the label of `addr`
+pub fn label(addr: usize) -> String {
+    format!("L_{:04X}", addr)
+}
+
+/// Addresses of `lines` that a jump or a call goes to, targets outside of the
+/// program or in the middle of an instruction keep their number
+pub fn find_labels(lines: &[DisasmLine]) -> BTreeSet<usize> {
+    let starts: BTreeSet<usize> = lines.iter().map(|line| line.addr).collect();
+    lines.iter().filter_map(DisasmLine::target).filter(|target| starts.contains(target)).collect()
+}
+
+/// Mnemonic of the line, bytes that can't be an instruction are shown as data
+impl fmt::Display for DisasmLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.instruction, self.long_address()) {
+            (_, Some(addr)) => write!(f, "LD I, LONG {:#06x}", addr),
+            (Some(instruction), None) => write!(f, "{}", instruction),
+            (None, None) => write!(f, "DB {:#04x}", self.bytes[0]),
+        }
+    }
+}
+
+/// Decodes every word of `rom` as if it was loaded at `base`, data included
+/// since telling it apart from code needs to run the program
+pub fn disassemble(rom: &[u8], base: usize, variant: Variant) -> Vec<DisasmLine> {
+    let mut lines = Vec::new();
+    let mut addr = base;
+
+    while let Some(line) = disassemble_at(rom, base, addr, variant) {
+        addr += line.bytes.len();
+        lines.push(line);
+    }
+    lines
+}
+
+/// Decodes the instruction at `addr` of `rom` loaded at `base`, `None` outside of it
+pub fn disassemble_at(rom: &[u8], base: usize, addr: usize, variant: Variant) -> Option<DisasmLine> {
+    let offset = addr.checked_sub(base).filter(|&offset| offset < rom.len())?;
+    let Some(word) = rom.get(offset..offset + 2) else {
+        let bytes = rom[offset..].to_vec();
+        return Some(DisasmLine { addr, bytes, instruction: None });
+    };
+
+    let instruction = decode(u16::from_be_bytes([word[0], word[1]]), variant);
+    // the address of `F000` takes the next word, unless the ROM ends first
+    let len = match instruction {
+        Instruction::LoadILong if offset + 4 <= rom.len() => 4,
+        _ => 2,
+    };
+    let bytes = rom[offset..offset + len].to_vec();
+    Some(DisasmLine { addr, bytes, instruction: Some(instruction) })
+}
@@ -0,0 +1,407 @@
+use alloc::boxed::Box;
+
+use crate::{Display, DISPLAY_HEIGHT, DISPLAY_WIDTH, HIRES_HEIGHT, HIRES_WIDTH, PLANES};
+
+/// Pixels of the screen the display instructions work on, one monochrome
+/// plane per XO-CHIP bit plane, 64x32 or 128x64 in high resolution
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Framebuffer {
+    planes: [[bool; HIRES_WIDTH * HIRES_HEIGHT]; PLANES], // row by row, `true` means lit
+    plane_mask: u8, // planes affected by drawing, clearing and scrolling
+    hires: bool,    // only the first 64x32 pixels of each plane are used in low resolution
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Framebuffer {
+    /// Blank low resolution screen drawing on the first plane
+    pub fn new() -> Framebuffer {
+        Framebuffer { planes: [[false; HIRES_WIDTH * HIRES_HEIGHT]; PLANES], plane_mask: 0b01, hires: false }
+    }
+
+    /// Width in pixels of the current mode
+    pub fn width(&self) -> usize {
+        if self.hires { HIRES_WIDTH } else { DISPLAY_WIDTH }
+    }
+
+    /// Height in pixels of the current mode
+    pub fn height(&self) -> usize {
+        if self.hires { HIRES_HEIGHT } else { DISPLAY_HEIGHT }
+    }
+
+    pub fn hires(&self) -> bool {
+        self.hires
+    }
+
+    /// Switches between the 64x32 and 128x64 modes, clearing every plane
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        // the old picture makes no sense with the new dimensions
+        self.planes = [[false; HIRES_WIDTH * HIRES_HEIGHT]; PLANES];
+    }
+
+    /// Pixels of one plane row by row, plane 0 is the regular display
+    pub fn plane(&self, index: usize) -> &[bool] {
+        &self.planes[index][..self.width() * self.height()]
+    }
+
+    /// Every plane, in the form `Display::present` takes them
+    pub fn planes(&self) -> [&[bool]; PLANES] {
+        core::array::from_fn(|p| self.plane(p))
+    }
+
+    pub fn plane_mask(&self) -> u8 {
+        self.plane_mask
+    }
+
+    /// Selects the planes the other operations affect, one bit per plane.
+    /// Bits of planes the framebuffer doesn't have are ignored
+    ///
+    /// ```
+    /// use cpu_caller::Framebuffer;
+    ///
+    /// let mut screen = Framebuffer::new();
+    /// screen.select_planes(0b10);
+    /// screen.draw(1, 0, 0, &[0b1000_0000], 8, false);
+    /// // clearing only touches the selected planes
+    /// screen.select_planes(0b01);
+    /// screen.clear();
+    /// assert_eq!((screen.plane(0)[0], screen.plane(1)[0]), (false, true));
+    /// ```
+    pub fn select_planes(&mut self, mask: u8) {
+        self.plane_mask = mask & ((1 << PLANES) - 1);
+    }
+
+    /// Indexes of the planes selected by the plane mask
+    pub fn selected_planes(&self) -> impl Iterator<Item = usize> {
+        let mask = self.plane_mask;
+        (0..PLANES).filter(move |p| mask & (1 << p) != 0)
+    }
+
+    /// Turns off every pixel of the selected planes
+    pub fn clear(&mut self) {
+        for p in self.selected_planes() {
+            self.planes[p] = [false; HIRES_WIDTH * HIRES_HEIGHT];
+        }
+    }
+
+    /// Turns off every pixel and selects the first plane, like a reset
+    pub fn reset(&mut self) {
+        *self = Framebuffer::new();
+    }
+
+    /// XORs a sprite into `plane` with its top left corner at `x`, `y`,
+    /// which wrap around the screen. Each row is `width` bits, 8 or 16, the
+    /// most significant one on the left. Pixels going past an edge continue
+    /// on the opposite one unless `clip`. Returns whether a lit pixel was
+    /// turned off. Planes the framebuffer doesn't have draw nothing and rows
+    /// are at most 16 bits wide
+    pub fn draw(&mut self, plane: usize, x: usize, y: usize, rows: &[u16], width: usize, clip: bool) -> bool {
+        if plane >= PLANES {
+            return false;
+        }
+        let width = width.min(16);
+        let (screen_width, screen_height) = (self.width(), self.height());
+        // the starting position always wraps, only the pixels after it can be clipped
+        let (origin_x, origin_y) = (x % screen_width, y % screen_height);
+        let mut collision = false;
+
+        for (row, &bits) in rows.iter().enumerate() {
+            for col in 0..width {
+                if bits & (1 << (width - 1 - col)) == 0 {
+                    continue;
+                }
+
+                let (mut px, mut py) = (origin_x + col, origin_y + row);
+                if px >= screen_width || py >= screen_height {
+                    if clip {
+                        continue;
+                    }
+                    px %= screen_width;
+                    py %= screen_height;
+                }
+                let pixel = &mut self.planes[plane][py * screen_width + px];
+
+                collision |= *pixel;
+                *pixel = !*pixel;
+            }
+        }
+        collision
+    }
+
+    /// Moves the selected planes `n` rows down, rows entering from the top are blank.
+    /// Rows are those of the current mode, in low resolution a row is 1/32 of the screen
+    ///
+    /// ```
+    /// use cpu_caller::Framebuffer;
+    ///
+    /// # fn picture(screen: &Framebuffer, rows: usize) -> String {
+    /// #     screen.plane(0).chunks(screen.width()).take(rows).map(|row| {
+    /// #         row[..4].iter().map(|&lit| if lit { '#' } else { '.' }).collect::<String>() + "\n"
+    /// #     }).collect()
+    /// # }
+    /// let mut screen = Framebuffer::new();
+    /// screen.draw(0, 0, 0, &[0b1100_0000, 0b0110_0000], 8, false);
+    /// screen.scroll_down(2);
+    /// assert_eq!(picture(&screen, 5), "....\n....\n##..\n.##.\n....\n");
+    ///
+    /// // rows pushed past the bottom are gone
+    /// screen.scroll_down(30);
+    /// assert!(screen.plane(0).iter().all(|&lit| !lit));
+    /// ```
+    pub fn scroll_down(&mut self, n: usize) {
+        let (width, height) = (self.width(), self.height());
+        let n = n.min(height);
+
+        for p in self.selected_planes() {
+            let plane = &mut self.planes[p][..width * height];
+            plane.copy_within(..(height - n) * width, n * width);
+            plane[..n * width].fill(false);
+        }
+    }
+
+    /// Moves the selected planes `n` rows up, rows entering from the bottom are blank
+    pub fn scroll_up(&mut self, n: usize) {
+        let (width, height) = (self.width(), self.height());
+        let n = n.min(height);
+
+        for p in self.selected_planes() {
+            let plane = &mut self.planes[p][..width * height];
+            plane.copy_within(n * width.., 0);
+            plane[(height - n) * width..].fill(false);
+        }
+    }
+
+    /// Moves the selected planes `n` pixels to the right, pixels of the current
+    /// mode so 4 of them are 1/16 of the screen in low resolution and 1/32 in
+    /// high resolution
+    ///
+    /// ```
+    /// use cpu_caller::Framebuffer;
+    ///
+    /// # fn picture(screen: &Framebuffer) -> String {
+    /// #     screen.plane(0).chunks(screen.width()).take(2).map(|row| {
+    /// #         row.iter().map(|&lit| if lit { '#' } else { '.' }).collect::<String>() + "\n"
+    /// #     }).collect()
+    /// # }
+    /// let mut screen = Framebuffer::new();
+    /// screen.draw(0, 60, 0, &[0b1111_0000, 0b1001_0000], 8, false);
+    /// screen.scroll_right(4);
+    /// // what leaves on the right doesn't come back on the left
+    /// assert_eq!(picture(&screen), format!("{}\n{}\n", ".".repeat(64), ".".repeat(64)));
+    ///
+    /// screen.set_hires(true);
+    /// screen.draw(0, 0, 0, &[0b1111_0000, 0b1001_0000], 8, false);
+    /// screen.scroll_right(4);
+    /// let blank = ".".repeat(120);
+    /// assert_eq!(picture(&screen), format!("....####{}\n....#..#{}\n", blank, blank));
+    /// ```
+    pub fn scroll_right(&mut self, n: usize) {
+        let (width, height) = (self.width(), self.height());
+        let n = n.min(width);
+
+        for p in self.selected_planes() {
+            for row in self.planes[p][..width * height].chunks_mut(width) {
+                row.copy_within(..width - n, n);
+                row[..n].fill(false);
+            }
+        }
+    }
+
+    /// Moves the selected planes `n` pixels to the left
+    pub fn scroll_left(&mut self, n: usize) {
+        let (width, height) = (self.width(), self.height());
+        let n = n.min(width);
+
+        for p in self.selected_planes() {
+            for row in self.planes[p][..width * height].chunks_mut(width) {
+                row.copy_within(n.., 0);
+                row[width - n..].fill(false);
+            }
+        }
+    }
+
+    /// Puts back planes saved one after the other at the high resolution size
+    pub(crate) fn load(&mut self, pixels: &[bool], plane_mask: u8, hires: bool) {
+        for (plane, pixels) in self.planes.iter_mut().zip(pixels.chunks(HIRES_WIDTH * HIRES_HEIGHT)) {
+            plane.copy_from_slice(pixels);
+        }
+        self.plane_mask = plane_mask;
+        self.hires = hires;
+    }
+
+    /// Every pixel of the planes one after the other at the high resolution size
+    pub(crate) fn pixels(&self) -> impl Iterator<Item = bool> + '_ {
+        self.planes.iter().flatten().copied()
+    }
+}
+
+/// What frontends implement to show the screen, given the whole framebuffer
+/// each time it changed. Its `width` and `height` are those of the mode of
+/// that frame. Anything implementing the simpler `Display` is one
+pub trait Renderer {
+    fn render(&mut self, framebuffer: &Framebuffer);
+}
+
+impl<D: Display + ?Sized> Renderer for D {
+    fn render(&mut self, framebuffer: &Framebuffer) {
+        self.present(framebuffer.width(), framebuffer.height(), framebuffer.planes());
+    }
+}
+
+impl<D: Display + ?Sized> Display for Box<D> {
+    fn present(&mut self, width: usize, height: usize, planes: [&[bool]; PLANES]) {
+        (**self).present(width, height, planes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Blank screen in the mode of `hires` with the pixels marked `#` in
+    /// `rows` lit in plane 0, the first row from `x`, `y`
+    fn bitmap(hires: bool, x: usize, y: usize, rows: &[&str]) -> Framebuffer {
+        let mut screen = Framebuffer::new();
+        screen.set_hires(hires);
+        for (dy, row) in rows.iter().enumerate() {
+            for (dx, c) in row.chars().enumerate() {
+                if c == '#' {
+                    screen.draw(0, x + dx, y + dy, &[0x80], 8, false);
+                }
+            }
+        }
+        screen
+    }
+
+    /// Bitmap with something at each corner of the screen, so that every
+    /// scroll pushes part of it out
+    fn corners(hires: bool) -> Framebuffer {
+        let (width, height) = if hires { (HIRES_WIDTH, HIRES_HEIGHT) } else { (DISPLAY_WIDTH, DISPLAY_HEIGHT) };
+        let mut screen = bitmap(hires, 0, 0, &["##..", "#..#"]);
+        for (x, y) in [(width - 4, 0), (0, height - 2), (width - 4, height - 2)] {
+            for (dy, bits) in [0b1100, 0b1001].into_iter().enumerate() {
+                screen.draw(0, x, y + dy, &[bits], 4, false);
+            }
+        }
+        screen
+    }
+
+    #[test]
+    fn scroll_down_lores() {
+        // 00C3: the bottom corners fall off, the top ones move down
+        let mut screen = corners(false);
+        screen.scroll_down(3);
+        let mut expected = bitmap(false, 0, 3, &["##..", "#..#"]);
+        expected.draw(0, 60, 3, &[0b1100, 0b1001], 4, false);
+        assert_eq!(screen, expected);
+
+        let mut screen = corners(false);
+        screen.scroll_down(1);
+        let mut expected = bitmap(false, 0, 1, &["##..", "#..#"]);
+        expected.draw(0, 60, 1, &[0b1100, 0b1001], 4, false);
+        expected.draw(0, 0, 31, &[0b1100], 4, false);
+        expected.draw(0, 60, 31, &[0b1100], 4, false);
+        assert_eq!(screen, expected);
+    }
+
+    #[test]
+    fn scroll_down_hires() {
+        let mut screen = corners(true);
+        screen.scroll_down(1);
+        let mut expected = bitmap(true, 0, 1, &["##..", "#..#"]);
+        expected.draw(0, 124, 1, &[0b1100, 0b1001], 4, false);
+        expected.draw(0, 0, 63, &[0b1100], 4, false);
+        expected.draw(0, 124, 63, &[0b1100], 4, false);
+        assert_eq!(screen, expected);
+
+        // 00CF moves 15 rows, as many rows as there are go
+        let mut screen = corners(true);
+        screen.scroll_down(15);
+        let mut expected = bitmap(true, 0, 15, &["##..", "#..#"]);
+        expected.draw(0, 124, 15, &[0b1100, 0b1001], 4, false);
+        assert_eq!(screen, expected);
+        screen.scroll_down(64);
+        assert_eq!(screen, bitmap(true, 0, 0, &[]));
+    }
+
+    #[test]
+    fn scroll_right_lores() {
+        // 00FB: the right corners leave, the left ones move 4 pixels
+        let mut screen = corners(false);
+        screen.scroll_right(4);
+        let mut expected = bitmap(false, 4, 0, &["##..", "#..#"]);
+        expected.draw(0, 4, 30, &[0b1100, 0b1001], 4, false);
+        assert_eq!(screen, expected);
+    }
+
+    #[test]
+    fn scroll_right_hires() {
+        let mut screen = corners(true);
+        screen.scroll_right(4);
+        let mut expected = bitmap(true, 4, 0, &["##..", "#..#"]);
+        expected.draw(0, 4, 62, &[0b1100, 0b1001], 4, false);
+        assert_eq!(screen, expected);
+    }
+
+    #[test]
+    fn scroll_left_lores() {
+        // 00FC: the left corners leave, the right ones move 4 pixels
+        let mut screen = corners(false);
+        screen.scroll_left(4);
+        let mut expected = bitmap(false, 56, 0, &["##..", "#..#"]);
+        expected.draw(0, 56, 30, &[0b1100, 0b1001], 4, false);
+        assert_eq!(screen, expected);
+
+        // half a corner stays after 2 pixels
+        let mut screen = corners(false);
+        screen.scroll_left(2);
+        let mut expected = bitmap(false, 0, 0, &["..", ".#"]);
+        expected.draw(0, 0, 31, &[0b0100], 4, false);
+        expected.draw(0, 58, 0, &[0b1100, 0b1001], 4, false);
+        expected.draw(0, 58, 30, &[0b1100, 0b1001], 4, false);
+        assert_eq!(screen, expected);
+    }
+
+    #[test]
+    fn scroll_left_hires() {
+        let mut screen = corners(true);
+        screen.scroll_left(4);
+        let mut expected = bitmap(true, 120, 0, &["##..", "#..#"]);
+        expected.draw(0, 120, 62, &[0b1100, 0b1001], 4, false);
+        assert_eq!(screen, expected);
+    }
+
+    #[test]
+    fn scrolls_move_the_selected_planes() {
+        let mut screen = Framebuffer::new();
+        screen.select_planes(0b11);
+        screen.draw(0, 8, 8, &[0x80], 8, false);
+        screen.draw(1, 8, 8, &[0x80], 8, false);
+        screen.select_planes(0b10);
+        screen.scroll_left(4);
+        screen.scroll_down(2);
+        assert!(screen.plane(0)[8 * 64 + 8]);
+        assert!(screen.plane(1)[10 * 64 + 4]);
+        assert_eq!(screen.plane(1).iter().filter(|&&lit| lit).count(), 1);
+    }
+
+    #[test]
+    fn draw_ignores_missing_planes() {
+        let mut screen = Framebuffer::new();
+        assert!(!screen.draw(PLANES, 0, 0, &[0xFF], 8, false));
+        assert_eq!(screen, Framebuffer::new());
+    }
+
+    #[test]
+    fn draw_takes_16_bits_at_most() {
+        let mut screen = Framebuffer::new();
+        screen.draw(0, 0, 0, &[0x8001], 32, false);
+        assert_eq!(screen.plane(0)[..16].iter().filter(|&&lit| lit).count(), 2);
+        assert!(screen.plane(0)[0] && screen.plane(0)[15]);
+    }
+}
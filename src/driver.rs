@@ -0,0 +1,238 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+use crate::{StopReason, CPU};
+
+/// State shared by a runner and the handles that control it
+#[derive(Default)]
+struct ControlState {
+    pause_requested: bool,
+    cancelled: bool,
+    runner_paused: bool,   // the runner saw the pause request
+    finished: bool,        // the runner future was dropped, nobody will pause or resume
+    runner: Option<Waker>, // wakes a paused runner
+    waiters: Vec<Waker>,   // `Pause` and `Resume` futures waiting for the runner
+}
+
+/// Handle that pauses, resumes or cancels a running [`run_until_stop`] from
+/// other tasks, clones control the same runner.
+///
+/// The runner only checks it between batches of instructions
+#[derive(Clone, Default)]
+pub struct RunControl {
+    state: Arc<Mutex<ControlState>>,
+}
+
+impl RunControl {
+    /// Creates a handle for a new runner
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ControlState> {
+        // the state stays consistent even if a waker panicked while it was held
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Asks the runner to pause, the future resolves once it has
+    pub fn pause(&self) -> Pause {
+        self.lock().pause_requested = true;
+        Pause { control: self.clone() }
+    }
+
+    /// Lets a paused runner go on, the future resolves once it has
+    pub fn resume(&self) -> Resume {
+        let mut state = self.lock();
+        state.pause_requested = false;
+        if let Some(runner) = state.runner.take() {
+            runner.wake();
+        }
+        drop(state);
+        Resume { control: self.clone() }
+    }
+
+    /// Makes the runner finish with `None` at the end of its current batch
+    pub fn cancel(&self) {
+        let mut state = self.lock();
+        state.cancelled = true;
+        if let Some(runner) = state.runner.take() {
+            runner.wake();
+        }
+    }
+
+    /// Tells if a pause was requested and not resumed yet
+    pub fn is_paused(&self) -> bool {
+        self.lock().pause_requested
+    }
+}
+
+/// Future of [`RunControl::pause`]
+pub struct Pause {
+    control: RunControl,
+}
+
+impl Future for Pause {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.control.lock();
+        // a resume that came first also settles the pause
+        if state.runner_paused || state.finished || !state.pause_requested {
+            return Poll::Ready(());
+        }
+        state.waiters.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// Future of [`RunControl::resume`]
+pub struct Resume {
+    control: RunControl,
+}
+
+impl Future for Resume {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.control.lock();
+        if !state.runner_paused || state.finished || state.pause_requested {
+            return Poll::Ready(());
+        }
+        state.waiters.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// Executes `cpu` in batches of `batch` instructions, yielding to the executor
+/// after each one so that other tasks get to run. It works with any executor.
+///
+/// Resolves to why the program stopped, or `None` when cancelled through
+/// `control`. Dropping the future also stops the program between two batches.
+///
+/// The future isn't `Send`: the CPU owns devices and hooks that can stay on
+/// one thread, like the renderers of the windows. It runs on executors that
+/// don't move it, such as `block_on` or tokio's `LocalSet` and `spawn_local`,
+/// while `RunControl`, `Pause` and `Resume` are `Send` and can be used from
+/// any task. [`EmulatorThread`](crate::EmulatorThread) runs a CPU for other threads
+pub fn run_until_stop<'a>(cpu: &'a mut CPU, batch: usize, control: &RunControl) -> RunUntilStop<'a> {
+    let mut state = control.lock();
+    state.runner_paused = false;
+    state.finished = false;
+    state.cancelled = false;
+    drop(state);
+
+    RunUntilStop { cpu, batch: batch.max(1), control: control.clone() }
+}
+
+/// Future of [`run_until_stop`], to be polled on the thread of its CPU
+pub struct RunUntilStop<'a> {
+    cpu: &'a mut CPU,
+    batch: usize,
+    control: RunControl,
+}
+
+impl Future for RunUntilStop<'_> {
+    type Output = Option<StopReason>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<StopReason>> {
+        let mut state = self.control.lock();
+        if state.cancelled {
+            return Poll::Ready(None);
+        }
+
+        let paused = state.pause_requested;
+        if paused != state.runner_paused {
+            state.runner_paused = paused;
+            state.waiters.drain(..).for_each(Waker::wake);
+        }
+        if paused {
+            state.runner = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        drop(state);
+
+        let batch = self.batch;
+        match self.cpu.run_for(batch).stopped {
+            StopReason::InstructionBudgetExhausted => {
+                // come back as soon as the executor has given others a turn
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            reason => Poll::Ready(Some(reason)),
+        }
+    }
+}
+
+impl Drop for RunUntilStop<'_> {
+    fn drop(&mut self) {
+        let mut state = self.control.lock();
+        state.finished = true;
+        state.runner = None;
+        state.waiters.drain(..).for_each(Waker::wake);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn poll<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        Pin::new(future).poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    #[test]
+    fn controls_are_send() {
+        fn send<T: Send>() {}
+        send::<RunControl>();
+        send::<Pause>();
+        send::<Resume>();
+    }
+
+    #[test]
+    fn run_pause_resume_drop() {
+        let mut cpu = CPU::new();
+        cpu.load_rom(&[0x70, 0x01, 0x12, 0x00]).unwrap(); // counts in V0 forever
+        let control = RunControl::new();
+
+        let mut runner = run_until_stop(&mut cpu, 10, &control);
+        assert!(poll(&mut runner).is_pending());
+
+        // the pause settles once the runner saw it
+        let mut pause = control.pause();
+        assert!(poll(&mut pause).is_pending());
+        assert!(poll(&mut runner).is_pending());
+        assert!(poll(&mut pause).is_ready());
+        assert!(poll(&mut runner).is_pending()); // paused, nothing runs
+
+        let mut resume = control.resume();
+        assert!(poll(&mut resume).is_pending());
+        assert!(poll(&mut runner).is_pending());
+        assert!(poll(&mut resume).is_ready());
+
+        // without a runner nothing is left to wait for
+        drop(runner);
+        assert!(poll(&mut control.pause()).is_ready());
+        assert_eq!(cpu.instructions_executed(), 20);
+    }
+
+    #[test]
+    fn stops_with_the_program() {
+        let mut cpu = CPU::new();
+        cpu.load_rom(&[0x00, 0xFD]).unwrap();
+        let mut runner = run_until_stop(&mut cpu, 10, &RunControl::new());
+        assert_eq!(poll(&mut runner), Poll::Ready(Some(StopReason::ExitOpcode)));
+    }
+
+    #[test]
+    fn cancel() {
+        let mut cpu = CPU::new();
+        cpu.load_rom(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        let control = RunControl::new();
+        let mut runner = run_until_stop(&mut cpu, 10, &control);
+        assert!(poll(&mut runner).is_pending());
+        control.cancel();
+        assert_eq!(poll(&mut runner), Poll::Ready(None));
+    }
+}
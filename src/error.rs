@@ -0,0 +1,36 @@
+use core::fmt;
+
+/// Fault that stops the execution of a program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuError {
+    StackOverflow { addr: u16 },                   // CALL at `addr` with every stack level in use
+    StackUnderflow { addr: u16 },                  // RET at `addr` outside of any function
+    UnknownOpcode { opcode: u16, addr: u16 },      // the instruction at `addr` doesn't exist
+    OutOfBounds { addr: usize },                   // the memory location `addr` doesn't exist
+    SysCall { routine: u16, addr: u16 },           // 0NNN at `addr` with `SysPolicy::Trap`
+    RomTooLarge { size: usize, available: usize }, // the ROM doesn't fit after its base address
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::StackOverflow { addr } => write!(f, "stack overflow at {:#05x}", addr),
+            CpuError::StackUnderflow { addr } => write!(f, "stack underflow at {:#05x}", addr),
+            CpuError::UnknownOpcode { opcode, addr } => {
+                write!(f, "unknown opcode {:04x} at {:#05x}", opcode, addr)
+            }
+            CpuError::OutOfBounds { addr } => write!(f, "memory access out of bounds at {:#x}", addr),
+            CpuError::SysCall { routine, addr } => {
+                write!(f, "machine code routine {:#05x} called at {:#05x}", routine, addr)
+            }
+            CpuError::RomTooLarge { size, available } => write!(
+                f,
+                "ROM of {} bytes does not fit in the {} bytes after its base address",
+                size, available
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for CpuError {}
@@ -0,0 +1,288 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use core::fmt;
+
+use crate::CPU;
+
+/// Expression over the state of a CPU, like `v0 == 0x1F && sp > 2`.
+///
+/// Names are the registers `v0` to `vf`, `i`, `pc`, `sp` (the stack depth),
+/// `dt` and `st`, `[addr]` reads the byte of memory at `addr`. Numbers are
+/// decimal, `0x` hexadecimal or `0b` binary. From the loosest to the tightest:
+/// `||`, `&&`, comparisons, `&` `|` `^`, `+` `-`, then `!` and `-` in front of
+/// a value. Comparisons give 1 or 0 and anything not 0 is true
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expr {
+    source: String, // as written, to show it back
+    node: Node,
+}
+
+/// Part of a parsed expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Number(i64),
+    Register(u8), // V0 to VF
+    I,
+    Pc,
+    Sp,
+    Delay,
+    Sound,
+    Memory(Box<Node>), // byte at the address given by the node
+    Not(Box<Node>),
+    Negate(Box<Node>),
+    Binary(Op, Box<Node>, Box<Node>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Add,
+    Sub,
+}
+
+/// Operators of each level of precedence, loosest first
+const LEVELS: [&[(&str, Op)]; 5] = [
+    &[("||", Op::Or)],
+    &[("&&", Op::And)],
+    &[("==", Op::Eq), ("!=", Op::Ne), ("<=", Op::Le), (">=", Op::Ge), ("<", Op::Lt), (">", Op::Gt)],
+    &[("&", Op::BitAnd), ("|", Op::BitOr), ("^", Op::BitXor)],
+    &[("+", Op::Add), ("-", Op::Sub)],
+];
+
+impl Expr {
+    /// Reads an expression, the error tells what was expected
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parser = Parser { text, pos: 0 };
+        let node = parser.level(0)?;
+        parser.skip_spaces();
+        if parser.pos < text.len() {
+            return Err(format!("unexpected `{}`", &text[parser.pos..]));
+        }
+        Ok(Expr { source: text.trim().to_string(), node })
+    }
+
+    /// Value of the expression for the current state of `cpu`
+    pub fn eval(&self, cpu: &CPU) -> i64 {
+        eval(&self.node, cpu)
+    }
+
+    /// Whether the expression holds, that is it isn't 0
+    pub fn holds(&self, cpu: &CPU) -> bool {
+        self.eval(cpu) != 0
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn eval(node: &Node, cpu: &CPU) -> i64 {
+    match node {
+        Node::Number(n) => *n,
+        Node::Register(x) => cpu.register(*x) as i64,
+        Node::I => cpu.i() as i64,
+        Node::Pc => cpu.pc() as i64,
+        Node::Sp => cpu.stack_pointer() as i64,
+        Node::Delay => cpu.delay_timer() as i64,
+        Node::Sound => cpu.sound_timer() as i64,
+        // addresses outside of memory read as 0
+        Node::Memory(addr) => usize::try_from(eval(addr, cpu))
+            .ok()
+            .and_then(|addr| cpu.read_memory(addr, 1).ok())
+            .map_or(0, |bytes| bytes[0] as i64),
+        Node::Not(node) => (eval(node, cpu) == 0) as i64,
+        Node::Negate(node) => eval(node, cpu).wrapping_neg(),
+        // `||` and `&&` only look at the right side when they need it
+        Node::Binary(Op::Or, a, b) => (eval(a, cpu) != 0 || eval(b, cpu) != 0) as i64,
+        Node::Binary(Op::And, a, b) => (eval(a, cpu) != 0 && eval(b, cpu) != 0) as i64,
+        Node::Binary(op, a, b) => {
+            let (a, b) = (eval(a, cpu), eval(b, cpu));
+            match op {
+                Op::Eq => (a == b) as i64,
+                Op::Ne => (a != b) as i64,
+                Op::Lt => (a < b) as i64,
+                Op::Le => (a <= b) as i64,
+                Op::Gt => (a > b) as i64,
+                Op::Ge => (a >= b) as i64,
+                Op::BitAnd => a & b,
+                Op::BitOr => a | b,
+                Op::BitXor => a ^ b,
+                Op::Add => a.wrapping_add(b),
+                Op::Sub => a.wrapping_sub(b),
+                Op::Or | Op::And => unreachable!("handled above"),
+            }
+        }
+    }
+}
+
+/// Recursive descent over the text of an expression
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize, // byte offset of the next character
+}
+
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.text[self.pos..]
+    }
+
+    fn skip_spaces(&mut self) {
+        self.pos = self.text.len() - self.rest().trim_start().len();
+    }
+
+    /// Moves past `token` if it comes next
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_spaces();
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Operator of `level` that comes next, `&` isn't taken for the start of `&&`
+    fn operator(&mut self, level: usize) -> Option<Op> {
+        self.skip_spaces();
+        let rest = self.rest();
+        if rest.starts_with("&&") && level > 1 || rest.starts_with("||") && level > 0 {
+            return None;
+        }
+        let &(token, op) = LEVELS[level].iter().find(|(token, _)| rest.starts_with(token))?;
+        self.pos += token.len();
+        Some(op)
+    }
+
+    /// Operators of `level` and tighter, left to right
+    fn level(&mut self, level: usize) -> Result<Node, String> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut node = self.level(level + 1)?;
+        while let Some(op) = self.operator(level) {
+            let right = self.level(level + 1)?;
+            node = Node::Binary(op, Box::new(node), Box::new(right));
+        }
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        if self.eat("!") {
+            return Ok(Node::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("-") {
+            return Ok(Node::Negate(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let node = self.level(0)?;
+            return if self.eat(")") { Ok(node) } else { Err("expected `)`".to_string()) };
+        }
+        if self.eat("[") {
+            let node = self.level(0)?;
+            return if self.eat("]") { Ok(Node::Memory(Box::new(node))) } else { Err("expected `]`".to_string()) };
+        }
+
+        let word: String = self.rest().chars().take_while(|c| c.is_ascii_alphanumeric() || *c == '_').collect();
+        if word.is_empty() {
+            return Err(match self.rest().chars().next() {
+                Some(c) => format!("expected a value, got `{}`", c),
+                None => "expected a value at the end".to_string(),
+            });
+        }
+        self.pos += word.len();
+        let lower = word.to_ascii_lowercase();
+        let node = match lower.as_str() {
+            "i" => Node::I,
+            "pc" => Node::Pc,
+            "sp" => Node::Sp,
+            "dt" => Node::Delay,
+            "st" => Node::Sound,
+            name if name.len() == 2 && name.starts_with('v') => match u8::from_str_radix(&name[1..], 16) {
+                Ok(x) => Node::Register(x),
+                Err(_) => return Err(format!("unknown name `{}`", word)),
+            },
+            number => Node::Number(parse_number(number).ok_or_else(|| format!("unknown name `{}`", word))?),
+        };
+        Ok(node)
+    }
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    if let Some(hex) = text.strip_prefix("0x") {
+        return i64::from_str_radix(hex, 16).ok();
+    }
+    if let Some(binary) = text.strip_prefix("0b") {
+        return i64::from_str_radix(binary, 2).ok();
+    }
+    text.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Value of `text` on a CPU with V0 = 0x1F, V3 = 2, I = 0x300 and the byte
+    /// 0x42 there, inside two calls
+    fn value(text: &str) -> i64 {
+        let mut cpu = CPU::new();
+        // CALL 0x202, CALL 0x204
+        cpu.load_rom(&[0x22, 0x02, 0x22, 0x04]).unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.set_register(0, 0x1F);
+        cpu.set_register(3, 2);
+        cpu.set_i(0x300);
+        cpu.write_memory(0x300, &[0x42]).unwrap();
+        cpu.set_delay_timer(9);
+        Expr::parse(text).unwrap().eval(&cpu)
+    }
+
+    #[test]
+    fn names_and_numbers() {
+        assert_eq!(value("v0"), 0x1F);
+        assert_eq!(value("V3 + vf"), 2);
+        assert_eq!(value("i"), 0x300);
+        assert_eq!(value("[i]"), 0x42);
+        assert_eq!(value("[i + 1]"), 0);
+        assert_eq!(value("[0x10000] + [-1]"), 0);
+        assert_eq!((value("sp"), value("pc"), value("dt"), value("st")), (2, 0x204, 9, 0));
+        assert_eq!(value("0x10 + 0b11 + 7"), 26);
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(value("1 + 2 == 3"), 1);
+        assert_eq!(value("10 - 2 - 3"), 5);
+        assert_eq!(value("v0 & 0xF == 0xF"), 1);
+        assert_eq!(value("v0 == 0x1F && sp > 2"), 0);
+        assert_eq!(value("v0 == 0x1F && sp >= 2"), 1);
+        assert_eq!(value("0 && 1 || 1"), 1);
+        assert_eq!(value("!(v3 != 2) && -v3 < 0"), 1);
+        assert_eq!(value("(1 | 2) ^ 1"), 2);
+    }
+
+    #[test]
+    fn mistakes() {
+        for text in ["", "v0 ==", "vg", "(v0", "[i", "v0 v1", "0xZZ", "pc @ 2"] {
+            assert!(Expr::parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn shows_what_was_written() {
+        assert_eq!(Expr::parse("  v0==1 ").unwrap().to_string(), "v0==1");
+    }
+}
@@ -0,0 +1,143 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::decoder::Instruction;
+use crate::disasm::{disassemble_at, DisasmLine};
+use crate::Variant;
+
+/// How a block leads to another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    Jump,        // 1NNN
+    Computed,    // BNNN, the target is only the smallest address it can go to
+    Call,        // 2NNN, the next block runs once the subroutine returns
+    Fallthrough, // the next block, also when a skip isn't taken
+    Skip,        // the skip is taken
+}
+
+/// Instructions that always run one after the other
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub lines: Vec<DisasmLine>,
+    pub successors: Vec<(usize, Edge)>, // addresses of the blocks that can come next
+}
+
+/// Blocks of a program reachable from its entry point, by their first address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlFlowGraph {
+    pub entry: usize,
+    pub blocks: BTreeMap<usize, BasicBlock>,
+}
+
+/// Instruction found while following the program
+struct Reached {
+    line: DisasmLine,
+    successors: Vec<(usize, Edge)>,
+    ends: bool, // the instruction is the last of its block
+}
+
+/// Follows every jump, call and skip of `rom` loaded at `base` from `entry`.
+/// Blocks end on the first instruction that doesn't go to the next one,
+/// calls included, and right before an instruction that something jumps to
+pub fn control_flow(rom: &[u8], base: usize, entry: usize, variant: Variant) -> ControlFlowGraph {
+    let mut code: BTreeMap<usize, Reached> = BTreeMap::new();
+    let mut leaders = BTreeSet::from([entry]);
+    let mut pending = vec![entry];
+
+    while let Some(addr) = pending.pop() {
+        if code.contains_key(&addr) {
+            continue;
+        }
+        let Some(line) = disassemble_at(rom, base, addr, variant) else {
+            continue; // outside of the ROM
+        };
+
+        let next = addr + line.bytes.len();
+        let (successors, ends) = match line.instruction {
+            None | Some(Instruction::Unknown(_) | Instruction::Halt | Instruction::Exit | Instruction::Ret) => {
+                (Vec::new(), true)
+            }
+            Some(Instruction::Jump { addr }) => (vec![(addr as usize, Edge::Jump)], true),
+            Some(Instruction::JumpV0 { addr, .. }) => (vec![(addr as usize, Edge::Computed)], true),
+            Some(Instruction::Call { addr }) => (vec![(addr as usize, Edge::Call), (next, Edge::Fallthrough)], true),
+            Some(
+                Instruction::SkipEqByte { .. }
+                | Instruction::SkipNeByte { .. }
+                | Instruction::SkipEq { .. }
+                | Instruction::SkipNe { .. }
+                | Instruction::SkipKey { .. }
+                | Instruction::SkipNoKey { .. },
+            ) => {
+                // skipping `F000 NNNN` jumps over both words
+                let skipped = disassemble_at(rom, base, next, variant).map_or(2, |line| line.bytes.len());
+                (vec![(next, Edge::Fallthrough), (next + skipped, Edge::Skip)], true)
+            }
+            Some(_) => (vec![(next, Edge::Fallthrough)], false),
+        };
+
+        for &(target, _) in &successors {
+            if ends {
+                leaders.insert(target);
+            }
+            pending.push(target);
+        }
+        code.insert(addr, Reached { line, successors, ends });
+    }
+
+    let mut blocks = BTreeMap::new();
+    for &leader in &leaders {
+        let mut addr = leader;
+        let mut lines = Vec::new();
+        while let Some(reached) = code.get(&addr) {
+            lines.push(reached.line.clone());
+            addr += reached.line.bytes.len();
+            if reached.ends || leaders.contains(&addr) || !code.contains_key(&addr) {
+                blocks.insert(leader, BasicBlock { lines, successors: reached.successors.clone() });
+                break;
+            }
+        }
+    }
+
+    ControlFlowGraph { entry, blocks }
+}
+
+impl ControlFlowGraph {
+    /// Graphviz description of the graph, `dot -Tsvg` draws it
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph cfg {\n    node [shape=box fontname=\"monospace\"];\n");
+        let mut outside = BTreeSet::new();
+
+        for (&start, block) in &self.blocks {
+            let mut label = String::new();
+            for line in &block.lines {
+                // `\l` ends a left-aligned line of the label
+                let _ = write!(label, "{:#05x}  {}\\l", line.addr, line);
+            }
+            let style = if start == self.entry { " style=bold" } else { "" };
+            let _ = writeln!(dot, "    \"{:#05x}\" [label=\"{}\"{}];", start, label, style);
+
+            for &(target, edge) in &block.successors {
+                let attributes = match edge {
+                    Edge::Jump | Edge::Fallthrough => "",
+                    Edge::Computed => " [label=\"+V0\" style=dotted]",
+                    Edge::Call => " [label=\"call\" style=dashed]",
+                    Edge::Skip => " [label=\"skip\"]",
+                };
+                let _ = writeln!(dot, "    \"{:#05x}\" -> \"{:#05x}\"{};", start, target, attributes);
+                if !self.blocks.contains_key(&target) {
+                    outside.insert(target);
+                }
+            }
+        }
+
+        for target in outside {
+            let _ = writeln!(dot, "    \"{:#05x}\" [label=\"{:#05x} outside the ROM\" style=dashed];", target, target);
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
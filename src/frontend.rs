@@ -0,0 +1,102 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cpu_caller::{Framebuffer, StopReason, CPU, DISPLAY_HEIGHT, DISPLAY_WIDTH, PLANES};
+
+use crate::cli::Scaling;
+use crate::config::Palette;
+
+/// Time between two frames of the program
+const FRAME: Duration = Duration::from_micros(16_667);
+
+/// Window pixels for each pixel of the low resolution display when a window
+/// opens without `--scale`
+const SCALE: u32 = 10;
+
+/// CHIP-8 key pressed by the keyboard key `c` in the layout `keys`
+pub fn chip8_key(keys: &[(char, u8)], c: char) -> Option<u8> {
+    let c = c.to_ascii_lowercase();
+    keys.iter().find(|&&(k, _)| k == c).map(|&(_, chip8)| chip8)
+}
+
+/// Color of each pixel of the framebuffer row by row, the one the palette
+/// gives to the planes lit there
+#[cfg_attr(not(any(feature = "window", feature = "sdl", feature = "minifb")), allow(dead_code))] // only the windows draw pixels
+pub fn colors<'a>(framebuffer: &'a Framebuffer, palette: &'a Palette) -> impl Iterator<Item = [u8; 3]> + 'a {
+    (0..framebuffer.width() * framebuffer.height()).map(move |i| palette.color(lit_planes(framebuffer, i)))
+}
+
+/// Planes lit at pixel `i` of the framebuffer, one bit per plane
+pub fn lit_planes(framebuffer: &Framebuffer, i: usize) -> usize {
+    (0..PLANES).filter(|&plane| framebuffer.plane(plane)[i]).fold(0, |bits, plane| bits | 1 << plane)
+}
+
+/// Size in pixels of a window when it opens
+#[cfg_attr(not(any(feature = "window", feature = "sdl", feature = "minifb")), allow(dead_code))]
+pub fn window_size(scaling: Scaling) -> (u32, u32) {
+    let scale = match scaling {
+        Scaling::Fixed(scale) => scale,
+        _ => SCALE,
+    };
+    (DISPLAY_WIDTH as u32 * scale, DISPLAY_HEIGHT as u32 * scale)
+}
+
+/// Size in window pixels of a picture of `picture` pixels drawn in a window
+/// of `window` pixels, centered in it. A fixed scale counts pixels of low
+/// resolution, so the picture keeps its size when the program switches to
+/// high resolution
+#[cfg_attr(not(any(feature = "window", feature = "sdl", feature = "minifb")), allow(dead_code))]
+pub fn picture_size(scaling: Scaling, picture: (usize, usize), window: (u32, u32)) -> (u32, u32) {
+    let (width, height) = (picture.0 as f64, picture.1 as f64);
+    let fits = (window.0 as f64 / width).min(window.1 as f64 / height);
+    let scale = match scaling {
+        Scaling::Stretch => return (window.0.max(1), window.1.max(1)),
+        Scaling::Whole => fits.floor().max(1.0),
+        Scaling::Fixed(scale) => scale as f64 * DISPLAY_WIDTH as f64 / width,
+        Scaling::Fit => fits,
+    };
+    (((width * scale) as u32).max(1), ((height * scale) as u32).max(1))
+}
+
+/// Colors of a picture of `picture` pixels, row by row, scaled to `size`:
+/// each pixel of the result has the color of the pixel under its center
+#[cfg_attr(not(any(feature = "window", feature = "minifb")), allow(dead_code))]
+pub fn scale(colors: &[[u8; 3]], picture: (usize, usize), size: (u32, u32)) -> impl Iterator<Item = [u8; 3]> + '_ {
+    let (width, height) = (size.0 as usize, size.1 as usize);
+    (0..height).flat_map(move |y| {
+        let row = (y * 2 + 1) * picture.1 / (height * 2);
+        (0..width).map(move |x| colors[row * picture.0 + (x * 2 + 1) * picture.0 / (width * 2)])
+    })
+}
+
+/// Waits until `next_frame` is due and moves it to the frame after. Frames
+/// are due every `FRAME` from the first one, so the time spent running them
+/// doesn't add up, and after a stall the lost frames are skipped instead of
+/// run all at once
+pub fn wait_frame(next_frame: &mut Instant) {
+    let now = Instant::now();
+    match next_frame.checked_duration_since(now) {
+        Some(wait) => thread::sleep(wait),
+        None => *next_frame = now,
+    }
+    *next_frame += FRAME;
+}
+
+/// Runs the program a frame at a time, 60 times per second: the instructions
+/// of a frame, then the timers tick once and the picture is presented once.
+/// Before each frame `events` gives the keys of the frontend to the CPU and
+/// tells whether to go on. Gives back what stopped the program, or `None`
+/// when `events` said to leave
+pub fn run_frames(cpu: &mut CPU, mut events: impl FnMut(&mut CPU) -> bool) -> Option<StopReason> {
+    let mut next_frame = Instant::now();
+    loop {
+        if !events(cpu) {
+            return None;
+        }
+        match cpu.advance(cpu.instructions_per_frame() as usize).stopped {
+            StopReason::InstructionBudgetExhausted | StopReason::WaitingForVblank | StopReason::WaitingForKey => {}
+            reason => return Some(reason),
+        }
+        wait_frame(&mut next_frame);
+    }
+}
@@ -0,0 +1,443 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use cpu_caller::{Access, StopReason, CPU};
+
+use crate::cli::{Options, Source};
+
+/// Registers in the order of `g` packets, with their size in bytes
+const REGISTERS: [(&str, usize); 21] = [
+    ("v0", 1), ("v1", 1), ("v2", 1), ("v3", 1), ("v4", 1), ("v5", 1), ("v6", 1), ("v7", 1),
+    ("v8", 1), ("v9", 1), ("va", 1), ("vb", 1), ("vc", 1), ("vd", 1), ("ve", 1), ("vf", 1),
+    ("i", 2), ("pc", 2), ("sp", 1), ("dt", 1), ("st", 1),
+];
+
+/// Byte that gdb sends to interrupt a running program, Ctrl-C
+const INTERRUPT: u8 = 0x03;
+
+/// What the stub does after answering a packet
+enum Next {
+    Wait,     // read the next packet
+    Continue, // run until something stops the program
+    Step,     // execute one instruction
+    Detach,   // the client left, wait for another one
+}
+
+/// Waits for gdb, or anything speaking its remote protocol, on `port` and
+/// lets it control the program at `path`: registers, memory, breakpoints,
+/// watchpoints, continue and step
+pub fn run(path: &str, port: u16, options: &Options) {
+    let mut cpu = crate::build_cpu(&Source::File(path.to_string()), options);
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("error: can't listen on port {}: {}", port, e);
+            std::process::exit(1);
+        }
+    };
+    println!("waiting for gdb on port {}, connect with `target remote :{}`", port, port);
+
+    for stream in listener.incoming() {
+        let result = stream.and_then(|stream| serve(&mut cpu, stream));
+        match result {
+            Ok(true) => break, // killed
+            Ok(false) => println!("gdb detached, waiting for another one"),
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+}
+
+/// Answers the packets of one connection, returns whether gdb killed the program
+fn serve(cpu: &mut CPU, stream: TcpStream) -> io::Result<bool> {
+    let mut session = Session { stream };
+    loop {
+        let Some(packet) = read_packet(&mut session.stream)? else {
+            return Ok(false);
+        };
+        if packet.starts_with('k') {
+            return Ok(true);
+        }
+        let (reply, next) = answer(cpu, &packet);
+        if let Some(reply) = reply {
+            session.write_packet(&reply)?;
+        }
+        match next {
+            Next::Wait => {}
+            Next::Detach => return Ok(false),
+            Next::Continue => {
+                let reply = session.resume(cpu)?;
+                session.write_packet(&reply)?;
+            }
+            Next::Step => session.write_packet(&stop_reply(step(cpu)))?,
+        }
+    }
+}
+
+/// Connection with a client
+struct Session {
+    stream: TcpStream, // unbuffered, to see an interrupt as soon as it comes
+}
+
+impl Session {
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        write!(self.stream, "${}#{:02x}", data, sum(data.as_bytes()))?;
+        self.stream.flush()
+    }
+
+    /// Runs the program a frame at a time until it stops or the client
+    /// interrupts it, and tells why it stopped
+    fn resume(&mut self, cpu: &mut CPU) -> io::Result<String> {
+        self.stream.set_nonblocking(true)?;
+        let reason = loop {
+            match cpu.advance(cpu.instructions_per_frame() as usize).stopped {
+                StopReason::InstructionBudgetExhausted | StopReason::WaitingForVblank => {}
+                reason => break stop_reply(reason),
+            }
+            let mut byte = [0];
+            match self.stream.peek(&mut byte) {
+                Ok(0) => break String::new(), // gone, the next read says so
+                Ok(_) if byte[0] == INTERRUPT => {
+                    let _ = self.stream.read(&mut byte);
+                    break "S02".to_string(); // SIGINT
+                }
+                Err(e) if e.kind() != io::ErrorKind::WouldBlock => {
+                    self.stream.set_nonblocking(false)?;
+                    return Err(e);
+                }
+                _ => {}
+            }
+        };
+        self.stream.set_nonblocking(false)?;
+        Ok(reason)
+    }
+}
+
+/// Next `$packet#checksum` of `stream`, acknowledged, `None` once the client is gone
+fn read_packet(stream: &mut (impl Read + Write)) -> io::Result<Option<String>> {
+    let mut byte = [0];
+    loop {
+        loop {
+            // acknowledgements and interrupts between packets don't matter here
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+
+        let mut data = Vec::new();
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut checksum = [0; 2];
+        stream.read_exact(&mut checksum)?;
+
+        let expected = std::str::from_utf8(&checksum).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        if expected == Some(sum(&data)) {
+            stream.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+        stream.write_all(b"-")?; // asks for it again
+    }
+}
+
+/// Executes one instruction, going past a breakpoint the PC is on
+fn step(cpu: &mut CPU) -> StopReason {
+    let executed = cpu.instructions_executed();
+    // waiting for the screen takes a few cycles before the instruction runs
+    while cpu.instructions_executed() == executed {
+        match cpu.advance(1).stopped {
+            StopReason::InstructionBudgetExhausted | StopReason::WaitingForVblank | StopReason::Breakpoint(_) => {}
+            reason => return reason,
+        }
+    }
+    StopReason::InstructionBudgetExhausted
+}
+
+/// Reply to a packet and what to do next, `None` for packets that only resume
+fn answer(cpu: &mut CPU, packet: &str) -> (Option<String>, Next) {
+    let ok = || Some("OK".to_string());
+    let reply = match packet.split_at(packet.len().min(1)) {
+        ("?", _) => Some("S05".to_string()),
+        ("g", _) => Some(read_registers(cpu)),
+        ("G", hex) => write_registers(cpu, hex).and(ok()),
+        ("p", number) => usize::from_str_radix(number, 16).ok().and_then(|n| read_register(cpu, n)),
+        ("P", assignment) => assignment
+            .split_once('=')
+            .and_then(|(number, value)| write_register(cpu, usize::from_str_radix(number, 16).ok()?, value))
+            .and(ok()),
+        ("m", args) => read_memory(cpu, args),
+        ("M", args) => write_memory(cpu, args).and(ok()),
+        ("c", _) => return (None, Next::Continue),
+        ("s", _) => return (None, Next::Step),
+        ("Z", args) => set_point(cpu, args, true).and(ok()),
+        ("z", args) => set_point(cpu, args, false).and(ok()),
+        ("D", _) => return (ok(), Next::Detach),
+        ("H", _) => ok(),
+        ("q", _) => query(packet),
+        // anything else isn't supported, an empty reply tells so
+        _ => Some(String::new()),
+    };
+    // a malformed packet gets an error
+    (Some(reply.unwrap_or_else(|| "E01".to_string())), Next::Wait)
+}
+
+/// Answers the `q` packets gdb sends when it connects
+fn query(packet: &str) -> Option<String> {
+    let reply = match packet.split(':').next()? {
+        "qSupported" => "PacketSize=1000;qXfer:features:read+".to_string(),
+        "qAttached" => "1".to_string(),
+        "qC" => "QC1".to_string(),
+        "qfThreadInfo" => "m1".to_string(),
+        "qsThreadInfo" => "l".to_string(),
+        "qXfer" if packet.starts_with("qXfer:features:read:target.xml:") => {
+            let (offset, length) = packet.rsplit(':').next()?.split_once(',')?;
+            let (offset, length) = (usize::from_str_radix(offset, 16).ok()?, usize::from_str_radix(length, 16).ok()?);
+            let xml = target_xml();
+            let part = xml.get(offset..).unwrap_or("");
+            // `m` when there's more to read, `l` for the last part
+            if part.len() > length {
+                format!("m{}", &part[..length])
+            } else {
+                format!("l{}", part)
+            }
+        }
+        _ => String::new(),
+    };
+    Some(reply)
+}
+
+/// Description of the registers, gdb doesn't know this machine
+fn target_xml() -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\"><target><feature name=\"org.cpu-caller.chip8\">");
+    for (number, (name, size)) in REGISTERS.iter().enumerate() {
+        let kind = if *name == "pc" { " type=\"code_ptr\"" } else { "" };
+        xml += &format!("<reg name=\"{}\" bitsize=\"{}\" regnum=\"{}\"{}/>", name, size * 8, number, kind);
+    }
+    xml + "</feature></target>"
+}
+
+/// Value of register `number` of `REGISTERS`
+fn register(cpu: &CPU, number: usize) -> Option<u64> {
+    Some(match REGISTERS.get(number)?.0 {
+        "i" => cpu.i() as u64,
+        "pc" => cpu.pc() as u64,
+        "sp" => cpu.stack_pointer() as u64,
+        "dt" => cpu.delay_timer() as u64,
+        "st" => cpu.sound_timer() as u64,
+        _ => cpu.register(number as u8) as u64,
+    })
+}
+
+/// Little-endian hexadecimal of register `number`
+fn read_register(cpu: &CPU, number: usize) -> Option<String> {
+    let value = register(cpu, number)?;
+    let size = REGISTERS[number].1;
+    Some(value.to_le_bytes()[..size].iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn read_registers(cpu: &CPU) -> String {
+    (0..REGISTERS.len()).filter_map(|number| read_register(cpu, number)).collect()
+}
+
+/// Sets register `number` from its little-endian hexadecimal, the stack pointer can't change
+fn write_register(cpu: &mut CPU, number: usize, hex: &str) -> Option<()> {
+    let bytes = parse_hex(hex)?;
+    let (name, size) = *REGISTERS.get(number)?;
+    let value = bytes.iter().take(size).rev().fold(0u64, |value, &b| value << 8 | b as u64);
+    match name {
+        "i" => cpu.set_i(value as u16),
+        "pc" => cpu.set_pc(value as usize),
+        "sp" => {}
+        "dt" => cpu.set_delay_timer(value as u8),
+        "st" => cpu.set_sound_timer(value as u8),
+        _ => cpu.set_register(number as u8, value as u8),
+    }
+    Some(())
+}
+
+fn write_registers(cpu: &mut CPU, hex: &str) -> Option<()> {
+    let mut offset = 0;
+    for (number, (_, size)) in REGISTERS.iter().enumerate() {
+        write_register(cpu, number, hex.get(offset..offset + size * 2)?)?;
+        offset += size * 2;
+    }
+    Some(())
+}
+
+/// `addr,length`
+fn read_memory(cpu: &CPU, args: &str) -> Option<String> {
+    let (addr, len) = args.split_once(',')?;
+    let (addr, len) = (usize::from_str_radix(addr, 16).ok()?, usize::from_str_radix(len, 16).ok()?);
+    // gdb reads past the end of memory while looking around, it gets what there is
+    let len = len.min(cpu.memory_size().saturating_sub(addr));
+    let bytes = cpu.read_memory(addr, len).ok()?;
+    Some(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// `addr,length:bytes`
+fn write_memory(cpu: &mut CPU, args: &str) -> Option<()> {
+    let (range, hex) = args.split_once(':')?;
+    let addr = usize::from_str_radix(range.split_once(',')?.0, 16).ok()?;
+    let bytes = parse_hex(hex)?;
+    addr.checked_add(bytes.len())?;
+    cpu.write_memory(addr, &bytes).ok()
+}
+
+/// `type,addr,kind` of `Z` and `z`: breakpoints and write, read or access watchpoints
+fn set_point(cpu: &mut CPU, args: &str, insert: bool) -> Option<()> {
+    let mut fields = args.split(',');
+    let kind = fields.next()?;
+    let addr = usize::from_str_radix(fields.next()?, 16).ok()?;
+    let len = fields.next().and_then(|len| usize::from_str_radix(len, 16).ok()).unwrap_or(1).max(1);
+    let access = match kind {
+        "0" | "1" if insert => {
+            cpu.add_breakpoint(addr);
+            return Some(());
+        }
+        "0" | "1" => {
+            cpu.remove_breakpoint(addr);
+            return Some(());
+        }
+        "2" => Access::Write,
+        "3" => Access::Read,
+        "4" => Access::Any,
+        _ => return None,
+    };
+    let range = addr..addr.checked_add(len)?;
+    if insert {
+        cpu.add_watchpoint(range, access);
+    } else {
+        cpu.remove_watchpoint(range);
+    }
+    Some(())
+}
+
+/// Stop reply for why the program stopped, as a signal number
+fn stop_reply(reason: StopReason) -> String {
+    match reason {
+        StopReason::Watchpoint { addr, access, .. } => {
+            let kind = match access {
+                Access::Write => "watch",
+                Access::Read => "rwatch",
+                Access::Any => "awatch",
+            };
+            format!("T05{}:{:x};", kind, addr)
+        }
+        StopReason::HaltOpcode | StopReason::ExitOpcode => "W00".to_string(),
+        StopReason::Error(_) => "S04".to_string(), // SIGILL
+        _ => "S05".to_string(), // SIGTRAP, also for a key nothing can press from gdb
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Checksum of a packet, the sum of its bytes
+fn sum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    /// Both ends of a connection, what the client sent and what the stub answered
+    struct Wire {
+        sent: Cursor<Vec<u8>>,
+        answered: Vec<u8>,
+    }
+
+    impl Read for Wire {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.sent.read(buf)
+        }
+    }
+
+    impl Write for Wire {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.answered.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn reply(cpu: &mut CPU, packet: &str) -> String {
+        answer(cpu, packet).0.unwrap()
+    }
+
+    #[test]
+    fn checksums() {
+        let mut wire = Wire { sent: Cursor::new(b"+$g#00$g#67".to_vec()), answered: Vec::new() };
+        assert_eq!(read_packet(&mut wire).unwrap().as_deref(), Some("g"));
+        assert_eq!(wire.answered, b"-+");
+        assert_eq!(read_packet(&mut wire).unwrap(), None);
+    }
+
+    #[test]
+    fn corrupt_packets_dont_grow_the_stack() {
+        let mut sent = b"$g#00".repeat(100_000);
+        sent.extend_from_slice(b"$?#3f");
+        let mut wire = Wire { sent: Cursor::new(sent), answered: Vec::new() };
+        assert_eq!(read_packet(&mut wire).unwrap().as_deref(), Some("?"));
+        assert_eq!(wire.answered.len(), 100_001);
+    }
+
+    #[test]
+    fn memory() {
+        let mut cpu = CPU::new();
+        assert_eq!(reply(&mut cpu, "M300,3:0a0b0c"), "OK");
+        assert_eq!(reply(&mut cpu, "m2ff,5"), "000a0b0c00");
+        // past the end gives what there is
+        assert_eq!(reply(&mut cpu, "mffe,10"), "0000");
+        assert_eq!(reply(&mut cpu, "M1000,1:01"), "E01");
+        assert_eq!(reply(&mut cpu, "Mffffffffffffffff,2:0102"), "E01");
+        assert_eq!(reply(&mut cpu, "m300"), "E01");
+    }
+
+    #[test]
+    fn points() {
+        let mut cpu = CPU::new();
+        assert_eq!(reply(&mut cpu, "Z0,204,2"), "OK");
+        assert_eq!(cpu.breakpoints().collect::<Vec<_>>(), [0x204]);
+        assert_eq!(reply(&mut cpu, "z0,204,2"), "OK");
+        assert_eq!(cpu.breakpoints().count(), 0);
+
+        assert_eq!(reply(&mut cpu, "Z2,300,2"), "OK");
+        assert_eq!(reply(&mut cpu, "Z3,310,1"), "OK");
+        assert_eq!(cpu.watchpoints().collect::<Vec<_>>(), [(0x300..0x302, Access::Write), (0x310..0x311, Access::Read)]);
+        assert_eq!(reply(&mut cpu, "z2,300,2"), "OK");
+        assert_eq!(cpu.watchpoints().count(), 1);
+
+        assert_eq!(reply(&mut cpu, "Z2,ffffffffffffffff,2"), "E01");
+        assert_eq!(reply(&mut cpu, "Z9,300,1"), "E01");
+    }
+
+    #[test]
+    fn registers() {
+        let mut cpu = CPU::new();
+        cpu.set_register(0xA, 0x42);
+        cpu.set_i(0x1234);
+        cpu.set_pc(0x200);
+        let registers = reply(&mut cpu, "g");
+        // v0-vf, i and pc little-endian, sp, dt and st
+        assert_eq!(registers, format!("{}42{}34120002000000", "00".repeat(10), "00".repeat(5)));
+
+        let written = format!("{}07cdab0403000506", "00".repeat(15));
+        assert_eq!(reply(&mut cpu, &format!("G{}", written)), "OK");
+        assert_eq!((cpu.register(0xF), cpu.i(), cpu.pc()), (0x07, 0xABCD, 0x304));
+        assert_eq!((cpu.delay_timer(), cpu.sound_timer()), (0x05, 0x06));
+        assert_eq!(reply(&mut cpu, "G00"), "E01");
+    }
+}
@@ -0,0 +1,291 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use winit::window::Window;
+
+use cpu_caller::{Framebuffer, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+use crate::cli::{Options, Scaling, Shader};
+use crate::config::Palette;
+use crate::frontend;
+use crate::window::Surface;
+
+/// Format of the texture the framebuffer is uploaded to, the colors of the
+/// palette go through unchanged
+const TEXTURE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+
+/// Window drawn with wgpu: the framebuffer is uploaded as a texture and a
+/// shader scales it to the size `--scale`, `--fit` or `--stretch` give it,
+/// applying the effect of `--shader` on the way
+pub struct GpuScreen {
+    surface: wgpu::Surface<'static>,
+    config: wgpu::SurfaceConfiguration,
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pipeline: wgpu::RenderPipeline,
+    layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    locals: wgpu::Buffer,
+    texture: wgpu::Texture,
+    bind_group: wgpu::BindGroup,
+    palette: Palette,
+    shader: Shader,
+    scaling: Scaling,
+    size: (u32, u32), // of the framebuffer drawn last
+}
+
+/// Opens the surface of the `wgpu` frontend
+pub fn open(window: Arc<Window>, options: &Options) -> Result<Rc<RefCell<dyn Surface>>, String> {
+    Ok(Rc::new(RefCell::new(GpuScreen::open(window, options)?)))
+}
+
+impl GpuScreen {
+    fn open(window: Arc<Window>, options: &Options) -> Result<GpuScreen, String> {
+        let size = window.inner_size();
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::new_without_display_handle_from_env());
+        let surface = instance.create_surface(window).map_err(|e| e.to_string())?;
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            compatible_surface: Some(&surface),
+            ..Default::default()
+        }))
+        .map_err(|e| e.to_string())?;
+        let (device, queue) = pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor::default())).map_err(|e| e.to_string())?;
+
+        let mut config = surface
+            .get_default_config(&adapter, size.width.max(1), size.height.max(1))
+            .ok_or("the window can't be drawn with this GPU")?;
+        // the palette is in sRGB already, a linear surface leaves it as it is
+        let formats = surface.get_capabilities(&adapter).formats;
+        config.format = formats.iter().copied().find(|format| !format.is_srgb()).unwrap_or(config.format);
+        surface.configure(&device, &config);
+
+        let module = device.create_shader_module(wgpu::include_wgsl!("gpu.wgsl"));
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("picture"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("picture"),
+            bind_group_layouts: &[Some(&layout)],
+            immediate_size: 0,
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("picture"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &module,
+                entry_point: Some("vs_main"),
+                buffers: &[],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            },
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            fragment: Some(wgpu::FragmentState {
+                module: &module,
+                entry_point: Some("fs_main"),
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: config.format,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            multiview_mask: None,
+            cache: None,
+        });
+
+        // the pixels stay sharp squares however much they're scaled
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("nearest"),
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        let locals = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("locals"),
+            size: 12 * 4,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let (width, height) = (DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32);
+        let texture = create_texture(&device, width, height);
+        let bind_group = create_bind_group(&device, &layout, &texture, &sampler, &locals);
+        let mut screen = GpuScreen {
+            surface,
+            config,
+            device,
+            queue,
+            pipeline,
+            layout,
+            sampler,
+            locals,
+            texture,
+            bind_group,
+            palette: options.palette,
+            shader: options.shader,
+            scaling: options.scaling,
+            size: (width, height),
+        };
+        screen.write_locals();
+        Ok(screen)
+    }
+
+    /// Tells the shader where the picture goes in the window and what to do with it
+    fn write_locals(&mut self) {
+        let (width, height) = self.size;
+        let window = (self.config.width, self.config.height);
+        let (picture_width, picture_height) = frontend::picture_size(self.scaling, (width as usize, height as usize), window);
+        let effect = match self.shader {
+            Shader::None => 0.0,
+            Shader::Scanlines => 1.0,
+            Shader::Crt => 2.0,
+        };
+        let [r, g, b] = self.palette.background.map(|c| c as f32 / 255.0);
+        let locals: [f32; 12] = [
+            picture_width as f32 / window.0 as f32,
+            picture_height as f32 / window.1 as f32,
+            width as f32,
+            height as f32,
+            effect,
+            0.0,
+            0.0,
+            0.0,
+            r,
+            g,
+            b,
+            1.0,
+        ];
+        let bytes: Vec<u8> = locals.iter().flat_map(|value| value.to_ne_bytes()).collect();
+        self.queue.write_buffer(&self.locals, 0, &bytes);
+    }
+}
+
+impl Surface for GpuScreen {
+    fn draw(&mut self, framebuffer: &Framebuffer) {
+        let (width, height) = (framebuffer.width() as u32, framebuffer.height() as u32);
+        if (width, height) != self.size {
+            self.texture = create_texture(&self.device, width, height);
+            self.bind_group = create_bind_group(&self.device, &self.layout, &self.texture, &self.sampler, &self.locals);
+            self.size = (width, height);
+            self.write_locals();
+        }
+
+        let pixels: Vec<u8> = frontend::colors(framebuffer, &self.palette).flat_map(|[r, g, b]| [r, g, b, 0xFF]).collect();
+        self.queue.write_texture(
+            self.texture.as_image_copy(),
+            &pixels,
+            wgpu::TexelCopyBufferLayout { offset: 0, bytes_per_row: Some(width * 4), rows_per_image: Some(height) },
+            self.texture.size(),
+        );
+        self.redraw();
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        // a minimized window has no size to draw in
+        if width == 0 || height == 0 {
+            return;
+        }
+        self.config.width = width;
+        self.config.height = height;
+        self.surface.configure(&self.device, &self.config);
+        self.write_locals();
+        self.redraw();
+    }
+
+    fn redraw(&mut self) {
+        let frame = match self.surface.get_current_texture() {
+            wgpu::CurrentSurfaceTexture::Success(frame) | wgpu::CurrentSurfaceTexture::Suboptimal(frame) => frame,
+            wgpu::CurrentSurfaceTexture::Outdated | wgpu::CurrentSurfaceTexture::Lost => {
+                // the next frame is drawn on a surface set up again
+                self.surface.configure(&self.device, &self.config);
+                return;
+            }
+            _ => return,
+        };
+        let view = frame.texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("frame") });
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("picture"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations { load: wgpu::LoadOp::Clear(wgpu::Color::BLACK), store: wgpu::StoreOp::Store },
+                    depth_slice: None,
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+                multiview_mask: None,
+            });
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &self.bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+        self.queue.submit(Some(encoder.finish()));
+        frame.present();
+    }
+}
+
+/// Texture the framebuffer is uploaded to, one texel per pixel
+fn create_texture(device: &wgpu::Device, width: u32, height: u32) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("framebuffer"),
+        size: wgpu::Extent3d { width, height, depth_or_array_layers: 1 },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: TEXTURE_FORMAT,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    })
+}
+
+fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    texture: &wgpu::Texture,
+    sampler: &wgpu::Sampler,
+    locals: &wgpu::Buffer,
+) -> wgpu::BindGroup {
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("picture"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry { binding: 0, resource: wgpu::BindingResource::TextureView(&view) },
+            wgpu::BindGroupEntry { binding: 1, resource: wgpu::BindingResource::Sampler(sampler) },
+            wgpu::BindGroupEntry { binding: 2, resource: locals.as_entire_binding() },
+        ],
+    })
+}
@@ -0,0 +1,62 @@
+// Scales the picture of the CHIP-8 up to the window and applies the effect
+// of `--shader` on the way
+
+struct Locals {
+    area: vec4<f32>,       // xy: part of the window the picture covers, zw: pixels of the picture
+    effect: vec4<f32>,     // x: 0 for none, 1 for scanlines, 2 for CRT
+    background: vec4<f32>, // around the picture
+}
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>, // 0, 0 at the top left corner of the window, 1, 1 at the bottom right one
+}
+
+@group(0) @binding(0) var picture: texture_2d<f32>;
+@group(0) @binding(1) var nearest: sampler;
+@group(0) @binding(2) var<uniform> locals: Locals;
+
+const PI: f32 = 3.14159265;
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    // one triangle larger than the window covers all of it
+    let corner = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.position = vec4<f32>(corner * 2.0 - 1.0, 0.0, 1.0);
+    out.uv = vec2<f32>(corner.x, 1.0 - corner.y);
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let effect = locals.effect.x;
+    var uv = (in.uv - 0.5) / locals.area.xy + 0.5;
+    if effect >= 2.0 {
+        // the glass bulges, pushing the edges of the picture out
+        let centered = uv - 0.5;
+        uv = 0.5 + centered * (1.0 + 0.15 * dot(centered, centered));
+    }
+    if any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0)) {
+        return locals.background;
+    }
+
+    var color = textureSampleLevel(picture, nearest, uv, 0.0).rgb;
+    if effect >= 2.0 {
+        // the light of the phosphors bleeds on the neighbours
+        let texel = vec2<f32>(1.0 / locals.area.z, 0.0);
+        let left = textureSampleLevel(picture, nearest, uv - texel, 0.0).rgb;
+        let right = textureSampleLevel(picture, nearest, uv + texel, 0.0).rgb;
+        color = max(color, (left + right) * 0.25);
+    }
+    if effect >= 1.0 {
+        // darkest between two rows of pixels, untouched in their middle
+        let row = fract(uv.y * locals.area.w);
+        color *= 0.6 + 0.4 * sin(row * PI);
+    }
+    if effect >= 2.0 {
+        let centered = uv - 0.5;
+        color *= 1.0 - 1.5 * dot(centered, centered) * dot(centered, centered);
+    }
+    return vec4<f32>(color, 1.0);
+}
@@ -0,0 +1,45 @@
+use crate::PLANES;
+
+/// Screen the CPU presents its frames to
+pub trait Display {
+    /// Shows a new frame, called at most once per 60 Hz tick and only when the picture changed.
+    /// Each plane holds `width * height` pixels row by row, `true` means lit. The size goes
+    /// from 64x32 to 128x64 and back whenever the program switches modes with 00FF and 00FE
+    fn present(&mut self, width: usize, height: usize, planes: [&[bool]; PLANES]);
+}
+
+/// Speaker the CPU plays its tone through
+pub trait AudioSink {
+    /// Starts or updates the tone: the 128 bits of `pattern` are played
+    /// most significant bit first, in a loop, at `frequency` bits per second
+    fn play(&mut self, pattern: &[u8; 16], frequency: f64);
+
+    /// Silences the tone, can be called while already silent
+    fn stop(&mut self);
+}
+
+/// Hexadecimal keyboard the CPU reads key events from
+pub trait Keypad {
+    /// Returns the next key event as the key 0-F and whether it was pressed,
+    /// `None` once there are no more events
+    fn poll(&mut self) -> Option<(u8, bool)>;
+}
+
+/// Display, speaker and keyboard that do nothing, used until a frontend provides its own
+pub struct Null;
+
+impl Display for Null {
+    fn present(&mut self, _width: usize, _height: usize, _planes: [&[bool]; PLANES]) {}
+}
+
+impl AudioSink for Null {
+    fn play(&mut self, _pattern: &[u8; 16], _frequency: f64) {}
+
+    fn stop(&mut self) {}
+}
+
+impl Keypad for Null {
+    fn poll(&mut self) -> Option<(u8, bool)> {
+        None
+    }
+}
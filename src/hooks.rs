@@ -0,0 +1,25 @@
+use alloc::boxed::Box;
+
+use crate::decoder::Instruction;
+
+/// Called before an instruction runs with its address and opcode
+pub type FetchHook = Box<dyn FnMut(u16, u16)>;
+
+/// Called after an instruction ran
+pub type ExecuteHook = Box<dyn FnMut(Instruction)>;
+
+/// Called when the program writes a byte with its address and value
+pub type MemoryWriteHook = Box<dyn FnMut(usize, u8)>;
+
+/// Called when a subroutine call pushes its return address
+pub type StackPushHook = Box<dyn FnMut(u16)>;
+
+/// Callbacks that observe execution, tracers and profilers register them
+/// on the CPU instead of running their own copy of the dispatch loop
+#[derive(Default)]
+pub(crate) struct Hooks {
+    pub on_fetch: Option<FetchHook>,
+    pub on_execute: Option<ExecuteHook>,
+    pub on_memory_write: Option<MemoryWriteHook>, // writes made by the frontend are not reported
+    pub on_stack_push: Option<StackPushHook>,
+}
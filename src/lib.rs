@@ -0,0 +1,99 @@
+//! CPU emulator for CHIP-8 programs and its SUPER-CHIP and XO-CHIP extensions.
+//!
+//! Build a [`CPU`], load a program into it and execute it either one
+//! instruction at a time with [`CPU::step`] or until it stops with [`CPU::run`]:
+//!
+//! ```no_run
+//! use cpu_caller::{StopReason, CPU};
+//!
+//! let rom = std::fs::read("pong.ch8").unwrap();
+//! let mut cpu = CPU::new();
+//! cpu.load_rom(&rom).unwrap();
+//!
+//! assert_eq!(cpu.run(), StopReason::HaltOpcode);
+//! ```
+//!
+//! The default `std` feature can be disabled to build the emulator for
+//! bare-metal targets, it then only needs an allocator.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+mod asm;
+mod builder;
+mod bus;
+mod calls;
+mod checkpoint;
+mod cpu;
+pub mod decoder;
+mod detect;
+mod disasm;
+mod display;
+#[cfg(feature = "std")]
+mod driver;
+mod error;
+mod expr;
+mod flow;
+mod font;
+mod hardware;
+mod hooks;
+mod lint;
+mod pattern;
+#[cfg(feature = "std")]
+mod loader;
+pub mod profiles;
+mod quirks;
+mod rng;
+mod snapshot;
+mod stats;
+mod symbols;
+#[cfg(feature = "std")]
+mod thread;
+mod trace;
+mod watchdog;
+
+#[cfg(feature = "std")]
+pub use asm::assemble_file;
+pub use asm::{assemble, assemble_with, AsmError, Program};
+pub use builder::{BuildError, CpuBuilder};
+pub use bus::{Bus, Device, FlatMemory, MappedBus};
+pub use calls::{call_graph, CallGraph};
+pub use checkpoint::Checkpointer;
+pub use cpu::{Access, RunSummary, StepOutcome, StopReason, SysHandler, SysPolicy, CPU, HISTORY_LEN};
+pub use detect::{detect_variant, detect_variant_at};
+pub use disasm::{disassemble, disassemble_at, find_labels, label, DisasmLine};
+pub use display::{Framebuffer, Renderer};
+#[cfg(feature = "std")]
+pub use driver::{run_until_stop, Pause, Resume, RunControl, RunUntilStop};
+pub use error::CpuError;
+pub use expr::Expr;
+pub use flow::{control_flow, BasicBlock, ControlFlowGraph, Edge};
+pub use font::{BIG_FONT_ADDRESS, FONT_ADDRESS};
+pub use hardware::{AudioSink, Display, Keypad, Null};
+pub use lint::{check_rom, Issue};
+pub use pattern::OpcodePattern;
+#[cfg(feature = "std")]
+pub use loader::{parse_hex, parse_intel_hex, HexImage, LoadError};
+pub use quirks::{Machine, Quirks, Variant};
+pub use rng::{Rng, XorShiftRng};
+pub use snapshot::{Change, Snapshot};
+pub use stats::{rom_stats, RomStats};
+pub use symbols::Symbols;
+#[cfg(feature = "std")]
+pub use thread::{Command, EmulatorThread, Event};
+pub use trace::{HistoryEntry, RegisterDelta, TraceRecord, TraceSink};
+
+pub const DISPLAY_WIDTH: usize = 64;
+pub const DISPLAY_HEIGHT: usize = 32;
+// SUPER-CHIP high resolution mode doubles both dimensions
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+
+// the 16-bit I register of XO-CHIP can address this much memory
+pub const XO_MEMORY_SIZE: usize = 0x10000;
+// programs start after the 512 bytes where the original interpreter lived
+pub const PROGRAM_START: usize = 0x200;
+// XO-CHIP bit planes, composited into colors by the frontends
+pub const PLANES: usize = 2;
+// nesting level of subroutine calls of the original interpreters
+pub const STACK_DEPTH: usize = 16;
@@ -0,0 +1,145 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::calls::{body, call_graph};
+use crate::decoder::Instruction;
+use crate::flow::{control_flow, Edge};
+use crate::{Variant, PROGRAM_START};
+
+/// Likely mistake found in a ROM without running it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Issue {
+    UnknownOpcode { addr: usize, opcode: u16 },       // reachable opcode that doesn't exist on the machine
+    OutsideProgram { addr: usize, target: usize },    // jump or call to memory the ROM doesn't fill
+    RunsOffEnd { addr: usize },                       // execution goes on past the last byte of the ROM
+    ReturnWithoutCall { addr: usize },                // 00EE reached outside of any subroutine
+    NoReturn { function: usize },                     // subroutine that leaves without 00EE, the stack grows
+    WriteBelowProgram { addr: usize, target: usize }, // FX33, FX55 or 5XY2 with I under 0x200
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Issue::UnknownOpcode { addr, opcode } => write!(f, "{:#05x}: unknown opcode {:04X}", addr, opcode),
+            Issue::OutsideProgram { addr, target } => {
+                write!(f, "{:#05x}: goes to {:#05x}, outside of the program", addr, target)
+            }
+            Issue::RunsOffEnd { addr } => write!(f, "{:#05x}: execution runs past the end of the program", addr),
+            Issue::ReturnWithoutCall { addr } => write!(f, "{:#05x}: returns without being in a subroutine", addr),
+            Issue::NoReturn { function } => write!(f, "{:#05x}: subroutine never returns", function),
+            Issue::WriteBelowProgram { addr, target } => {
+                write!(f, "{:#05x}: writes to {:#05x}, below the program", addr, target)
+            }
+        }
+    }
+}
+
+/// Looks for mistakes in the code of `rom` loaded at `base` reachable from `entry`,
+/// sorted by address
+pub fn check_rom(rom: &[u8], base: usize, entry: usize, variant: Variant) -> Vec<Issue> {
+    let graph = control_flow(rom, base, entry, variant);
+    let mut issues = Vec::new();
+
+    // nothing to follow when the program doesn't even start in the ROM
+    if !(base..base + rom.len()).contains(&entry) {
+        let issue = if rom.is_empty() { Issue::RunsOffEnd { addr: entry } } else { Issue::OutsideProgram { addr: entry, target: entry } };
+        issues.push(issue);
+    }
+
+    for block in graph.blocks.values() {
+        let last = block.lines[block.lines.len() - 1].addr;
+        // value of I while the block runs, when it is set by the block itself
+        let mut i = None;
+
+        for line in &block.lines {
+            match line.instruction {
+                None => issues.push(Issue::RunsOffEnd { addr: line.addr }),
+                Some(Instruction::Unknown(opcode)) => issues.push(Issue::UnknownOpcode { addr: line.addr, opcode }),
+                Some(Instruction::LoadI { addr }) => i = Some(addr as usize),
+                Some(Instruction::LoadILong) => i = line.long_address().map(usize::from),
+                Some(Instruction::StoreRegs { .. } | Instruction::Bcd { .. } | Instruction::SaveRange { .. }) => {
+                    if let Some(target) = i.filter(|&target| target < PROGRAM_START) {
+                        issues.push(Issue::WriteBelowProgram { addr: line.addr, target });
+                    }
+                    i = None; // some machines move I past what was written
+                }
+                Some(
+                    Instruction::AddI { .. }
+                    | Instruction::LoadRegs { .. }
+                    | Instruction::LoadFont { .. }
+                    | Instruction::LoadBigFont { .. },
+                ) => i = None,
+                Some(_) => {}
+            }
+        }
+
+        for &(target, edge) in &block.successors {
+            if graph.blocks.contains_key(&target) {
+                continue;
+            }
+            match edge {
+                Edge::Jump | Edge::Computed | Edge::Call => issues.push(Issue::OutsideProgram { addr: last, target }),
+                Edge::Fallthrough | Edge::Skip => issues.push(Issue::RunsOffEnd { addr: last }),
+            }
+        }
+    }
+
+    // calls and returns have to come in pairs
+    let calls = call_graph(rom, base, entry, variant);
+    for &function in calls.functions.keys() {
+        let lines = body(&graph, function)
+            .into_iter()
+            .filter_map(|start| graph.blocks.get(&start))
+            .flat_map(|block| &block.lines);
+        let mut returns = Vec::new();
+        let mut stops = false;
+        for line in lines {
+            match line.instruction {
+                Some(Instruction::Ret) => returns.push(line.addr),
+                Some(Instruction::Halt | Instruction::Exit) => stops = true,
+                _ => {}
+            }
+        }
+
+        if function == entry {
+            issues.extend(returns.into_iter().map(|addr| Issue::ReturnWithoutCall { addr }));
+        } else if returns.is_empty() && !stops {
+            issues.push(Issue::NoReturn { function });
+        }
+    }
+
+    issues.sort_by_key(|issue| match *issue {
+        Issue::UnknownOpcode { addr, .. }
+        | Issue::OutsideProgram { addr, .. }
+        | Issue::RunsOffEnd { addr }
+        | Issue::ReturnWithoutCall { addr }
+        | Issue::WriteBelowProgram { addr, .. } => addr,
+        Issue::NoReturn { function } => function,
+    });
+    issues.dedup();
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_rom() {
+        let issues = check_rom(&[], PROGRAM_START, PROGRAM_START, Variant::Chip8);
+        assert_eq!(issues, [Issue::RunsOffEnd { addr: PROGRAM_START }]);
+    }
+
+    #[test]
+    fn one_byte_rom() {
+        let issues = check_rom(&[0x12], PROGRAM_START, PROGRAM_START, Variant::Chip8);
+        assert_eq!(issues, [Issue::RunsOffEnd { addr: PROGRAM_START }]);
+    }
+
+    #[test]
+    fn entry_outside_rom() {
+        // a jump to itself would be fine if it was where the program starts
+        let issues = check_rom(&[0x13, 0x00], 0x300, PROGRAM_START, Variant::Chip8);
+        assert_eq!(issues, [Issue::OutsideProgram { addr: PROGRAM_START, target: PROGRAM_START }]);
+    }
+}
@@ -0,0 +1,216 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::{CpuError, CPU, PROGRAM_START, XO_MEMORY_SIZE};
+
+/// Why a program couldn't be loaded from a file
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),                           // the file couldn't be read
+    Empty,                                   // there are no bytes, usually a wrong path or a failed build
+    Cpu(CpuError),                           // the program doesn't fit in memory
+    Format { line: usize, message: String }, // the text representation of the program is wrong
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "{}", e),
+            LoadError::Empty => write!(f, "the file is empty"),
+            LoadError::Cpu(e) => write!(f, "{}", e),
+            LoadError::Format { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+impl From<CpuError> for LoadError {
+    fn from(e: CpuError) -> Self {
+        LoadError::Cpu(e)
+    }
+}
+
+/// Reads the bytes of a program written in hexadecimal, either separated
+/// by whitespace (`60 05 70 01`) or all together (`60057001`)
+pub fn parse_hex(text: &str) -> Result<Vec<u8>, LoadError> {
+    let mut bytes = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let error = |message: String| LoadError::Format { line: index + 1, message };
+
+        for word in line.split_whitespace() {
+            let digits = word.strip_prefix("0x").unwrap_or(word);
+            if digits.len() % 2 != 0 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(error(format!("`{}` is not a sequence of hexadecimal bytes", word)));
+            }
+
+            for pair in digits.as_bytes().chunks(2) {
+                let pair = std::str::from_utf8(pair).expect("hexadecimal digits are ASCII");
+                bytes.push(u8::from_str_radix(pair, 16).expect("the digits were checked"));
+            }
+        }
+    }
+
+    if bytes.is_empty() {
+        return Err(LoadError::Empty);
+    }
+    Ok(bytes)
+}
+
+/// Program read from an Intel HEX file, made of blocks of bytes at given addresses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HexImage {
+    pub segments: Vec<(usize, Vec<u8>)>, // address of the first byte and the bytes, in file order
+    pub start: Option<usize>,            // entry point given by a start address record
+}
+
+impl HexImage {
+    /// First address used by the image and the bytes up to its last one,
+    /// the gaps between segments are zeroed. Fails when they go past the
+    /// largest memory, before anything is allocated for them
+    pub fn flatten(&self) -> Result<(usize, Vec<u8>), CpuError> {
+        let first = self.segments.iter().map(|(addr, _)| *addr).min().unwrap_or(0);
+        let end = self.segments.iter().map(|(addr, data)| addr + data.len()).max().unwrap_or(0);
+        if end > XO_MEMORY_SIZE {
+            return Err(CpuError::RomTooLarge { size: end - first, available: XO_MEMORY_SIZE.saturating_sub(first) });
+        }
+
+        let mut bytes = vec![0; end - first];
+        for (addr, data) in &self.segments {
+            bytes[addr - first..addr - first + data.len()].copy_from_slice(data);
+        }
+        Ok((first, bytes))
+    }
+}
+
+/// Reads a program in the Intel HEX format emitted by many assemblers.
+/// Extended segment and linear address records are honored, the checksum
+/// of every record is verified
+pub fn parse_intel_hex(text: &str) -> Result<HexImage, LoadError> {
+    let mut segments: Vec<(usize, Vec<u8>)> = Vec::new();
+    let mut start = None;
+    let mut base = 0; // set by the extended address records
+
+    for (index, line) in text.lines().enumerate() {
+        let error = |message: &str| LoadError::Format { line: index + 1, message: message.to_string() };
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let record = line.strip_prefix(':').ok_or_else(|| error("records start with `:`"))?;
+        let bytes = parse_hex(record).map_err(|_| error("the record is not hexadecimal"))?;
+
+        // length, address (2 bytes), type, data and checksum
+        if bytes.len() < 5 || bytes.len() != 5 + bytes[0] as usize {
+            return Err(error("the record length doesn't match its data"));
+        }
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(error("wrong checksum"));
+        }
+
+        let addr = (bytes[1] as usize) << 8 | bytes[2] as usize;
+        let data = &bytes[4..bytes.len() - 1];
+        let word = || data.iter().fold(0usize, |value, b| value << 8 | *b as usize);
+        match bytes[3] {
+            0x00 => {
+                let addr = base + addr;
+                // consecutive records usually continue the same segment
+                match segments.last_mut() {
+                    Some((first, bytes)) if *first + bytes.len() == addr => bytes.extend_from_slice(data),
+                    _ => segments.push((addr, data.to_vec())),
+                }
+            }
+            0x01 => break,
+            0x02 if data.len() == 2 => base = word() << 4,
+            0x04 if data.len() == 2 => base = word() << 16,
+            0x03 if data.len() == 4 => start = Some((word() >> 16 << 4) + (word() & 0xFFFF)), // CS:IP
+            0x05 if data.len() == 4 => start = Some(word()),
+            0x02..=0x05 => return Err(error("wrong length for an address record")),
+            _ => return Err(error("unknown record type")),
+        }
+    }
+
+    if segments.iter().all(|(_, data)| data.is_empty()) {
+        return Err(LoadError::Empty);
+    }
+    Ok(HexImage { segments, start })
+}
+
+impl CPU {
+    /// Loads a raw binary like `pong.ch8` at 0x200, see [`CPU::load_rom`].
+    /// Files ending in `.hex` are read as Intel HEX images instead
+    pub fn load_rom_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), LoadError> {
+        let path = path.as_ref();
+        if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("hex")) {
+            let image = parse_intel_hex(&fs::read_to_string(path)?)?;
+            self.load_hex_image(&image)?;
+            return Ok(());
+        }
+
+        let rom = fs::read(path)?;
+        if rom.is_empty() {
+            return Err(LoadError::Empty);
+        }
+        self.load_rom(&rom)?;
+        Ok(())
+    }
+
+    /// Loads every segment of an Intel HEX image at its address and starts at
+    /// its entry point, 0x200 when it doesn't give one
+    pub fn load_hex_image(&mut self, image: &HexImage) -> Result<(), CpuError> {
+        let (first, bytes) = image.flatten()?;
+        let first = u16::try_from(first).map_err(|_| CpuError::OutOfBounds { addr: first })?;
+
+        self.load_rom_at(&bytes, first)?;
+        self.set_start_address(image.start.unwrap_or(PROGRAM_START));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_records() {
+        let image = parse_intel_hex(":040200006005700124\n:00000001FF\n").unwrap();
+        assert_eq!(image.segments, [(0x200, vec![0x60, 0x05, 0x70, 0x01])]);
+        assert_eq!(image.start, None);
+    }
+
+    #[test]
+    fn wrong_checksum() {
+        let error = parse_intel_hex(":0402000060057001FF\n").unwrap_err();
+        assert!(matches!(error, LoadError::Format { line: 1, ref message } if message == "wrong checksum"));
+    }
+
+    #[test]
+    fn extended_segment_address() {
+        // 0x0010 << 4 puts the data at 0x100 + 0x0100
+        let image = parse_intel_hex(":020000020010EC\n:020100001234B7\n").unwrap();
+        assert_eq!(image.segments, [(0x200, vec![0x12, 0x34])]);
+    }
+
+    #[test]
+    fn extended_linear_address() {
+        let image = parse_intel_hex(":020000040001F9\n:020000001234B8\n").unwrap();
+        assert_eq!(image.segments, [(0x10000, vec![0x12, 0x34])]);
+    }
+
+    #[test]
+    fn huge_image_is_not_allocated() {
+        // one byte at 0 and one at 0xFFFF0000
+        let image = parse_intel_hex(":0100000012ED\n:02000004FFFFFC\n:0100000034CB\n").unwrap();
+        assert!(matches!(image.flatten(), Err(CpuError::RomTooLarge { .. })));
+    }
+}
@@ -1,120 +1,616 @@
-struct CPU {
-    registers: [u8; 16], // (container of data that the CPU accesses directly
-    position_in_memory: usize,
-    memory: [u8; 4096],
-    stack: [u16; 16], // specialized memory for storing addresses
-    stack_pointer: usize,
+mod bench;
+mod cli;
+mod config;
+mod crash;
+mod dap;
+mod debugger;
+mod frontend;
+#[cfg(feature = "wgpu")]
+mod gpu;
+mod gdb;
+mod json;
+#[cfg(feature = "minifb")]
+mod minifb_window;
+mod remote;
+mod repl;
+mod runner;
+#[cfg(feature = "rhai")]
+mod script;
+#[cfg(feature = "sdl")]
+mod sdl;
+mod terminal;
+mod tui;
+mod watch;
+#[cfg(feature = "window")]
+mod window;
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc;
+use std::time::Instant;
+
+use cli::{Cli, Command, Frontend, Graph, GraphFormat, Options, Source};
+use crash::CrashReport;
+use cpu_caller::decoder::{decode, Instruction};
+use cpu_caller::profiles::QuirkDatabase;
+use cpu_caller::{
+    assemble_file, call_graph, check_rom, control_flow, detect_variant_at, disassemble, find_labels, label, parse_hex, parse_intel_hex,
+    rom_stats, DisasmLine, HexImage, LoadError, StopReason, Symbols, Variant, CPU, PROGRAM_START,
+};
+
+/// Instructions `trace` executes unless `--instructions` says otherwise
+const TRACE_BUDGET: u64 = 1_000_000;
+
+fn main() {
+    let cli = Cli::parse(std::env::args_os()).unwrap_or_else(|e| e.exit());
+
+    match cli.command {
+        Command::Demo => demo(),
+        Command::Repl => repl::run(&cli.options),
+        Command::Trace { rom, output } => trace(&rom, output.as_deref(), &cli.options),
+        Command::TraceDiff { a, b } => trace_diff(&a, &b),
+        Command::Bench { rom } => bench::run(&mut build_cpu(&Source::File(rom), &cli.options), &cli.options),
+        Command::Gdb { rom, port } => gdb::run(&rom, port, &cli.options),
+        Command::Remote { rom, port } => remote::run(&rom, port, &cli.options),
+        Command::Dap => dap::run(&cli.options),
+        Command::Test { dir } => runner::run(&dir, &cli.options),
+        Command::Run { rom: Source::File(path), watch: Some(reload) } => watch::run(&path, reload, &cli.options),
+        Command::Run { rom, .. } => run(&rom, &cli.options),
+        Command::Disasm { rom, graph: None } => disasm(&rom, &cli.options),
+        Command::Disasm { rom, graph: Some(graph) } => print_graph(&rom, graph, &cli.options),
+        Command::Hexdump { rom } => hexdump(&rom, &cli.options),
+        Command::Check { rom } => check(&rom, &cli.options),
+        Command::Stats { rom } => stats(&rom, &cli.options),
+        Command::Asm { source, output } => asm(&source, output.as_deref()),
+        Command::Patch { rom, at, bytes, output } => patch(&rom, at, &bytes, output.as_deref()),
+        Command::Debug { rom, tui: false, script } => debugger::run(&rom, script.as_deref(), &cli.options),
+        Command::Debug { rom, tui: true, .. } => tui::run(&rom, &cli.options),
+    }
+}
+
+/// Creates a CPU with the settings of the command line and loads the ROM into it
+fn build_cpu(source: &Source, options: &Options) -> CPU {
+    match load_cpu(source, options) {
+        Ok(cpu) => cpu,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    }
 }
 
-impl CPU {
-    /// Reads an opcode from memory by combining two values into a single u16 value
-    fn read_opcode(&self) -> u16 {
-        let p = self.position_in_memory;
-        let op_byte1 = self.memory[p] as u16;
-        let op_byte2 = self.memory[p + 1] as u16;
+/// Like `build_cpu`, giving back the error instead of exiting
+fn load_cpu(source: &Source, options: &Options) -> Result<CPU, String> {
+    let mut cpu = match options.machine {
+        Some(machine) => CPU::for_machine(machine),
+        None => CPU::new(),
+    };
+    // `--variant` skips the detection of loaded ROMs
+    if let Some(variant) = options.variant {
+        cpu.set_variant_override(variant);
+    }
+    if let Some(path) = &options.quirk_db {
+        cpu.set_quirk_database(QuirkDatabase::from_file(path).map_err(|e| format!("can't read `{}`: {}", path, e))?);
+    }
+
+    match source {
+        Source::File(path) if is_assembly(path) => {
+            let program = assemble_file(path).map_err(|e| e.to_string())?;
+            cpu.load_rom(&program.rom).map_err(|e| format!("can't load `{}`: {}", path, e))?;
+        }
+        Source::File(path) => cpu.load_rom_file(path).map_err(|e| format!("can't load `{}`: {}", path, e))?,
+        Source::StdinHex => read_stdin_hex(&mut cpu).map_err(|e| format!("can't load standard input: {}", e))?,
+    }
 
-        // Move the value of ´óp_byte1´ 8 places to the left 
-        // and allocate the value of ´op_byte2´ to the right
-        // in order to create and u16 value (16 bits)
-        op_byte1 << 8 | op_byte2 // same as (op_byte1 << 8) | op_byte2
+    // the quirks of the command line win over the ones chosen for the ROM
+    if !options.quirks.is_empty() {
+        let mut quirks = *cpu.quirks();
+        for (name, flag) in &options.quirks {
+            *quirks.by_name(name).expect("quirk names are checked while parsing") = *flag;
+        }
+        cpu.set_quirks(quirks);
+    }
+    if let Some(speed) = options.speed {
+        cpu.set_tickrate(speed);
+    }
+    cpu.set_watchdog(options.watchdog);
+    for &(addr, byte) in &options.pokes {
+        cpu.write_memory(addr, &[byte]).map_err(|e| format!("can't poke {:#05x}: {}", addr, e))?;
     }
+    Ok(cpu)
+}
+
+/// Whether `path` is a program to assemble before running it
+fn is_assembly(path: &str) -> bool {
+    let extension = Path::new(path).extension().unwrap_or_default();
+    extension.eq_ignore_ascii_case("s") || extension.eq_ignore_ascii_case("asm")
+}
+
+/// Loads the hexadecimal bytes written to standard input
+fn read_stdin_hex(cpu: &mut CPU) -> Result<(), LoadError> {
+    let text = io::read_to_string(io::stdin())?;
+    let rom = parse_hex(&text)?;
+    cpu.load_rom(&rom)?;
+    Ok(())
+}
 
-    /// Calls a function
-    fn call(&mut self, addr: u16) {
-        let sp = self.stack_pointer;
-        let stack = &mut self.stack;
+/// Executes a ROM until it stops
+fn run(source: &Source, options: &Options) {
+    let mut cpu = build_cpu(source, options);
 
-        if sp > stack.len() {
-            panic!("Stack overflow");
+    let reason = match options.frontend {
+        Frontend::Headless => {
+            // the same 60 frames per second as on a screen, `bench` runs as fast as it can
+            let mut next_frame = Instant::now();
+            loop {
+                match cpu.advance(cpu.instructions_per_frame() as usize).stopped {
+                    StopReason::InstructionBudgetExhausted | StopReason::WaitingForVblank => frontend::wait_frame(&mut next_frame),
+                    // nothing can press a key without a keyboard
+                    reason => break reason,
+                }
+            }
+        }
+        Frontend::Terminal | Frontend::Braille if *source == Source::StdinHex => {
+            eprintln!("error: the keys typed for the terminal frontend can't come from standard input with the ROM");
+            process::exit(1);
+        }
+        Frontend::Terminal | Frontend::Braille => match terminal::run(&mut cpu, options) {
+            Some(reason) => reason,
+            None => return, // the user left
+        },
+        #[cfg(feature = "window")]
+        Frontend::Window => match window::run(&mut cpu, options, window::open_pixels) {
+            Ok(Some(reason)) => reason,
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("error: can't open the window: {}", e);
+                process::exit(1);
+            }
+        },
+        #[cfg(not(feature = "window"))]
+        Frontend::Window => {
+            eprintln!("error: this build has no window frontend, build it with `--features window`");
+            process::exit(1);
+        }
+        #[cfg(feature = "minifb")]
+        Frontend::Minifb => match minifb_window::run(&mut cpu, options) {
+            Ok(Some(reason)) => reason,
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("error: can't open the window: {}", e);
+                process::exit(1);
+            }
+        },
+        #[cfg(not(feature = "minifb"))]
+        Frontend::Minifb => {
+            eprintln!("error: this build has no minifb frontend, build it with `--features minifb`");
+            process::exit(1);
+        }
+        #[cfg(feature = "wgpu")]
+        Frontend::Wgpu => match window::run(&mut cpu, options, gpu::open) {
+            Ok(Some(reason)) => reason,
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("error: can't open the window: {}", e);
+                process::exit(1);
+            }
+        },
+        #[cfg(not(feature = "wgpu"))]
+        Frontend::Wgpu => {
+            eprintln!("error: this build has no wgpu frontend, build it with `--features wgpu`");
+            process::exit(1);
+        }
+        #[cfg(feature = "sdl")]
+        Frontend::Sdl => match sdl::run(&mut cpu, options) {
+            Ok(Some(reason)) => reason,
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("error: can't open the SDL window: {}", e);
+                process::exit(1);
+            }
+        },
+        #[cfg(not(feature = "sdl"))]
+        Frontend::Sdl => {
+            eprintln!("error: this build has no SDL frontend, build it with `--features sdl`");
+            process::exit(1);
         }
+    };
 
-        // ´position_in_memory´ is two bytes higher than the calling location
-        stack[sp] = self.position_in_memory as u16;
-        self.stack_pointer += 1; // prevent memory to be overwritten
-        self.position_in_memory = addr as usize;
+    match reason {
+        StopReason::Error(e) => {
+            let symbols = match source {
+                Source::File(path) => load_symbols(path, options),
+                Source::StdinHex => Symbols::new(),
+            };
+            CrashReport::new(&cpu, &symbols, e).print(options.crash_report);
+            process::exit(1);
+        }
+        StopReason::Stuck { start, end } => {
+            eprintln!("error: {}", stuck(&cpu, start, end));
+            process::exit(1);
+        }
+        reason => println!("stopped: {:?} at {:#05x}", reason, cpu.pc()),
     }
+}
+
+/// Explains a stop of the watchdog
+fn stuck(cpu: &CPU, start: u16, end: u16) -> String {
+    let limit = cpu.watchdog().unwrap_or_default();
+    let place = if start == end { format!("at {:#05x}", start) } else { format!("around {:#05x}-{:#05x}", start, end) };
+    format!("stuck {} for {} instructions without changing memory, the display or the timers", place, limit)
+}
+
+/// Prints the mnemonic, address and opcode of every instruction of a ROM,
+/// with labels where jumps and calls go
+fn disasm(path: &str, options: &Options) {
+    let segments = match read_segments(path) {
+        Ok(segments) => segments,
+        Err(e) => {
+            eprintln!("error: can't load `{}`: {}", path, e);
+            process::exit(1);
+        }
+    };
 
-    /// Returns from a function
-    fn ret(&mut self) {
-        if self.stack_pointer == 0 {
-            panic!("Stack underflow");
+    let symbols = load_symbols(path, options);
+    let mut out = io::stdout().lock();
+    for (base, rom) in segments {
+        let variant = options
+            .variant
+            .or(options.machine.map(|machine| machine.variant()))
+            .unwrap_or_else(|| detect_variant_at(&rom, base, base));
+
+        // the address and bytes are comments so that the output can be assembled again
+        let lines = disassemble(&rom, base, variant);
+        let mut names = Symbols::new();
+        for addr in find_labels(&lines) {
+            names.insert(addr, &label(addr));
+        }
+        // names in the middle of an instruction would be lost, so they stay numbers
+        for line in &lines {
+            if let Some(name) = symbols.name(line.addr) {
+                names.insert(line.addr, name);
+            }
         }
 
-        self.stack_pointer -= 1;
-        let addr = self.stack[self.stack_pointer];
-        self.position_in_memory = addr as usize; // set memory asdress to the previous CALL opcode
+        if let Err(e) = write_listing(&mut out, &lines, &names) {
+            return finish_output(Err(e));
+        }
     }
+}
 
-    /// Adds two numbers located in registers of CPU
-    fn add_xy(&mut self, x: u8, y: u8) {
-        let arg1 = self.registers[x as usize];
-        let arg2 = self.registers[y as usize];
+/// Ends a command whose output couldn't be written, quietly when the
+/// reader went away like `head` does
+fn finish_output(result: io::Result<()>) {
+    match result {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => {
+            eprintln!("error: can't write the output: {}", e);
+            process::exit(1);
+        }
+        _ => {}
+    }
+}
 
-        let (val, overflow_detected) = arg1.overflowing_add(arg2);
-        self.registers[x as usize] = val;
+/// Writes the instructions of `disasm` with their labels
+fn write_listing(out: &mut impl Write, lines: &[DisasmLine], names: &Symbols) -> io::Result<()> {
+    for line in lines {
+        if let Some(name) = names.name(line.addr) {
+            writeln!(out, "{}:", name)?;
+        }
+        let bytes: String = line.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        writeln!(out, "    {:<24}; {:#05x}  {}", line.symbolic(names), line.addr, bytes)?;
+    }
+    out.flush()
+}
 
-        // the last register is termed *carry flag* that indicates if an operation
-        // has overflowed
-        if overflow_detected {
-            self.registers[0xF] = 1;
-        } else {
-            self.registers[0xF] = 0;
+/// Names of the addresses of a ROM, from the file given with `--symbols` or
+/// else from the `.sym` file next to the ROM that `asm` writes. An assembly
+/// program has its own
+fn load_symbols(path: &str, options: &Options) -> Symbols {
+    match read_symbols(path, options) {
+        Ok(symbols) => symbols,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
         }
     }
+}
 
-    // Call functions exeuting them in the CPU emulator
-    fn run(&mut self) {
-        loop {
-            let opcode = self.read_opcode();
-            self.position_in_memory += 2;
+/// Like `load_symbols`, giving back the error instead of exiting
+fn read_symbols(path: &str, options: &Options) -> Result<Symbols, String> {
+    let file = match &options.symbols {
+        Some(file) => PathBuf::from(file),
+        // the errors of the program are the business of whatever loads it
+        None if is_assembly(path) => return Ok(assemble_file(path).map(|program| program.symbols).unwrap_or_default()),
+        None => match Path::new(path).with_extension("sym") {
+            file if file.exists() => file,
+            _ => return Ok(Symbols::new()),
+        },
+    };
+    fs::read_to_string(&file)
+        .map_err(|e| e.to_string())
+        .and_then(|text| Symbols::parse(&text))
+        .map_err(|e| format!("can't load the symbols of `{}`: {}", file.display(), e))
+}
 
-            let c = ((opcode & 0xF000) >> 12) as u8;
-            let x = ((opcode & 0x0F00) >> 8) as u8;
-            let y = ((opcode & 0x00F0) >> 4) as u8;
-            let d = ((opcode & 0x000F) >> 0) as u8;
+/// Compiles an assembly program into a ROM, written next to it unless `output`
+/// is given, and its labels into a symbol file next to the ROM
+fn asm(source: &str, output: Option<&str>) {
+    let program = match assemble_file(source) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    };
 
-            // get memory address from opcode
-            let nnn = opcode & 0xFFF;
+    let output = match output {
+        Some(output) => PathBuf::from(output),
+        None => Path::new(source).with_extension("ch8"),
+    };
+    let symbols = output.with_extension("sym");
+    for (file, contents) in [(&output, program.rom), (&symbols, program.symbols.to_string().into_bytes())] {
+        if let Err(e) = fs::write(file, contents) {
+            eprintln!("error: can't write `{}`: {}", file.display(), e);
+            process::exit(1);
+        }
+    }
+}
 
-            match(c, x, y, d) {
-                ( 0, 0, 0, 0) => { return; },
-                ( 0, 0, 0xE, 0xE) => self.ret(),
-                (0x2, _, _, _) => self.call(nnn),
-                (0x8, _, _, 0x4) => self.add_xy(x, y),
-                _ => todo!("opcode {:04x}", opcode),
-            }
+/// Prints each word of a ROM as hexadecimal, as characters and as the
+/// instruction it decodes to, code and data alike
+fn hexdump(path: &str, options: &Options) {
+    let (rom, base, _, variant) = analyzed_rom(path, options);
+    finish_output(write_hexdump(&mut io::stdout().lock(), &rom, base, variant));
+}
+
+fn write_hexdump(out: &mut impl Write, rom: &[u8], base: usize, variant: Variant) -> io::Result<()> {
+    for (offset, word) in rom.chunks(2).enumerate().map(|(i, word)| (i * 2, word)) {
+        let hex: Vec<String> = word.iter().map(|b| format!("{:02X}", b)).collect();
+        let text: String = word.iter().map(|&b| if b.is_ascii_graphic() { b as char } else { '.' }).collect();
+        let decoded = match *word {
+            [hi, lo] => decode(u16::from_be_bytes([hi, lo]), variant).to_string(),
+            _ => String::new(),
+        };
+        writeln!(out, "{:#05x}  {:<5}  {:<2}  {}", base + offset, hex.join(" "), text, decoded)?;
+    }
+    out.flush()
+}
+
+/// Prints the control-flow or call graph of a ROM
+fn print_graph(path: &str, (graph, format): (Graph, GraphFormat), options: &Options) {
+    let (rom, base, entry, variant) = analyzed_rom(path, options);
+    let text = match (graph, format) {
+        (Graph::ControlFlow, _) => control_flow(&rom, base, entry, variant).to_dot(),
+        (Graph::Calls, GraphFormat::Text) => call_graph(&rom, base, entry, variant).to_string(),
+        (Graph::Calls, GraphFormat::Dot) => call_graph(&rom, base, entry, variant).to_dot(),
+    };
+    let mut out = io::stdout().lock();
+    finish_output(out.write_all(text.as_bytes()).and_then(|()| out.flush()));
+}
+
+/// Prints the mistakes found in a ROM, exits with 1 if there are any
+fn check(path: &str, options: &Options) {
+    let (rom, base, entry, variant) = analyzed_rom(path, options);
+    let issues = check_rom(&rom, base, entry, variant);
+    let mut out = io::stdout().lock();
+    finish_output(issues.iter().try_for_each(|issue| writeln!(out, "{}", issue)).and_then(|()| out.flush()));
+    if !issues.is_empty() {
+        eprintln!("{}: {} issue(s) found", path, issues.len());
+        process::exit(1);
+    }
+}
+
+/// Prints statistics about the code of a ROM
+fn stats(path: &str, options: &Options) {
+    let (rom, base, entry, variant) = analyzed_rom(path, options);
+    let mut out = io::stdout().lock();
+    finish_output(write!(out, "{}", rom_stats(&rom, base, entry, variant)).and_then(|()| out.flush()));
+}
+
+/// Reads a ROM for the static analyses: its bytes, their address, where the
+/// analysis starts and the instruction set. The entry point is 0x200, or the
+/// start of Intel HEX files that don't cover it
+fn analyzed_rom(path: &str, options: &Options) -> (Vec<u8>, usize, usize, Variant) {
+    let segments = match read_segments(path) {
+        Ok(segments) => segments,
+        Err(e) => {
+            eprintln!("error: can't load `{}`: {}", path, e);
+            process::exit(1);
+        }
+    };
+    let (base, rom) = match (HexImage { segments, start: None }).flatten() {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("error: can't load `{}`: {}", path, e);
+            process::exit(1);
+        }
+    };
+
+    let entry = if (base..base + rom.len()).contains(&PROGRAM_START) { PROGRAM_START } else { base };
+    let variant = options
+        .variant
+        .or(options.machine.map(|machine| machine.variant()))
+        .unwrap_or_else(|| detect_variant_at(&rom, base, entry));
+    (rom, base, entry, variant)
+}
 
+/// Writes a copy of a raw ROM with `bytes` at the memory address `at`,
+/// ROMs too short for them are padded with zeros
+fn patch(path: &str, at: usize, bytes: &[u8], output: Option<&str>) {
+    let mut rom = match fs::read(path) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("error: can't read `{}`: {}", path, e);
+            process::exit(1);
         }
+    };
+    let Some(offset) = at.checked_sub(PROGRAM_START) else {
+        eprintln!("error: {:#05x} is below the program, which starts at {:#05x}", at, PROGRAM_START);
+        process::exit(1);
+    };
+
+    if rom.len() < offset + bytes.len() {
+        rom.resize(offset + bytes.len(), 0);
+    }
+    rom[offset..offset + bytes.len()].copy_from_slice(bytes);
+
+    let output = match output {
+        Some(output) => PathBuf::from(output),
+        None => Path::new(path).with_extension("patched.ch8"),
+    };
+    if let Err(e) = fs::write(&output, &rom) {
+        eprintln!("error: can't write `{}`: {}", output.display(), e);
+        process::exit(1);
     }
 }
 
-fn main() {
-    let mut cpu = CPU {
-        registers: [0; 16],
-        memory: [0; 4096],
-        position_in_memory: 0,
-        stack: [0; 16],
-        stack_pointer: 0,
+/// Bytes of a ROM file and where they are loaded, Intel HEX files can have
+/// several parts and assembly programs are assembled first
+fn read_segments(path: &str) -> Result<Vec<(usize, Vec<u8>)>, String> {
+    if is_assembly(path) {
+        return Ok(vec![(PROGRAM_START, assemble_file(path).map_err(|e| e.to_string())?.rom)]);
+    }
+    if Path::new(path).extension().is_some_and(|ext| ext.eq_ignore_ascii_case("hex")) {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        return Ok(parse_intel_hex(&text).map_err(|e| e.to_string())?.segments);
+    }
+    Ok(vec![(PROGRAM_START, fs::read(path).map_err(|e| e.to_string())?)])
+}
+
+/// Executes a ROM without a screen and writes a line for each instruction
+fn trace(path: &str, output: Option<&str>, options: &Options) {
+    let mut out: Box<dyn Write> = match output {
+        None => Box::new(io::stdout().lock()),
+        Some(output) => match File::create(output) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                eprintln!("error: can't write `{}`: {}", output, e);
+                process::exit(1);
+            }
+        },
     };
+    let (sender, records) = mpsc::channel();
+    let mut cpu = build_cpu(&Source::File(path.to_string()), options);
+    cpu.set_trace_sink(Box::new(sender));
 
-    cpu.registers[0] = 5;
-    cpu.registers[1] = 10;
+    // a frame at a time so that the timers go on as they would on screen
+    let budget = options.instructions.unwrap_or(TRACE_BUDGET);
+    let reason = loop {
+        let frame = (cpu.instructions_per_frame() as u64).min(budget - cpu.instructions_executed());
+        let reason = cpu.advance(frame as usize).stopped;
+        for record in records.try_iter() {
+            if writeln!(out, "{}", record).is_err() {
+                process::exit(1); // the reader went away, like `head` does
+            }
+        }
+        match reason {
+            StopReason::InstructionBudgetExhausted | StopReason::WaitingForVblank if cpu.instructions_executed() < budget => {}
+            reason => break reason,
+        }
+    };
+    let _ = out.flush();
+    eprintln!("stopped: {:?} at {:#05x} after {} instructions", reason, cpu.pc(), cpu.instructions_executed());
+}
+
+/// Prints the first line where two traces differ, exits with 1 if they do
+fn trace_diff(a: &str, b: &str) {
+    let read = |path: &str| match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("error: can't read `{}`: {}", path, e);
+            process::exit(2);
+        }
+    };
+    let (text_a, text_b) = (read(a), read(b));
+    let (mut lines_a, mut lines_b) = (text_a.lines(), text_b.lines());
+
+    for number in 1.. {
+        match (lines_a.next(), lines_b.next()) {
+            (None, None) => {
+                println!("the traces are identical, {} instructions", number - 1);
+                return;
+            }
+            (line_a, line_b) if line_a == line_b => {}
+            (line_a, line_b) => {
+                println!("the traces differ at line {}:", number);
+                println!("    {}: {}", a, line_a.unwrap_or("<end of the trace>"));
+                println!("    {}: {}", b, line_b.unwrap_or("<end of the trace>"));
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// Adds 5 + (10 * 2) + (10 * 2) by calling a function twice
+fn demo() {
+    let mut cpu = CPU::new();
+
+    cpu.set_register(0, 5);
+    cpu.set_register(1, 10);
     
     // Load a a function into memory
     // this usually is done with a programming language
-    // but here it is done with hard-coded operation codes
-    let mem = &mut cpu.memory;
-    mem[0x000] = 0x21;  mem[0x001] = 0x00;
-    mem[0x002] = 0x21;  mem[0x003] = 0x00;
-    mem[0x004] = 0x00;  mem[0x005] = 0x00;
-    
-    mem[0x100] = 0x80;  mem[0x101] = 0x14;
-    mem[0x102] = 0x80;  mem[0x103] = 0x14;
-    mem[0x104] = 0x00;  mem[0x105] = 0xEE;
+    // but here it is done with the instructions themselves
+    let main = [Instruction::Call { addr: 0x100 }, Instruction::Call { addr: 0x100 }, Instruction::Halt];
+    let function = [Instruction::Add { x: 0, y: 1 }, Instruction::Add { x: 0, y: 1 }, Instruction::Ret];
+
+    let mut program = [0; 0x106];
+    for (addr, instruction) in (0x000..).step_by(2).zip(main).chain((0x100..).step_by(2).zip(function)) {
+        program[addr..addr + 2].copy_from_slice(&instruction.to_bytes());
+    }
+    cpu.load_rom_at(&program, 0x000).expect("the program fits in memory");
+
+    if let StopReason::Error(e) = cpu.run() {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+
+    assert_eq!(cpu.register(0), 45);
+    println!("5 + (10 * 2) + (10 * 2) = {}", cpu.register(0));
 
-    cpu.run();
+}
 
-    assert_eq!(cpu.registers[0], 45);
-    println!("5 + (10 * 2) + (10 * 2) = {}", cpu.registers[0]);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn hexdump_lines() {
+        let mut out = Vec::new();
+        write_hexdump(&mut out, &[0x60, 0x41, 0x42], PROGRAM_START, Variant::Chip8).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(text, "0x200  60 41  `A  LD V0, 0x41\n0x202  42     B   \n");
+    }
+
+    #[test]
+    fn hexdump_stops_at_the_first_error() {
+        /// Reader that went away after the first line
+        struct Closed(usize);
+        impl Write for Closed {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0 += 1;
+                match self.0 {
+                    1 => Ok(buf.len()),
+                    _ => Err(io::ErrorKind::BrokenPipe.into()),
+                }
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut out = Closed(0);
+        let e = write_hexdump(&mut out, &[0; 64], PROGRAM_START, Variant::Chip8).unwrap_err();
+        assert_eq!((e.kind(), out.0), (io::ErrorKind::BrokenPipe, 2));
+    }
+
+    #[test]
+    fn stuck_diagnostics() {
+        let mut cpu = CPU::new();
+        cpu.set_watchdog(Some(50));
+        let text = "stuck around 0x200-0x202 for 50 instructions without changing memory, the display or the timers";
+        assert_eq!(stuck(&cpu, 0x200, 0x202), text);
+        assert!(stuck(&cpu, 0x204, 0x204).starts_with("stuck at 0x204 for 50 instructions"));
+    }
 }
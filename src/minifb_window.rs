@@ -0,0 +1,108 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use minifb::{Key, KeyRepeat, ScaleMode, Window, WindowOptions};
+
+use cpu_caller::{Framebuffer, Renderer, StopReason, CPU, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+use crate::cli::{Options, Scaling};
+use crate::config::Palette;
+use crate::frontend;
+
+/// Last frame of the CPU and the same scaled to the window as minifb takes
+/// it, one `0RGB` word per pixel
+struct Picture {
+    colors: Vec<[u8; 3]>,
+    size: (usize, usize),
+    scaled: Vec<u32>,
+    scaled_size: (u32, u32), // zero until the frame is scaled
+}
+
+impl Picture {
+    /// Shows the frame in the window, scaled to the size `scaling` gives it
+    fn show(&mut self, window: &mut Window, scaling: Scaling) {
+        let (width, height) = window.get_size();
+        let size = frontend::picture_size(scaling, self.size, (width as u32, height as u32));
+        if size != self.scaled_size {
+            self.scaled.clear();
+            self.scaled.extend(frontend::scale(&self.colors, self.size, size).map(|[r, g, b]| u32::from_be_bytes([0, r, g, b])));
+            self.scaled_size = size;
+        }
+        let _ = window.update_with_buffer(&self.scaled, size.0 as usize, size.1 as usize);
+    }
+}
+
+/// Keeps the frames of the CPU for the window, which shows them when it
+/// next handles its events
+struct PictureRenderer {
+    picture: Rc<RefCell<Picture>>,
+    palette: Palette,
+}
+
+impl Renderer for PictureRenderer {
+    fn render(&mut self, framebuffer: &Framebuffer) {
+        let mut picture = self.picture.borrow_mut();
+        picture.colors.clear();
+        picture.colors.extend(frontend::colors(framebuffer, &self.palette));
+        picture.size = (framebuffer.width(), framebuffer.height());
+        picture.scaled_size = (0, 0);
+    }
+}
+
+/// Runs the program 60 frames per second in a minifb window, with the keys
+/// of the keyboard going to the program. Gives back what stopped it, or
+/// `None` when the window was closed or Escape pressed
+pub fn run(cpu: &mut CPU, options: &Options) -> Result<Option<StopReason>, String> {
+    // the picture is scaled before minifb gets it
+    let window_options = WindowOptions { resize: true, scale_mode: ScaleMode::Center, ..WindowOptions::default() };
+    let (width, height) = frontend::window_size(options.scaling);
+    let mut window = Window::new("cpu-caller", width as usize, height as usize, window_options).map_err(|e| e.to_string())?;
+    let [r, g, b] = options.palette.background;
+    window.set_background_color(r, g, b);
+
+    let picture = Picture { colors: Vec::new(), size: (DISPLAY_WIDTH, DISPLAY_HEIGHT), scaled: Vec::new(), scaled_size: (0, 0) };
+    let picture = Rc::new(RefCell::new(picture));
+    let mut renderer = PictureRenderer { picture: picture.clone(), palette: options.palette };
+    renderer.render(cpu.screen());
+    cpu.set_renderer(Box::new(renderer));
+
+    let reason = frontend::run_frames(cpu, |cpu| {
+        picture.borrow_mut().show(&mut window, options.scaling);
+        if !window.is_open() || window.is_key_down(Key::Escape) {
+            return false;
+        }
+        for key in window.get_keys_pressed(KeyRepeat::No) {
+            if let Some(key) = chip8_key(options, key) {
+                cpu.set_key(key, true);
+            }
+        }
+        for key in window.get_keys_released() {
+            if let Some(key) = chip8_key(options, key) {
+                cpu.set_key(key, false);
+            }
+        }
+        true
+    });
+
+    if reason.is_some() {
+        // the last picture stays until the window is closed or a key pressed
+        picture.borrow_mut().show(&mut window, options.scaling);
+        while window.is_open() && window.get_keys_pressed(KeyRepeat::No).is_empty() {
+            thread::sleep(Duration::from_millis(16));
+            picture.borrow_mut().show(&mut window, options.scaling);
+        }
+    }
+    Ok(reason)
+}
+
+/// CHIP-8 key of a key of the keyboard, only the letters and digits can be one
+fn chip8_key(options: &Options, key: Key) -> Option<u8> {
+    let c = match key as u32 {
+        n @ 0..=9 => char::from(b'0' + n as u8),
+        n @ 10..=35 => char::from(b'a' + (n - 10) as u8),
+        _ => return None,
+    };
+    frontend::chip8_key(&options.keys, c)
+}
@@ -0,0 +1,88 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Opcode with wildcards, like `D___` for any sprite drawn or `F_55` for any
+/// registers stored to memory.
+///
+/// Each of the 4 characters is a hexadecimal digit the opcode must have, or
+/// `_`, `?`, `x`, `y`, `n` or `k` for any digit, the letters reading like the
+/// usual descriptions of the instructions, as in `Dxyn` or `Fx55`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpcodePattern {
+    mask: u16,  // bits of the opcode that must match
+    value: u16, // what they must be
+}
+
+impl OpcodePattern {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let digits: Vec<char> = text.trim().chars().collect();
+        if digits.len() != 4 {
+            return Err(format!("`{}` is not 4 digits of an opcode", text));
+        }
+        let (mut mask, mut value) = (0, 0);
+        for c in digits {
+            mask <<= 4;
+            value <<= 4;
+            match c {
+                '_' | '?' | 'x' | 'y' | 'n' | 'k' | 'X' | 'Y' | 'N' | 'K' => {}
+                c => {
+                    let digit = c.to_digit(16).ok_or_else(|| format!("`{}` is not a hexadecimal digit or a wildcard", c))?;
+                    mask |= 0xF;
+                    value |= digit as u16;
+                }
+            }
+        }
+        Ok(OpcodePattern { mask, value })
+    }
+
+    pub fn matches(&self, opcode: u16) -> bool {
+        opcode & self.mask == self.value
+    }
+}
+
+/// Like `D___` or `F_55`
+impl fmt::Display for OpcodePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for shift in [12, 8, 4, 0] {
+            match (self.mask >> shift) & 0xF {
+                0 => write!(f, "_")?,
+                _ => write!(f, "{:X}", (self.value >> shift) & 0xF)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::string::ToString;
+
+    #[test]
+    fn wildcards() {
+        let draw = OpcodePattern::parse("Dxyn").unwrap();
+        assert!(draw.matches(0xD015) && draw.matches(0xDFFF));
+        assert!(!draw.matches(0xC015));
+        let store = OpcodePattern::parse(" f_55 ").unwrap();
+        assert!(store.matches(0xF355) && !store.matches(0xF365) && !store.matches(0xE355));
+        let exact = OpcodePattern::parse("00E0").unwrap();
+        assert!(exact.matches(0x00E0) && !exact.matches(0x00EE));
+        assert_eq!(OpcodePattern::parse("????").unwrap(), OpcodePattern::parse("xykn").unwrap());
+    }
+
+    #[test]
+    fn shown_with_underscores() {
+        assert_eq!(OpcodePattern::parse("Dxyn").unwrap().to_string(), "D___");
+        assert_eq!(OpcodePattern::parse("f?55").unwrap().to_string(), "F_55");
+    }
+
+    #[test]
+    fn mistakes() {
+        for text in ["", "D__", "D____", "G___", "D-__"] {
+            assert!(OpcodePattern::parse(text).is_err(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::{fs, io, path::Path};
+
+use crate::Quirks;
+
+/// Known-good settings for a specific ROM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomProfile {
+    pub quirks: Vec<(&'static str, bool)>, // the quirks listed, in order, named like `Quirks::NAMES`
+    pub tickrate: Option<u32>,             // instructions executed per 60 Hz frame
+}
+
+impl RomProfile {
+    /// `quirks`, those of the machine the ROM runs on, with the ones the
+    /// profile lists changed
+    pub fn apply(&self, mut quirks: Quirks) -> Quirks {
+        for &(name, flag) in &self.quirks {
+            *quirks.by_name(name).expect("quirk names are checked while parsing") = flag;
+        }
+        quirks
+    }
+}
+
+/// Error found while reading a profile database
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseError {}
+
+/// Maps the SHA-1 hash of ROMs to the settings they need, in the spirit of
+/// the chip-8-database project.
+///
+/// The file is a small subset of TOML, one table per ROM:
+///
+/// ```toml
+/// # Space Invaders (David Winter)
+/// [a1b2c3...]
+/// shift_uses_vy = false
+/// tickrate = 15
+/// ```
+///
+/// Quirks that are not listed keep the value of the machine emulated.
+#[derive(Debug, Clone, Default)]
+pub struct QuirkDatabase {
+    profiles: BTreeMap<String, RomProfile>,
+}
+
+impl QuirkDatabase {
+    /// Reads a database from a file
+    #[cfg(feature = "std")]
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Reads a database from its text representation
+    pub fn parse(text: &str) -> Result<Self, ParseError> {
+        let mut profiles = BTreeMap::new();
+        let mut current: Option<(String, RomProfile)> = None;
+
+        for (index, raw) in text.lines().enumerate() {
+            let line = index + 1;
+            let error = |message: String| ParseError { line, message };
+
+            // everything after `#` is a comment
+            let content = raw.split('#').next().unwrap_or("").trim();
+            if content.is_empty() {
+                continue;
+            }
+
+            if let Some(header) = content.strip_prefix('[') {
+                let hash = header
+                    .strip_suffix(']')
+                    .ok_or_else(|| error("missing `]`".to_string()))?
+                    .trim()
+                    .trim_matches('"')
+                    .to_ascii_lowercase();
+
+                if hash.len() != 40 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(error(format!("`{}` is not a SHA-1 hash", hash)));
+                }
+
+                if let Some((hash, profile)) = current.take() {
+                    profiles.insert(hash, profile);
+                }
+                let profile = RomProfile { quirks: Vec::new(), tickrate: None };
+                current = Some((hash, profile));
+                continue;
+            }
+
+            let (key, value) = content
+                .split_once('=')
+                .ok_or_else(|| error("expected `key = value`".to_string()))?;
+            let (key, value) = (key.trim(), value.trim());
+
+            let (_, profile) = current
+                .as_mut()
+                .ok_or_else(|| error(format!("`{}` appears before any ROM hash", key)))?;
+
+            if key == "tickrate" {
+                let rate = value
+                    .parse()
+                    .map_err(|_| error(format!("`{}` is not a number", value)))?;
+                profile.tickrate = Some(rate);
+                continue;
+            }
+
+            let flag = match value {
+                "true" => true,
+                "false" => false,
+                _ => return Err(error(format!("`{}` is not a boolean", value))),
+            };
+
+            let name = Quirks::NAMES
+                .iter()
+                .find(|&&name| name == key)
+                .ok_or_else(|| error(format!("unknown setting `{}`", key)))?;
+            profile.quirks.push((name, flag));
+        }
+
+        if let Some((hash, profile)) = current {
+            profiles.insert(hash, profile);
+        }
+
+        Ok(QuirkDatabase { profiles })
+    }
+
+    /// Finds the profile of a ROM by its contents
+    pub fn lookup(&self, rom: &[u8]) -> Option<&RomProfile> {
+        self.profiles.get(&hex(&sha1(rom)))
+    }
+}
+
+/// Lowercase hexadecimal representation of some bytes
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// SHA-1 digest, the hash used by the chip-8-database project to identify ROMs
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476, 0xC3D2_E1F0];
+
+    // the message is padded with a 1 bit, zeros and its length in bits
+    // until it is a multiple of 64 bytes
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (t, word) in chunk.chunks(4).enumerate() {
+            w[t] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for t in 16..80 {
+            w[t] = (w[t - 3] ^ w[t - 8] ^ w[t - 14] ^ w[t - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (t, word) in w.iter().enumerate() {
+            let (f, k) = match t {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, word) in digest.chunks_mut(4).zip(h) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha1_of_known_texts() {
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(&sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        // two blocks of padding
+        let text = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        assert_eq!(hex(&sha1(text)), "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+    }
+
+    #[test]
+    fn lookup_by_contents() {
+        let text = "
+            # the hash of `abc`
+            [A9993E364706816ABA3E25717850C26C9CD0D89D]
+            shift_uses_vy = true
+            tickrate = 30
+
+            [\"da39a3ee5e6b4b0d3255bfef95601890afd80709\"]
+            vf_reset = true
+        ";
+        let database = QuirkDatabase::parse(text).unwrap();
+        let profile = database.lookup(b"abc").unwrap();
+        assert_eq!(profile.quirks, [("shift_uses_vy", true)]);
+        assert_eq!(profile.tickrate, Some(30));
+        assert_eq!(database.lookup(b"").unwrap().quirks, [("vf_reset", true)]);
+        assert_eq!(database.lookup(b"abd"), None);
+    }
+
+    #[test]
+    fn quirks_not_listed_stay() {
+        let profile = RomProfile { quirks: vec![("vf_reset", false)], tickrate: None };
+        let vip = Quirks { vf_reset: true, display_wait: true, ..Quirks::default() };
+        assert_eq!(profile.apply(vip), Quirks { vf_reset: false, ..vip });
+    }
+
+    #[test]
+    fn mistakes() {
+        let hash = "[a9993e364706816aba3e25717850c26c9cd0d89d]";
+        let cases = [
+            ("[abc]", 1, "`abc` is not a SHA-1 hash"),
+            ("[a9993e364706816aba3e25717850c26c9cd0d89d", 1, "missing `]`"),
+            ("vf_reset = true", 1, "`vf_reset` appears before any ROM hash"),
+            (&*format!("{}\nvf_reset", hash), 2, "expected `key = value`"),
+            (&*format!("{}\nvf_reset = yes", hash), 2, "`yes` is not a boolean"),
+            (&*format!("{}\nturbo = true", hash), 2, "unknown setting `turbo`"),
+            (&*format!("{}\ntickrate = fast", hash), 2, "`fast` is not a number"),
+        ];
+        for (text, line, message) in cases {
+            assert_eq!(QuirkDatabase::parse(text).unwrap_err(), ParseError { line, message: message.to_string() }, "{}", text);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use crate::font::{DEFAULT_FONT, VIP_FONT};
+
+/// Machine whose instruction set is emulated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    Chip8,
+    SuperChip, // SCHIP 1.1 adds a high resolution mode, scrolling and bigger sprites
+    XoChip,    // Octo's extension of SUPER-CHIP with 64 KB of memory, colors and sound
+}
+
+impl Variant {
+    /// Parses the name used on the command line: `chip8`, `schip` or `xochip`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "chip8" => Some(Variant::Chip8),
+            "schip" => Some(Variant::SuperChip),
+            "xochip" => Some(Variant::XoChip),
+            _ => None,
+        }
+    }
+}
+
+/// Behaviors that differ between CHIP-8 interpreters, ROMs written for one
+/// interpreter may misbehave unless these match the one they were made for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    pub shift_uses_vy: bool,           // 8xy6/8xyE shift Vy into Vx instead of shifting Vx in place
+    pub load_store_increments_i: bool, // Fx55/Fx65 leave I past the last register
+    pub vf_reset: bool,                // 8xy1/8xy2/8xy3 clear VF
+    pub clip_sprites: bool,            // DXYN cuts sprites at the screen borders instead of wrapping them
+    pub jump_with_vx: bool,            // BXNN jumps to XNN + Vx instead of NNN + V0
+    pub display_wait: bool,            // DXYN waits for the next vertical blank
+    pub i_overflow_sets_vf: bool,      // Fx1E sets VF when I goes past 0xFFF
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: true,
+            vf_reset: false,
+            clip_sprites: false,
+            jump_with_vx: false,
+            display_wait: false,
+            i_overflow_sets_vf: false,
+        }
+    }
+}
+
+impl Quirks {
+    /// Names of the quirks, as used in the profile database and on the command line
+    pub const NAMES: [&'static str; 7] = [
+        "shift_uses_vy",
+        "load_store_increments_i",
+        "vf_reset",
+        "clip_sprites",
+        "jump_with_vx",
+        "display_wait",
+        "i_overflow_sets_vf",
+    ];
+
+    /// Finds a quirk by its name
+    pub fn by_name(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "shift_uses_vy" => Some(&mut self.shift_uses_vy),
+            "load_store_increments_i" => Some(&mut self.load_store_increments_i),
+            "vf_reset" => Some(&mut self.vf_reset),
+            "clip_sprites" => Some(&mut self.clip_sprites),
+            "jump_with_vx" => Some(&mut self.jump_with_vx),
+            "display_wait" => Some(&mut self.display_wait),
+            "i_overflow_sets_vf" => Some(&mut self.i_overflow_sets_vf),
+            _ => None,
+        }
+    }
+}
+
+/// Historical interpreters, each one bundles the instruction set, quirks,
+/// speed and font that ROMs written for it expect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Machine {
+    VipChip8,    // the original interpreter of the COSMAC VIP (1977)
+    Chip48,      // the HP-48 port, first to change the shift and load/store behaviors
+    SchipLegacy, // SUPER-CHIP 1.1 as it ran on the HP-48
+    SchipModern, // SUPER-CHIP as implemented by modern interpreters like Octo
+    XoChip,
+}
+
+impl Machine {
+    /// Parses the name used on the command line: `vip`, `chip48`, `schip-legacy`,
+    /// `schip` or `xochip`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "vip" => Some(Machine::VipChip8),
+            "chip48" => Some(Machine::Chip48),
+            "schip-legacy" => Some(Machine::SchipLegacy),
+            "schip" => Some(Machine::SchipModern),
+            "xochip" => Some(Machine::XoChip),
+            _ => None,
+        }
+    }
+
+    /// Machine used for ROMs of `variant` when nothing else is known about them
+    pub fn for_variant(variant: Variant) -> Self {
+        match variant {
+            Variant::Chip8 => Machine::VipChip8,
+            Variant::SuperChip => Machine::SchipModern,
+            Variant::XoChip => Machine::XoChip,
+        }
+    }
+
+    /// Instruction set of the machine
+    pub fn variant(self) -> Variant {
+        match self {
+            Machine::VipChip8 | Machine::Chip48 => Variant::Chip8,
+            Machine::SchipLegacy | Machine::SchipModern => Variant::SuperChip,
+            Machine::XoChip => Variant::XoChip,
+        }
+    }
+
+    /// Compatibility settings of the machine
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Machine::VipChip8 => Quirks {
+                shift_uses_vy: true,
+                vf_reset: true,
+                clip_sprites: true,
+                display_wait: true,
+                ..Quirks::default()
+            },
+            Machine::Chip48 => Quirks {
+                clip_sprites: true,
+                jump_with_vx: true,
+                ..Quirks::default()
+            },
+            // the HP-48 waited for the vertical blank like the VIP did
+            Machine::SchipLegacy => Quirks {
+                load_store_increments_i: false,
+                clip_sprites: true,
+                jump_with_vx: true,
+                display_wait: true,
+                ..Quirks::default()
+            },
+            Machine::SchipModern => Quirks {
+                load_store_increments_i: false,
+                clip_sprites: true,
+                jump_with_vx: true,
+                ..Quirks::default()
+            },
+            // the defaults of Octo
+            Machine::XoChip => Quirks {
+                shift_uses_vy: true,
+                ..Quirks::default()
+            },
+        }
+    }
+
+    /// Instructions executed per 60 Hz frame to match the speed of the machine
+    pub fn tickrate(self) -> u32 {
+        match self {
+            Machine::VipChip8 => 15,
+            Machine::Chip48 | Machine::SchipLegacy | Machine::SchipModern => 30,
+            Machine::XoChip => 100,
+        }
+    }
+
+    /// Hexadecimal font of the machine, the VIP glyphs differ from later ones
+    pub fn font(self) -> &'static [u8; 80] {
+        match self {
+            Machine::VipChip8 => &VIP_FONT,
+            _ => &DEFAULT_FONT,
+        }
+    }
+}
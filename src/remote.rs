@@ -0,0 +1,339 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Range;
+
+use cpu_caller::{Access, Checkpointer, Expr, OpcodePattern, StopReason, Symbols, CPU};
+
+use crate::cli::{parse_address, Options, Source};
+use crate::debugger::{self, CHECKPOINTS, CHECKPOINT_INTERVAL};
+use crate::json::Json;
+
+/// Most instructions a `step` request executes, about a second of the fastest programs
+const MAX_STEPS: u64 = 1_000_000;
+
+/// Most frames a `run` request runs, a minute of the program
+const MAX_FRAMES: u64 = 60 * 60;
+
+/// Listens on `port` for tools controlling the program at `path` with one
+/// JSON request per line, like `{"id": 1, "command": "step", "count": 10}`.
+/// Each gets one line back, `{"id": 1, "ok": true, "result": ...}` or
+/// `{"id": 1, "ok": false, "error": "..."}`. The program only runs when a
+/// request says so.
+///
+/// The commands are `state`, `step` with a `count`, `back` to go back `count`
+/// instructions, `run` for a number of `frames`, `break` at an `addr` with an
+/// optional `condition` or on an `opcode` pattern like `D___`, `delete`,
+/// `breakpoints`, `watch` a `len` of bytes at `addr` for `read`, `write` or
+/// any `access`, `read` and `write` memory, `set` a register, `key` to press
+/// or release one, `display` and `quit`. `step` and `run` are limited by
+/// `MAX_STEPS` and `MAX_FRAMES`, and memory past the end is an error
+pub fn run(path: &str, port: u16, options: &Options) {
+    let mut cpu = crate::build_cpu(&Source::File(path.to_string()), options);
+    let symbols = crate::load_symbols(path, options);
+    let mut checkpoints = Checkpointer::new(CHECKPOINT_INTERVAL, CHECKPOINTS);
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("error: can't listen on port {}: {}", port, e);
+            std::process::exit(1);
+        }
+    };
+    println!("waiting for a client on port {}", port);
+
+    for stream in listener.incoming() {
+        match stream.and_then(|stream| serve(&mut cpu, &mut checkpoints, &symbols, stream)) {
+            Ok(true) => break, // asked to quit
+            Ok(false) => println!("the client left, waiting for another one"),
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+}
+
+/// Answers the requests of one connection, returns whether the client asked to quit
+fn serve(cpu: &mut CPU, checkpoints: &mut Checkpointer, symbols: &Symbols, stream: TcpStream) -> io::Result<bool> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (id, command, result) = match Json::parse(&line) {
+            Ok(request) => {
+                let command = request.get("command").as_str().unwrap_or("").to_string();
+                (request.get("id").clone(), command.clone(), answer(cpu, checkpoints, symbols, &command, &request))
+            }
+            Err(e) => (Json::Null, String::new(), Err(format!("not JSON: {}", e))),
+        };
+        let reply = match result {
+            Ok(result) => Json::object([("id", id), ("ok", true.into()), ("result", result)]),
+            Err(error) => Json::object([("id", id), ("ok", false.into()), ("error", error.into())]),
+        };
+        writeln!(writer, "{}", reply)?;
+        if command == "quit" {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Result of a request, or why it failed
+fn answer(cpu: &mut CPU, checkpoints: &mut Checkpointer, symbols: &Symbols, command: &str, request: &Json) -> Result<Json, String> {
+    let address = |key: &str| address(symbols, request.get(key)).ok_or_else(|| format!("`{}` needs an address for `{}`", command, key));
+    match command {
+        "state" => Ok(state(cpu)),
+        "step" => {
+            let count = at_most(request, "count", MAX_STEPS)?;
+            let reason = debugger::step(cpu, checkpoints, count as usize).unwrap_or(StopReason::InstructionBudgetExhausted);
+            Ok(stopped(cpu, reason))
+        }
+        "back" => {
+            debugger::step_back(cpu, checkpoints, request.get("count").as_u64().unwrap_or(1))?;
+            Ok(state(cpu))
+        }
+        "run" => {
+            // a frame is a 60th of a second of the program
+            let frames = at_most(request, "frames", MAX_FRAMES)?;
+            let mut reason = StopReason::InstructionBudgetExhausted;
+            for _ in 0..frames {
+                checkpoints.record(cpu);
+                match cpu.advance(cpu.instructions_per_frame() as usize).stopped {
+                    StopReason::InstructionBudgetExhausted | StopReason::WaitingForVblank | StopReason::WaitingForKey => {}
+                    stopped => {
+                        reason = stopped;
+                        break;
+                    }
+                }
+            }
+            Ok(stopped(cpu, reason))
+        }
+        "break" if request.get("opcode") != &Json::Null => {
+            let pattern = request.get("opcode").as_str().ok_or("`opcode` needs a pattern like \"D___\"")?;
+            cpu.add_opcode_breakpoint(OpcodePattern::parse(pattern)?);
+            Ok(Json::Null)
+        }
+        "break" => {
+            let addr = address("addr")?;
+            match request.get("condition").as_str() {
+                Some(condition) => cpu.add_breakpoint_if(addr, Expr::parse(condition)?),
+                None => cpu.add_breakpoint(addr),
+            }
+            Ok(Json::Null)
+        }
+        "delete" => match (request.get("addr"), request.get("opcode")) {
+            (Json::Null, Json::Null) => {
+                cpu.clear_breakpoints();
+                cpu.clear_opcode_breakpoints();
+                Ok(Json::Null)
+            }
+            (Json::Null, pattern) => {
+                let pattern = OpcodePattern::parse(pattern.as_str().unwrap_or(""))?;
+                if !cpu.remove_opcode_breakpoint(pattern) {
+                    return Err("there's no breakpoint on that opcode".to_string());
+                }
+                Ok(Json::Null)
+            }
+            _ if cpu.remove_breakpoint(address("addr")?) => Ok(Json::Null),
+            _ => Err("there's no breakpoint there".to_string()),
+        },
+        "breakpoints" => {
+            // addresses, then opcode patterns
+            let addresses = cpu.breakpoints().map(Json::from);
+            let patterns = cpu.opcode_breakpoints().map(|pattern| Json::from(pattern.to_string()));
+            Ok(addresses.chain(patterns).collect::<Vec<_>>().into())
+        }
+        "watch" => {
+            let addr = address("addr")?;
+            let len = request.get("len").as_u64().unwrap_or(1).max(1) as usize;
+            let access = match request.get("access").as_str().unwrap_or("write") {
+                "read" => Access::Read,
+                "write" => Access::Write,
+                "access" => Access::Any,
+                other => return Err(format!("unknown access `{}`, expected read, write or access", other)),
+            };
+            cpu.add_watchpoint(in_memory(cpu, addr, len)?, access);
+            Ok(Json::Null)
+        }
+        "read" => {
+            let addr = address("addr")?;
+            let len = request.get("len").as_u64().unwrap_or(1) as usize;
+            in_memory(cpu, addr, len)?;
+            let bytes = cpu.read_memory(addr, len).map_err(|e| e.to_string())?;
+            Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>().into())
+        }
+        "write" => {
+            let addr = address("addr")?;
+            let hex = request.get("bytes").as_str().ok_or("`write` needs the hexadecimal `bytes` to write")?;
+            let bytes = cpu_caller::parse_hex(hex).map_err(|e| e.to_string())?;
+            in_memory(cpu, addr, bytes.len())?;
+            cpu.write_memory(addr, &bytes).map_err(|e| e.to_string())?;
+            Ok(Json::Null)
+        }
+        "set" => {
+            let name = request.get("name").as_str().unwrap_or("").to_ascii_lowercase();
+            let value = request.get("value").as_u64().ok_or("`set` needs a number for `value`")?;
+            match name.as_str() {
+                "i" => cpu.set_i(value as u16),
+                "pc" => cpu.set_pc(value as usize),
+                "dt" => cpu.set_delay_timer(value as u8),
+                "st" => cpu.set_sound_timer(value as u8),
+                name if name.len() == 2 && name.starts_with('v') => {
+                    let x = u8::from_str_radix(&name[1..], 16).map_err(|_| format!("there's no register `{}`", name))?;
+                    cpu.set_register(x, value as u8);
+                }
+                name => return Err(format!("there's no register `{}`", name)),
+            }
+            Ok(Json::Null)
+        }
+        "key" => {
+            let key = request.get("key").as_u64().filter(|&key| key < 16).ok_or("`key` needs a `key` from 0 to 15")?;
+            cpu.set_key(key as u8, request.get("pressed").as_bool().unwrap_or(true));
+            Ok(Json::Null)
+        }
+        "display" => {
+            // one string of 0 and 1 per row of pixels
+            let width = cpu.display_width();
+            let rows: Vec<Json> = cpu
+                .framebuffer()
+                .chunks(width)
+                .map(|row| row.iter().map(|&on| if on { '1' } else { '0' }).collect::<String>().into())
+                .collect();
+            Ok(Json::object([("width", width.into()), ("height", cpu.display_height().into()), ("rows", rows.into())]))
+        }
+        "quit" => Ok(Json::Null),
+        "" => Err("the request has no `command`".to_string()),
+        command => Err(format!("unknown command `{}`", command)),
+    }
+}
+
+/// Number of `key` of the request, 1 without one
+fn at_most(request: &Json, key: &str, max: u64) -> Result<u64, String> {
+    match request.get(key).as_u64().unwrap_or(1) {
+        n if n > max => Err(format!("`{}` can be at most {}", key, max)),
+        n => Ok(n),
+    }
+}
+
+/// Addresses of `len` bytes from `addr`, when memory has them all
+fn in_memory(cpu: &CPU, addr: usize, len: usize) -> Result<Range<usize>, String> {
+    match addr.checked_add(len) {
+        Some(end) if end <= cpu.memory_size() => Ok(addr..end),
+        _ => Err(format!("{} bytes from {:#x} go past the end of memory", len, addr)),
+    }
+}
+
+/// Number, or text with a number or a name of the symbol file
+fn address(symbols: &Symbols, value: &Json) -> Option<usize> {
+    match value {
+        Json::String(text) => parse_address(text).or_else(|| symbols.address(text)),
+        value => value.as_u64().map(|addr| addr as usize),
+    }
+}
+
+/// Registers, I, PC, the stack, the timers and how many instructions ran
+fn state(cpu: &CPU) -> Json {
+    let registers: Vec<Json> = cpu.registers().iter().map(|&v| Json::from(v as u64)).collect();
+    let stack: Vec<Json> = cpu.stack().iter().map(|&addr| Json::from(addr as u64)).collect();
+    Json::object([
+        ("v", registers.into()),
+        ("i", (cpu.i() as u64).into()),
+        ("pc", cpu.pc().into()),
+        ("stack", stack.into()),
+        ("dt", (cpu.delay_timer() as u64).into()),
+        ("st", (cpu.sound_timer() as u64).into()),
+        ("instructions", cpu.instructions_executed().into()),
+    ])
+}
+
+/// Why `step` or `run` stopped and the state it left
+fn stopped(cpu: &CPU, reason: StopReason) -> Json {
+    let reason = match reason {
+        StopReason::InstructionBudgetExhausted => Json::Null, // it did what it was asked
+        StopReason::Breakpoint(addr) => Json::object([("breakpoint", (addr as u64).into())]),
+        StopReason::OpcodeBreakpoint { pc, opcode } => Json::object([("opcode", (opcode as u64).into()), ("pc", (pc as u64).into())]),
+        StopReason::Watchpoint { pc, addr, .. } => Json::object([("watchpoint", (addr as u64).into()), ("pc", (pc as u64).into())]),
+        StopReason::Error(e) => Json::object([("error", e.to_string().into())]),
+        reason => format!("{:?}", reason).into(),
+    };
+    Json::object([("stopped", reason), ("state", state(cpu))])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answer to the request of the JSON text
+    fn request(cpu: &mut CPU, text: &str) -> Result<Json, String> {
+        let request = Json::parse(text).unwrap();
+        let command = request.get("command").as_str().unwrap_or("").to_string();
+        let mut symbols = Symbols::new();
+        symbols.insert(0x300, "score");
+        answer(cpu, &mut Checkpointer::new(CHECKPOINT_INTERVAL, CHECKPOINTS), &symbols, &command, &request)
+    }
+
+    fn cpu() -> CPU {
+        let mut cpu = CPU::new();
+        // ADD V0, 1 and jump back
+        cpu.load_rom(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn state_and_steps() {
+        let mut cpu = cpu();
+        let result = request(&mut cpu, r#"{"command": "step", "count": 5}"#).unwrap();
+        assert_eq!(result.get("stopped"), &Json::Null);
+        assert_eq!(result.get("state").get("v").as_array()[0], Json::from(3u64));
+        assert_eq!(result.get("state").get("instructions"), &Json::from(5u64));
+
+        let e = request(&mut cpu, r#"{"command": "step", "count": 1e15}"#).unwrap_err();
+        assert_eq!(e, "`count` can be at most 1000000");
+        assert!(request(&mut cpu, r#"{"command": "run", "frames": 1e9}"#).is_err());
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut cpu = cpu();
+        request(&mut cpu, r#"{"command": "break", "addr": "0x202"}"#).unwrap();
+        request(&mut cpu, r#"{"command": "break", "opcode": "D___"}"#).unwrap();
+        let listed = request(&mut cpu, r#"{"command": "breakpoints"}"#).unwrap();
+        assert_eq!(listed, Json::from(vec![Json::from(0x202u64), Json::from("D___")]));
+
+        let result = request(&mut cpu, r#"{"command": "run"}"#).unwrap();
+        assert_eq!(result.get("stopped").get("breakpoint"), &Json::from(0x202u64));
+        request(&mut cpu, r#"{"command": "delete"}"#).unwrap();
+        assert_eq!(request(&mut cpu, r#"{"command": "delete", "addr": 514}"#).unwrap_err(), "there's no breakpoint there");
+    }
+
+    #[test]
+    fn memory() {
+        let mut cpu = cpu();
+        request(&mut cpu, r#"{"command": "write", "addr": "score", "bytes": "0102"}"#).unwrap();
+        let bytes = request(&mut cpu, r#"{"command": "read", "addr": 767, "len": 4}"#).unwrap();
+        assert_eq!(bytes, Json::from("00010200"));
+
+        let huge = [
+            r#"{"command": "read", "addr": 0, "len": 18446744073709551615}"#,
+            r#"{"command": "read", "addr": 4095, "len": 2}"#,
+            r#"{"command": "watch", "addr": 18446744073709551615, "len": 2}"#,
+            r#"{"command": "write", "addr": 18446744073709551615, "bytes": "01"}"#,
+        ];
+        for text in huge {
+            assert!(request(&mut cpu, text).unwrap_err().contains("past the end of memory"), "{}", text);
+        }
+        assert!(cpu.watchpoints().next().is_none());
+
+        request(&mut cpu, r#"{"command": "watch", "addr": "0x300", "len": 2, "access": "read"}"#).unwrap();
+        assert_eq!(cpu.watchpoints().collect::<Vec<_>>(), [(0x300..0x302, Access::Read)]);
+    }
+
+    #[test]
+    fn mistakes() {
+        let mut cpu = cpu();
+        assert_eq!(request(&mut cpu, "{}").unwrap_err(), "the request has no `command`");
+        assert_eq!(request(&mut cpu, r#"{"command": "fly"}"#).unwrap_err(), "unknown command `fly`");
+        assert!(request(&mut cpu, r#"{"command": "key", "key": 16}"#).is_err());
+        assert!(request(&mut cpu, r#"{"command": "set", "name": "vg", "value": 1}"#).is_err());
+        request(&mut cpu, r#"{"command": "set", "name": "VA", "value": 7}"#).unwrap();
+        assert_eq!(cpu.register(0xA), 7);
+    }
+}
@@ -0,0 +1,88 @@
+use std::io::{self, BufRead, Write};
+
+use cpu_caller::{assemble, StepOutcome, CPU, PROGRAM_START};
+
+use crate::cli::Options;
+
+const HELP: &str = "\
+type an instruction like `LD V0, 5` or an opcode like `6005`, it runs right away
+    .reset   power the CPU off and on again
+    .quit    leave, like end of input";
+
+/// Reads instructions from standard input and executes each one at the
+/// program counter of a CPU that keeps its state between them
+pub fn run(options: &Options) {
+    let mut cpu = match options.machine {
+        Some(machine) => CPU::for_machine(machine),
+        None => CPU::new(),
+    };
+    if let Some(variant) = options.variant {
+        cpu.set_variant(variant);
+    }
+    cpu.set_pc(PROGRAM_START);
+
+    println!("{}", HELP);
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("{:#05x}> ", cpu.pc());
+        let _ = io::stdout().flush();
+        let Some(Ok(line)) = lines.next() else {
+            break;
+        };
+
+        match line.trim() {
+            "" => {}
+            ".quit" => break,
+            ".reset" => {
+                cpu.reset(false);
+                print_state(&cpu);
+            }
+            ".help" => println!("{}", HELP),
+            text => match execute(&mut cpu, text) {
+                Ok(()) => print_state(&cpu),
+                Err(e) => println!("error: {}", e),
+            },
+        }
+    }
+}
+
+/// Puts the instruction at the program counter and executes it
+fn execute(cpu: &mut CPU, text: &str) -> Result<(), String> {
+    let bytes = opcode(text).map_or_else(|| assemble(text).map_err(|e| e.message), Ok)?;
+    if bytes.is_empty() {
+        return Ok(()); // a comment or a label
+    }
+
+    cpu.write_memory(cpu.pc(), &bytes).map_err(|e| e.to_string())?;
+    match cpu.step() {
+        Ok(StepOutcome::Continue) => Ok(()),
+        Ok(StepOutcome::Stopped(reason)) => Err(format!("stopped: {:?}", reason)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Bytes of an opcode typed in hexadecimal, 4 digits or 8 for `F000 NNNN`
+fn opcode(text: &str) -> Option<Vec<u8>> {
+    let digits: String = text.trim_start_matches("0x").split_whitespace().collect();
+    if !matches!(digits.len(), 4 | 8) || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..digits.len()).step_by(2).map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok()).collect()
+}
+
+/// Prints the registers, I, the timers and the stack
+pub fn print_state(cpu: &CPU) {
+    let registers: Vec<String> = cpu.registers().iter().enumerate().map(|(x, v)| format!("V{:X}={:02X}", x, v)).collect();
+    println!("{}", registers[..8].join(" "));
+    println!("{}", registers[8..].join(" "));
+    let stack: Vec<String> = cpu.stack()[..cpu.stack_pointer()].iter().map(|addr| format!("{:#05x}", addr)).collect();
+    println!(
+        "I={:#05x} PC={:#05x} DT={} ST={} stack=[{}]",
+        cpu.i(),
+        cpu.pc(),
+        cpu.delay_timer(),
+        cpu.sound_timer(),
+        stack.join(", ")
+    );
+}
@@ -0,0 +1,49 @@
+/// Source of random numbers for the CXNN opcode
+pub trait Rng {
+    /// Returns the next random byte
+    fn next_u8(&mut self) -> u8;
+
+    /// Whatever decides the bytes that come next, `None` when it can't be
+    /// saved. Restoring a snapshot of a CPU only repeats its random numbers
+    /// when its generator has a state
+    fn state(&self) -> Option<u64> {
+        None
+    }
+
+    /// Goes back to a state returned by `state`
+    fn set_state(&mut self, _state: u64) {}
+}
+
+/// Small xorshift generator
+pub struct XorShiftRng {
+    state: u32,
+}
+
+impl XorShiftRng {
+    /// Creates a generator, the same seed always yields the same sequence
+    pub fn new(seed: u32) -> Self {
+        // xorshift gets stuck on zero so replace it with any other value
+        let state = if seed == 0 { 0x2545_F491 } else { seed };
+        XorShiftRng { state }
+    }
+}
+
+impl Rng for XorShiftRng {
+    fn next_u8(&mut self) -> u8 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+
+        (x >> 24) as u8 // the high bits are the most random ones
+    }
+
+    fn state(&self) -> Option<u64> {
+        Some(self.state as u64)
+    }
+
+    fn set_state(&mut self, state: u64) {
+        self.state = state as u32;
+    }
+}
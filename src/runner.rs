@@ -0,0 +1,216 @@
+use std::cell::Cell;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::rc::Rc;
+
+use cpu_caller::{Machine, StopReason, CPU};
+
+use crate::cli::Options;
+use crate::config::{number, strip_comment, string, unquote};
+
+/// File of a test directory with the expectations of its ROMs
+pub const MANIFEST: &str = "tests.toml";
+
+/// Instructions executed when the manifest doesn't say
+const DEFAULT_BUDGET: u64 = 1_000_000;
+
+/// What a ROM has to do to pass, from its section of the manifest:
+///
+/// ```toml
+/// [ibm-logo.ch8]
+/// instructions = 200          # how long it runs at most
+/// machine = "vip"
+/// reached = 0x228             # stops as soon as the PC gets there
+/// framebuffer = "5d2ecf71a1c3b04e"
+/// V0 = 0x05                   # same for V1 to VF and I
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Expectations {
+    budget: Option<u64>,
+    machine: Option<Machine>,
+    reached: Option<usize>,
+    framebuffer: Option<u64>,
+    registers: Vec<(u8, u8)>,
+    i: Option<u16>,
+}
+
+/// How a ROM did
+struct Outcome {
+    name: String,
+    executed: u64,
+    failures: Vec<String>, // empty when the ROM passed
+}
+
+/// Runs each ROM of `dir` and checks what `tests.toml` expects from it,
+/// exits with 1 if any of them fails
+pub fn run(dir: &str, options: &Options) {
+    let manifest = Path::new(dir).join(MANIFEST);
+    let expectations = match fs::read_to_string(&manifest) {
+        Ok(text) => parse_manifest(&text).unwrap_or_else(|e| {
+            eprintln!("error: {}: {}", manifest.display(), e);
+            process::exit(2);
+        }),
+        Err(_) => Vec::new(), // every ROM only has to run without errors
+    };
+
+    let mut roms: Vec<String> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| [".ch8", ".c8", ".sc8", ".xo8", ".hex"].iter().any(|ext| name.ends_with(ext)))
+            .collect(),
+        Err(e) => {
+            eprintln!("error: can't read `{}`: {}", dir, e);
+            process::exit(2);
+        }
+    };
+    roms.sort();
+    for (name, _) in &expectations {
+        if !roms.contains(name) {
+            roms.push(name.clone()); // reported as missing
+        }
+    }
+
+    let outcomes: Vec<Outcome> = roms
+        .iter()
+        .map(|name| {
+            let expected = expectations.iter().find(|(rom, _)| rom == name).map(|(_, e)| e.clone()).unwrap_or_default();
+            run_rom(&Path::new(dir).join(name), name, &expected, options)
+        })
+        .collect();
+
+    let width = outcomes.iter().map(|o| o.name.len()).max().unwrap_or(0).max(3);
+    println!("{:<width$}  result  instructions  notes", "ROM", width = width);
+    for outcome in &outcomes {
+        let result = if outcome.failures.is_empty() { "pass" } else { "FAIL" };
+        let notes = outcome.failures.join("; ");
+        println!("{:<width$}  {:<6}  {:>12}  {}", outcome.name, result, outcome.executed, notes, width = width);
+    }
+
+    let failed = outcomes.iter().filter(|o| !o.failures.is_empty()).count();
+    println!("\n{} passed, {} failed", outcomes.len() - failed, failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+/// Executes one ROM and compares its final state with the expectations
+fn run_rom(path: &Path, name: &str, expected: &Expectations, options: &Options) -> Outcome {
+    let fail = |executed, failure: String| Outcome { name: name.to_string(), executed, failures: vec![failure] };
+
+    let mut cpu = match expected.machine.or(options.machine) {
+        Some(machine) => CPU::for_machine(machine),
+        None => CPU::new(),
+    };
+    if let Some(variant) = options.variant {
+        cpu.set_variant_override(variant);
+    }
+    if let Err(e) = cpu.load_rom_file(path) {
+        return fail(0, format!("can't load: {}", e));
+    }
+
+    let reached = Rc::new(Cell::new(false));
+    if let Some(target) = expected.reached {
+        let reached = reached.clone();
+        cpu.on_fetch(move |addr, _| reached.set(reached.get() || addr as usize == target));
+    }
+
+    // a frame at a time so that the timers go on as they would on screen
+    let budget = expected.budget.unwrap_or(DEFAULT_BUDGET);
+    let mut failures = Vec::new();
+    while cpu.instructions_executed() < budget && !reached.get() {
+        let frame = (cpu.instructions_per_frame() as u64).min(budget - cpu.instructions_executed());
+        match cpu.advance(frame as usize).stopped {
+            StopReason::InstructionBudgetExhausted | StopReason::WaitingForVblank => {}
+            StopReason::Error(e) => {
+                failures.push(format!("error: {}", e));
+                break;
+            }
+            _ => break, // halted, exited or waiting for a key that won't come
+        }
+    }
+    let executed = cpu.instructions_executed();
+
+    if expected.reached.is_some() && !reached.get() {
+        failures.push(format!("never reached {:#05x}", expected.reached.unwrap_or_default()));
+    }
+    for &(x, value) in &expected.registers {
+        if cpu.register(x) != value {
+            failures.push(format!("V{:X} is {:#04x}, expected {:#04x}", x, cpu.register(x), value));
+        }
+    }
+    if let Some(i) = expected.i.filter(|&i| i != cpu.i()) {
+        failures.push(format!("I is {:#05x}, expected {:#05x}", cpu.i(), i));
+    }
+    if let Some(hash) = expected.framebuffer {
+        let actual = framebuffer_hash(&cpu);
+        if actual != hash {
+            failures.push(format!("framebuffer is {:016x}, expected {:016x}", actual, hash));
+        }
+    }
+
+    Outcome { name: name.to_string(), executed, failures }
+}
+
+/// FNV-1a hash of the size and pixels of the display, the same picture gives
+/// the same hash on every machine
+pub fn framebuffer_hash(cpu: &CPU) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let size = [cpu.display_width() as u8, cpu.display_height() as u8];
+    let pixels = cpu.framebuffer().iter().map(|&on| on as u8);
+    for byte in size.into_iter().chain(pixels) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Reads the sections of the manifest, in file order
+fn parse_manifest(text: &str) -> Result<Vec<(String, Expectations)>, String> {
+    let mut roms: Vec<(String, Expectations)> = Vec::new();
+
+    for (index, raw) in text.lines().enumerate() {
+        let error = |message: String| format!("line {}: {}", index + 1, message);
+        let content = strip_comment(raw);
+        if content.is_empty() {
+            continue;
+        }
+
+        if let Some(header) = content.strip_prefix('[') {
+            let name = header.strip_suffix(']').ok_or_else(|| error("missing `]`".to_string()))?;
+            roms.push((unquote(name.trim()).to_string(), Expectations::default()));
+            continue;
+        }
+
+        let (key, value) = content.split_once('=').ok_or_else(|| error("expected `key = value`".to_string()))?;
+        let (key, value) = (unquote(key.trim()), value.trim());
+        let Some((_, expected)) = roms.last_mut() else {
+            return Err(error("settings go in the `[rom]` section they are about".to_string()));
+        };
+        let numeric = |max: u32| {
+            number(value).filter(|&n| n <= max).ok_or_else(|| error(format!("`{}` is not a number up to {:#x}", value, max)))
+        };
+
+        match key {
+            "instructions" => expected.budget = Some(numeric(u32::MAX)? as u64),
+            "reached" => expected.reached = Some(numeric(0xFFFF)? as usize),
+            "I" => expected.i = Some(numeric(0xFFFF)? as u16),
+            "machine" => {
+                let name = string(value).map_err(error)?;
+                expected.machine = Some(Machine::from_name(name).ok_or_else(|| error(format!("unknown machine `{}`", name)))?);
+            }
+            "framebuffer" => {
+                let hash = string(value).map_err(error)?;
+                let hash = u64::from_str_radix(hash, 16).map_err(|_| error(format!("`{}` is not a hash", hash)))?;
+                expected.framebuffer = Some(hash);
+            }
+            register if register.len() == 2 && register.starts_with('V') => {
+                let x = u8::from_str_radix(&register[1..], 16).map_err(|_| error(format!("unknown register `{}`", register)))?;
+                expected.registers.push((x, numeric(0xFF)? as u8));
+            }
+            key => return Err(error(format!("unknown setting `{}`", key))),
+        }
+    }
+
+    Ok(roms)
+}
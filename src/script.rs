@@ -0,0 +1,165 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Map, Scope, AST, INT};
+
+use cpu_caller::CPU;
+
+/// Debugger script written in rhai. Its statements run once when it's loaded
+/// and the debugger calls its `on_break` and `on_step` functions, which see
+/// the program through functions like `v(3)` or `mem(0x300)` and keep what
+/// they want between calls in the map `this`
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    machine: Rc<RefCell<CPU>>, // the CPU of the debugger while the script runs, a spare one otherwise
+    state: Dynamic,            // `this` of the functions
+}
+
+impl Script {
+    /// Compiles the script at `path` and runs its statements on `cpu`
+    pub fn load(path: &str, cpu: &mut CPU) -> Result<Script, String> {
+        let machine = Rc::new(RefCell::new(CPU::new()));
+        let engine = engine(&machine);
+        let ast = engine.compile_file(path.into()).map_err(|e| format!("can't load `{}`: {}", path, e))?;
+        let script = Script { engine, ast, machine, state: Dynamic::from(Map::new()) };
+        script.with(cpu, |engine, ast| engine.run_ast(ast)).map_err(|e| format!("`{}`: {}", path, e))?;
+        Ok(script)
+    }
+
+    /// Whether the script has a function `name` without parameters
+    pub fn has(&self, name: &str) -> bool {
+        self.ast.iter_functions().any(|function| function.name == name && function.params.is_empty())
+    }
+
+    /// Calls the function `name` of the script on `cpu`. What it gives back
+    /// tells whether the program goes on, `None` when it isn't `true` or `false`
+    pub fn call(&mut self, name: &str, cpu: &mut CPU) -> Result<Option<bool>, String> {
+        let mut state = std::mem::take(&mut self.state);
+        let result = self.with(cpu, |engine, ast| {
+            let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(&mut state);
+            engine.call_fn_with_options::<Dynamic>(options, &mut Scope::new(), ast, name, ())
+        });
+        self.state = state;
+        result.map(|verdict| verdict.as_bool().ok()).map_err(|e| format!("`{}`: {}", name, e))
+    }
+
+    /// Lends `cpu` to the functions of the engine while `run` runs
+    fn with<T>(&self, cpu: &mut CPU, run: impl FnOnce(&Engine, &AST) -> T) -> T {
+        std::mem::swap(cpu, &mut self.machine.borrow_mut());
+        let result = run(&self.engine, &self.ast);
+        std::mem::swap(cpu, &mut self.machine.borrow_mut());
+        result
+    }
+}
+
+/// Engine with the functions scripts read and change the CPU with, over the
+/// same names as the conditions of `break`
+fn engine(machine: &Rc<RefCell<CPU>>) -> Engine {
+    let mut engine = Engine::new();
+    let cpu = machine.clone();
+    engine.register_fn("pc", move || cpu.borrow().pc() as INT);
+    let cpu = machine.clone();
+    engine.register_fn("set_pc", move |addr: INT| cpu.borrow_mut().set_pc(addr as usize));
+    let cpu = machine.clone();
+    engine.register_fn("v", move |x: INT| cpu.borrow().register(x as u8) as INT);
+    let cpu = machine.clone();
+    engine.register_fn("set_v", move |x: INT, value: INT| cpu.borrow_mut().set_register(x as u8, value as u8));
+    let cpu = machine.clone();
+    engine.register_fn("i", move || cpu.borrow().i() as INT);
+    let cpu = machine.clone();
+    engine.register_fn("set_i", move |value: INT| cpu.borrow_mut().set_i(value as u16));
+    let cpu = machine.clone();
+    engine.register_fn("sp", move || cpu.borrow().stack_pointer() as INT);
+    let cpu = machine.clone();
+    engine.register_fn("dt", move || cpu.borrow().delay_timer() as INT);
+    let cpu = machine.clone();
+    engine.register_fn("set_dt", move |value: INT| cpu.borrow_mut().set_delay_timer(value as u8));
+    let cpu = machine.clone();
+    engine.register_fn("st", move || cpu.borrow().sound_timer() as INT);
+    let cpu = machine.clone();
+    engine.register_fn("set_st", move |value: INT| cpu.borrow_mut().set_sound_timer(value as u8));
+    let cpu = machine.clone();
+    engine.register_fn("instructions", move || cpu.borrow().instructions_executed() as INT);
+    let cpu = machine.clone();
+    engine.register_fn("mem", move |addr: INT| -> Result<INT, Box<EvalAltResult>> {
+        let bytes = cpu.borrow().read_memory(address(addr)?, 1).map_err(|e| e.to_string())?;
+        Ok(bytes[0] as INT)
+    });
+    let cpu = machine.clone();
+    engine.register_fn("set_mem", move |addr: INT, value: INT| -> Result<(), Box<EvalAltResult>> {
+        cpu.borrow_mut().write_memory(address(addr)?, &[value as u8]).map_err(|e| e.to_string().into())
+    });
+    let cpu = machine.clone();
+    engine.register_fn("breakpoint", move |addr: INT| -> Result<(), Box<EvalAltResult>> {
+        cpu.borrow_mut().add_breakpoint(address(addr)?);
+        Ok(())
+    });
+    engine
+}
+
+fn address(addr: INT) -> Result<usize, Box<EvalAltResult>> {
+    usize::try_from(addr).map_err(|_| format!("{} is not an address", addr).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cpu_caller::PROGRAM_START;
+
+    /// Script of the text, written to a file like the ones `source` reads
+    fn script(name: &str, text: &str, cpu: &mut CPU) -> Result<Script, String> {
+        let path = std::env::temp_dir().join(format!("cpu-caller-{}-{}.rhai", name, std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let script = Script::load(path.to_str().unwrap(), cpu);
+        std::fs::remove_file(&path).unwrap();
+        script
+    }
+
+    #[test]
+    fn statements_run_when_loaded() {
+        let mut cpu = CPU::new();
+        cpu.load_rom(&[0x60, 0x05, 0x12, 0x00]).unwrap();
+        script("load", "breakpoint(0x202); set_v(3, v(3) + 7);", &mut cpu).unwrap();
+        assert_eq!(cpu.register(3), 7);
+        assert_eq!(cpu.breakpoints().collect::<Vec<_>>(), [0x202]);
+    }
+
+    #[test]
+    fn functions_decide() {
+        let mut cpu = CPU::new();
+        cpu.load_rom(&[0x60, 0x05]).unwrap();
+        let text = "
+            fn on_break() { set_mem(0x300, mem(pc()) + 1); v(0) == 0 }
+            fn on_step() { print(\"not a verdict\"); }
+        ";
+        let mut script = script("decide", text, &mut cpu).unwrap();
+        assert!(script.has("on_break"));
+        assert!(!script.has("on_fault"));
+        assert_eq!(script.call("on_break", &mut cpu), Ok(Some(true)));
+        assert_eq!(cpu.read_memory(0x300, 1).unwrap(), [0x61]);
+        assert_eq!(script.call("on_step", &mut cpu), Ok(None));
+        assert_eq!(cpu.pc(), PROGRAM_START);
+    }
+
+    #[test]
+    fn this_is_kept() {
+        let mut cpu = CPU::new();
+        let text = "fn on_step() { this.steps = (this.steps ?? 0) + 1; this.steps < 3 }";
+        let mut script = script("this", text, &mut cpu).unwrap();
+        assert_eq!(script.call("on_step", &mut cpu), Ok(Some(true)));
+        assert_eq!(script.call("on_step", &mut cpu), Ok(Some(true)));
+        assert_eq!(script.call("on_step", &mut cpu), Ok(Some(false)));
+    }
+
+    #[test]
+    fn errors_keep_the_cpu() {
+        let mut cpu = CPU::new();
+        assert!(script("syntax", "fn on_break( {", &mut cpu).is_err());
+        cpu.set_register(1, 9);
+        let mut script = script("errors", "fn on_break() { mem(-1) }", &mut cpu).unwrap();
+        assert!(script.call("on_break", &mut cpu).is_err());
+        assert_eq!(cpu.register(1), 9);
+    }
+}
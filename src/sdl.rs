@@ -0,0 +1,176 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+use cpu_caller::{AudioSink, Framebuffer, Renderer, StopReason, CPU};
+
+use crate::cli::{Options, Scaling};
+use crate::config::Palette;
+use crate::frontend;
+
+/// Loudness of the tone, out of 1
+const VOLUME: f32 = 0.1;
+
+/// Draws the frames in the middle of the window, at the size `--scale`,
+/// `--fit` or `--stretch` give them
+struct WindowRenderer {
+    canvas: Canvas<Window>,
+    palette: Palette,
+    scaling: Scaling,
+}
+
+impl Renderer for WindowRenderer {
+    fn render(&mut self, framebuffer: &Framebuffer) {
+        let (width, height) = (framebuffer.width(), framebuffer.height());
+        let creator = self.canvas.texture_creator();
+        let Ok(mut texture) = creator.create_texture_streaming(PixelFormatEnum::RGB24, width as u32, height as u32) else {
+            return;
+        };
+        let _ = texture.with_lock(None, |bytes, pitch| {
+            for (i, color) in frontend::colors(framebuffer, &self.palette).enumerate() {
+                let at = i / width * pitch + i % width * 3;
+                bytes[at..at + 3].copy_from_slice(&color);
+            }
+        });
+
+        let Ok(window) = self.canvas.output_size() else {
+            return;
+        };
+        let (picture_width, picture_height) = frontend::picture_size(self.scaling, (width, height), window);
+        let x = (window.0 as i32 - picture_width as i32) / 2;
+        let y = (window.1 as i32 - picture_height as i32) / 2;
+        let [r, g, b] = self.palette.background;
+        self.canvas.set_draw_color(Color::RGB(r, g, b));
+        self.canvas.clear();
+        let _ = self.canvas.copy(&texture, None, Rect::new(x, y, picture_width, picture_height));
+        self.canvas.present();
+    }
+}
+
+/// Renderer the CPU and the window share, the window draws the last frame
+/// again after it was resized
+struct Shared(Rc<RefCell<WindowRenderer>>);
+
+impl Renderer for Shared {
+    fn render(&mut self, framebuffer: &Framebuffer) {
+        self.0.borrow_mut().render(framebuffer);
+    }
+}
+
+/// Square wave playing the 128 bits of the audio pattern in a loop
+struct Tone {
+    pattern: [u8; 16],
+    frequency: f64, // bits per second
+    sample_rate: f64,
+    position: f64, // bit being played, with the fraction of it already played
+}
+
+impl AudioCallback for Tone {
+    type Channel = f32;
+
+    fn callback(&mut self, samples: &mut [f32]) {
+        for sample in samples {
+            let bit = self.position as usize;
+            let high = self.pattern[bit / 8] & (0x80 >> (bit % 8)) != 0;
+            *sample = if high { VOLUME } else { -VOLUME };
+            self.position = (self.position + self.frequency / self.sample_rate) % 128.0;
+        }
+    }
+}
+
+/// Speaker of the CPU, the audio device is paused while silent
+struct Speaker(AudioDevice<Tone>);
+
+impl AudioSink for Speaker {
+    fn play(&mut self, pattern: &[u8; 16], frequency: f64) {
+        {
+            let mut tone = self.0.lock();
+            tone.pattern = *pattern;
+            tone.frequency = frequency;
+        }
+        self.0.resume();
+    }
+
+    fn stop(&mut self) {
+        self.0.pause();
+    }
+}
+
+/// Runs the program 60 frames per second in an SDL window, with the keys of
+/// the keyboard going to the program and the tone played on the speakers.
+/// Gives back what stopped it, or `None` when the window was closed or
+/// Escape pressed
+pub fn run(cpu: &mut CPU, options: &Options) -> Result<Option<StopReason>, String> {
+    let sdl = sdl2::init()?;
+    let video = sdl.video()?;
+    let (width, height) = frontend::window_size(options.scaling);
+    let window = video.window("cpu-caller", width, height).position_centered().resizable().build().map_err(|e| e.to_string())?;
+    let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+    let renderer = Rc::new(RefCell::new(WindowRenderer { canvas, palette: options.palette, scaling: options.scaling }));
+    renderer.borrow_mut().render(cpu.screen());
+    cpu.set_renderer(Box::new(Shared(renderer.clone())));
+
+    // a machine without sound still runs
+    let spec = AudioSpecDesired { freq: Some(44_100), channels: Some(1), samples: None };
+    let device = sdl.audio().and_then(|audio| {
+        audio.open_playback(None, &spec, |spec| Tone {
+            pattern: *cpu.audio_pattern(),
+            frequency: cpu.audio_frequency(),
+            sample_rate: spec.freq as f64,
+            position: 0.0,
+        })
+    });
+    if let Ok(device) = device {
+        cpu.set_audio_sink(Box::new(Speaker(device)));
+    }
+
+    let mut events = sdl.event_pump()?;
+    let reason = frontend::run_frames(cpu, |cpu| {
+        for event in events.poll_iter() {
+            match event {
+                Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::ESCAPE), .. } => return false,
+                Event::KeyDown { keycode: Some(keycode), repeat: false, .. } => {
+                    if let Some(key) = chip8_key(options, keycode) {
+                        cpu.set_key(key, true);
+                    }
+                }
+                Event::KeyUp { keycode: Some(keycode), .. } => {
+                    if let Some(key) = chip8_key(options, keycode) {
+                        cpu.set_key(key, false);
+                    }
+                }
+                Event::Window { win_event: WindowEvent::SizeChanged(..) | WindowEvent::Exposed, .. } => {
+                    renderer.borrow_mut().render(cpu.screen());
+                }
+                _ => {}
+            }
+        }
+        true
+    });
+
+    if reason.is_some() {
+        // the last picture stays until the window is closed or a key pressed
+        cpu.set_audio_sink(Box::new(cpu_caller::Null));
+        loop {
+            match events.wait_event() {
+                Event::Quit { .. } | Event::KeyDown { .. } => break,
+                _ => {}
+            }
+        }
+    }
+    Ok(reason)
+}
+
+/// CHIP-8 key of a key of the keyboard, the keycodes of SDL are the
+/// characters the keys type
+fn chip8_key(options: &Options, keycode: Keycode) -> Option<u8> {
+    let c = char::from_u32(keycode.into_i32() as u32)?;
+    frontend::chip8_key(&options.keys, c)
+}
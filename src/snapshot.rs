@@ -0,0 +1,215 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::CPU;
+
+/// Copy of the state a program can observe, taken between two instructions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub registers: [u8; 16],
+    pub i: u16,
+    pub pc: usize,
+    pub stack: Vec<u16>, // return addresses, the innermost call last
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub instructions_executed: u64,
+    pub memory: Vec<u8>, // whole address space, as read without side effects
+    pub(crate) internals: Internals,
+}
+
+/// Rest of the machine, what decides what happens next without being
+/// something a debugger shows, so that `CPU::restore` gives back the same CPU
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Internals {
+    pub planes: Vec<bool>, // one plane after the other, at the high resolution size
+    pub plane_mask: u8,
+    pub hires: bool,
+    pub rpl_flags: [u8; 16],
+    pub audio_pattern: [u8; 16],
+    pub pitch: u8,
+    pub keypad: [bool; 16],
+    pub waiting_for_key: Option<u8>,
+    pub pending_key: Option<u8>,
+    pub waiting_for_vblank: bool,
+    pub cycles_since_tick: u32,
+    pub rng: Option<u64>, // `None` for generators that can't be saved
+}
+
+/// Something that differs between two snapshots, with its value in the first
+/// one and in the second one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Register { x: u8, before: u8, after: u8 },
+    I { before: u16, after: u16 },
+    Pc { before: usize, after: usize },
+    Stack { before: Vec<u16>, after: Vec<u16> },
+    DelayTimer { before: u8, after: u8 },
+    SoundTimer { before: u8, after: u8 },
+    Memory { addr: usize, before: u8, after: u8 },
+}
+
+/// Like `V3 05->07` or `[0x2ea] 00->ff`
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Register { x, before, after } => write!(f, "V{:X} {:02x}->{:02x}", x, before, after),
+            Change::I { before, after } => write!(f, "I {:03x}->{:03x}", before, after),
+            Change::Pc { before, after } => write!(f, "PC {:03x}->{:03x}", before, after),
+            Change::Stack { before, after } => write!(f, "stack {:03x?}->{:03x?}", before, after),
+            Change::DelayTimer { before, after } => write!(f, "DT {}->{}", before, after),
+            Change::SoundTimer { before, after } => write!(f, "ST {}->{}", before, after),
+            Change::Memory { addr, before, after } => write!(f, "[{:#05x}] {:02x}->{:02x}", addr, before, after),
+        }
+    }
+}
+
+impl Snapshot {
+    /// What changed from this snapshot to `later`, registers first and then
+    /// memory by address. The internals aren't compared
+    pub fn diff(&self, later: &Snapshot) -> Vec<Change> {
+        let mut changes = Vec::new();
+        for (x, (&before, &after)) in self.registers.iter().zip(&later.registers).enumerate() {
+            if before != after {
+                changes.push(Change::Register { x: x as u8, before, after });
+            }
+        }
+        if self.i != later.i {
+            changes.push(Change::I { before: self.i, after: later.i });
+        }
+        if self.pc != later.pc {
+            changes.push(Change::Pc { before: self.pc, after: later.pc });
+        }
+        if self.stack != later.stack {
+            changes.push(Change::Stack { before: self.stack.clone(), after: later.stack.clone() });
+        }
+        if self.delay_timer != later.delay_timer {
+            changes.push(Change::DelayTimer { before: self.delay_timer, after: later.delay_timer });
+        }
+        if self.sound_timer != later.sound_timer {
+            changes.push(Change::SoundTimer { before: self.sound_timer, after: later.sound_timer });
+        }
+        // memory only one of them has, after XO-CHIP grew it, reads as zero
+        let size = self.memory.len().max(later.memory.len());
+        for addr in 0..size {
+            let before = self.memory.get(addr).copied().unwrap_or(0);
+            let after = later.memory.get(addr).copied().unwrap_or(0);
+            if before != after {
+                changes.push(Change::Memory { addr, before, after });
+            }
+        }
+        changes
+    }
+}
+
+impl CPU {
+    /// Copies the current state, e.g. to show it in a debugger
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: *self.registers(),
+            i: self.i(),
+            pc: self.pc(),
+            stack: self.stack().to_vec(),
+            delay_timer: self.delay_timer(),
+            sound_timer: self.sound_timer(),
+            instructions_executed: self.instructions_executed(),
+            // addresses devices refuse to show read as zero
+            memory: (0..self.memory_size())
+                .map(|addr| self.bus().peek8(addr).unwrap_or(0))
+                .collect(),
+            internals: self.internals(),
+        }
+    }
+
+    /// What changed since `earlier` was taken, e.g. to see what a subroutine
+    /// touched
+    pub fn changes_since(&self, earlier: &Snapshot) -> Vec<Change> {
+        earlier.diff(&self.snapshot())
+    }
+
+    /// Puts the CPU back in the state of `snapshot`, taken from this CPU or
+    /// one with the same ROM, quirks and devices. Running from there does
+    /// the same as it did after the snapshot, e.g. to step backwards by
+    /// running again from an earlier snapshot
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        for (x, &value) in snapshot.registers.iter().enumerate() {
+            self.set_register(x as u8, value);
+        }
+        self.set_i(snapshot.i);
+        self.set_pc(snapshot.pc);
+        self.set_delay_timer(snapshot.delay_timer);
+        for (addr, &byte) in snapshot.memory.iter().enumerate() {
+            // devices can reject writes, memory is restored as far as possible
+            let _ = self.bus_mut().write8(addr, byte);
+        }
+        self.set_internals(&snapshot.internals, &snapshot.stack, snapshot.instructions_executed);
+        // after the internals so that the tone uses the restored pattern
+        self.set_sound_timer(snapshot.sound_timer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::string::ToString;
+
+    /// LD V0, 7, LD I, 0x300, LD [I], V0, LD DT, V0, CALL 0x20A and halts
+    const ROM: [u8; 14] = [0x60, 0x07, 0xA3, 0x00, 0xF0, 0x55, 0xF0, 0x15, 0x22, 0x0C, 0x00, 0x00, 0x00, 0x00];
+
+    fn cpu() -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load_rom(&ROM).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn changes_of_the_instructions() {
+        let mut cpu = cpu();
+        let before = cpu.snapshot();
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+        let changes = [
+            Change::Register { x: 0, before: 0, after: 7 },
+            // the default machine moves I past what it stored
+            Change::I { before: 0, after: 0x301 },
+            Change::Pc { before: 0x200, after: 0x20C },
+            Change::Stack { before: Vec::new(), after: Vec::from([0x20A]) },
+            Change::DelayTimer { before: 0, after: 7 },
+            Change::Memory { addr: 0x300, before: 0, after: 7 },
+        ];
+        assert_eq!(cpu.changes_since(&before), changes);
+        assert_eq!(before.diff(&cpu.snapshot()), changes);
+        assert!(cpu.changes_since(&cpu.snapshot()).is_empty());
+
+        let text: Vec<_> = changes.iter().map(Change::to_string).collect();
+        assert_eq!(text, ["V0 00->07", "I 000->301", "PC 200->20c", "stack []->[20a]", "DT 0->7", "[0x300] 00->07"]);
+    }
+
+    #[test]
+    fn memory_one_snapshot_lacks_reads_as_zero() {
+        let before = cpu().snapshot();
+        let mut after = before.clone();
+        after.memory.extend([0, 5]);
+        assert_eq!(before.diff(&after), [Change::Memory { addr: before.memory.len() + 1, before: 0, after: 5 }]);
+        assert_eq!(after.diff(&before), [Change::Memory { addr: before.memory.len() + 1, before: 5, after: 0 }]);
+    }
+
+    #[test]
+    fn restoring_runs_the_same_again() {
+        let mut cpu = cpu();
+        cpu.step().unwrap();
+        let early = cpu.snapshot();
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        let late = cpu.snapshot();
+
+        cpu.restore(&early);
+        assert_eq!(cpu.snapshot(), early);
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.snapshot(), late);
+    }
+}
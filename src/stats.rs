@@ -0,0 +1,114 @@
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::calls::{call_graph, CallGraph};
+use crate::detect::{is_schip_opcode, is_xochip_opcode};
+use crate::flow::control_flow;
+use crate::Variant;
+
+/// Figures about the code a ROM executes, found without running it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomStats {
+    pub size: usize,
+    pub families: [usize; 16],      // reachable instructions by the first nibble of their opcode
+    pub code_bytes: usize,          // bytes of reachable instructions, the rest is taken as data
+    pub call_depth: Option<usize>,  // deepest nesting of calls, `None` when a subroutine calls itself
+    pub schip_instructions: usize,  // reachable instructions added by SUPER-CHIP
+    pub xochip_instructions: usize, // reachable instructions added by XO-CHIP
+}
+
+/// Gathers the statistics of `rom` loaded at `base` from `entry`
+pub fn rom_stats(rom: &[u8], base: usize, entry: usize, variant: Variant) -> RomStats {
+    let graph = control_flow(rom, base, entry, variant);
+
+    let mut families = [0; 16];
+    let mut code = BTreeSet::new();
+    let mut schip_instructions = 0;
+    let mut xochip_instructions = 0;
+    for line in graph.blocks.values().flat_map(|block| &block.lines) {
+        code.extend(line.addr..line.addr + line.bytes.len());
+        let [hi, lo, ..] = line.bytes[..] else {
+            continue; // the odd byte at the end
+        };
+        let opcode = u16::from_be_bytes([hi, lo]);
+        families[(opcode >> 12) as usize] += 1;
+        if is_xochip_opcode(opcode) {
+            xochip_instructions += 1;
+        } else if is_schip_opcode(opcode) {
+            schip_instructions += 1;
+        }
+    }
+
+    RomStats {
+        size: rom.len(),
+        families,
+        code_bytes: code.len(),
+        call_depth: depth(&call_graph(rom, base, entry, variant), entry, &mut Vec::new()),
+        schip_instructions,
+        xochip_instructions,
+    }
+}
+
+/// Deepest nesting of calls made from `function`, `None` on recursion
+fn depth(calls: &CallGraph, function: usize, active: &mut Vec<usize>) -> Option<usize> {
+    if active.contains(&function) {
+        return None;
+    }
+    active.push(function);
+    let mut deepest = 0;
+    for &callee in calls.functions.get(&function).into_iter().flatten() {
+        deepest = deepest.max(depth(calls, callee, active)? + 1);
+    }
+    active.pop();
+    Some(deepest)
+}
+
+/// Names of the opcode families, by their first nibble
+const FAMILIES: [&str; 16] = [
+    "0NNN  system, screen and return",
+    "1NNN  jump",
+    "2NNN  call",
+    "3XKK  skip if equal to a byte",
+    "4XKK  skip if not equal to a byte",
+    "5XY_  skip if equal, XO-CHIP ranges",
+    "6XKK  load a byte",
+    "7XKK  add a byte",
+    "8XY_  arithmetic and logic",
+    "9XY0  skip if not equal",
+    "ANNN  load I",
+    "BNNN  computed jump",
+    "CXKK  random",
+    "DXYN  draw",
+    "EX__  skip on a key",
+    "FX__  timers, memory and I",
+];
+
+/// Report of the statistics, one figure per line
+impl fmt::Display for RomStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "size: {} bytes", self.size)?;
+        writeln!(f, "code: {} bytes", self.code_bytes)?;
+        writeln!(f, "data: {} bytes (not reached from the entry point)", self.size.saturating_sub(self.code_bytes))?;
+        match self.call_depth {
+            Some(depth) => writeln!(f, "deepest call nesting: {}", depth)?,
+            None => writeln!(f, "deepest call nesting: unbounded, a subroutine calls itself")?,
+        }
+
+        let extensions: Vec<&str> = [(self.schip_instructions, "SUPER-CHIP"), (self.xochip_instructions, "XO-CHIP")]
+            .iter()
+            .filter(|(count, _)| *count > 0)
+            .map(|(_, name)| *name)
+            .collect();
+        match extensions.as_slice() {
+            [] => writeln!(f, "extensions: none")?,
+            names => writeln!(f, "extensions: {}", names.join(", "))?,
+        }
+
+        writeln!(f, "instructions:")?;
+        for (family, count) in FAMILIES.iter().zip(self.families).filter(|(_, count)| *count > 0) {
+            writeln!(f, "    {:<40}{:>6}", family, count)?;
+        }
+        Ok(())
+    }
+}
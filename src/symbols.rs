@@ -0,0 +1,131 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use core::fmt;
+
+/// Names of addresses of a program, like the labels of its source, and the
+/// lines of source their instructions come from.
+///
+/// A symbol file has one `address = name` per line, e.g. `0x0342 = draw_score`,
+/// or `address @ file:line` for a line of source, e.g. `0x0344 @ main.s:12`.
+/// Blank lines and the text after `;` or `#` are ignored
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Symbols {
+    names: BTreeMap<usize, String>,
+    lines: BTreeMap<usize, (String, usize)>, // file and line, starting at 1
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the text of a symbol file, the error tells the line that is wrong
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut symbols = Symbols::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split([';', '#']).next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let location = line.split_once('@').and_then(|(addr, location)| {
+                let (file, number) = location.trim().rsplit_once(':')?;
+                Some((parse_address(addr)?, file, number.parse().ok().filter(|&n: &usize| n > 0)?))
+            });
+            if let Some((addr, file, number)) = location.filter(|(_, file, _)| !file.is_empty()) {
+                symbols.insert_line(addr, file, number);
+                continue;
+            }
+
+            let parsed = line.split_once('=').and_then(|(addr, name)| Some((parse_address(addr)?, name.trim())));
+            match parsed {
+                Some((addr, name)) if !name.is_empty() && !name.contains(char::is_whitespace) => {
+                    symbols.insert(addr, name);
+                }
+                _ => return Err(format!("line {}: expected `address = name` or `address @ file:line`, got `{}`", index + 1, line)),
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// Names `addr`, replacing the name it had
+    pub fn insert(&mut self, addr: usize, name: &str) {
+        self.names.insert(addr, name.to_string());
+    }
+
+    /// Tells that the instruction at `addr` comes from `line` of `file`
+    pub fn insert_line(&mut self, addr: usize, file: &str, line: usize) {
+        self.lines.insert(addr, (file.to_string(), line));
+    }
+
+    pub fn name(&self, addr: usize) -> Option<&str> {
+        self.names.get(&addr).map(String::as_str)
+    }
+
+    /// Closest name at `addr` or before it and its address, like the
+    /// function an instruction is in
+    pub fn enclosing(&self, addr: usize) -> Option<(usize, &str)> {
+        self.names.range(..=addr).next_back().map(|(&start, name)| (start, name.as_str()))
+    }
+
+    pub fn address(&self, name: &str) -> Option<usize> {
+        self.names.iter().find(|(_, n)| *n == name).map(|(&addr, _)| addr)
+    }
+
+    /// File and line of the source of the instruction at `addr`
+    pub fn line(&self, addr: usize) -> Option<(&str, usize)> {
+        self.lines.get(&addr).map(|(file, line)| (file.as_str(), *line))
+    }
+
+    /// Lowest address of the first line of `file` from `line` on that has an
+    /// instruction, with that line
+    pub fn address_of_line(&self, file: &str, line: usize) -> Option<(usize, usize)> {
+        self.lines
+            .iter()
+            .filter(|(_, (f, l))| f == file && *l >= line)
+            .min_by_key(|&(&addr, (_, l))| (*l, addr))
+            .map(|(&addr, (_, l))| (addr, *l))
+    }
+
+    /// Addresses and their names, lowest address first
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.names.iter().map(|(&addr, name)| (addr, name.as_str()))
+    }
+
+    /// Addresses and the file and line of their source, lowest address first
+    pub fn lines(&self) -> impl Iterator<Item = (usize, &str, usize)> {
+        self.lines.iter().map(|(&addr, (file, line))| (addr, file.as_str(), *line))
+    }
+
+    /// How many addresses have a name
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.lines.is_empty()
+    }
+}
+
+fn parse_address(text: &str) -> Option<usize> {
+    let text = text.trim();
+    match text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// The symbol file, one `0x0342 = draw_score` per line and then one
+/// `0x0344 @ main.s:12` per line of source
+impl fmt::Display for Symbols {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (addr, name) in self.iter() {
+            writeln!(f, "{:#06x} = {}", addr, name)?;
+        }
+        for (addr, file, line) in self.lines() {
+            writeln!(f, "{:#06x} @ {}:{}", addr, file, line)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+use std::io::{self, Write};
+use std::time::Duration;
+
+use cpu_caller::{Framebuffer, Renderer, StopReason, CPU};
+use ratatui::crossterm::cursor::MoveTo;
+use ratatui::crossterm::style::{Color, Colors, Print, ResetColor, SetColors};
+use ratatui::crossterm::terminal::{Clear, ClearType};
+use ratatui::crossterm::{execute, queue};
+
+use crate::cli::{Frontend, Options};
+use crate::config::Palette;
+use crate::frontend;
+use crate::tui::{self, HeldKeys, Key, Terminal};
+
+/// First of the braille characters, the others add one bit per dot
+const BRAILLE: u32 = 0x2800;
+
+/// Bit of each dot of a braille character, by row and then column
+const BRAILLE_DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+/// Draws the frames on the terminal with characters of several pixels, in
+/// the colors of the palette
+struct TerminalRenderer {
+    braille: bool, // 2x4 pixels per character instead of 1x2
+    palette: Palette,
+}
+
+/// Character of the picture with the colors of its text and of its background
+type Cell = (char, [u8; 3], [u8; 3]);
+
+impl Renderer for TerminalRenderer {
+    fn render(&mut self, framebuffer: &Framebuffer) {
+        // the whole picture is written at once, never half of it
+        let mut text = Vec::new();
+        let _ = draw(&mut text, &cells(framebuffer, &self.palette, self.braille));
+        let mut stdout = io::stdout().lock();
+        let _ = stdout.write_all(&text);
+        let _ = stdout.flush();
+    }
+}
+
+/// Writes the commands that draw `lines` from the top left corner
+fn draw(out: &mut impl Write, lines: &[Vec<Cell>]) -> io::Result<()> {
+    for (row, line) in lines.iter().enumerate() {
+        queue!(out, MoveTo(0, row as u16))?;
+        let mut last = None;
+        for &(c, fg, bg) in line {
+            // the colors only change where they differ from the previous character's
+            if last != Some((fg, bg)) {
+                queue!(out, SetColors(Colors::new(rgb(fg), rgb(bg))))?;
+                last = Some((fg, bg));
+            }
+            queue!(out, Print(c))?;
+        }
+        // the rest of the line is cleared in the colors of the terminal
+        queue!(out, ResetColor, Clear(ClearType::UntilNewLine))?;
+    }
+    // a smaller picture than the last one leaves nothing behind
+    queue!(out, MoveTo(0, lines.len() as u16), Clear(ClearType::FromCursorDown))
+}
+
+fn rgb([r, g, b]: [u8; 3]) -> Color {
+    Color::Rgb { r, g, b }
+}
+
+/// Lines of characters showing the framebuffer. A half block shows two
+/// pixels in their own colors, a braille character shows the dots lit in
+/// any plane in the color of all the planes lit under it
+fn cells(framebuffer: &Framebuffer, palette: &Palette, braille: bool) -> Vec<Vec<Cell>> {
+    let (width, height) = (framebuffer.width(), framebuffer.height());
+    let planes = |x: usize, y: usize| frontend::lit_planes(framebuffer, y * width + x);
+    if braille {
+        return (0..height.div_ceil(4))
+            .map(|row| {
+                (0..width / 2)
+                    .map(|column| {
+                        let (mut bits, mut lit) = (0, 0);
+                        for (dy, dots) in BRAILLE_DOTS.iter().enumerate() {
+                            for (dx, bit) in dots.iter().enumerate() {
+                                let (x, y) = (column * 2 + dx, row * 4 + dy);
+                                if y < height && planes(x, y) != 0 {
+                                    bits |= bit;
+                                    lit |= planes(x, y);
+                                }
+                            }
+                        }
+                        (char::from_u32(BRAILLE + bits).unwrap_or(' '), palette.color(lit), palette.background)
+                    })
+                    .collect()
+            })
+            .collect();
+    }
+    (0..height / 2)
+        .map(|row| (0..width).map(|x| ('▀', palette.color(planes(x, row * 2)), palette.color(planes(x, row * 2 + 1)))).collect())
+        .collect()
+}
+
+/// Runs the program 60 frames per second on the terminal with the keys
+/// typed going to the program. Gives back what stopped it, or `None` when
+/// Escape or Ctrl-C did. The terminal is set up the way `debug --tui` does,
+/// and where it tells when keys are released the keys of the program stay
+/// down as long as they are held
+pub fn run(cpu: &mut CPU, options: &Options) -> Option<StopReason> {
+    let terminal = match Terminal::enter() {
+        Ok(terminal) => terminal,
+        Err(e) => {
+            eprintln!("error: the terminal frontend needs a terminal: {}", e);
+            std::process::exit(1);
+        }
+    };
+    cpu.set_renderer(Box::new(TerminalRenderer { braille: options.frontend == Frontend::Braille, palette: options.palette }));
+    let _ = execute!(io::stdout(), Clear(ClearType::All));
+    cpu.present();
+
+    let mut held = HeldKeys::new(terminal.releases());
+    let reason = frontend::run_frames(cpu, |cpu| {
+        while let Ok(Some(event)) = tui::read_key(Some(Duration::ZERO)) {
+            match tui::key(event) {
+                Some(Key::Escape | Key::Char('\x03')) => return false,
+                _ => held.event(cpu, &options.keys, event),
+            }
+        }
+        held.frame(cpu);
+        true
+    });
+
+    if let Some(reason) = &reason {
+        // the last picture stays until a key is pressed
+        print!("stopped: {:?}, press a key to leave", reason);
+        let _ = io::stdout().flush();
+        while let Ok(event) = tui::read_key(None) {
+            if event.and_then(tui::key).is_some() {
+                break;
+            }
+        }
+    }
+    drop(terminal);
+    reason
+}
@@ -0,0 +1,226 @@
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::{AudioSink, CpuError, Display, Snapshot, StepOutcome, StopReason, CPU, PLANES};
+
+/// Time between two 60 Hz ticks
+const FRAME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+/// Request sent to the emulator thread
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Load(Vec<u8>),                   // loads a ROM at 0x200, the CPU stays paused
+    Run,                             // executes `instructions_per_frame` instructions 60 times per second until paused or stopped
+    Pause,
+    Step,                            // executes one instruction, only while paused
+    SetKey { key: u8, pressed: bool },
+    Snapshot,                        // asks for an `Event::Snapshot` of the current state
+    Shutdown,                        // ends the thread, also sent when dropping `EmulatorThread`
+}
+
+/// Something that happened on the emulator thread
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Frame { width: usize, height: usize, planes: [Vec<bool>; PLANES] }, // the picture changed
+    Tone { pattern: [u8; 16], frequency: f64 }, // the speaker started or changed its sound
+    Silence,
+    Stopped(StopReason), // the program can't go on, `Run` is paused
+    Snapshot(Box<Snapshot>),
+    LoadFailed(CpuError),
+    Fault(CpuError), // the program failed while running or stepping, `Run` is paused
+}
+
+/// Runs a CPU on a background thread so that frontends can keep drawing
+/// while it executes, talking to it through commands and events
+pub struct EmulatorThread {
+    commands: Sender<Command>,
+    events: Receiver<Event>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl EmulatorThread {
+    /// Starts the thread, the CPU is built on it by `make_cpu` because
+    /// the devices and hooks it may own can't move between threads
+    pub fn spawn<F>(make_cpu: F) -> Self
+    where
+        F: FnOnce() -> CPU + Send + 'static,
+    {
+        let (commands, command_rx) = mpsc::channel();
+        let (event_tx, events) = mpsc::channel();
+
+        let handle = thread::spawn(move || {
+            let mut cpu = make_cpu();
+            cpu.set_display(Box::new(ChannelDisplay(event_tx.clone())));
+            cpu.set_audio_sink(Box::new(ChannelAudio { events: event_tx.clone(), silent: false }));
+            run_thread(cpu, command_rx, event_tx);
+        });
+
+        EmulatorThread { commands, events, handle: Some(handle) }
+    }
+
+    /// Sends a command, returns `false` if the thread has already ended
+    pub fn send(&self, command: Command) -> bool {
+        self.commands.send(command).is_ok()
+    }
+
+    /// Events emitted by the thread, in the order they happened
+    pub fn events(&self) -> &Receiver<Event> {
+        &self.events
+    }
+}
+
+impl Drop for EmulatorThread {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Command::Shutdown);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Body of the emulator thread
+fn run_thread(mut cpu: CPU, commands: Receiver<Command>, events: Sender<Event>) {
+    let mut running = false;
+    let mut next_frame = Instant::now();
+
+    loop {
+        // a paused CPU has nothing to do until the next command
+        let command = if running {
+            match commands.try_recv() {
+                Ok(command) => Some(command),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        } else {
+            match commands.recv() {
+                Ok(command) => Some(command),
+                Err(_) => return,
+            }
+        };
+
+        match command {
+            Some(Command::Load(rom)) => {
+                running = false;
+                cpu.reset(false);
+                if let Err(e) = cpu.load_rom(&rom) {
+                    let _ = events.send(Event::LoadFailed(e));
+                }
+                cpu.present();
+            }
+            Some(Command::Run) => {
+                running = true;
+                next_frame = Instant::now();
+            }
+            Some(Command::Pause) => running = false,
+            Some(Command::Step) if !running => {
+                let reason = match cpu.step() {
+                    Ok(StepOutcome::Stopped(reason)) => Some(reason),
+                    Ok(StepOutcome::Continue) => None,
+                    Err(e) => Some(StopReason::Error(e)),
+                };
+                if let Some(reason) = reason {
+                    let _ = events.send(stopped(reason));
+                }
+                cpu.present();
+            }
+            Some(Command::Step) => {}
+            Some(Command::SetKey { key, pressed }) => cpu.set_key(key, pressed),
+            Some(Command::Snapshot) => {
+                let _ = events.send(Event::Snapshot(Box::new(cpu.snapshot())));
+            }
+            Some(Command::Shutdown) => return,
+            None => {}
+        }
+
+        if running && Instant::now() >= next_frame {
+            let summary = cpu.advance(cpu.instructions_per_frame() as usize);
+            match summary.stopped {
+                StopReason::InstructionBudgetExhausted
+                | StopReason::WaitingForKey
+                | StopReason::WaitingForVblank => {}
+                reason => {
+                    running = false;
+                    cpu.present(); // the last frame would wait for a tick that won't come
+                    let _ = events.send(stopped(reason));
+                }
+            }
+            // after a stall the lost frames are skipped instead of run all at once
+            let now = Instant::now();
+            next_frame = (next_frame + FRAME).max(now.checked_sub(FRAME).unwrap_or(now));
+        } else if running {
+            // poll for commands a few times per frame without burning the CPU
+            let wait = next_frame.saturating_duration_since(Instant::now());
+            thread::sleep(wait.min(Duration::from_millis(2)));
+        }
+    }
+}
+
+/// Event telling why the program stopped, faults have their own
+fn stopped(reason: StopReason) -> Event {
+    match reason {
+        StopReason::Error(e) => Event::Fault(e),
+        reason => Event::Stopped(reason),
+    }
+}
+
+/// Turns presented frames into events
+struct ChannelDisplay(Sender<Event>);
+
+impl Display for ChannelDisplay {
+    fn present(&mut self, width: usize, height: usize, planes: [&[bool]; PLANES]) {
+        let planes = planes.map(|plane| plane.to_vec());
+        let _ = self.0.send(Event::Frame { width, height, planes });
+    }
+}
+
+/// Turns the speaker commands into events
+struct ChannelAudio {
+    events: Sender<Event>,
+    silent: bool, // the CPU asks for silence more often than the sound actually stops
+}
+
+impl AudioSink for ChannelAudio {
+    fn play(&mut self, pattern: &[u8; 16], frequency: f64) {
+        self.silent = false;
+        let _ = self.events.send(Event::Tone { pattern: *pattern, frequency });
+    }
+
+    fn stop(&mut self) {
+        if !self.silent {
+            self.silent = true;
+            let _ = self.events.send(Event::Silence);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// First event that isn't about the picture or the sound
+    fn next_outcome(thread: &EmulatorThread) -> Event {
+        loop {
+            match thread.events().recv_timeout(Duration::from_secs(5)).expect("no event from the thread") {
+                Event::Frame { .. } | Event::Tone { .. } | Event::Silence => {}
+                event => return event,
+            }
+        }
+    }
+
+    #[test]
+    fn faults_have_their_own_event() {
+        let thread = EmulatorThread::spawn(CPU::new);
+        thread.send(Command::Load(vec![0xE0, 0x00]));
+        thread.send(Command::Run);
+        assert_eq!(next_outcome(&thread), Event::Fault(CpuError::UnknownOpcode { opcode: 0xE000, addr: 0x200 }));
+    }
+
+    #[test]
+    fn halts_are_stops() {
+        let thread = EmulatorThread::spawn(CPU::new);
+        thread.send(Command::Load(vec![0x00, 0xFD]));
+        thread.send(Command::Step);
+        assert_eq!(next_outcome(&thread), Event::Stopped(StopReason::ExitOpcode));
+    }
+}
@@ -0,0 +1,79 @@
+use alloc::format;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::decoder::Instruction;
+
+/// Register whose value changed while executing an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterDelta {
+    pub register: u8,
+    pub before: u8,
+    pub after: u8,
+}
+
+/// What one executed instruction did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub cycle: u64, // instructions executed before this one
+    pub pc: u16,
+    pub instruction: Instruction,
+    pub registers: Vec<RegisterDelta>, // only the registers that changed
+    pub i: Option<(u16, u16)>,         // value of I before and after, when it changed
+}
+
+/// One line with the cycle, the address, the instruction and what changed,
+/// like `      12 0x206  ADD V0, 0x01          V0 04->05`
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>8} {:#05x}  ", self.cycle, self.pc)?;
+        if self.registers.is_empty() && self.i.is_none() {
+            return write!(f, "{}", self.instruction);
+        }
+        write!(f, "{:<22}", format!("{}", self.instruction))?;
+        for delta in &self.registers {
+            write!(f, " V{:X} {:02x}->{:02x}", delta.register, delta.before, delta.after)?;
+        }
+        if let Some((before, after)) = self.i {
+            write!(f, " I {:03x}->{:03x}", before, after)?;
+        }
+        Ok(())
+    }
+}
+
+/// Instruction kept in the history of the last ones executed, see `CPU::history`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub cycle: u64, // instructions executed before this one
+    pub pc: u16,
+    pub opcode: u16, // first two bytes, as they were when it ran
+    pub instruction: Instruction,
+}
+
+/// Like `      12 0x206  7001  ADD V0, 0x01`
+impl fmt::Display for HistoryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>8} {:#05x}  {:04X}  {}", self.cycle, self.pc, self.opcode, self.instruction)
+    }
+}
+
+/// Receives a record for every instruction executed while tracing, e.g. to
+/// compare runs against a reference emulator or to count hot spots
+pub trait TraceSink {
+    fn record(&mut self, record: TraceRecord);
+}
+
+impl<F: FnMut(TraceRecord)> TraceSink for F {
+    fn record(&mut self, record: TraceRecord) {
+        self(record)
+    }
+}
+
+/// Sends the records to another thread
+#[cfg(feature = "std")]
+impl TraceSink for std::sync::mpsc::Sender<TraceRecord> {
+    fn record(&mut self, record: TraceRecord) {
+        // nobody is listening anymore, tracing just stops mattering
+        let _ = self.send(record);
+    }
+}
@@ -0,0 +1,583 @@
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cpu_caller::{disassemble_at, Checkpointer, StopReason, Symbols, CPU, DISPLAY_WIDTH, PLANES};
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags, PopKeyboardEnhancementFlags,
+    PushKeyboardEnhancementFlags,
+};
+use ratatui::crossterm::{cursor, execute, terminal};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::Frame;
+
+use crate::cli::{parse_address, Options, Source};
+use crate::debugger::{self, CHECKPOINTS, CHECKPOINT_INTERVAL};
+use crate::frontend;
+
+const HELP: &str = "s step  n next  u back  c continue  b breakpoint  ↑↓ move  . PC  [ ] memory  g go to  i memory at I  q quit";
+
+/// Time between two frames while the program runs
+const FRAME: Duration = Duration::from_micros(16_667);
+
+/// Frames a key of the program stays down when the terminal doesn't tell when it's released
+const KEY_FRAMES: u32 = 6;
+
+/// Instructions of the disassembly above the selected one
+const CONTEXT: usize = 4;
+
+/// Return addresses shown in the stack pane
+const STACK_ROWS: u16 = 5;
+
+/// The pixels of a character, top left, top right, bottom left and bottom right
+const QUADRANTS: [char; 16] = [' ', '▘', '▝', '▀', '▖', '▌', '▞', '▛', '▗', '▚', '▐', '▜', '▄', '▙', '▟', '█'];
+
+/// Key read from the terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char), // Ctrl-C is '\x03', the character terminals send for it
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Enter,
+    Backspace,
+    Escape,
+}
+
+/// What the program runs until
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Running {
+    Continue,      // something stops it
+    Return(usize), // no more than this many functions are being executed
+}
+
+/// Full-screen debugger, with panes for the display, the disassembly, the
+/// registers, the stack and memory
+struct Tui {
+    cpu: CPU,
+    symbols: Symbols,
+    checkpoints: Checkpointer,
+    keys: Vec<(char, u8)>, // keyboard keys of the program while it runs
+    cursor: usize,         // selected instruction of the disassembly
+    memory: usize,         // first address of the memory pane
+    running: Option<Running>,
+    held: HeldKeys,
+    prompt: Option<String>,     // address being typed after `g`
+    status: String,
+}
+
+/// Terminal in raw mode showing the alternate screen, put back as it was
+/// when dropped. crossterm sets it up, on every system
+pub struct Terminal {
+    enhanced: bool, // the keys are reported with the kitty protocol, releases included
+}
+
+/// Keys of the program held down on the terminal. Where the terminal tells
+/// when keys are released they go up with the keys of the keyboard, several
+/// at once, elsewhere one at a time `KEY_FRAMES` after being pressed
+pub struct HeldKeys {
+    releases: bool,
+    held: Vec<(u8, u32)>, // CHIP-8 key and the frames left
+}
+
+/// Loads the ROM at `path` and debugs it on the whole terminal until `q`
+pub fn run(path: &str, options: &Options) {
+    let cpu = crate::build_cpu(&Source::File(path.to_string()), options);
+    let mut tui = Tui {
+        cursor: cpu.pc(),
+        memory: cpu.i() as usize,
+        cpu,
+        symbols: crate::load_symbols(path, options),
+        checkpoints: Checkpointer::new(CHECKPOINT_INTERVAL, CHECKPOINTS),
+        keys: options.keys.clone(),
+        running: None,
+        held: HeldKeys::new(false),
+        prompt: None,
+        status: format!("debugging {}", path),
+    };
+
+    let guard = match Terminal::enter() {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("error: `--tui` needs a terminal: {}", e);
+            std::process::exit(1);
+        }
+    };
+    tui.held = HeldKeys::new(guard.releases());
+    let result = ratatui::Terminal::new(CrosstermBackend::new(io::stdout())).and_then(|mut terminal| tui.run(&mut terminal));
+    drop(guard);
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+    }
+}
+
+impl Terminal {
+    pub fn enter() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        // from here on dropping it puts the terminal back
+        let mut terminal = Terminal { enhanced: false };
+        execute!(io::stdout(), terminal::EnterAlternateScreen, cursor::Hide)?;
+        if terminal::supports_keyboard_enhancement().unwrap_or(false) {
+            let flags = KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES
+                | KeyboardEnhancementFlags::REPORT_EVENT_TYPES
+                | KeyboardEnhancementFlags::REPORT_ALL_KEYS_AS_ESCAPE_CODES;
+            execute!(io::stdout(), PushKeyboardEnhancementFlags(flags))?;
+            terminal.enhanced = true;
+        }
+        Ok(terminal)
+    }
+
+    /// Whether the terminal tells when keys are released, the console of
+    /// Windows always does
+    pub fn releases(&self) -> bool {
+        self.enhanced || cfg!(windows)
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        if self.enhanced {
+            let _ = execute!(io::stdout(), PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(io::stdout(), cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+        let _ = io::stdout().flush();
+    }
+}
+
+/// Waits for the next event of the terminal, no longer than `timeout` when
+/// there is one. Gives back the key pressed or released, `None` for the
+/// other events like a change of size
+pub fn read_key(timeout: Option<Duration>) -> io::Result<Option<KeyEvent>> {
+    if let Some(timeout) = timeout {
+        if !event::poll(timeout)? {
+            return Ok(None);
+        }
+    }
+    match event::read()? {
+        Event::Key(key) => Ok(Some(key)),
+        _ => Ok(None),
+    }
+}
+
+/// Key of a key press, `None` for a release and the keys nothing uses
+pub fn key(event: KeyEvent) -> Option<Key> {
+    if event.kind == KeyEventKind::Release {
+        return None;
+    }
+    match event.code {
+        KeyCode::Char('c') if event.modifiers.contains(KeyModifiers::CONTROL) => Some(Key::Char('\x03')),
+        KeyCode::Char(c) => Some(Key::Char(c)),
+        KeyCode::Up => Some(Key::Up),
+        KeyCode::Down => Some(Key::Down),
+        KeyCode::PageUp => Some(Key::PageUp),
+        KeyCode::PageDown => Some(Key::PageDown),
+        KeyCode::Enter => Some(Key::Enter),
+        KeyCode::Backspace => Some(Key::Backspace),
+        KeyCode::Esc => Some(Key::Escape),
+        _ => None,
+    }
+}
+
+impl HeldKeys {
+    pub fn new(releases: bool) -> Self {
+        HeldKeys { releases, held: Vec::new() }
+    }
+
+    /// Presses or releases the key of the program that `event` is in the layout `keys`
+    pub fn event(&mut self, cpu: &mut CPU, keys: &[(char, u8)], event: KeyEvent) {
+        let KeyCode::Char(c) = event.code else {
+            return;
+        };
+        let Some(chip8) = frontend::chip8_key(keys, c) else {
+            return;
+        };
+        if event.kind == KeyEventKind::Release {
+            cpu.set_key(chip8, false);
+            self.held.retain(|&(key, _)| key != chip8);
+            return;
+        }
+        // without releases a key goes up when another one goes down
+        if !self.releases {
+            for (key, _) in self.held.drain(..) {
+                cpu.set_key(key, false);
+            }
+        }
+        self.held.retain(|&(key, _)| key != chip8);
+        self.held.push((chip8, KEY_FRAMES));
+        cpu.set_key(chip8, true);
+    }
+
+    /// Counts a frame, releasing the keys whose time is up when the terminal
+    /// doesn't tell
+    pub fn frame(&mut self, cpu: &mut CPU) {
+        if self.releases {
+            return;
+        }
+        self.held.retain_mut(|(key, frames)| match frames.checked_sub(1) {
+            Some(left) => {
+                *frames = left;
+                true
+            }
+            None => {
+                cpu.set_key(*key, false);
+                false
+            }
+        });
+    }
+}
+
+impl Tui {
+    fn run(&mut self, terminal: &mut ratatui::DefaultTerminal) -> io::Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            if self.running.is_none() {
+                // anything else, like a change of size, only draws again
+                if let Some(key) = read_key(None)?.and_then(key) {
+                    if !self.command(key) {
+                        return Ok(());
+                    }
+                }
+                continue;
+            }
+
+            let start = Instant::now();
+            while let Some(event) = read_key(Some(Duration::ZERO))? {
+                if !self.running_key(event) {
+                    return Ok(());
+                }
+            }
+            self.frame();
+            thread::sleep(FRAME.saturating_sub(start.elapsed()));
+        }
+    }
+
+    /// Carries out the command of a key while the program is stopped,
+    /// returns whether to go on
+    fn command(&mut self, key: Key) -> bool {
+        if let Some(prompt) = &mut self.prompt {
+            match key {
+                Key::Char(c) if c.is_ascii_graphic() => prompt.push(c),
+                Key::Backspace => {
+                    prompt.pop();
+                }
+                Key::Enter => {
+                    let prompt = self.prompt.take().unwrap_or_default();
+                    match parse_address(&prompt).or_else(|| self.symbols.address(&prompt)) {
+                        Some(addr) => self.memory = addr,
+                        None => self.status = format!("`{}` is not an address or a known name", prompt),
+                    }
+                }
+                Key::Escape => self.prompt = None,
+                _ => {}
+            }
+            return true;
+        }
+
+        match key {
+            Key::Char('q' | '\x03') => return false,
+            Key::Char('s' | ' ') => self.step(),
+            Key::Char('n') => self.next(),
+            Key::Char('u') => match debugger::step_back(&mut self.cpu, &mut self.checkpoints, 1) {
+                Ok(()) => self.status = "went back one instruction".to_string(),
+                Err(e) => self.status = e,
+            },
+            Key::Char('c') => self.resume(Running::Continue),
+            Key::Char('b') => {
+                if self.cpu.remove_breakpoint(self.cursor) {
+                    self.status = format!("removed the breakpoint at {:#05x}", self.cursor);
+                } else {
+                    self.cpu.add_breakpoint(self.cursor);
+                    self.status = format!("breakpoint at {:#05x}", self.cursor);
+                }
+            }
+            Key::Up => self.cursor = self.cursor.saturating_sub(2),
+            Key::Down => self.cursor += self.instruction(self.cursor).map_or(2, |(len, _)| len),
+            Key::Char('.') => self.cursor = self.cpu.pc(),
+            Key::Char('[') => self.memory = self.memory.saturating_sub(0x10),
+            Key::Char(']') => self.memory += 0x10,
+            Key::PageUp => self.memory = self.memory.saturating_sub(0x100),
+            Key::PageDown => self.memory += 0x100,
+            Key::Char('g') => self.prompt = Some(String::new()),
+            Key::Char('i') => self.memory = self.cpu.i() as usize,
+            _ => {}
+        }
+        true
+    }
+
+    /// Handles a key while the program runs: the keys of the program press
+    /// its keys, Escape stops it. Returns whether to go on
+    fn running_key(&mut self, event: KeyEvent) -> bool {
+        match key(event) {
+            Some(Key::Char('\x03')) => return false,
+            Some(Key::Escape) => self.stopped(StopReason::InstructionBudgetExhausted),
+            _ => self.held.event(&mut self.cpu, &self.keys, event),
+        }
+        true
+    }
+
+    fn step(&mut self) {
+        match debugger::step(&mut self.cpu, &mut self.checkpoints, 1) {
+            Some(reason) => self.stopped(reason),
+            None => {
+                self.cursor = self.cpu.pc();
+                self.status = format!("stepped, {} instructions executed", self.cpu.instructions_executed());
+            }
+        }
+    }
+
+    /// Steps over a call by running until it returns
+    fn next(&mut self) {
+        let depth = self.cpu.stack_pointer();
+        let call = self.cpu.read_memory(self.cpu.pc(), 1).is_ok_and(|bytes| bytes[0] >> 4 == 0x2);
+        self.step();
+        if call && self.cpu.stack_pointer() > depth {
+            self.running = Some(Running::Return(depth));
+            self.status = "running until the call returns, Escape stops".to_string();
+        }
+    }
+
+    /// Lets the program run, past the breakpoint the PC is on
+    fn resume(&mut self, running: Running) {
+        if self.cpu.breakpoints().any(|addr| addr == self.cpu.pc()) {
+            if let Some(reason) = debugger::step(&mut self.cpu, &mut self.checkpoints, 1) {
+                return self.stopped(reason);
+            }
+        }
+        self.running = Some(running);
+        self.status = "running, Escape stops, the keys go to the program".to_string();
+    }
+
+    /// Runs the program for a frame
+    fn frame(&mut self) {
+        self.checkpoints.record(&self.cpu);
+        self.held.frame(&mut self.cpu);
+
+        let reason = match self.running {
+            None => return,
+            Some(Running::Continue) => self.cpu.advance(self.cpu.instructions_per_frame() as usize).stopped,
+            // an instruction at a time to notice the return
+            Some(Running::Return(depth)) => {
+                let mut reason = StopReason::InstructionBudgetExhausted;
+                for _ in 0..self.cpu.instructions_per_frame() {
+                    reason = self.cpu.advance(1).stopped;
+                    if self.cpu.stack_pointer() <= depth && reason == StopReason::InstructionBudgetExhausted {
+                        self.running = None;
+                        self.cursor = self.cpu.pc();
+                        self.status = "the call returned".to_string();
+                        return;
+                    }
+                    if !matches!(reason, StopReason::InstructionBudgetExhausted | StopReason::WaitingForVblank) {
+                        break;
+                    }
+                }
+                reason
+            }
+        };
+        // a program waiting for a key goes on running so that one can be pressed
+        if !matches!(reason, StopReason::InstructionBudgetExhausted | StopReason::WaitingForVblank | StopReason::WaitingForKey) {
+            self.stopped(reason);
+        }
+    }
+
+    /// Stops running and tells why
+    fn stopped(&mut self, reason: StopReason) {
+        self.running = None;
+        self.cursor = self.cpu.pc();
+        self.status = match reason {
+            StopReason::Breakpoint(addr) => format!("breakpoint at {}", self.describe(addr as usize)),
+            StopReason::OpcodeBreakpoint { pc, opcode } => format!("breakpoint on opcode {:04X} at {}", opcode, self.describe(pc as usize)),
+            StopReason::Watchpoint { pc, addr, .. } => {
+                format!("watchpoint: the instruction at {} accessed {}", self.describe(pc as usize), self.describe(addr as usize))
+            }
+            StopReason::Error(e) => format!("error: {}", e),
+            StopReason::InstructionBudgetExhausted => format!("stopped at {}", self.describe(self.cpu.pc())),
+            reason => format!("stopped: {:?}", reason),
+        };
+    }
+
+    /// Address with its name when the symbol file has one, like `0x206 <draw_score>`
+    fn describe(&self, addr: usize) -> String {
+        match self.symbols.name(addr) {
+            Some(name) => format!("{:#05x} <{}>", addr, name),
+            None => format!("{:#05x}", addr),
+        }
+    }
+
+    /// Length and text of the instruction at `addr`
+    fn instruction(&self, addr: usize) -> Option<(usize, String)> {
+        // `F000 NNNN` is the longest instruction
+        let len = 4.min(self.cpu.memory_size().saturating_sub(addr));
+        let bytes = self.cpu.read_memory(addr, len).ok()?;
+        let line = disassemble_at(&bytes, addr, addr, self.cpu.variant())?;
+        let hex: String = line.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        Some((line.bytes.len(), format!("{:#05x}  {:<8}  {}", addr, hex, line.symbolic(&self.symbols))))
+    }
+
+    /// Draws every pane over the whole terminal
+    fn draw(&self, frame: &mut Frame) {
+        let [title, body, bottom] = Layout::vertical([Constraint::Length(1), Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        frame.render_widget(Line::from(format!(" cpu-caller  {} instructions  {}", self.cpu.instructions_executed(), self.status)), title);
+        let help = match &self.prompt {
+            Some(prompt) => format!(" memory at: {}_", prompt),
+            None => format!(" {}", HELP),
+        };
+        frame.render_widget(Line::from(help), bottom);
+
+        // the display and memory on the left, the rest on the right
+        let display = self.display();
+        let [left, right] = Layout::horizontal([Constraint::Length(DISPLAY_WIDTH as u16 + 2), Constraint::Min(0)]).spacing(1).areas(body);
+        let [display_area, memory_area] = Layout::vertical([Constraint::Length(display.len() as u16 + 2), Constraint::Min(0)]).areas(left);
+        frame.render_widget(pane("display", display.into_iter().map(Line::from).collect()), display_area);
+        frame.render_widget(pane("memory", self.memory_lines(inner_rows(memory_area))), memory_area);
+
+        let [registers_area, stack_area, disasm_area] =
+            Layout::vertical([Constraint::Length(8), Constraint::Length(STACK_ROWS + 2), Constraint::Min(0)]).areas(right);
+        let cpu = &self.cpu;
+        let registers: Vec<String> = cpu.registers().iter().enumerate().map(|(x, v)| format!("V{:X} {:02X}", x, v)).collect();
+        let mut lines: Vec<Line> = registers.chunks(4).map(|chunk| Line::from(chunk.join("  "))).collect();
+        lines.push(Line::from(format!("I  {:#06x}  PC {:#06x}", cpu.i(), cpu.pc())));
+        lines.push(Line::from(format!("DT {:<6}  ST {}", cpu.delay_timer(), cpu.sound_timer())));
+        frame.render_widget(pane("registers", lines), registers_area);
+
+        // innermost call first
+        let mut lines: Vec<Line> =
+            cpu.stack().iter().rev().take(STACK_ROWS as usize).map(|&addr| Line::from(format!("returns to {}", self.describe(addr as usize)))).collect();
+        if lines.is_empty() {
+            lines.push(Line::from("(empty)"));
+        }
+        frame.render_widget(pane("stack", lines), stack_area);
+
+        frame.render_widget(pane("disassembly", self.disassembly(inner_rows(disasm_area))), disasm_area);
+    }
+
+    /// Lines of 16 bytes of memory from the first address of the pane
+    fn memory_lines(&self, rows: usize) -> Vec<Line<'static>> {
+        let mut lines = Vec::new();
+        for row in 0..rows {
+            let addr = self.memory + row * 16;
+            let len = 16.min(self.cpu.memory_size().saturating_sub(addr));
+            let Ok(bytes) = self.cpu.read_memory(addr, len) else {
+                break;
+            };
+            if bytes.is_empty() {
+                break;
+            }
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            lines.push(Line::from(format!("{:#06x}  {}", addr, hex.join(" "))));
+        }
+        lines
+    }
+
+    /// Lines of the instructions around the selected one, with the names of
+    /// the symbol file above the instructions they name
+    fn disassembly(&self, rows: usize) -> Vec<Line<'static>> {
+        let mut lines = Vec::new();
+        let mut addr = self.cursor.saturating_sub(CONTEXT * 2);
+        while lines.len() < rows {
+            let Some((len, text)) = self.instruction(addr) else {
+                break;
+            };
+            if let Some(name) = self.symbols.name(addr) {
+                lines.push(Line::from(format!("{}:", name)));
+                if lines.len() == rows {
+                    break;
+                }
+            }
+            // `>` for the PC, `*` for a breakpoint and brackets around the selected one
+            let pc = if addr == self.cpu.pc() { '>' } else { ' ' };
+            let breakpoint = if self.cpu.breakpoints().any(|b| b == addr) { '*' } else { ' ' };
+            let text = if addr == self.cursor {
+                format!("{}{}[{}]", pc, breakpoint, text)
+            } else {
+                format!("{}{} {}", pc, breakpoint, text)
+            };
+            lines.push(Line::from(text));
+            addr += len;
+        }
+        lines
+    }
+
+    /// Lines of the display, two rows of pixels per line and one or two
+    /// columns per character in high resolution
+    fn display(&self) -> Vec<String> {
+        let (width, height) = (self.cpu.display_width(), self.cpu.display_height());
+        let planes: Vec<&[bool]> = (0..PLANES).map(|plane| self.cpu.plane(plane)).collect();
+        let lit = |x: usize, y: usize| planes.iter().any(|plane| plane[y * width + x]) as usize;
+        let columns = width / DISPLAY_WIDTH;
+        (0..height / 2)
+            .map(|row| {
+                let y = row * 2;
+                (0..DISPLAY_WIDTH)
+                    .map(|column| {
+                        let (left, right) = (column * columns, column * columns + columns - 1);
+                        QUADRANTS[lit(left, y) | lit(right, y) << 1 | lit(left, y + 1) << 2 | lit(right, y + 1) << 3]
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Box with its title on the top edge around `lines`
+fn pane<'a>(title: &str, lines: Vec<Line<'a>>) -> Paragraph<'a> {
+    Paragraph::new(lines).block(Block::bordered().title(format!(" {} ", title)))
+}
+
+/// Lines inside the box of a pane drawn over `area`
+fn inner_rows(area: Rect) -> usize {
+    area.height.saturating_sub(2) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::DEFAULT_KEYS;
+
+    fn event(c: char, kind: KeyEventKind) -> KeyEvent {
+        KeyEvent::new_with_kind(KeyCode::Char(c), KeyModifiers::NONE, kind)
+    }
+
+    #[test]
+    fn keys_go_up_when_released() {
+        let mut cpu = CPU::new();
+        let mut held = HeldKeys::new(true);
+        // w and e are 5 and 6
+        held.event(&mut cpu, &DEFAULT_KEYS, event('w', KeyEventKind::Press));
+        held.event(&mut cpu, &DEFAULT_KEYS, event('e', KeyEventKind::Press));
+        for _ in 0..KEY_FRAMES * 2 {
+            held.frame(&mut cpu);
+        }
+        assert!(cpu.key(0x5) && cpu.key(0x6));
+        held.event(&mut cpu, &DEFAULT_KEYS, event('w', KeyEventKind::Release));
+        assert!(!cpu.key(0x5) && cpu.key(0x6));
+    }
+
+    #[test]
+    fn keys_go_up_after_a_while_without_releases() {
+        let mut cpu = CPU::new();
+        let mut held = HeldKeys::new(false);
+        held.event(&mut cpu, &DEFAULT_KEYS, event('w', KeyEventKind::Press));
+        held.event(&mut cpu, &DEFAULT_KEYS, event('e', KeyEventKind::Press));
+        assert!(!cpu.key(0x5) && cpu.key(0x6));
+        for _ in 0..KEY_FRAMES {
+            held.frame(&mut cpu);
+        }
+        assert!(cpu.key(0x6));
+        held.frame(&mut cpu);
+        assert!(!cpu.key(0x6));
+    }
+
+    #[test]
+    fn keys_of_the_terminal() {
+        assert_eq!(key(KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL)), Some(Key::Char('\x03')));
+        assert_eq!(key(KeyEvent::new(KeyCode::PageDown, KeyModifiers::NONE)), Some(Key::PageDown));
+        assert_eq!(key(event('s', KeyEventKind::Release)), None);
+        assert_eq!(key(event('s', KeyEventKind::Repeat)), Some(Key::Char('s')));
+    }
+}
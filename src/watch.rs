@@ -0,0 +1,74 @@
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use cpu_caller::{StopReason, CPU};
+
+use crate::cli::{Options, Reload, Source};
+
+/// How long a frame lasts at 60 Hz
+const FRAME: Duration = Duration::from_micros(16_667);
+
+/// Runs the program at `path` in real time and loads it again each time the
+/// file changes, until interrupted. Only `path` is watched, not the files an
+/// assembly program includes
+pub fn run(path: &str, reload: Reload, options: &Options) {
+    let mut cpu = crate::build_cpu(&Source::File(path.to_string()), options);
+    let mut modified = last_modified(path);
+    let mut running = true;
+    println!("watching `{}`, stop with Ctrl-C", path);
+
+    loop {
+        let start = Instant::now();
+        if running {
+            let summary = cpu.advance(cpu.instructions_per_frame() as usize);
+            match summary.stopped {
+                StopReason::InstructionBudgetExhausted | StopReason::WaitingForKey | StopReason::WaitingForVblank => {}
+                StopReason::Error(e) => {
+                    eprintln!("error: {}", e);
+                    running = false;
+                }
+                reason => {
+                    println!("stopped: {:?} at {:#05x}", reason, cpu.pc());
+                    running = false;
+                }
+            }
+        }
+
+        // editors write the file more than once while saving, a change is
+        // picked up at the next frame that sees a new modification time
+        let now = last_modified(path);
+        if now != modified {
+            modified = now;
+            match load(&mut cpu, path, reload, options) {
+                Ok(()) => {
+                    println!("reloaded `{}`", path);
+                    running = true;
+                }
+                // the old program keeps going until the file is fixed
+                Err(e) => eprintln!("error: {}", e),
+            }
+        }
+
+        thread::sleep(FRAME.saturating_sub(start.elapsed()));
+    }
+}
+
+/// Puts the new contents of the file in the CPU
+fn load(cpu: &mut CPU, path: &str, reload: Reload, options: &Options) -> Result<(), String> {
+    match reload {
+        Reload::Reset => *cpu = crate::load_cpu(&Source::File(path.to_string()), options)?,
+        // the bytes after a shorter program keep what the old one had there
+        Reload::KeepState => {
+            for (base, rom) in crate::read_segments(path)? {
+                cpu.write_memory(base, &rom).map_err(|e| format!("can't load `{}`: {}", path, e))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// When the file was last written, `None` while it can't be read
+fn last_modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
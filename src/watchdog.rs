@@ -0,0 +1,100 @@
+/// Longest span of addresses, in bytes, a loop found stuck can go around
+const MAX_LOOP: usize = 32;
+
+/// Notices a program going around the same few instructions without doing
+/// anything, like a loop waiting for a key nobody will press
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Watchdog {
+    limit: u64,                    // instructions without effect before the program counts as stuck
+    quiet: u64,                    // instructions since the last effect, all inside `range`
+    range: Option<(usize, usize)>, // first and last address reached while quiet
+    timers: (u8, u8),              // delay and sound timers seen by the last check
+}
+
+impl Watchdog {
+    pub(crate) fn new(limit: u64) -> Watchdog {
+        Watchdog { limit: limit.max(1), quiet: 0, range: None, timers: (0, 0) }
+    }
+
+    /// Counts the instruction that ran at `pc`, `busy` when it changed
+    /// something, and the timers after it. Timers that moved since the last
+    /// instruction mean time is passing for the program. Returns the
+    /// addresses of the loop once it's been stuck for the limit
+    pub(crate) fn check(&mut self, pc: usize, busy: bool, timers: (u8, u8)) -> Option<(usize, usize)> {
+        let ticked = timers != self.timers;
+        self.timers = timers;
+        if busy || ticked {
+            self.reset();
+            return None;
+        }
+        let (start, end) = match self.range {
+            Some((start, end)) => (start.min(pc), end.max(pc)),
+            None => (pc, pc),
+        };
+        if end - start >= MAX_LOOP {
+            // it's going somewhere, the loop has to start again from here
+            self.range = Some((pc, pc));
+            self.quiet = 1;
+        } else {
+            self.range = Some((start, end));
+            self.quiet += 1;
+        }
+        if self.quiet < self.limit {
+            return None;
+        }
+        self.reset();
+        Some((start, end))
+    }
+
+    pub(crate) fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.quiet = 0;
+        self.range = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks of `count` quiet instructions going around `0x200..=0x202`
+    /// with the timers at `timers`, the last result
+    fn around(watchdog: &mut Watchdog, count: u64, timers: (u8, u8)) -> Option<(usize, usize)> {
+        (0..count).map(|n| watchdog.check(0x200 + 2 * (n as usize % 2), false, timers)).last().flatten()
+    }
+
+    #[test]
+    fn quiet_loops_are_stuck() {
+        let mut watchdog = Watchdog::new(5);
+        assert_eq!(around(&mut watchdog, 4, (0, 0)), None);
+        assert_eq!(watchdog.check(0x202, false, (0, 0)), Some((0x200, 0x202)));
+        // it counts again after telling
+        assert_eq!(around(&mut watchdog, 4, (0, 0)), None);
+    }
+
+    #[test]
+    fn effects_start_counting_again() {
+        let mut watchdog = Watchdog::new(5);
+        around(&mut watchdog, 4, (0, 0));
+        assert_eq!(watchdog.check(0x200, true, (0, 0)), None);
+        assert_eq!(around(&mut watchdog, 4, (0, 0)), None);
+        // a timer that ran
+        assert_eq!(watchdog.check(0x200, false, (3, 0)), None);
+        assert_eq!(around(&mut watchdog, 4, (3, 0)), None);
+        assert_eq!(around(&mut watchdog, 1, (3, 0)), Some((0x200, 0x202)));
+    }
+
+    #[test]
+    fn going_somewhere_isnt_stuck() {
+        let mut watchdog = Watchdog::new(20);
+        for pc in (0x200..0x300).step_by(2) {
+            assert_eq!(watchdog.check(pc, false, (0, 0)), None);
+        }
+        // then a loop nearly as long as they can be
+        let stuck = (0x300..0x320).step_by(2).cycle().take(100).find_map(|pc| watchdog.check(pc, false, (0, 0)));
+        assert_eq!(stuck, Some((0x300, 0x31E)));
+    }
+}
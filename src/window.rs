@@ -0,0 +1,218 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use pixels::{wgpu, Pixels, PixelsBuilder, SurfaceTexture};
+use winit::application::ApplicationHandler;
+use winit::dpi::LogicalSize;
+use winit::event::{ElementState, WindowEvent};
+use winit::event_loop::{ActiveEventLoop, EventLoop};
+use winit::keyboard::{Key, NamedKey};
+use winit::platform::pump_events::{EventLoopExtPumpEvents, PumpStatus};
+use winit::window::{Window, WindowId};
+
+use cpu_caller::{Framebuffer, Renderer, StopReason, CPU, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+use crate::cli::{Options, Scaling};
+use crate::config::Palette;
+use crate::frontend;
+
+/// What draws the picture in a window
+pub trait Surface {
+    /// Shows a new frame of the CPU
+    fn draw(&mut self, framebuffer: &Framebuffer);
+
+    /// Fits the picture to a window of `width` by `height` pixels, which
+    /// may be 0 while the window is minimized
+    fn resize(&mut self, width: u32, height: u32);
+
+    /// Shows the last frame again, when the window needs it
+    fn redraw(&mut self);
+}
+
+/// Picture of the window, scaled on the processor to the size `--scale`,
+/// `--fit` or `--stretch` give it and drawn as it is in the middle of the
+/// window. What's left around it is in the background color
+struct Screen {
+    pixels: Pixels<'static>,
+    palette: Palette,
+    scaling: Scaling,
+    colors: Vec<[u8; 3]>, // of each pixel of the last frame
+    size: (usize, usize), // of the framebuffer drawn last
+    window: (u32, u32),
+    buffer: (u32, u32), // of the pixels, the size of the scaled picture
+}
+
+impl Screen {
+    fn open(window: Arc<Window>, options: &Options) -> Result<Screen, String> {
+        let size = window.inner_size();
+        let [r, g, b] = options.palette.background.map(|c| c as f64 / 255.0);
+        let pixels = PixelsBuilder::new(DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32, SurfaceTexture::new(size.width, size.height, window))
+            .clear_color(wgpu::Color { r, g, b, a: 1.0 })
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Screen {
+            pixels,
+            palette: options.palette,
+            scaling: options.scaling,
+            colors: vec![options.palette.background; DISPLAY_WIDTH * DISPLAY_HEIGHT],
+            size: (DISPLAY_WIDTH, DISPLAY_HEIGHT),
+            window: (size.width, size.height),
+            buffer: (DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32),
+        })
+    }
+
+    /// Scales the last frame to the window and shows it
+    fn present(&mut self) {
+        let buffer = frontend::picture_size(self.scaling, self.size, self.window);
+        if buffer != self.buffer && self.pixels.resize_buffer(buffer.0, buffer.1).is_ok() {
+            self.buffer = buffer;
+        }
+        let scaled = frontend::scale(&self.colors, self.size, self.buffer);
+        for (pixel, [r, g, b]) in self.pixels.frame_mut().chunks_exact_mut(4).zip(scaled) {
+            pixel.copy_from_slice(&[r, g, b, 0xFF]);
+        }
+        let _ = self.pixels.render();
+    }
+}
+
+impl Surface for Screen {
+    fn draw(&mut self, framebuffer: &Framebuffer) {
+        self.colors.clear();
+        self.colors.extend(frontend::colors(framebuffer, &self.palette));
+        self.size = (framebuffer.width(), framebuffer.height());
+        self.present();
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        // a minimized window has no size to draw in
+        if width > 0 && height > 0 && self.pixels.resize_surface(width, height).is_ok() {
+            self.window = (width, height);
+            self.present();
+        }
+    }
+
+    fn redraw(&mut self) {
+        let _ = self.pixels.render();
+    }
+}
+
+/// Opens the surface of the `window` frontend, drawn with pixels
+pub fn open_pixels(window: Arc<Window>, options: &Options) -> Result<Rc<RefCell<dyn Surface>>, String> {
+    Ok(Rc::new(RefCell::new(Screen::open(window, options)?)))
+}
+
+/// Creates the surface of a frontend in the window that just opened
+pub type Open = fn(Arc<Window>, &Options) -> Result<Rc<RefCell<dyn Surface>>, String>;
+
+/// Draws the frames of the CPU on the screen of the window
+struct WindowRenderer(Rc<RefCell<dyn Surface>>);
+
+impl Renderer for WindowRenderer {
+    fn render(&mut self, framebuffer: &Framebuffer) {
+        self.0.borrow_mut().draw(framebuffer);
+    }
+}
+
+/// Receives the events of the window between two frames
+struct App<'a> {
+    options: &'a Options,
+    open: Open,
+    screen: Option<Rc<RefCell<dyn Surface>>>, // once the window is open
+    error: Option<String>,                    // why the window couldn't open
+    keys: Vec<(u8, bool)>,                    // CHIP-8 keys pressed and released since the last frame
+    typed: bool,                              // any key was pressed since the last frame
+    left: bool,                               // the window was closed or Escape pressed
+}
+
+impl ApplicationHandler for App<'_> {
+    fn resumed(&mut self, event_loop: &ActiveEventLoop) {
+        if self.screen.is_some() {
+            return;
+        }
+        let (width, height) = frontend::window_size(self.options.scaling);
+        let attributes = Window::default_attributes().with_title("cpu-caller").with_inner_size(LogicalSize::new(width, height));
+        let window = match event_loop.create_window(attributes) {
+            Ok(window) => Arc::new(window),
+            Err(e) => return self.error = Some(e.to_string()),
+        };
+        match (self.open)(window, self.options) {
+            Ok(screen) => self.screen = Some(screen),
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    fn window_event(&mut self, _event_loop: &ActiveEventLoop, _window: WindowId, event: WindowEvent) {
+        match event {
+            WindowEvent::CloseRequested => self.left = true,
+            WindowEvent::Resized(size) => {
+                if let Some(screen) = &self.screen {
+                    screen.borrow_mut().resize(size.width, size.height);
+                }
+            }
+            WindowEvent::RedrawRequested => {
+                if let Some(screen) = &self.screen {
+                    screen.borrow_mut().redraw();
+                }
+            }
+            WindowEvent::KeyboardInput { event, .. } => {
+                let pressed = event.state == ElementState::Pressed;
+                match event.logical_key {
+                    Key::Named(NamedKey::Escape) => self.left = true,
+                    _ if event.repeat => {}
+                    Key::Character(text) => {
+                        let key = text.chars().next().and_then(|c| frontend::chip8_key(&self.options.keys, c));
+                        self.keys.extend(key.map(|key| (key, pressed)));
+                    }
+                    _ => {}
+                }
+                self.typed |= pressed;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Runs the program 60 frames per second in a window drawn by the surface
+/// `open` creates, with the keys of the keyboard going to the program.
+/// Gives back what stopped it, or `None` when the window was closed or
+/// Escape pressed
+pub fn run(cpu: &mut CPU, options: &Options, open: Open) -> Result<Option<StopReason>, String> {
+    let mut event_loop = EventLoop::new().map_err(|e| e.to_string())?;
+    let mut app = App { options, open, screen: None, error: None, keys: Vec::new(), typed: false, left: false };
+
+    // the window opens once the event loop starts
+    let screen = loop {
+        if let PumpStatus::Exit(_) = event_loop.pump_app_events(Some(Duration::ZERO), &mut app) {
+            return Ok(None);
+        }
+        if let Some(e) = app.error.take() {
+            return Err(e);
+        }
+        if let Some(screen) = &app.screen {
+            break screen.clone();
+        }
+    };
+    screen.borrow_mut().draw(cpu.screen());
+    cpu.set_renderer(Box::new(WindowRenderer(screen)));
+
+    let reason = frontend::run_frames(cpu, |cpu| {
+        let status = event_loop.pump_app_events(Some(Duration::ZERO), &mut app);
+        for (key, pressed) in app.keys.drain(..) {
+            cpu.set_key(key, pressed);
+        }
+        !app.left && matches!(status, PumpStatus::Continue)
+    });
+
+    if reason.is_some() {
+        // the last picture stays until the window is closed or a key pressed
+        app.typed = false;
+        while !app.left && !app.typed {
+            if let PumpStatus::Exit(_) = event_loop.pump_app_events(None, &mut app) {
+                break;
+            }
+        }
+    }
+    Ok(reason)
+}